@@ -0,0 +1,70 @@
+//! Batch analysis of positions read from a file.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+};
+
+use crate::{
+    batching,
+    engine::{Engine, SearchResults},
+    game::GameImpl,
+    params::Params,
+    timemgmt::Limits,
+};
+
+/// The number of alternative moves written for each position.
+const TOP_K: usize = 3;
+
+/// Searches every position in `fen_path`, one FEN per line, and writes the results to `out_path` as CSV.
+/// Each row holds the position, the best move, the value for the side to move, and the most-visited moves
+/// with their visit counts. Blank lines and lines starting with `#` are skipped.
+pub fn analyse_file<G: GameImpl>(
+    fen_path: &str,
+    out_path: &str,
+    limits: Limits,
+    model_path: Option<&str>,
+) -> anyhow::Result<()> {
+    let network = batching::load_network::<G>(model_path.unwrap_or("./model.onnx"))?;
+    let executor = batching::executor(&network, 1)?.into_iter().next().unwrap();
+    let mut engine = Engine::new(Params::default(), limits, &G::default(), executor);
+
+    let positions = BufReader::new(File::open(fen_path)?);
+    let mut out = BufWriter::new(File::create(out_path)?);
+    writeln!(out, "fen,best_move,value,alternatives")?;
+
+    let mut analysed = 0;
+    for (line_number, line) in positions.lines().enumerate() {
+        let line = line?;
+        let fen = line.trim();
+        if fen.is_empty() || fen.starts_with('#') {
+            continue;
+        }
+        let Ok(board) = fen.parse::<G>() else {
+            log::warn!("skipping invalid fen on line {}: {fen}", line_number + 1);
+            continue;
+        };
+        if board.outcome().is_some() {
+            log::warn!("skipping finished game on line {}: {fen}", line_number + 1);
+            continue;
+        }
+
+        engine.set_position(&board);
+        engine.set_limits(limits);
+        let SearchResults { best_move, value, root_moves, .. } = engine.go()?;
+        let alternatives = root_moves
+            .iter()
+            .filter(|root_move| root_move.visits > 0)
+            .take(TOP_K)
+            .map(|root_move| format!("{}:{}", root_move.mv, root_move.visits))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(out, "{fen},{best_move},{value:.4},{alternatives}")?;
+        analysed += 1;
+    }
+    out.flush()?;
+
+    println!("Analysed {analysed} positions, results written to {out_path}");
+
+    Ok(())
+}
@@ -1,35 +1,81 @@
-use std::fmt::Debug;
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::File,
+    io::Read,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    path::Path,
+    ptr::NonNull,
+};
+
+use anyhow::Context;
+
+/// The integer type underlying a handle.
+/// This is 32 bits by default to keep nodes small, which limits the tree to about four billion nodes.
+#[cfg(not(feature = "huge-trees"))]
+type RawHandle = u32;
+/// The integer type underlying a handle.
+#[cfg(feature = "huge-trees")]
+type RawHandle = u64;
+
+/// The most objects an arena can hold, as the largest index is reserved for the null handle.
+#[allow(clippy::cast_possible_truncation)]
+pub const CAPACITY: usize = RawHandle::MAX as usize;
 
 /// A handle to an object in the arena.
 ///
 /// TODO: Make this non-null, with a separate nullable handle.
 #[derive(Clone, Copy, PartialEq, Eq)]
-pub struct Handle(u32);
+pub struct Handle(RawHandle);
 
 impl Handle {
     /// Returns the null handle.
     pub const fn null() -> Self {
-        Self(u32::MAX)
+        Self(RawHandle::MAX)
     }
 
     /// Returns true if this is the null handle.
     pub const fn is_null(self) -> bool {
-        self.0 == u32::MAX
+        self.0 == RawHandle::MAX
     }
 
     /// Returns the index of this handle.
+    #[allow(clippy::cast_possible_truncation)]
     pub const fn index(self) -> usize {
         assert!(!self.is_null());
         self.0 as usize
     }
 
     /// Returns the handle with the given index.
+    /// Panics if the index cannot be represented, rather than aliasing another node or the null handle.
     pub fn from_index<T>(index: usize, memory: &[T]) -> Self {
         assert!(index < memory.len());
+        assert!(index < CAPACITY, "arena index {index} overflows the handle type, the tree is too large");
         Self(index.try_into().expect("index too large"))
     }
 }
 
+/// Hints to the CPU that the object behind `handle` will be read soon.
+/// Does nothing for the null handle, or on platforms without a prefetch instruction.
+#[inline]
+pub fn prefetch<T>(memory: &[T], handle: Handle) {
+    if handle.is_null() {
+        return;
+    }
+    #[cfg(target_arch = "x86_64")]
+    if let Some(object) = memory.get(handle.index()) {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+        // SAFETY: prefetching is only a hint, and the pointer is valid regardless.
+        #[allow(unused_unsafe)]
+        unsafe {
+            _mm_prefetch::<_MM_HINT_T0>(std::ptr::from_ref(object).cast());
+        }
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = memory;
+}
+
 impl Debug for Handle {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if self.is_null() {
@@ -39,3 +85,340 @@ impl Debug for Handle {
         }
     }
 }
+
+/// The number of bytes at the start of a memory-mapped arena that are locked into RAM.
+/// Nodes near the root are allocated first and visited on every descent, so they live here.
+const RESIDENT_BYTES: usize = 256 << 20;
+/// The bytes an arena file starts with, so that a file that is not one is never overwritten by mistake.
+const MAGIC: &[u8] = b"veritas search tree\n";
+/// The size of the header of an arena file, which holds `MAGIC`. This is a page, so that the objects after it
+/// stay aligned.
+const HEADER_BYTES: usize = 4096;
+/// The alignment of the spare bytes after the objects, which is enough for anything they are carved into.
+const SPARE_ALIGN: usize = 64;
+
+/// Growable storage for the search tree.
+///
+/// By default this is an ordinary `Vec`, but it can instead be backed by a memory-mapped
+/// file of fixed capacity, letting trees larger than RAM spill to disk.
+pub struct Arena<T> {
+    storage: Storage<T>,
+}
+
+enum Storage<T> {
+    Heap(Vec<T>),
+    /// A file holding a header, then room for `capacity` objects, then spare bytes for what the objects own.
+    Mapped {
+        map: memmap2::MmapMut,
+        len: usize,
+        capacity: usize,
+        spare: SpareState,
+        _marker: PhantomData<T>,
+    },
+}
+
+/// The bookkeeping of the spare bytes of a memory-mapped arena.
+#[derive(Default)]
+struct SpareState {
+    /// The number of bytes from the start that have been handed out.
+    used: usize,
+    /// The offsets of runs of bytes that were handed out and given back, by their lengths.
+    free: HashMap<usize, Vec<usize>>,
+    /// Whether running out of bytes has been reported.
+    exhausted: bool,
+}
+
+/// The spare bytes of a memory-mapped arena, handed out for data the objects own, such as the edge lists
+/// of nodes, so that it lives in the file too. Runs of bytes given back are reused for runs of the same
+/// length, and once the bytes run out, objects must keep what they own on the heap.
+pub struct Spare<'a> {
+    base: *mut u8,
+    len: usize,
+    state: &'a mut SpareState,
+}
+
+impl Spare<'_> {
+    /// Takes room for `count` values of type `U`, or returns `None` if there is not enough left.
+    pub fn alloc<U>(&mut self, count: usize) -> Option<NonNull<U>> {
+        let bytes = count.checked_mul(std::mem::size_of::<U>())?;
+        let base = self.base;
+        let aligned = |offset: usize| base.wrapping_add(offset).align_offset(std::mem::align_of::<U>()) == 0;
+        if let Some(offsets) = self.state.free.get_mut(&bytes) {
+            if let Some(index) = offsets.iter().position(|&offset| aligned(offset)) {
+                let offset = offsets.swap_remove(index);
+                // SAFETY: the run was handed out before, so lies within the spare bytes.
+                return NonNull::new(unsafe { base.add(offset) }.cast());
+            }
+        }
+        // SAFETY: `used` never exceeds `len`, so the pointer is within the spare bytes or one past them.
+        let next = unsafe { base.add(self.state.used) };
+        let start = self.state.used.checked_add(next.align_offset(std::mem::align_of::<U>()))?;
+        let end = start.checked_add(bytes)?;
+        if end > self.len {
+            if !std::mem::replace(&mut self.state.exhausted, true) {
+                log::warn!("the tree file is out of room for edges, so further edges are kept on the heap");
+            }
+            return None;
+        }
+        self.state.used = end;
+        // SAFETY: `start` lies within the spare bytes, as `end` does.
+        NonNull::new(unsafe { base.add(start) }.cast())
+    }
+
+    /// Gives back the room for `count` values of type `U` at `ptr`, taken by `alloc`, to be reused.
+    /// Pointers outside the spare bytes, such as those to the heap, are ignored.
+    pub fn free<U>(&mut self, ptr: NonNull<U>, count: usize) {
+        let bytes = count * std::mem::size_of::<U>();
+        let offset = (ptr.as_ptr() as usize).wrapping_sub(self.base as usize);
+        if bytes > 0 && offset < self.len {
+            self.state.free.entry(bytes).or_default().push(offset);
+        }
+    }
+}
+
+impl<T> Arena<T> {
+    /// Creates a new heap-backed arena.
+    pub const fn new() -> Self {
+        Self { storage: Storage::Heap(Vec::new()) }
+    }
+
+    /// Creates a new arena holding up to `capacity` objects and `spare_bytes` bytes of what they own,
+    /// backed by the file at `path`. The file is created, or truncated if it is an old arena file, and is only
+    /// scratch space for the lifetime of the arena. Any other file is left alone, and an error returned.
+    pub fn mapped(path: &Path, capacity: usize, spare_bytes: usize) -> anyhow::Result<Self> {
+        let objects = capacity.checked_mul(std::mem::size_of::<T>()).context("arena size overflows")?;
+        let bytes = (HEADER_BYTES + objects)
+            .checked_next_multiple_of(SPARE_ALIGN)
+            .and_then(|start| start.checked_add(spare_bytes))
+            .context("arena size overflows")?;
+        if let Ok(mut existing) = File::open(path) {
+            let mut header = vec![0; MAGIC.len()];
+            let is_arena = existing.read_exact(&mut header).is_ok() && header == MAGIC;
+            let is_empty = existing.metadata().is_ok_and(|metadata| metadata.len() == 0);
+            anyhow::ensure!(
+                is_arena || is_empty,
+                "refusing to overwrite {}, which is not a search tree file",
+                path.display()
+            );
+        }
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("failed to open arena file {}", path.display()))?;
+        file.set_len(bytes.try_into()?).with_context(|| format!("failed to resize arena file to {bytes} bytes"))?;
+        // SAFETY: the file is ours alone for the lifetime of the arena.
+        let mut map = unsafe { memmap2::MmapMut::map_mut(&file) }.context("failed to map arena file")?;
+        map[..MAGIC.len()].copy_from_slice(MAGIC);
+        #[cfg(unix)]
+        {
+            let resident = RESIDENT_BYTES.min(bytes);
+            // SAFETY: the range lies within the mapping.
+            if resident > 0 && unsafe { libc::mlock(map.as_mut_ptr().cast(), resident) } != 0 {
+                log::warn!("failed to lock the first {resident} bytes of the arena into memory");
+            }
+        }
+        Ok(Self {
+            storage: Storage::Mapped { map, len: 0, capacity, spare: SpareState::default(), _marker: PhantomData },
+        })
+    }
+
+    /// The most objects this arena can hold, if it is bounded.
+    pub const fn capacity_limit(&self) -> Option<usize> {
+        match self.storage {
+            Storage::Heap(_) => None,
+            Storage::Mapped { capacity, .. } => Some(capacity),
+        }
+    }
+
+    /// Appends an object to the arena.
+    pub fn push(&mut self, value: T) {
+        match &mut self.storage {
+            Storage::Heap(vec) => vec.push(value),
+            Storage::Mapped { map, len, capacity, .. } => {
+                assert!(*len < *capacity, "memory-mapped arena is full ({capacity} objects)");
+                // SAFETY: the slot is within the mapping and currently uninitialised.
+                unsafe { objects(map).add(*len).write(value) };
+                *len += 1;
+            }
+        }
+    }
+
+    /// Removes all objects from the arena, and with them, everything they kept in its spare bytes.
+    pub fn clear(&mut self) {
+        self.retain(|_| false);
+        if let Storage::Mapped { spare, .. } = &mut self.storage {
+            *spare = SpareState::default();
+        }
+    }
+
+    /// Returns the objects of the arena, together with its spare bytes if it is memory-mapped.
+    pub fn with_spare(&mut self) -> (&mut [T], Option<Spare<'_>>) {
+        match &mut self.storage {
+            Storage::Heap(vec) => (vec, None),
+            Storage::Mapped { map, len, capacity, spare, .. } => {
+                let spare_start = (HEADER_BYTES + *capacity * std::mem::size_of::<T>()).next_multiple_of(SPARE_ALIGN);
+                let spare_len = map.len() - spare_start;
+                // SAFETY: the first `len` object slots are initialised, and the spare bytes lie after every
+                // object slot, so the two borrows do not overlap.
+                unsafe {
+                    let objects = std::slice::from_raw_parts_mut(objects(map), *len);
+                    let spare = Spare { base: map.as_mut_ptr().add(spare_start), len: spare_len, state: spare };
+                    (objects, Some(spare))
+                }
+            }
+        }
+    }
+
+    /// Retains only the objects for which the predicate returns true, preserving their order.
+    pub fn retain(&mut self, mut f: impl FnMut(&T) -> bool) {
+        match &mut self.storage {
+            Storage::Heap(vec) => vec.retain(f),
+            Storage::Mapped { map, len, .. } => {
+                let base = objects(map);
+                let old_len = std::mem::replace(len, 0);
+                let mut kept = 0;
+                for index in 0..old_len {
+                    // SAFETY: slots below `old_len` are initialised, and each is read or dropped exactly
+                    // once. `len` is zero meanwhile, so a panicking predicate leaks rather than double-drops.
+                    unsafe {
+                        let slot = base.add(index);
+                        if f(&*slot) {
+                            if kept != index {
+                                std::ptr::copy_nonoverlapping(slot, base.add(kept), 1);
+                            }
+                            kept += 1;
+                        } else {
+                            std::ptr::drop_in_place(slot);
+                        }
+                    }
+                }
+                *len = kept;
+            }
+        }
+    }
+}
+
+/// The first object slot of a memory-mapped arena, after its header.
+fn objects<T>(map: &mut memmap2::MmapMut) -> *mut T {
+    // SAFETY: the mapping is at least a header long.
+    unsafe { map.as_mut_ptr().add(HEADER_BYTES).cast() }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Deref for Arena<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.storage {
+            Storage::Heap(vec) => vec,
+            // SAFETY: the first `len` slots are initialised.
+            Storage::Mapped { map, len, .. } => unsafe {
+                std::slice::from_raw_parts(map.as_ptr().add(HEADER_BYTES).cast(), *len)
+            },
+        }
+    }
+}
+
+impl<T> DerefMut for Arena<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match &mut self.storage {
+            Storage::Heap(vec) => vec,
+            // SAFETY: the first `len` slots are initialised.
+            Storage::Mapped { map, len, .. } => unsafe { std::slice::from_raw_parts_mut(objects(map), *len) },
+        }
+    }
+}
+
+impl<T> Drop for Arena<T> {
+    fn drop(&mut self) {
+        if matches!(self.storage, Storage::Mapped { .. }) {
+            self.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[test]
+    fn mapped_arena_behaves_like_a_vec() {
+        let path = std::env::temp_dir().join(format!("veritas-arena-test-{}", std::process::id()));
+        let counter = Rc::new(());
+        {
+            let mut arena = Arena::mapped(&path, 8, 0).unwrap();
+            for i in 0..6 {
+                arena.push((i, Rc::clone(&counter)));
+            }
+            assert_eq!(arena.len(), 6);
+            arena.retain(|(i, _)| i % 2 == 0);
+            assert_eq!(arena.iter().map(|(i, _)| *i).collect::<Vec<_>>(), [0, 2, 4]);
+            // the three removed elements have been dropped.
+            assert_eq!(Rc::strong_count(&counter), 4);
+            arena[1].0 = 7;
+            assert_eq!(arena[1].0, 7);
+        }
+        assert_eq!(Rc::strong_count(&counter), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn spare_bytes_are_handed_out_until_they_run_out() {
+        let path = std::env::temp_dir().join(format!("veritas-arena-spare-test-{}", std::process::id()));
+        let mut arena = Arena::<u8>::mapped(&path, 3, 16).unwrap();
+        arena.push(1);
+        let (objects, spare) = arena.with_spare();
+        assert_eq!(objects, [1]);
+        let mut spare = spare.unwrap();
+        let first = spare.alloc::<u32>(2).unwrap();
+        assert_eq!(first.as_ptr() as usize % std::mem::align_of::<u32>(), 0);
+        assert!(spare.alloc::<u32>(2).is_some());
+        assert!(spare.alloc::<u32>(1).is_none());
+        arena.clear();
+        assert!(arena.with_spare().1.unwrap().alloc::<u32>(4).is_some());
+        drop(arena);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn freed_spare_bytes_are_reused() {
+        let path = std::env::temp_dir().join(format!("veritas-arena-reuse-test-{}", std::process::id()));
+        let mut arena = Arena::<u8>::mapped(&path, 1, 16).unwrap();
+        let mut spare = arena.with_spare().1.unwrap();
+        let first = spare.alloc::<u32>(2).unwrap();
+        let second = spare.alloc::<u32>(2).unwrap();
+        assert!(spare.alloc::<u32>(2).is_none());
+        spare.free(first, 2);
+        // heap memory is never taken for spare bytes.
+        spare.free(NonNull::from(&mut 0_u32), 1);
+        assert!(spare.alloc::<u32>(1).is_none());
+        assert_eq!(spare.alloc::<u32>(2), Some(first));
+        spare.free(second, 2);
+        assert_eq!(spare.alloc::<u32>(2), Some(second));
+        drop(arena);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn other_files_are_never_overwritten() {
+        let path = std::env::temp_dir().join(format!("veritas-arena-clobber-test-{}", std::process::id()));
+        std::fs::write(&path, "precious").unwrap();
+        assert!(Arena::<u8>::mapped(&path, 8, 0).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "precious");
+        std::fs::remove_file(&path).unwrap();
+        drop(Arena::<u8>::mapped(&path, 8, 0).unwrap());
+        // an old tree file is fair game.
+        drop(Arena::<u8>::mapped(&path, 8, 0).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,504 @@
+//! Ataxx on square boards of any size up to 8x8, with optional blocked squares.
+//!
+//! The standard 7x7 game is served by `ataxxgen`; this implementation exists so that
+//! the engine can play the smaller (and gapped) variants.
+//!
+//! Boards of every size are laid out on the largest, with the squares off a smaller board blocked,
+//! so that the policy and the network's input have the same shape whatever the size.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+use crate::game::Player;
+
+/// The smallest supported board side length.
+pub const MIN_SIZE: usize = 3;
+/// The largest supported board side length, such that a board fits in a `u64`.
+pub const MAX_SIZE: usize = 8;
+/// The number of squares of the largest board, on which every board is laid out.
+pub const SQUARES: usize = MAX_SIZE * MAX_SIZE;
+
+/// The halfmove clock value at which the game is drawn.
+const HALFMOVE_LIMIT: u32 = 100;
+
+/// Index of a square given its row and column.
+const fn square(row: usize, col: usize) -> usize {
+    row * MAX_SIZE + col
+}
+
+/// Bitboard of the squares off a board of the given size, which play as blocked squares.
+const fn off_board(size: usize) -> u64 {
+    let mut bb = 0;
+    let mut row = 0;
+    while row < MAX_SIZE {
+        let mut col = 0;
+        while col < MAX_SIZE {
+            if row >= size || col >= size {
+                bb |= 1 << square(row, col);
+            }
+            col += 1;
+        }
+        row += 1;
+    }
+    bb
+}
+
+/// Bitboard of the squares within `radius` king-steps of `sq`, excluding `sq` itself.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+fn neighbours(sq: usize, radius: isize) -> u64 {
+    let (row, col) = ((sq / MAX_SIZE) as isize, (sq % MAX_SIZE) as isize);
+    let last = MAX_SIZE as isize - 1;
+    let mut bb = 0;
+    for r in (row - radius).max(0)..=(row + radius).min(last) {
+        for c in (col - radius).max(0)..=(col + radius).min(last) {
+            if r != row || c != col {
+                bb |= 1 << square(r as usize, c as usize);
+            }
+        }
+    }
+    bb
+}
+
+/// The squares a stone on `sq` can jump to.
+fn jumps(sq: usize) -> u64 {
+    neighbours(sq, 2) & !neighbours(sq, 1)
+}
+
+/// Iterates over the set bits of a bitboard.
+fn squares(mut bb: u64) -> impl Iterator<Item = usize> {
+    std::iter::from_fn(move || {
+        if bb == 0 {
+            return None;
+        }
+        let sq = bb.trailing_zeros() as usize;
+        bb &= bb - 1;
+        Some(sq)
+    })
+}
+
+fn square_name(sq: usize) -> String {
+    let file = (b'a' + u8::try_from(sq % MAX_SIZE).expect("file out of range")) as char;
+    format!("{file}{}", sq / MAX_SIZE + 1)
+}
+
+/// Parses a square of a board of any size, so whether it is on a smaller board is left to the legality of moves.
+fn parse_square(s: &str) -> anyhow::Result<usize> {
+    let mut chars = s.chars();
+    let file = chars.next().with_context(|| "empty square")?;
+    if !file.is_ascii_lowercase() {
+        anyhow::bail!("invalid file {file:?} in square {s:?}");
+    }
+    let file = usize::from(file as u8 - b'a');
+    let rank: usize = chars.as_str().parse().with_context(|| format!("invalid rank in square {s:?}"))?;
+    if file >= MAX_SIZE || rank == 0 || rank > MAX_SIZE {
+        anyhow::bail!("square {s:?} is off the largest board, which is {MAX_SIZE}x{MAX_SIZE}");
+    }
+    Ok(square(rank - 1, file))
+}
+
+/// An ataxx move: a single (clone) move, a double (jump) move, or a pass.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Move {
+    from: u8,
+    to: u8,
+}
+
+impl Move {
+    const PASS_SQUARE: u8 = u8::MAX;
+
+    fn single(to: usize) -> Self {
+        Self::double(to, to)
+    }
+
+    fn double(from: usize, to: usize) -> Self {
+        let [from, to] = [from, to].map(|x| u8::try_from(x).expect("square out of range"));
+        Self { from, to }
+    }
+
+    const fn pass() -> Self {
+        Self { from: Self::PASS_SQUARE, to: Self::PASS_SQUARE }
+    }
+
+    pub const fn is_pass(self) -> bool {
+        self.from == Self::PASS_SQUARE
+    }
+
+    pub const fn is_single(self) -> bool {
+        self.from == self.to
+    }
+
+    /// The index of this move in the policy, `from * squares + to`.
+    /// Passes are only ever legal alone, so they share index zero.
+    pub const fn index(self) -> usize {
+        if self.is_pass() {
+            0
+        } else {
+            self.from as usize * SQUARES + self.to as usize
+        }
+    }
+}
+
+impl Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_pass() {
+            write!(f, "0000")
+        } else if self.is_single() {
+            write!(f, "{}", square_name(self.to.into()))
+        } else {
+            write!(f, "{}{}", square_name(self.from.into()), square_name(self.to.into()))
+        }
+    }
+}
+
+impl FromStr for Move {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "0000" {
+            return Ok(Self::pass());
+        }
+        // split at the start of the second file letter, if any.
+        let split = s.char_indices().skip(1).find(|(_, c)| c.is_ascii_alphabetic()).map(|(i, _)| i);
+        match split {
+            None => Ok(Self::single(parse_square(s)?)),
+            Some(i) => Ok(Self::double(parse_square(&s[..i])?, parse_square(&s[i..])?)),
+        }
+    }
+}
+
+/// An ataxx position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Board {
+    size: usize,
+    /// The stones of the first and second players.
+    stones: [u64; 2],
+    /// Squares that can never be occupied, including those off the board.
+    blocked: u64,
+    turn: Player,
+    halfmove_clock: u32,
+    fullmove_number: u32,
+}
+
+impl Default for Board {
+    /// The standard 7x7 starting position.
+    fn default() -> Self {
+        Self::new(7)
+    }
+}
+
+impl Board {
+    /// Creates a board of the given size with the standard corner starting position.
+    pub const fn new(size: usize) -> Self {
+        let top_left = square(size - 1, 0);
+        let top_right = square(size - 1, size - 1);
+        let bottom_left = square(0, 0);
+        let bottom_right = square(0, size - 1);
+        Self {
+            size,
+            stones: [1 << top_left | 1 << bottom_right, 1 << top_right | 1 << bottom_left],
+            blocked: off_board(size),
+            turn: Player::First,
+            halfmove_clock: 0,
+            fullmove_number: 1,
+        }
+    }
+
+    pub const fn turn(&self) -> Player {
+        self.turn
+    }
+
+    /// The side length of the board.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    const fn empty(&self) -> u64 {
+        !(self.stones[0] | self.stones[1] | self.blocked)
+    }
+
+    const fn side_index(player: Player) -> usize {
+        match player {
+            Player::Second => 1,
+            _ => 0,
+        }
+    }
+
+    fn can_move(&self, player: Player) -> bool {
+        let ours = self.stones[Self::side_index(player)];
+        squares(self.empty()).any(|sq| neighbours(sq, 2) & ours != 0)
+    }
+
+    pub fn outcome(&self) -> Option<Player> {
+        let [first, second] = self.stones.map(u64::count_ones);
+        let over = first == 0
+            || second == 0
+            || self.empty() == 0
+            || self.halfmove_clock >= HALFMOVE_LIMIT
+            || (!self.can_move(Player::First) && !self.can_move(Player::Second));
+        if !over {
+            return None;
+        }
+        Some(match first.cmp(&second) {
+            std::cmp::Ordering::Greater => Player::First,
+            std::cmp::Ordering::Less => Player::Second,
+            std::cmp::Ordering::Equal => Player::None,
+        })
+    }
+
+    pub fn make_move(&mut self, mv: Move) {
+        let us = Self::side_index(self.turn);
+        let them = 1 - us;
+        if mv.is_pass() {
+            self.halfmove_clock += 1;
+        } else {
+            let to = usize::from(mv.to);
+            if mv.is_single() {
+                self.halfmove_clock = 0;
+            } else {
+                self.stones[us] &= !(1 << mv.from);
+                self.halfmove_clock += 1;
+            }
+            let captured = neighbours(to, 1) & self.stones[them];
+            self.stones[us] |= 1 << to | captured;
+            self.stones[them] &= !captured;
+        }
+        if self.turn == Player::Second {
+            self.fullmove_number += 1;
+        }
+        self.turn = self.turn.opposite();
+    }
+
+    /// Generate a list of legal moves.
+    /// Iteration short-circuits if the callback returns `true`.
+    pub fn generate_moves(&self, mut f: impl FnMut(Move) -> bool) {
+        if self.outcome().is_some() {
+            return;
+        }
+        let ours = self.stones[Self::side_index(self.turn)];
+        let mut any = false;
+        for to in squares(self.empty()) {
+            if neighbours(to, 1) & ours != 0 {
+                any = true;
+                if f(Move::single(to)) {
+                    return;
+                }
+            }
+        }
+        for to in squares(self.empty()) {
+            for from in squares(jumps(to) & ours) {
+                any = true;
+                if f(Move::double(from, to)) {
+                    return;
+                }
+            }
+        }
+        if !any {
+            f(Move::pass());
+        }
+    }
+
+    /// Calls the callback with the index and owner of every stone on the board.
+    pub fn ownership(&self, mut f: impl FnMut(usize, Player)) {
+        for (side, player) in [Player::First, Player::Second].into_iter().enumerate() {
+            for sq in squares(self.stones[side]) {
+                f(sq, player);
+            }
+        }
+    }
+
+    /// Calls the callback with the index of every active feature:
+    /// the side to move's stones, then the opponent's stones, then the blocked squares, which mark out
+    /// the edge of a board smaller than the largest.
+    pub fn feature_map(&self, mut f: impl FnMut(usize)) {
+        let us = Self::side_index(self.turn);
+        for sq in squares(self.stones[us]) {
+            f(sq);
+        }
+        for sq in squares(self.stones[1 - us]) {
+            f(SQUARES + sq);
+        }
+        for sq in squares(self.blocked) {
+            f(2 * SQUARES + sq);
+        }
+    }
+
+    const fn char_at(&self, sq: usize) -> Option<char> {
+        if self.stones[0] & 1 << sq != 0 {
+            Some('x')
+        } else if self.stones[1] & 1 << sq != 0 {
+            Some('o')
+        } else if self.blocked & 1 << sq != 0 {
+            Some('-')
+        } else {
+            None
+        }
+    }
+
+    pub fn fen(&self) -> String {
+        let mut fen = String::new();
+        for row in (0..self.size).rev() {
+            let mut empty = 0;
+            for col in 0..self.size {
+                let Some(c) = self.char_at(square(row, col)) else {
+                    empty += 1;
+                    continue;
+                };
+                if empty > 0 {
+                    fen += &empty.to_string();
+                    empty = 0;
+                }
+                fen.push(c);
+            }
+            if empty > 0 {
+                fen += &empty.to_string();
+            }
+            if row > 0 {
+                fen.push('/');
+            }
+        }
+        let side = if self.turn == Player::First { 'x' } else { 'o' };
+        format!("{fen} {side} {} {}", self.halfmove_clock, self.fullmove_number)
+    }
+}
+
+impl Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in (0..self.size).rev() {
+            for col in 0..self.size {
+                write!(f, "{}", self.char_at(square(row, col)).unwrap_or('.'))?;
+            }
+            if row > 0 {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Board {
+    type Err = anyhow::Error;
+
+    /// Parses a FEN of the form `x3o/5/2-2/5/o3x x 0 1`, taking the board size from the FEN.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_ascii_whitespace();
+        let rows = parts.next().with_context(|| format!("empty fen {s:?}"))?.split('/').collect::<Vec<_>>();
+        let size = rows.len();
+        if !(MIN_SIZE..=MAX_SIZE).contains(&size) {
+            anyhow::bail!("ataxx board size must be between {MIN_SIZE} and {MAX_SIZE}, got {size} in fen {s:?}");
+        }
+        let mut board = Self {
+            size,
+            stones: [0; 2],
+            blocked: off_board(size),
+            turn: Player::First,
+            halfmove_clock: 0,
+            fullmove_number: 1,
+        };
+        for (i, row_text) in rows.iter().enumerate() {
+            let row = size - 1 - i;
+            let mut col = 0;
+            for c in row_text.chars() {
+                if let Some(digit) = c.to_digit(10) {
+                    col += digit as usize;
+                    continue;
+                }
+                if col >= size {
+                    anyhow::bail!("row {row_text:?} is too long for a {size}x{size} board in fen {s:?}");
+                }
+                let bit = 1 << square(row, col);
+                match c {
+                    'x' => board.stones[0] |= bit,
+                    'o' => board.stones[1] |= bit,
+                    '-' => board.blocked |= bit,
+                    _ => anyhow::bail!("invalid character {c:?} in fen {s:?}"),
+                }
+                col += 1;
+            }
+            if col != size {
+                anyhow::bail!("row {row_text:?} does not have {size} squares in fen {s:?}");
+            }
+        }
+        board.turn = match parts.next() {
+            Some("x") => Player::First,
+            Some("o") => Player::Second,
+            other => anyhow::bail!("invalid side-to-move {other:?} in fen {s:?}"),
+        };
+        if let Some(halfmove_clock) = parts.next() {
+            board.halfmove_clock =
+                halfmove_clock.parse().with_context(|| format!("invalid halfmove clock in {s:?}"))?;
+        }
+        if let Some(fullmove_number) = parts.next() {
+            board.fullmove_number =
+                fullmove_number.parse().with_context(|| format!("invalid fullmove number in {s:?}"))?;
+        }
+        Ok(board)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn perft(board: Board, depth: usize) -> u64 {
+        if depth == 0 {
+            return 1;
+        }
+        let mut count = 0;
+        board.generate_moves(|mv| {
+            let mut child = board;
+            child.make_move(mv);
+            count += perft(child, depth - 1);
+            false
+        });
+        count
+    }
+
+    #[test]
+    fn standard_perft() {
+        let board = Board::new(7);
+        assert_eq!(board.fen(), "x5o/7/7/7/7/7/o5x x 0 1");
+        assert_eq!(perft(board, 1), 16);
+        assert_eq!(perft(board, 2), 256);
+        assert_eq!(perft(board, 3), 6460);
+    }
+
+    #[test]
+    fn small_board_fen_round_trip() {
+        let board = "x3o/5/2-2/5/o3x x 0 1".parse::<Board>().unwrap();
+        assert_eq!(board.size, 5);
+        assert_eq!(board.fen(), "x3o/5/2-2/5/o3x x 0 1");
+        assert_eq!("x5o/7/7/7/7/7/o5x x 0 1".parse::<Board>().unwrap(), Board::new(7));
+    }
+
+    #[test]
+    fn stones_never_leave_a_smaller_board() {
+        // x on e5 could only clone or jump off the board, so x must make some other move.
+        let board = "4x/5/5/5/o4 x 0 1".parse::<Board>().unwrap();
+        assert_eq!(perft(board, 1), 8);
+        board.generate_moves(|mv| {
+            assert!(mv.to_string().chars().all(|c| !matches!(c, 'f'..='h' | '6'..='8')), "{mv} leaves the board");
+            false
+        });
+    }
+
+    #[test]
+    fn captures_and_passes() {
+        // x is walled in, so must pass.
+        let board = "x--2/---2/---2/5/4o x 0 1".parse::<Board>().unwrap();
+        let mut moves = Vec::new();
+        board.generate_moves(|mv| {
+            moves.push(mv);
+            false
+        });
+        assert_eq!(moves, [Move::pass()]);
+        assert_eq!(board.outcome(), None);
+        let board = "xo3/5/5/5/4o x 0 1".parse::<Board>().unwrap();
+        let mut child = board;
+        child.make_move(Move::single(square(3, 1)));
+        assert_eq!(child.fen(), "xx3/1x3/5/5/4o o 0 1");
+    }
+}
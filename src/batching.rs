@@ -1,125 +1,975 @@
+use std::{
+    cell::Cell,
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display},
+    panic::AssertUnwindSafe,
+    str::FromStr,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::RecvTimeoutError;
 use kn_cuda_eval::{executor::CudaExecutor, CudaDevice};
 use kn_graph::{
     dtype::{DTensor, Tensor},
     graph::Graph,
-    ndarray::s,
+    ndarray::{Axis, Dimension},
+};
+
+use kn_graph::optimizer::OptimizerSettings;
+
+use crate::{
+    game::{self, GameImpl},
+    native::NativeNet,
 };
 
-use crate::game::GameImpl;
+/// The most positions the executor evaluates at once, unless it is told otherwise.
+pub const DEFAULT_BATCH_SIZE: usize = 1024;
+/// How long a partly-filled batch waits for more requests before it is evaluated anyway.
+const PARTIAL_BATCH_TIMEOUT: Duration = Duration::from_millis(2);
+/// How many batches the GPU must evaluate cleanly after a failure before the batch size is grown again.
+const RECOVERY_BATCHES: usize = 1000;
+
+/// A network to evaluate positions with.
+pub enum Network {
+    /// A graph loaded from ONNX, run with CUDA or on the CPU, and where its heads are among its outputs.
+    Onnx(Graph, Heads),
+    /// A small network in the native format, run by the built-in evaluator.
+    Native(NativeNet),
+    /// No network at all: a uniform policy, and the result of a random rollout as the value.
+    Uniform,
+    /// The path of an ONNX model for ONNX Runtime, which is loaded onto its device by the executor.
+    #[cfg(feature = "onnxruntime")]
+    OnnxRuntime(String),
+}
+
+/// The library that runs ONNX networks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Kyanite, which runs networks with CUDA or on the CPU.
+    #[default]
+    Kyanite,
+    /// ONNX Runtime, which supports more operators and more devices, in builds with the `onnxruntime` feature.
+    OnnxRuntime,
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kyanite => write!(f, "kyanite"),
+            Self::OnnxRuntime => write!(f, "onnxruntime"),
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kyanite" => Ok(Self::Kyanite),
+            "onnxruntime" | "ort" => Ok(Self::OnnxRuntime),
+            _ => Err(anyhow::anyhow!("unknown backend {s:?}, expected kyanite or onnxruntime")),
+        }
+    }
+}
+
+/// Where to evaluate ONNX networks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Device {
+    /// The CUDA device with this ordinal.
+    Cuda(i32),
+    /// The DirectML device with this ordinal, which only ONNX Runtime can use.
+    DirectMl(i32),
+    /// The ROCm device with this ordinal, which only ONNX Runtime can use.
+    Rocm(i32),
+    /// The CPU, which is always available.
+    Cpu,
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Self::Cuda(0)
+    }
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            Self::DirectMl(ordinal) => write!(f, "directml:{ordinal}"),
+            Self::Rocm(ordinal) => write!(f, "rocm:{ordinal}"),
+            Self::Cpu => write!(f, "cpu"),
+        }
+    }
+}
+
+impl FromStr for Device {
+    type Err = anyhow::Error;
+
+    /// Parses `cpu`, or `cuda`, `directml` or `rocm`, each with an optional `:<ordinal>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "cpu" {
+            return Ok(Self::Cpu);
+        }
+        let (kind, ordinal) = s.split_once(':').unwrap_or((s, "0"));
+        let device = match kind {
+            "cuda" => Self::Cuda,
+            "directml" => Self::DirectMl,
+            "rocm" => Self::Rocm,
+            _ => anyhow::bail!("unknown device {s:?}, expected cpu, cuda, directml or rocm"),
+        };
+        Ok(device(ordinal.parse().map_err(|_| anyhow::anyhow!("invalid {kind} device {ordinal:?}"))?))
+    }
+}
+
+/// Where and with what the executor evaluates networks, and how many positions it evaluates at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExecutorOptions {
+    pub backend: Backend,
+    pub device: Device,
+    /// The most positions evaluated together, which is also the batch size a CUDA backend is built for.
+    pub batch_size: usize,
+    /// The most positions each pipe sends at once, as a search that selects several leaves per round does.
+    /// Batches can be no larger than this many positions from every pipe.
+    pub slots_per_pipe: usize,
+}
+
+impl Default for ExecutorOptions {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            device: Device::default(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            slots_per_pipe: 1,
+        }
+    }
+}
+
+/// Loads the network at `path` for the default backend, using the native format for `.safetensors` files
+/// and ONNX otherwise.
+pub fn load_network<G: GameImpl>(path: &str) -> anyhow::Result<Network> {
+    load_network_with::<G>(path, Backend::default())
+}
+
+/// Loads the network at `path` for `backend`, using the native format for `.safetensors` files and ONNX otherwise.
+pub fn load_network_with<G: GameImpl>(path: &str, backend: Backend) -> anyhow::Result<Network> {
+    if path.ends_with(".safetensors") {
+        return Ok(Network::Native(NativeNet::load::<G>(path)?));
+    }
+    if backend == Backend::OnnxRuntime {
+        // the runtime loads the model itself, onto the device the executor is started on.
+        #[cfg(feature = "onnxruntime")]
+        return Ok(Network::OnnxRuntime(path.to_string()));
+        #[cfg(not(feature = "onnxruntime"))]
+        anyhow::bail!("this build has no onnxruntime backend, rebuild it with --features onnxruntime");
+    }
+    // Load an onnx file into a Graph.
+    let raw_graph = kn_graph::onnx::load_graph_from_onnx_path(path, false)
+        .map_err(|e| anyhow::anyhow!("failed to load onnx network {path}: {e:?}"))?;
+    // the optimiser keeps the order of the outputs, but not their names.
+    let names = raw_graph.outputs().iter().map(|&value| raw_graph[value].debug_id.clone()).collect::<Vec<_>>();
+    let heads = Heads::from_names(&names).map_err(|e| anyhow::anyhow!("failed to find the heads of {path}: {e}"))?;
+    // Optimise the graph.
+    let graph = kn_graph::optimizer::optimize_graph(&raw_graph, OptimizerSettings::default());
+    // shapes are checked for a single position, as the batch dimension is free.
+    let dims = |values: &[kn_graph::graph::Value]| {
+        values.iter().map(|&value| graph[value].shape.eval(1).dims[1..].to_vec()).collect::<Vec<_>>()
+    };
+    check_shapes::<G>(path, &dims(graph.inputs()), &dims(graph.outputs()), &heads)?;
+    Ok(Network::Onnx(graph, heads))
+}
+
+/// Checks that a network with inputs and outputs of these shapes, without their batch dimension,
+/// takes the features of `G` and has heads of the right shapes for it, so that a model for
+/// another game fails here, rather than with a tensor error in the middle of a search.
+pub fn check_shapes<G: GameImpl>(
+    path: &str,
+    inputs: &[Vec<usize>],
+    outputs: &[Vec<usize>],
+    heads: &Heads,
+) -> anyhow::Result<()> {
+    let game = G::name();
+    let expected = G::tensor_dims(1).slice()[1..].to_vec();
+    match inputs {
+        [input] if *input == expected => {}
+        [input] => {
+            anyhow::bail!("model {path} takes input of shape {input:?}, but {game} needs input of shape {expected:?}")
+        }
+        _ => anyhow::bail!("model {path} has {} inputs, but {game} needs one, of shape {expected:?}", inputs.len()),
+    }
+    let expected_heads = [
+        ("policy", Some(heads.policy), G::policy_dim()),
+        ("value", heads.value, 1),
+        ("wdl", heads.wdl, 3),
+        ("moves_left", heads.moves_left, 1),
+    ];
+    for (head, index, size) in expected_heads {
+        let Some(shape) = index.and_then(|index| outputs.get(index)) else {
+            continue;
+        };
+        anyhow::ensure!(
+            *shape == [size],
+            "model {path} has a {head} head of shape {shape:?}, but {game} needs a {head} head of shape [{size}]"
+        );
+    }
+    Ok(())
+}
+
+/// Loads the network at `path` for `backend`, as `load_network_with` does, unless there is no file there,
+/// in which case positions are evaluated by a uniform policy and random rollouts, so that the engine still plays.
+pub fn load_network_or_uniform<G: GameImpl>(path: &str, backend: Backend) -> anyhow::Result<Network> {
+    if std::path::Path::new(path).exists() {
+        return load_network_with::<G>(path, backend);
+    }
+    println!("info string no model found at {path}, evaluating with a uniform policy and random rollouts");
+    Ok(Network::Uniform)
+}
+
+/// The network's evaluation of a position.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Evaluation {
+    /// The policy logits, indexed by move.
+    pub policy: Vec<f32>,
+    /// The win probability of the side to move.
+    pub value: f32,
+    /// The plies left until the game ends, if the network has a moves-left head.
+    pub plies_left: Option<f32>,
+    /// The win, draw and loss probabilities of the side to move, if the network has a WDL head.
+    pub wdl: Option<[f32; 3]>,
+    /// The outputs of any heads the engine has no use for itself, by name.
+    pub auxiliary: Vec<(Arc<str>, Vec<f32>)>,
+}
+
+/// Where each head of a network is among its outputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Heads {
+    pub policy: usize,
+    /// The value head, or `None` if the value is read from the WDL head.
+    pub value: Option<usize>,
+    pub wdl: Option<usize>,
+    pub moves_left: Option<usize>,
+    /// The remaining outputs, passed through to the engine as they are.
+    pub auxiliary: Vec<(usize, Arc<str>)>,
+}
 
-const EXECUTOR_BATCH_SIZE: usize = 1024;
+impl Default for Heads {
+    /// A policy and then a value, and nothing else.
+    fn default() -> Self {
+        Self { policy: 0, value: Some(1), wdl: None, moves_left: None, auxiliary: Vec::new() }
+    }
+}
+
+impl Heads {
+    /// Finds the heads among outputs with these names, which are `policy`, `value`, `wdl` and `moves_left`.
+    /// Networks without a `policy` output are read by position instead: the policy first, then the value,
+    /// then the plies left in the game, if there is a third output.
+    pub fn from_names(names: &[String]) -> anyhow::Result<Self> {
+        let find = |head: &str| names.iter().position(|name| name == head);
+        let heads = if let Some(policy) = find("policy") {
+            let (value, wdl, moves_left) = (find("value"), find("wdl"), find("moves_left"));
+            anyhow::ensure!(value.is_some() || wdl.is_some(), "the network has neither a value nor a wdl output");
+            Self { policy, value, wdl, moves_left, auxiliary: Vec::new() }
+        } else {
+            anyhow::ensure!(names.len() >= 2, "the network has {} outputs, expected a policy and a value", names.len());
+            Self { moves_left: (names.len() > 2).then_some(2), ..Self::default() }
+        };
+        let known = [Some(heads.policy), heads.value, heads.wdl, heads.moves_left];
+        let auxiliary = names
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| !known.contains(&Some(index)))
+            .map(|(index, name)| (index, Arc::from(name.as_str())))
+            .collect();
+        Ok(Self { auxiliary, ..heads })
+    }
+
+    /// Reads the evaluation of a position from its row of each output.
+    pub fn read(&self, mut rows: Vec<Vec<f32>>) -> Evaluation {
+        let wdl = self.wdl.map(|index| [rows[index][0], rows[index][1], rows[index][2]]);
+        let value = match (self.value, wdl) {
+            (Some(index), _) => rows[index][0],
+            (None, Some([win, draw, _])) => draw.mul_add(0.5, win),
+            (None, None) => unreachable!("networks have a value or a wdl head"),
+        };
+        let plies_left = self.moves_left.map(|index| rows[index][0].max(0.0));
+        let auxiliary =
+            self.auxiliary.iter().map(|(index, name)| (Arc::clone(name), std::mem::take(&mut rows[*index]))).collect();
+        let policy = std::mem::take(&mut rows[self.policy]);
+        Evaluation { policy, value, plies_left, wdl, auxiliary }
+    }
+}
+
+/// How urgently a handle's evaluations are needed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Latency-sensitive work, such as a search a user is waiting on.
+    /// These requests are dispatched as soon as possible, without waiting for a full batch.
+    Interactive = 0,
+    /// Throughput-oriented work, such as data generation.
+    Background = 1,
+}
+
+/// A message from a handle to the executor.
+#[derive(Clone)]
+enum Request<G: GameImpl> {
+    /// Evaluate a position, completing into the slot of the handle with the given id.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    Evaluate { id: usize, priority: Priority, board: G },
+    /// Evaluate several positions, completing into the slot of the handle with the given id in order.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    EvaluateBatch { id: usize, priority: Priority, boards: Vec<G> },
+    /// The handle with the given id has been dropped, and will make no more requests.
+    Hangup { id: usize },
+}
+
+/// The slot into which the executor writes the evaluation requested by a single handle.
+#[derive(Default)]
+struct Completion {
+    state: Mutex<CompletionState>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct CompletionState {
+    /// The evaluations not yet received, in the order their positions were sent.
+    results: VecDeque<Evaluation>,
+    /// Set when the executor shuts down, so that waiters don't block forever.
+    disconnected: bool,
+}
 
 pub struct ExecutorHandle<G: GameImpl> {
-    pub sender: crossbeam::channel::Sender<G>,
-    pub receiver: crossbeam::channel::Receiver<(Vec<f32>, f32)>,
+    /// The id of this handle, indexing its completion slot.
+    id: usize,
+    /// The lane in which this handle's requests are queued.
+    priority: Priority,
+    requests: crossbeam::channel::Sender<Request<G>>,
+    completions: Arc<[Completion]>,
+    /// Total time spent blocked waiting for evaluations.
+    wait_time: Cell<Duration>,
+    /// A description of the backend the executor is evaluating on.
+    backend: Arc<Mutex<String>>,
+}
+
+impl<G: GameImpl> ExecutorHandle<G> {
+    /// Queues a position for evaluation.
+    /// Each handle may have at most one request in flight, so every `send` must be paired with a `recv`.
+    /// The positions of a `send_batch` count as one request, and need a `recv` each.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    pub fn send(&self, board: G) -> anyhow::Result<()> {
+        self.requests
+            .send(Request::Evaluate { id: self.id, priority: self.priority, board })
+            .map_err(|_| anyhow::anyhow!("the executor has shut down"))
+    }
+
+    /// Queues several positions for evaluation together, whose evaluations are received in the same order.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    pub fn send_batch(&self, boards: Vec<G>) -> anyhow::Result<()> {
+        self.requests
+            .send(Request::EvaluateBatch { id: self.id, priority: self.priority, boards })
+            .map_err(|_| anyhow::anyhow!("the executor has shut down"))
+    }
+
+    /// Sets the priority of this handle's requests.
+    pub const fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Blocks until an evaluation arrives, recording the time spent waiting.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    pub fn recv(&self) -> anyhow::Result<Evaluation> {
+        let start = Instant::now();
+        let completion = &self.completions[self.id];
+        let mut state = completion.state.lock().expect("completion lock poisoned");
+        let result = loop {
+            if let Some(result) = state.results.pop_front() {
+                break Ok(result);
+            }
+            if state.disconnected {
+                break Err(anyhow::anyhow!("the executor has shut down"));
+            }
+            state = completion.ready.wait(state).expect("completion lock poisoned");
+        };
+        drop(state);
+        self.wait_time.set(self.wait_time.get() + start.elapsed());
+        result
+    }
+
+    /// Returns the total time spent blocked in `recv`.
+    pub const fn wait_time(&self) -> Duration {
+        self.wait_time.get()
+    }
+
+    /// Returns a description of the backend the executor is currently evaluating on.
+    pub fn backend(&self) -> String {
+        self.backend.lock().expect("backend lock poisoned").clone()
+    }
 }
 
-pub struct EvalPipe<G: GameImpl> {
-    pub sender: crossbeam::channel::Sender<(Vec<f32>, f32)>,
-    pub receiver: crossbeam::channel::Receiver<G>,
+impl<G: GameImpl> Drop for ExecutorHandle<G> {
+    fn drop(&mut self) {
+        // the executor may already be gone, in which case there is no one to tell.
+        let _ = self.requests.send(Request::Hangup { id: self.id });
+    }
 }
 
 pub struct Executor<G: GameImpl> {
+    /// The CUDA backend, or `None` when evaluating on the CPU.
     internal: Option<CudaExecutor>,
-    eval_pipes: Vec<EvalPipe<G>>,
-    in_waiting: Vec<(usize, G)>,
+    /// The device to rebuild the CUDA backend on after a failure.
+    device: Option<CudaDevice>,
+    /// The network, kept for rebuilding the backend.
+    graph: Graph,
+    /// Where the heads of the graph are among its outputs.
+    heads: Heads,
+    /// The built-in evaluator, which replaces the graph when the network is in the native format.
+    native: Option<NativeNet>,
+    /// Whether there is no network, so that positions are evaluated by a uniform policy and random rollouts.
+    uniform: bool,
+    /// The ONNX Runtime backend, which replaces the graph when the network is run by ONNX Runtime.
+    #[cfg(feature = "onnxruntime")]
+    onnxruntime: Option<crate::onnxruntime::OnnxRuntime>,
+    /// A description of the current backend, shared with the handles.
+    backend: Arc<Mutex<String>>,
+    requests: crossbeam::channel::Receiver<Request<G>>,
+    completions: Arc<[Completion]>,
+    /// The number of handles that have not yet hung up.
+    live_handles: usize,
+    /// Requests waiting to be evaluated, in one lane per priority, most urgent first,
+    /// each keyed by a hash of its position so that duplicates can be evaluated once.
+    in_waiting: [Vec<(usize, u64, G)>; 2],
     batch_size: usize,
+    /// The batch size asked for, which a batch size shrunk after a GPU failure grows back towards.
+    full_batch_size: usize,
+    /// The number of batches evaluated on the GPU since it last failed or the batch size last grew.
+    clean_batches: usize,
+    /// The id of the pipe to serve first in the next batch, rotating so that no pipe is starved.
+    next_pipe: usize,
 }
 
 impl<G: GameImpl> Executor<G> {
-    pub fn new(cuda_device: Option<CudaDevice>, num_pipes: usize, graph: &Graph) -> (Self, Vec<ExecutorHandle<G>>) {
-        let batch_size = EXECUTOR_BATCH_SIZE.min(num_pipes);
+    pub fn new(
+        cuda_device: Option<CudaDevice>,
+        num_pipes: usize,
+        batch_size: usize,
+        network: &Network,
+    ) -> (Self, Vec<ExecutorHandle<G>>) {
+        let batch_size = batch_size.max(1);
+        let (graph, heads, native, cuda_device) = match network {
+            Network::Onnx(graph, heads) => (graph.clone(), heads.clone(), None, cuda_device),
+            Network::Native(net) => (Graph::new(), Heads::default(), Some(net.clone()), None),
+            Network::Uniform => (Graph::new(), Heads::default(), None, None),
+            #[cfg(feature = "onnxruntime")]
+            Network::OnnxRuntime(_) => (Graph::new(), Heads::default(), None, None),
+        };
+        let uniform = matches!(network, Network::Uniform);
+        let graph = &graph;
         let internal = cuda_device.map(|cd| CudaExecutor::new(cd, graph, batch_size));
-        let mut eval_pipes = Vec::new();
-        let mut handles = Vec::new();
-        for _ in 0..num_pipes {
-            let (board_sender, board_receiver) = crossbeam::channel::bounded(1);
-            let (eval_sender, eval_receiver) = crossbeam::channel::bounded(1);
-            eval_pipes.push(EvalPipe { sender: eval_sender, receiver: board_receiver });
-            handles.push(ExecutorHandle { sender: board_sender, receiver: eval_receiver });
-        }
-        (Self { internal, eval_pipes, in_waiting: Vec::new(), batch_size }, handles)
-    }
-
-    /// Fill the `in_waiting` queue with boards from the pipes.
-    /// This function will block until the queue is full.
-    pub fn pull(&mut self) -> Result<(), crossbeam::channel::RecvTimeoutError> {
-        let mut found_anything = true;
-        while found_anything && self.in_waiting.len() < self.batch_size {
-            found_anything = false;
-            for (pipe_index, board) in self.eval_pipes.iter().enumerate() {
-                if let Ok(board) = board.receiver.try_recv() {
-                    self.in_waiting.push((pipe_index, board));
-                    found_anything = true;
-                }
+        // every handle has at most one request in flight, plus its hangup.
+        let (request_sender, request_receiver) = crossbeam::channel::bounded(2 * num_pipes);
+        let completions: Arc<[Completion]> = (0..num_pipes).map(|_| Completion::default()).collect();
+        let backend = Arc::new(Mutex::new(match (&native, cuda_device) {
+            _ if uniform => "uniform policy with random rollouts".to_string(),
+            (Some(_), _) => "native".to_string(),
+            (None, Some(cd)) => format!("cuda, device {}", cd.name()),
+            (None, None) => "cpu".to_string(),
+        }));
+        let handles = (0..num_pipes)
+            .map(|id| ExecutorHandle {
+                id,
+                requests: request_sender.clone(),
+                completions: Arc::clone(&completions),
+                priority: Priority::Background,
+                wait_time: Cell::new(Duration::ZERO),
+                backend: Arc::clone(&backend),
+            })
+            .collect();
+        let executor = Self {
+            internal,
+            device: cuda_device,
+            graph: graph.clone(),
+            heads,
+            native,
+            uniform,
+            #[cfg(feature = "onnxruntime")]
+            onnxruntime: None,
+            backend,
+            requests: request_receiver,
+            completions,
+            live_handles: num_pipes,
+            in_waiting: [Vec::new(), Vec::new()],
+            batch_size,
+            full_batch_size: batch_size,
+            clean_batches: 0,
+            next_pipe: 0,
+        };
+        (executor, handles)
+    }
+
+    /// Fill the `in_waiting` queues with requests.
+    /// This function will block until a full batch is waiting, unless an interactive request
+    /// arrives, in which case it returns as soon as the queue is drained. Smaller batches are
+    /// also formed once every handle that has not hung up is waiting on a request, as no more
+    /// can arrive until some are answered, or once the first request waiting has waited for
+    /// `PARTIAL_BATCH_TIMEOUT`, so that handles which are idle without hanging up cannot hold
+    /// the others back. Returns false once every handle has hung up and no work remains.
+    pub fn pull(&mut self) -> bool {
+        let mut deadline = None;
+        loop {
+            // take whatever is already queued without blocking.
+            while let Ok(request) = self.requests.try_recv() {
+                self.accept(request);
+            }
+            let waiting = self.in_waiting.iter().map(Vec::len).sum::<usize>();
+            let urgent = !self.in_waiting[Priority::Interactive as usize].is_empty();
+            if urgent || waiting >= self.batch_size || self.waiting_pipes() >= self.live_handles {
+                return waiting > 0;
+            }
+            let received = if waiting == 0 {
+                self.requests.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + PARTIAL_BATCH_TIMEOUT);
+                self.requests.recv_deadline(deadline)
+            };
+            match received {
+                Ok(request) => self.accept(request),
+                Err(RecvTimeoutError::Timeout) => return true,
+                Err(RecvTimeoutError::Disconnected) => self.live_handles = 0,
             }
         }
-        // if we have enough to fill the queue, return
-        if self.in_waiting.len() >= self.batch_size {
-            return Ok(());
-        }
-        // otherwise, block until we have enough
-        let mut select = crossbeam::channel::Select::new();
-        for pipe in &self.eval_pipes {
-            select.recv(&pipe.receiver);
+    }
+
+    /// The number of pipes with requests waiting. Each pipe has at most one request in flight,
+    /// however many positions it holds.
+    fn waiting_pipes(&self) -> usize {
+        let mut pipes = self.in_waiting.iter().flatten().map(|&(id, _, _)| id).collect::<Vec<_>>();
+        pipes.sort_unstable();
+        pipes.dedup();
+        pipes.len()
+    }
+
+    fn accept(&mut self, request: Request<G>) {
+        match request {
+            Request::Evaluate { id, priority, board } => {
+                self.in_waiting[priority as usize].push((id, game::feature_hash(&board), board));
+            }
+            Request::EvaluateBatch { id, priority, boards } => {
+                let keyed = boards.into_iter().map(|board| (id, game::feature_hash(&board), board));
+                self.in_waiting[priority as usize].extend(keyed);
+            }
+            Request::Hangup { id } => {
+                log::trace!("executor handle {id} hung up");
+                self.live_handles -= 1;
+            }
         }
-        loop {
-            let oper = select.select();
-            let index = oper.index();
-            let board = oper.recv(&self.eval_pipes[index].receiver)?;
-            self.in_waiting.push((index, board));
-            if self.in_waiting.len() >= self.batch_size {
-                break Ok(());
+    }
+
+    /// Takes up to `batch_size` requests from `in_waiting`, most urgent lane first.
+    /// Within a lane, pipes are served round-robin, starting after the last pipe served,
+    /// and the positions of each pipe in the order they were sent.
+    fn next_batch(&mut self) -> Vec<(usize, u64, G)> {
+        let num_pipes = self.completions.len();
+        let mut batch = Vec::with_capacity(self.batch_size);
+        for lane in &mut self.in_waiting {
+            let take = (self.batch_size - batch.len()).min(lane.len());
+            if take < lane.len() {
+                let next_pipe = self.next_pipe;
+                lane.sort_by_key(|&(id, _, _)| (id + num_pipes - next_pipe) % num_pipes);
             }
+            batch.extend(lane.drain(..take));
+        }
+        if let Some(&(id, _, _)) = batch.last() {
+            self.next_pipe = (id + 1) % num_pipes;
         }
+        batch
     }
 
     pub fn tick(&mut self) {
-        // take the first EXECUTOR_BATCH_SIZE elements from in_waiting,
+        // take up to batch_size elements from in_waiting, most urgent first,
         // evaluate them, and send the results to the corresponding pipes
-        let mut indices = Vec::new();
-        let mut input = Tensor::zeros(G::tensor_dims(self.batch_size));
-        for (batch_index, (pipe_index, board)) in self.in_waiting.drain(..self.batch_size).enumerate() {
-            // fill the slice with the feature map
-            board.fill_feature_map(|index| {
-                input[[batch_index, index]] = 1.0;
-            });
-            indices.push(pipe_index);
+        let (requests, boards) = coalesce(self.next_batch());
+        let results = self.evaluate(&boards);
+        for (pipe_index, slot) in requests {
+            let completion = &self.completions[pipe_index];
+            completion.state.lock().expect("completion lock poisoned").results.push_back(results[slot].clone());
+            completion.ready.notify_one();
         }
-        let inputs = [DTensor::F32(input)];
-        let tensors = self.internal.as_mut().expect("no CUDA executor exists.").evaluate(&inputs);
+    }
 
-        let policy = tensors[0].unwrap_f32().unwrap();
-        let value = tensors[1].unwrap_f32().unwrap();
-        for (batch_index, pipe_index) in indices.into_iter().enumerate() {
-            let policy_vec = policy.slice(s![batch_index, ..]).to_vec();
-            let value = value[[batch_index, 0]];
-            self.eval_pipes[pipe_index].sender.send((policy_vec, value)).unwrap();
+    /// Evaluates the boards, one chunk of at most `batch_size` at a time.
+    /// Backend failures are survived by shrinking the batch, and then by falling back to the CPU.
+    fn evaluate(&mut self, boards: &[G]) -> Vec<Evaluation> {
+        if self.uniform {
+            return boards
+                .iter()
+                .map(|board| Evaluation {
+                    policy: vec![0.0; G::policy_dim()],
+                    value: board.rollout(),
+                    plies_left: None,
+                    wdl: None,
+                    auxiliary: Vec::new(),
+                })
+                .collect();
+        }
+        if let Some(net) = &self.native {
+            return boards
+                .iter()
+                .map(|board| {
+                    let (policy, value) = net.evaluate(board);
+                    Evaluation { policy, value, plies_left: None, wdl: None, auxiliary: Vec::new() }
+                })
+                .collect();
+        }
+        #[cfg(feature = "onnxruntime")]
+        if let Some(runtime) = &self.onnxruntime {
+            // the runtime has no smaller configuration to fall back to, so a failure ends the executor,
+            // and its handles report that it has shut down.
+            return runtime.evaluate(boards, self.batch_size).unwrap_or_else(|e| panic!("onnxruntime failed: {e:#}"));
+        }
+        let mut results = Vec::with_capacity(boards.len());
+        while results.len() < boards.len() {
+            let chunk = &boards[results.len()..boards.len().min(results.len() + self.batch_size)];
+            // a CUDA backend is built for a fixed batch size, so its input is padded to it,
+            // while the CPU evaluates only the positions there are.
+            let padded_size = if self.internal.is_some() { self.batch_size } else { chunk.len() };
+            let mut input = Tensor::zeros(G::tensor_dims(padded_size));
+            let features = G::tensor_dims(1).size();
+            G::fill_feature_batch(chunk, features, input.as_slice_mut().expect("new tensors are contiguous"));
+            let inputs = [DTensor::F32(input)];
+            let Some(tensors) = self.run(&inputs, padded_size) else {
+                self.degrade();
+                continue;
+            };
+            self.recover();
+
+            let outputs = tensors.iter().map(|tensor| tensor.unwrap_f32().unwrap()).collect::<Vec<_>>();
+            for batch_index in 0..chunk.len() {
+                let rows =
+                    outputs.iter().map(|output| output.index_axis(Axis(0), batch_index).iter().copied().collect());
+                results.push(self.heads.read(rows.collect()));
+            }
+        }
+        results
+    }
+
+    /// Runs the network on the current backend on a batch of `batch_size` positions,
+    /// returning `None` if the GPU fails.
+    fn run(&mut self, inputs: &[DTensor], batch_size: usize) -> Option<Vec<DTensor>> {
+        let Some(cuda) = self.internal.as_mut() else {
+            return Some(kn_graph::cpu::cpu_eval_graph(&self.graph, batch_size, inputs));
+        };
+        // CUDA errors surface as panics, so catch them rather than taking down the process.
+        std::panic::catch_unwind(AssertUnwindSafe(|| cuda.evaluate(inputs).to_vec())).ok()
+    }
+
+    /// Recovers from a failed evaluation by rebuilding the CUDA executor at half the batch size,
+    /// or by moving to the CPU backend once the batch can shrink no further.
+    fn degrade(&mut self) {
+        self.internal = None;
+        self.clean_batches = 0;
+        if let Some(device) = self.device.filter(|_| self.batch_size > 1) {
+            self.batch_size /= 2;
+            log::warn!("GPU evaluation failed, retrying with batch size {}", self.batch_size);
+            let (graph, batch_size) = (&self.graph, self.batch_size);
+            self.internal =
+                std::panic::catch_unwind(AssertUnwindSafe(|| CudaExecutor::new(device, graph, batch_size))).ok();
+            if self.internal.is_some() {
+                return;
+            }
+        }
+        log::warn!("GPU evaluation failed, falling back to the CPU backend");
+        self.device = None;
+        *self.backend.lock().expect("backend lock poisoned") = "cpu (fallback after a GPU failure)".to_string();
+    }
+
+    /// Counts a batch evaluated without failure, and once the GPU has run cleanly for long enough
+    /// at a batch size shrunk by `degrade`, rebuilds the CUDA executor at twice that size.
+    /// A failed rebuild leaves the smaller executor in place, to be tried again as long again later.
+    fn recover(&mut self) {
+        if self.internal.is_none() || self.batch_size >= self.full_batch_size {
+            return;
+        }
+        self.clean_batches += 1;
+        if self.clean_batches < RECOVERY_BATCHES {
+            return;
+        }
+        self.clean_batches = 0;
+        let Some(device) = self.device else {
+            return;
+        };
+        let (graph, batch_size) = (&self.graph, (self.batch_size * 2).min(self.full_batch_size));
+        if let Ok(cuda) = std::panic::catch_unwind(AssertUnwindSafe(|| CudaExecutor::new(device, graph, batch_size))) {
+            log::info!("GPU evaluation has run cleanly since its failure, growing the batch size to {batch_size}");
+            self.internal = Some(cuda);
+            self.batch_size = batch_size;
+        }
+    }
+}
+
+impl<G: GameImpl> Drop for Executor<G> {
+    fn drop(&mut self) {
+        for completion in self.completions.iter() {
+            completion.state.lock().expect("completion lock poisoned").disconnected = true;
+            completion.ready.notify_all();
         }
     }
 }
 
-/// Starts the executor thread and returns a list of handles to the pipes.
-pub fn executor<G: GameImpl>(graph: &Graph, batch_size: usize) -> anyhow::Result<Vec<ExecutorHandle<G>>> {
+/// Starts the executor thread on the default device and returns a list of `num_pipes` handles to the pipes.
+pub fn executor<G: GameImpl>(network: &Network, num_pipes: usize) -> anyhow::Result<Vec<ExecutorHandle<G>>> {
+    executor_on(network, num_pipes, ExecutorOptions::default())
+}
+
+/// Starts the executor thread with `options` and returns a list of `num_pipes` handles to the pipes,
+/// failing if the device is not available. Native networks always run on the CPU.
+#[cfg_attr(feature = "pure-mcts", allow(unused_variables))]
+pub fn executor_on<G: GameImpl>(
+    network: &Network,
+    num_pipes: usize,
+    options: ExecutorOptions,
+) -> anyhow::Result<Vec<ExecutorHandle<G>>> {
+    anyhow::ensure!(options.batch_size > 0, "the batch size must be at least 1");
     #[cfg(feature = "pure-mcts")]
     let cuda_device = None;
     #[cfg(not(feature = "pure-mcts"))]
-    let cuda_device = {
-        let cd = CudaDevice::new(0).map_err(|_| anyhow::anyhow!("No cuda device available"))?;
-        log::info!("Using device: {}", cd.name());
-        Some(cd)
+    let cuda_device = match (network, options.device) {
+        (Network::Native(_) | Network::Uniform, _) | (_, Device::Cpu) => None,
+        #[cfg(feature = "onnxruntime")]
+        (Network::OnnxRuntime(_), _) => None,
+        (Network::Onnx(..), Device::Cuda(ordinal)) => {
+            let cd = CudaDevice::new(ordinal).map_err(|_| anyhow::anyhow!("No cuda device {ordinal} available"))?;
+            log::info!("Using device: {}", cd.name());
+            Some(cd)
+        }
+        (Network::Onnx(..), device) => anyhow::bail!("device {device} needs the onnxruntime backend"),
     };
-    let (mut executor, handles) = Executor::new(cuda_device, batch_size, graph);
+    // a batch larger than every pipe could fill at once would only ever be padded.
+    let batch_size = options.batch_size.min(num_pipes * options.slots_per_pipe.max(1));
+    let (mut executor, handles) = Executor::new(cuda_device, num_pipes, batch_size, network);
+    #[cfg(feature = "onnxruntime")]
+    if let Network::OnnxRuntime(path) = network {
+        executor.onnxruntime = Some(crate::onnxruntime::OnnxRuntime::load::<G>(path, options.device)?);
+        *executor.backend.lock().expect("backend lock poisoned") = format!("onnxruntime, device {}", options.device);
+    }
     std::thread::Builder::new()
         .name("executor".into())
-        .spawn(move || loop {
-            let res = executor.pull();
-            if res.is_err() {
-                break;
+        .spawn(move || {
+            while executor.pull() {
+                executor.tick();
+                log::debug!("Batch of evaluations completed.");
             }
-            executor.tick();
-            log::debug!("Batch of evaluations completed.");
         })
         .expect("Couldn't start executor thread");
     Ok(handles)
 }
+
+/// Collapses requests for the same position, which are common when self-play games start together,
+/// into one board each. Returns the pipe of each request, with the index of its board, and the boards.
+fn coalesce<G>(batch: Vec<(usize, u64, G)>) -> (Vec<(usize, usize)>, Vec<G>) {
+    let mut slots = HashMap::with_capacity(batch.len());
+    let mut requests = Vec::with_capacity(batch.len());
+    let mut boards = Vec::with_capacity(batch.len());
+    for (id, key, board) in batch {
+        let slot = *slots.entry(key).or_insert_with(|| {
+            boards.push(board);
+            boards.len() - 1
+        });
+        requests.push((id, slot));
+    }
+    (requests, boards)
+}
+
+#[cfg(test)]
+mod tests {
+    use kn_graph::graph::Graph;
+
+    use super::*;
+    use crate::uttt::Board;
+
+    #[test]
+    fn busy_pipes_are_served_evenly() {
+        const PIPES: usize = 8;
+        let (mut executor, handles) =
+            Executor::<Board>::new(None, PIPES, DEFAULT_BATCH_SIZE, &Network::Onnx(Graph::new(), Heads::default()));
+        executor.batch_size = 3;
+        for handle in &handles {
+            handle.send(Board::default()).unwrap();
+        }
+        executor.pull();
+
+        // every pipe resubmits as soon as it is served, so a fixed service order would starve the last pipes.
+        let mut served = [0; PIPES];
+        for _ in 0..PIPES * 10 {
+            for (id, _, board) in executor.next_batch() {
+                served[id] += 1;
+                executor.accept(Request::Evaluate { id, priority: Priority::Background, board });
+            }
+        }
+        assert!(served.iter().all(|&count| count == 30), "unbalanced service: {served:?}");
+    }
+
+    #[test]
+    fn failures_without_a_gpu_to_retry_on_fall_back_to_the_cpu() {
+        let (mut executor, handles) =
+            Executor::<Board>::new(None, 1, DEFAULT_BATCH_SIZE, &Network::Onnx(Graph::new(), Heads::default()));
+        executor.degrade();
+        assert!(executor.internal.is_none());
+        assert!(executor.device.is_none());
+        assert_eq!(executor.batch_size, DEFAULT_BATCH_SIZE);
+        assert_eq!(handles[0].backend(), "cpu (fallback after a GPU failure)");
+        // the CPU has no batch size to grow back to.
+        for _ in 0..RECOVERY_BATCHES {
+            executor.recover();
+        }
+        assert_eq!(executor.batch_size, DEFAULT_BATCH_SIZE);
+    }
+
+    #[test]
+    fn partial_batches_are_evaluated_after_a_timeout() {
+        let (mut executor, handles) =
+            Executor::<Board>::new(None, 4, DEFAULT_BATCH_SIZE, &Network::Onnx(Graph::new(), Heads::default()));
+        handles[2].send(Board::default()).unwrap();
+        // the other three handles are idle, so a full batch would never arrive.
+        let start = Instant::now();
+        assert!(executor.pull());
+        assert!(start.elapsed() >= PARTIAL_BATCH_TIMEOUT);
+        assert_eq!(executor.next_batch().len(), 1);
+    }
+
+    #[test]
+    fn a_single_pipe_can_fill_a_batch() {
+        let (mut executor, handles) =
+            Executor::<Board>::new(None, 2, 8, &Network::Onnx(Graph::new(), Heads::default()));
+        let mut boards = Vec::new();
+        Board::default().generate_moves(|mv| {
+            let mut board = Board::default();
+            board.make_move(mv);
+            boards.push(board);
+            boards.len() == 8
+        });
+        // the other pipe is idle, but eight positions make a full batch without waiting for it.
+        handles[0].send_batch(boards).unwrap();
+        assert!(executor.pull());
+        assert_eq!(executor.next_batch().len(), 8);
+    }
+
+    #[test]
+    fn duplicate_positions_are_evaluated_once() {
+        let (mut executor, handles) = Executor::<Board>::new(None, 3, DEFAULT_BATCH_SIZE, &Network::Uniform);
+        let mut first_move = None;
+        Board::default().generate_moves(|mv| {
+            first_move = Some(mv);
+            true
+        });
+        let mut opened = Board::default();
+        opened.make_move(first_move.unwrap());
+        handles[0].send(Board::default()).unwrap();
+        handles[1].send(opened).unwrap();
+        handles[2].send(Board::default()).unwrap();
+        executor.pull();
+
+        let (requests, boards) = coalesce(executor.next_batch());
+        assert_eq!(boards, vec![Board::default(), opened]);
+        assert_eq!(requests, vec![(0, 0), (1, 1), (2, 0)]);
+    }
+
+    #[test]
+    fn batched_positions_keep_their_order() {
+        let (mut executor, handles) =
+            Executor::<Board>::new(None, 2, DEFAULT_BATCH_SIZE, &Network::Onnx(Graph::new(), Heads::default()));
+        executor.batch_size = 3;
+        let mut boards = Vec::new();
+        Board::default().generate_moves(|mv| {
+            let mut board = Board::default();
+            board.make_move(mv);
+            boards.push(board);
+            boards.len() == 4
+        });
+        handles[1].send(Board::default()).unwrap();
+        handles[0].send_batch(boards.clone()).unwrap();
+        executor.pull();
+
+        let mut served = Vec::new();
+        while served.len() < 5 {
+            served.extend(executor.next_batch());
+        }
+        let pipe_zero = served.iter().filter(|&&(id, _, _)| id == 0).map(|&(_, _, board)| board).collect::<Vec<_>>();
+        assert_eq!(pipe_zero, boards);
+    }
+
+    #[test]
+    fn missing_models_fall_back_to_a_uniform_policy() {
+        let network = load_network_or_uniform::<Board>("./no/such/model.onnx", Backend::default()).unwrap();
+        assert!(matches!(network, Network::Uniform));
+        let (mut executor, _handles) = Executor::<Board>::new(None, 1, DEFAULT_BATCH_SIZE, &network);
+        let evaluations = executor.evaluate(&[Board::default()]);
+        assert_eq!(evaluations[0].policy, vec![0.0; 81]);
+        assert!((0.0..=1.0).contains(&evaluations[0].value));
+    }
+
+    #[test]
+    fn model_shapes_must_match_the_game() {
+        let (features, moves) = (Board::tensor_dims(1)[1], Board::policy_dim());
+        let check = |input: usize, outputs: &[usize]| {
+            let names = (0..outputs.len()).map(|index| format!("output{index}")).collect::<Vec<_>>();
+            let outputs = outputs.iter().map(|&size| vec![size]).collect::<Vec<_>>();
+            check_shapes::<Board>("model.onnx", &[vec![input]], &outputs, &Heads::from_names(&names)?)
+        };
+        assert!(check(features, &[moves, 1]).is_ok());
+        assert!(check(features, &[moves, 1, 1]).is_ok());
+
+        let message = check(162, &[moves, 1]).unwrap_err().to_string();
+        assert!(message.contains("uttt") && message.contains("[162]"), "unhelpful error: {message}");
+        assert!(check(features, &[81]).is_err());
+        assert!(check(features, &[82, 1]).is_err());
+        assert!(check(features, &[moves, 3]).is_err());
+    }
+
+    #[test]
+    fn heads_are_found_by_name() {
+        let names = ["value", "ownership", "policy", "wdl"].map(String::from);
+        let heads = Heads::from_names(&names).unwrap();
+        assert_eq!((heads.policy, heads.value, heads.wdl, heads.moves_left), (2, Some(0), Some(3), None));
+        assert_eq!(heads.auxiliary, vec![(1, Arc::from("ownership"))]);
+
+        let evaluation = heads.read(vec![vec![0.25], vec![1.0, -1.0], vec![0.5; 81], vec![0.2, 0.3, 0.5]]);
+        assert!((evaluation.value - 0.25).abs() < 1e-6);
+        assert_eq!(evaluation.wdl, Some([0.2, 0.3, 0.5]));
+        assert_eq!(evaluation.policy, vec![0.5; 81]);
+        assert_eq!(evaluation.auxiliary, vec![(Arc::from("ownership"), vec![1.0, -1.0])]);
+
+        // without a value head, the value is the expected score of the wdl head.
+        let heads = Heads::from_names(&["wdl", "policy"].map(String::from)).unwrap();
+        let evaluation = heads.read(vec![vec![0.2, 0.3, 0.5], vec![0.0; 81]]);
+        assert!((evaluation.value - 0.35).abs() < 1e-6);
+
+        // unnamed outputs are read by position.
+        let heads = Heads::from_names(&["a", "b", "c"].map(String::from)).unwrap();
+        assert_eq!(heads, Heads { moves_left: Some(2), ..Heads::default() });
+        assert!(Heads::from_names(&["a".to_string()]).is_err());
+        assert!(Heads::from_names(&["policy", "ownership"].map(String::from)).is_err());
+    }
+
+    #[test]
+    fn devices_parse() {
+        assert_eq!("cpu".parse::<Device>().unwrap(), Device::Cpu);
+        assert_eq!("cuda".parse::<Device>().unwrap(), Device::Cuda(0));
+        assert_eq!("cuda:2".parse::<Device>().unwrap(), Device::Cuda(2));
+        assert!("cuda:x".parse::<Device>().is_err());
+        assert_eq!("rocm:1".parse::<Device>().unwrap(), Device::Rocm(1));
+        assert!("tpu".parse::<Device>().is_err());
+        for device in [Device::Cpu, Device::Cuda(0), Device::Cuda(3), Device::DirectMl(0), Device::Rocm(1)] {
+            assert_eq!(device.to_string().parse::<Device>().unwrap(), device);
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "pure-mcts"))]
+    fn other_devices_need_onnxruntime() {
+        assert_eq!("ort".parse::<Backend>().unwrap(), Backend::OnnxRuntime);
+        assert!("tensorrt".parse::<Backend>().is_err());
+        let options = ExecutorOptions { device: Device::DirectMl(0), ..ExecutorOptions::default() };
+        assert!(executor_on::<Board>(&Network::Onnx(Graph::new(), Heads::default()), 1, options).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! A benchmark of search speed over a bundled suite of positions, so that performance regressions
+//! can be caught between builds.
+//!
+//! Each position is searched from scratch for a fixed number of nodes, and the total nodes and time
+//! are reported. Without a model, positions are evaluated by a seeded native network, as in the
+//! regression suite, so that the benchmark runs anywhere.
+
+use std::{
+    fmt::{self, Display},
+    time::Instant,
+};
+
+use crate::{
+    batching::{self, ExecutorHandle, Network},
+    engine::{Engine, SearchResults},
+    game::GameImpl,
+    native::NativeNet,
+    params::Params,
+    timemgmt::Limits,
+};
+
+/// The nodes searched in each position when no count is given.
+pub const DEFAULT_NODES: u64 = 1600;
+
+/// The seed and width of the network that evaluates positions when no model is given.
+const NETWORK_SEED: u64 = 0x6265_6e63_6821;
+const NETWORK_WIDTH: usize = 32;
+
+const ATAXX_POSITIONS: &[&str] = &[
+    "x6/7/5o1/7/7/7/o4xx x 1 2",
+    "x6/7/6o/7/7/7/o3x2 x 2 2",
+    "7/4o2/1x5/7/7/7/o5x x 2 2",
+    "x5o/7/7/7/7/o6/o4xx x 0 2",
+];
+
+const GOMOKU9_POSITIONS: &[&str] = &[
+    "x......../....o..../........./........./........./........./........./........./......... x 2",
+    ".x......./........./........./...o...../........./........./........./........./......... x 2",
+    "..x....../........./........./........./........./..o....../........./........./......... x 2",
+    "...x...../........./........./........./........./........./........./.o......./......... x 2",
+];
+
+const GOMOKU15_POSITIONS: &[&str] = &[
+    ".............../........x....../.............../.............../.............../.............../.............../o............../.............../.............../.............../.............../.............../.............../............... x 2",
+    ".............x./.............o./.............../.............../.............../.............../.............../.............../.............../.............../.............../.............../.............../.............../............... x 2",
+    ".............../.............../.............../......x......o./.............../.............../.............../.............../.............../.............../.............../.............../.............../.............../............... x 2",
+];
+
+const UTTT_POSITIONS: &[&str] = &["9/9/9/9/4x4/9/9/9/9 o 5", "9/9/9/3o5/4x4/5x3/9/9/9 o 6"];
+
+/// The bundled positions of the game with the given name, besides its starting position, which is
+/// always searched. Games without a suite of their own are benchmarked from their starting position alone.
+pub fn positions(game: &str) -> &'static [&'static str] {
+    match game {
+        "ataxx" => ATAXX_POSITIONS,
+        "gomoku9" => GOMOKU9_POSITIONS,
+        "gomoku15" => GOMOKU15_POSITIONS,
+        "uttt" => UTTT_POSITIONS,
+        _ => &[],
+    }
+}
+
+/// The totals of a benchmark run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub positions: usize,
+    pub nodes: u64,
+    pub millis: u128,
+}
+
+impl Report {
+    /// The nodes searched per second.
+    pub fn nps(&self) -> u128 {
+        u128::from(self.nodes) * 1000 / self.millis.max(1)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} positions {} nodes {} ms {} nps", self.positions, self.nodes, self.millis, self.nps())
+    }
+}
+
+/// Searches `startpos` and each of `fens` for `nodes` nodes, evaluating positions through `eval_pipe`.
+pub fn run<G: GameImpl>(
+    startpos: G,
+    eval_pipe: ExecutorHandle<G>,
+    fens: &[&str],
+    nodes: u64,
+) -> anyhow::Result<Report> {
+    let mut roots = vec![startpos];
+    for fen in fens {
+        roots.push(G::parse_fen(fen)?);
+    }
+    // each position is searched from scratch, however close it is to the one before.
+    let params = Params { reuse_tree: false, ..Params::default() };
+    let mut engine = Engine::new(params, Limits::nodes(nodes), &startpos, eval_pipe);
+    let mut report = Report { positions: roots.len(), nodes: 0, millis: 0 };
+    for root in roots {
+        engine.set_position(&root);
+        let start = Instant::now();
+        let SearchResults { root_dist, .. } = engine.go()?;
+        report.millis += start.elapsed().as_millis();
+        report.nodes += root_dist.iter().sum::<u64>();
+    }
+    Ok(report)
+}
+
+/// Runs the benchmark for the game with the given name from the command line, with the model at
+/// `model_path`, or a seeded native network if there is none. `startpos` is the starting position of the game.
+pub fn main<G: GameImpl>(game: &str, startpos: G, nodes: u64, model_path: Option<&str>) -> anyhow::Result<()> {
+    let network = match model_path {
+        Some(path) => batching::load_network::<G>(path)?,
+        None => Network::Native(NativeNet::seeded::<G>(NETWORK_SEED, NETWORK_WIDTH)),
+    };
+    let eval_pipe = batching::executor::<G>(&network, 1)?.into_iter().next().unwrap();
+    let report = run(startpos, eval_pipe, positions(game), nodes)?;
+    println!("{report}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_positions_are_valid() {
+        for fen in UTTT_POSITIONS {
+            assert!(crate::uttt::Board::parse_fen(fen).is_ok(), "invalid uttt position {fen}");
+        }
+        for fen in ATAXX_POSITIONS {
+            assert!(ataxxgen::Board::parse_fen(fen).is_ok(), "invalid ataxx position {fen}");
+        }
+        for fen in GOMOKU9_POSITIONS {
+            assert!(gomokugen::board::Board::<9>::parse_fen(fen).is_ok(), "invalid gomoku9 position {fen}");
+        }
+        for fen in GOMOKU15_POSITIONS {
+            assert!(gomokugen::board::Board::<15>::parse_fen(fen).is_ok(), "invalid gomoku15 position {fen}");
+        }
+    }
+
+    #[test]
+    fn every_position_is_searched() {
+        let network = Network::Native(NativeNet::seeded::<crate::uttt::Board>(NETWORK_SEED, 16));
+        let eval_pipe = batching::executor(&network, 1).unwrap().into_iter().next().unwrap();
+        let report = run(crate::uttt::Board::default(), eval_pipe, UTTT_POSITIONS, 50).unwrap();
+        assert_eq!(report.positions, 3);
+        assert!(report.nodes > 0);
+    }
+}
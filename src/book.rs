@@ -0,0 +1,192 @@
+//! Building opening books from stored games, and playing from them.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use anyhow::Context;
+
+use crate::{
+    game::{GameImpl, Player},
+    hash, pgn,
+};
+
+/// The fewest games a move must appear in to be kept in the book.
+const MIN_GAMES: u32 = 4;
+/// The lowest average score a move must have for the player making it to be kept in the book.
+const MIN_SCORE: f64 = 0.4;
+
+/// Statistics about a move played from a book position.
+#[derive(Default)]
+struct MoveStats {
+    games: u32,
+    /// The total score of the player making the move, counting draws as half.
+    score: f64,
+}
+
+/// An opening book, read from a file written by `build_book`, from which the engine plays while it can.
+pub struct Book {
+    /// The moves of each position in the book, with their weights, by the FNV-1a hash of the position's FEN.
+    positions: HashMap<u64, Vec<(String, u32)>>,
+}
+
+impl Book {
+    /// Reads the book at `path`.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("failed to read book {path}"))?;
+        let mut positions = HashMap::new();
+        for (line_number, line) in text.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+            let context = || format!("line {} of book {path}", line_number + 1);
+            let mut fields = line.splitn(3, ',');
+            let (Some(key), Some(fen), Some(moves)) = (fields.next(), fields.next(), fields.next()) else {
+                anyhow::bail!("expected <hash>,<fen>,<moves> on {}", context());
+            };
+            let key = u64::from_str_radix(key, 16).with_context(|| format!("invalid hash {key:?} on {}", context()))?;
+            anyhow::ensure!(key == hash::fnv1a(fen.as_bytes()), "hash does not match the fen on {}", context());
+            let moves = moves
+                .split_ascii_whitespace()
+                .map(|entry| {
+                    let (mv, weight) =
+                        entry.split_once(':').with_context(|| format!("expected <move>:<weight>, found {entry:?}"))?;
+                    Ok((mv.to_owned(), weight.parse().with_context(|| format!("invalid weight in {entry:?}"))?))
+                })
+                .collect::<anyhow::Result<_>>()
+                .with_context(context)?;
+            positions.insert(key, moves);
+        }
+        Ok(Self { positions })
+    }
+
+    /// Chooses a legal move of `board` from the book, at random in proportion to the weights of the moves,
+    /// or `None` if the position is not in the book.
+    pub fn probe<G: GameImpl>(&self, board: &G) -> Option<G::Move> {
+        let mut moves = Vec::new();
+        for (text, weight) in self.positions.get(&hash::fnv1a(board.fen().as_bytes()))? {
+            let Ok(mv) = G::parse_move(text) else {
+                continue;
+            };
+            let mut legal = false;
+            board.generate_moves(|legal_mv| {
+                legal = legal_mv == mv;
+                legal
+            });
+            if legal && *weight > 0 {
+                moves.push((mv, *weight));
+            }
+        }
+        let total = moves.iter().map(|&(_, weight)| weight).sum::<u32>();
+        if total == 0 {
+            return None;
+        }
+        let mut target = fastrand::u32(0..total);
+        for (mv, weight) in moves {
+            if target < weight {
+                return Some(mv);
+            }
+            target -= weight;
+        }
+        None
+    }
+}
+
+/// Builds an opening book from the games in `pgn_paths`, covering the first `max_ply` plies of each game,
+/// and writes it to `out_path`. Datagen writes its games in this format as `games.pgn`.
+///
+/// Each line of the book is the 64-bit FNV-1a hash of a position's FEN, the FEN, and its moves with their weights,
+/// which are the number of games in which each was played:
+/// `<hash>,<fen>,<move>:<weight> <move>:<weight> ...`.
+/// Only moves played in at least `MIN_GAMES` games and scoring at least `MIN_SCORE` are kept.
+pub fn build_book<G: GameImpl>(pgn_paths: &[&str], out_path: &str, max_ply: usize) -> anyhow::Result<()> {
+    let mut positions = HashMap::<String, HashMap<String, MoveStats>>::new();
+    let mut games_read = 0;
+    for path in pgn_paths {
+        let text = std::fs::read_to_string(path)?;
+        for game in pgn::parse_games::<G>(&text).with_context(|| format!("failed to read games from {path}"))? {
+            let Some(outcome) = game.outcome else {
+                continue;
+            };
+            games_read += 1;
+            let mut board = game.root;
+            for &mv in game.moves.iter().take(max_ply) {
+                let score = match outcome {
+                    Player::None => 0.5,
+                    winner if winner == board.to_move() => 1.0,
+                    _ => 0.0,
+                };
+                let stats = positions.entry(board.fen()).or_default().entry(mv.to_string()).or_default();
+                stats.games += 1;
+                stats.score += score;
+                board.make_move(mv);
+            }
+        }
+    }
+
+    let mut book = positions
+        .into_iter()
+        .filter_map(|(fen, moves)| {
+            let mut moves = moves
+                .into_iter()
+                .filter(|(_, stats)| stats.games >= MIN_GAMES && stats.score / f64::from(stats.games) >= MIN_SCORE)
+                .map(|(mv, stats)| (mv, stats.games))
+                .collect::<Vec<_>>();
+            moves.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            (!moves.is_empty()).then_some((fen, moves))
+        })
+        .collect::<Vec<_>>();
+    book.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out = BufWriter::new(File::create(out_path)?);
+    for (fen, moves) in &book {
+        let moves = moves.iter().map(|(mv, weight)| format!("{mv}:{weight}")).collect::<Vec<_>>().join(" ");
+        writeln!(out, "{:016x},{fen},{moves}", hash::fnv1a(fen.as_bytes()))?;
+    }
+    out.flush()?;
+
+    println!("Read {games_read} games, wrote {} book positions to {out_path}", book.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uttt::Board;
+
+    #[test]
+    fn built_books_are_played_from() {
+        let root = Board::default();
+        let mut first = None;
+        root.generate_moves(|mv| {
+            first = Some(mv);
+            true
+        });
+        let first = first.unwrap();
+        let dir = std::env::temp_dir().join(format!("veritas-book-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (pgn_path, book_path) = (dir.join("games.pgn"), dir.join("book.txt"));
+        let mut games = Vec::new();
+        for _ in 0..MIN_GAMES {
+            pgn::write_game(&mut games, "test", &root, &[first], Player::None).unwrap();
+        }
+        std::fs::write(&pgn_path, games).unwrap();
+        build_book::<Board>(&[pgn_path.to_str().unwrap()], book_path.to_str().unwrap(), 1).unwrap();
+
+        let book = Book::load(book_path.to_str().unwrap()).unwrap();
+        assert_eq!(book.probe(&root), Some(first));
+        let mut after = root;
+        after.make_move(first);
+        assert_eq!(book.probe(&after), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn books_with_wrong_hashes_are_rejected() {
+        let path = std::env::temp_dir().join(format!("veritas-bad-book-test-{}", std::process::id()));
+        let fen = Board::default().fen();
+        std::fs::write(&path, format!("{:016x},{fen},e5:1\n", hash::fnv1a(fen.as_bytes()) ^ 1)).unwrap();
+        assert!(Book::load(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! Connect6: players place stones on a 19x19 board, the first player placing one stone
+//! on their first turn and each turn thereafter consisting of two stones.
+//! The first to get six (or more) in a row wins.
+//!
+//! Each stone is its own move, so a turn spans two moves, and the side to move is
+//! unchanged after the first stone of a turn.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+use crate::game::Player;
+
+/// The board side length.
+pub const SIZE: usize = 19;
+/// The number of cells on the board.
+pub const CELLS: usize = SIZE * SIZE;
+/// The number of stones in a row needed to win.
+const WIN_LENGTH: usize = 6;
+
+/// A move in Connect6, i.e. the cell on which to place a single stone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Move(u16);
+
+impl Move {
+    /// The index of the cell, `row * 19 + col`.
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = (b'a' + u8::try_from(self.index() % SIZE).expect("file out of range")) as char;
+        write!(f, "{file}{}", self.index() / SIZE + 1)
+    }
+}
+
+impl FromStr for Move {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let file = chars.next().with_context(|| "empty move string")?;
+        if !file.is_ascii_lowercase() || usize::from(file as u8 - b'a') >= SIZE {
+            anyhow::bail!("invalid file {file:?} in move {s:?}");
+        }
+        let rank: usize = chars.as_str().parse().with_context(|| format!("invalid rank in move {s:?}"))?;
+        if !(1..=SIZE).contains(&rank) {
+            anyhow::bail!("invalid rank {rank} in move {s:?}");
+        }
+        let index = (rank - 1) * SIZE + usize::from(file as u8 - b'a');
+        Ok(Self(index.try_into()?))
+    }
+}
+
+/// A Connect6 position.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Board {
+    cells: [Player; CELLS],
+    /// The number of stones on the board, which determines the side to move.
+    stones: usize,
+    winner: Option<Player>,
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self { cells: [Player::None; CELLS], stones: 0, winner: None }
+    }
+}
+
+impl Board {
+    /// The player to place the next stone.
+    /// The first player places stone 1, the second stones 2 and 3, the first 4 and 5, etc.
+    pub const fn turn(&self) -> Player {
+        if self.stones == 0 || ((self.stones - 1) / 2) % 2 == 1 {
+            Player::First
+        } else {
+            Player::Second
+        }
+    }
+
+    pub const fn outcome(&self) -> Option<Player> {
+        if self.winner.is_some() {
+            self.winner
+        } else if self.stones == CELLS {
+            Some(Player::None)
+        } else {
+            None
+        }
+    }
+
+    pub fn make_move(&mut self, mv: Move) {
+        let index = mv.index();
+        debug_assert_eq!(self.cells[index], Player::None, "cell {mv} is already occupied");
+        let player = self.turn();
+        self.cells[index] = player;
+        self.stones += 1;
+        if self.makes_six(index, player) {
+            self.winner = Some(player);
+        }
+    }
+
+    /// Whether the stone on the given cell is part of a line of at least six stones.
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+    fn makes_six(&self, index: usize, player: Player) -> bool {
+        let (row, col) = ((index / SIZE) as isize, (index % SIZE) as isize);
+        let in_bounds = |r: isize, c: isize| (0..SIZE as isize).contains(&r) && (0..SIZE as isize).contains(&c);
+        [(0, 1), (1, 0), (1, 1), (1, -1)].into_iter().any(|(dr, dc)| {
+            let mut count = 1;
+            for sign in [1, -1] {
+                let (mut r, mut c) = (row + dr * sign, col + dc * sign);
+                while in_bounds(r, c) && self.cells[r as usize * SIZE + c as usize] == player {
+                    count += 1;
+                    r += dr * sign;
+                    c += dc * sign;
+                }
+            }
+            count >= WIN_LENGTH
+        })
+    }
+
+    /// Generate a list of legal moves.
+    /// Iteration short-circuits if the callback returns `true`.
+    pub fn generate_moves(&self, mut f: impl FnMut(Move) -> bool) {
+        if self.outcome().is_some() {
+            return;
+        }
+        for index in 0..CELLS {
+            if self.cells[index] == Player::None && f(Move(index.try_into().expect("index out of range"))) {
+                return;
+            }
+        }
+    }
+
+    /// Calls the callback with the index and owner of every stone on the board.
+    pub fn feature_map(&self, mut f: impl FnMut(usize, Player)) {
+        for (index, &stone) in self.cells.iter().enumerate() {
+            if stone != Player::None {
+                f(index, stone);
+            }
+        }
+    }
+
+    /// Whether the side to move is placing the second stone of their turn.
+    pub const fn is_second_stone(&self) -> bool {
+        self.stones > 0 && self.stones & 1 == 0
+    }
+
+    pub fn fen(&self) -> String {
+        let mut fen = String::new();
+        for row in (0..SIZE).rev() {
+            let mut empty = 0;
+            for col in 0..SIZE {
+                let c = match self.cells[row * SIZE + col] {
+                    Player::None => {
+                        empty += 1;
+                        continue;
+                    }
+                    Player::First => 'x',
+                    Player::Second => 'o',
+                };
+                if empty > 0 {
+                    fen += &empty.to_string();
+                    empty = 0;
+                }
+                fen.push(c);
+            }
+            if empty > 0 {
+                fen += &empty.to_string();
+            }
+            if row > 0 {
+                fen.push('/');
+            }
+        }
+        fen.push_str(if self.turn() == Player::First { " x" } else { " o" });
+        fen
+    }
+}
+
+impl Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in (0..SIZE).rev() {
+            for col in 0..SIZE {
+                let c = match self.cells[row * SIZE + col] {
+                    Player::None => '.',
+                    Player::First => 'x',
+                    Player::Second => 'o',
+                };
+                write!(f, "{c}")?;
+            }
+            if row > 0 {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Board({})", self.fen())
+    }
+}
+
+impl FromStr for Board {
+    type Err = anyhow::Error;
+
+    /// Parses a FEN of the form `19/19/.../9x9/... o`.
+    /// The side to move is implied by the stone count, so the last field is only checked for consistency.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_ascii_whitespace();
+        let rows = parts.next().with_context(|| format!("empty fen {s:?}"))?.split('/').collect::<Vec<_>>();
+        if rows.len() != SIZE {
+            anyhow::bail!("expected {SIZE} rows in fen {s:?}, found {}", rows.len());
+        }
+        let mut board = Self::default();
+        for (i, row_text) in rows.iter().enumerate() {
+            let row = SIZE - 1 - i;
+            let mut col = 0;
+            let mut empty = 0;
+            for c in row_text.chars() {
+                if let Some(digit) = c.to_digit(10) {
+                    empty = empty * 10 + digit as usize;
+                    continue;
+                }
+                col += empty;
+                empty = 0;
+                if col >= SIZE {
+                    anyhow::bail!("row {row_text:?} is too long in fen {s:?}");
+                }
+                board.cells[row * SIZE + col] = match c {
+                    'x' => Player::First,
+                    'o' => Player::Second,
+                    _ => anyhow::bail!("invalid character {c:?} in fen {s:?}"),
+                };
+                board.stones += 1;
+                col += 1;
+            }
+            col += empty;
+            if col != SIZE {
+                anyhow::bail!("row {row_text:?} does not have {SIZE} cells in fen {s:?}");
+            }
+        }
+        for index in 0..CELLS {
+            let stone = board.cells[index];
+            if stone != Player::None && board.makes_six(index, stone) {
+                board.winner = Some(stone);
+            }
+        }
+        let expected = if board.turn() == Player::First { "x" } else { "o" };
+        match parts.next() {
+            Some(side) if side == expected => {}
+            None => {}
+            Some(side) => anyhow::bail!("side-to-move {side:?} is inconsistent with the stone count in fen {s:?}"),
+        }
+        Ok(board)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turn_order() {
+        use Player::{First, Second};
+        let mut board = Board::default();
+        let mut turns = Vec::new();
+        for mv in ["a1", "b1", "c1", "d1", "e1", "f1"] {
+            turns.push(board.turn());
+            board.make_move(mv.parse().unwrap());
+        }
+        assert_eq!(turns, [First, Second, Second, First, First, Second]);
+    }
+
+    #[test]
+    fn six_in_a_row_wins() {
+        let mut board = Board::default();
+        // x: j10, then pairs on row 10; o: pairs on row 1.
+        for mv in ["j10", "a1", "b1", "k10", "l10", "c1", "d1", "m10", "n10", "e1", "s1"] {
+            assert_eq!(board.outcome(), None);
+            board.make_move(mv.parse().unwrap());
+        }
+        assert_eq!(board.outcome(), None);
+        board.make_move("o10".parse().unwrap());
+        assert_eq!(board.outcome(), Some(Player::First));
+    }
+
+    #[test]
+    fn fen_round_trip() {
+        let mut board = Board::default();
+        for mv in ["j10", "a1", "s19"] {
+            board.make_move(mv.parse().unwrap());
+        }
+        let parsed: Board = board.fen().parse().unwrap();
+        assert_eq!(parsed, board);
+        assert_eq!(parsed.turn(), Player::First);
+    }
+}
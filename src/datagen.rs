@@ -1,44 +1,266 @@
 use std::{
     fs::File,
     io::{BufWriter, Write},
-    sync::atomic::AtomicUsize,
+    str::FromStr,
+    sync::{atomic::AtomicUsize, Arc},
+    time::{Duration, Instant},
 };
 
-use kn_graph::{ndarray::Dimension, optimizer::OptimizerSettings};
-use rand::{seq::SliceRandom, Rng as _};
+use anyhow::Context;
+use kn_graph::ndarray::Dimension;
+use rand::{seq::SliceRandom, Rng};
 
 use crate::{
-    batching::{self, ExecutorHandle},
+    batching::{self, ExecutorHandle, ExecutorOptions},
     engine::{Engine, SearchResults},
-    game::{GameImpl, Player},
+    game::{GameImpl, MovePolicyIndex, Player},
     params::Params,
+    pgn,
     timemgmt::Limits,
 };
 
 struct GameRecord<G: GameImpl> {
     root: G,
-    move_list: Vec<(G::Move, Vec<u64>, bool)>,
+    /// Each move played, with the policy target of its position and whether it was searched at full strength.
+    move_list: Vec<(G::Move, Vec<f64>, bool)>,
     outcome: Option<Player>,
+    /// The name of the profile the game was played with.
+    profile: Arc<str>,
 }
 
-static GAMES_GENERATED: AtomicUsize = AtomicUsize::new(0);
-static POSITIONS_GENERATED: AtomicUsize = AtomicUsize::new(0);
+/// Search settings for some of the self-play workers, so that a run can generate data from
+/// a population of differently-exploring players rather than from one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub c_puct: f64,
+    /// The share of the root's policy replaced by Dirichlet noise. Nodes below the root are never noised.
+    pub root_noise_weight: f64,
+    pub policy_temperature: f32,
+    /// The temperature at which moves are sampled from the root visit distribution.
+    pub move_temperature: f64,
+    /// The number of root moves that Gumbel sequential halving chooses between, or `None` for PUCT.
+    pub gumbel_root_moves: Option<usize>,
+    /// The ply of the game by which c-PUCT has decayed to its floor, or zero to keep it constant.
+    pub cpuct_decay_plies: usize,
+    /// The weight of the network's value against random rollouts in the evaluation of leaves.
+    pub value_blend: f32,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        let params = Params::default();
+        Self {
+            name: "default".into(),
+            c_puct: params.c_puct,
+            root_noise_weight: params.noise_weight_at(0),
+            policy_temperature: params.root_policy_softmax_temp,
+            move_temperature: params.temperature,
+            gumbel_root_moves: Some(GUMBEL_ROOT_MOVES),
+            cpuct_decay_plies: params.cpuct_decay_plies,
+            value_blend: params.value_blend,
+        }
+    }
+}
+
+impl Profile {
+    /// The default search parameters, with this profile's settings in place.
+    fn params(&self) -> Params<'static> {
+        Params {
+            c_puct: self.c_puct,
+            noise_weight: self.root_noise_weight,
+            interior_noise_weight: 0.0,
+            root_policy_softmax_temp: self.policy_temperature,
+            temperature: self.move_temperature,
+            gumbel_root_moves: self.gumbel_root_moves,
+            cpuct_decay_plies: self.cpuct_decay_plies,
+            value_blend: self.value_blend,
+            ..Params::default()
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = anyhow::Error;
+
+    /// Parses a profile of the form `<name> [cpuct=<f>] [noise=<f>] [temp=<f>] [movetemp=<f>] [gumbel=<n>]
+    /// [decay=<n>] [blend=<f>]`, where omitted settings take their default values, `gumbel=0` selects root moves
+    /// by PUCT, `decay` is the ply by which c-PUCT has decayed to its floor, and `blend` weighs the network's
+    /// value against random rollouts, for when the network is still weak.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_ascii_whitespace();
+        let name = words.next().with_context(|| "empty profile")?;
+        let mut profile = Self { name: name.into(), ..Self::default() };
+        for word in words {
+            let (key, value) = word.split_once('=').with_context(|| format!("expected <key>=<value>, got {word:?}"))?;
+            let invalid = || format!("invalid {key} value {value:?} in profile {name}");
+            match key {
+                "cpuct" => profile.c_puct = value.parse().with_context(invalid)?,
+                "noise" => profile.root_noise_weight = value.parse().with_context(invalid)?,
+                "temp" => profile.policy_temperature = value.parse().with_context(invalid)?,
+                "movetemp" => profile.move_temperature = value.parse().with_context(invalid)?,
+                "gumbel" => {
+                    let moves = value.parse().with_context(invalid)?;
+                    profile.gumbel_root_moves = if moves > 0 { Some(moves) } else { None };
+                }
+                "decay" => profile.cpuct_decay_plies = value.parse().with_context(invalid)?,
+                "blend" => {
+                    let blend = value.parse::<f32>().with_context(invalid)?;
+                    anyhow::ensure!((0.0..=1.0).contains(&blend), "blend must be between 0 and 1 in profile {name}");
+                    profile.value_blend = blend;
+                }
+                _ => anyhow::bail!(
+                    "unknown setting {key:?} in profile {name}, expected cpuct, noise, temp, movetemp, gumbel, decay, or blend"
+                ),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+/// Reads a population of profiles, one per line, skipping blank lines and `#` comments.
+pub fn load_profiles(path: &str) -> anyhow::Result<Vec<Profile>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+    let profiles = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect::<anyhow::Result<Vec<Profile>>>()?;
+    anyhow::ensure!(!profiles.is_empty(), "{path} does not define any profiles");
+    Ok(profiles)
+}
+
+/// The settings for a single data generation run.
+pub struct DatagenConfig<'a> {
+    pub num_threads: usize,
+    pub time_allocated_millis: u128,
+    pub model_path: Option<&'a str>,
+    /// Whether to downsample the over-represented decisive result when writing.
+    pub balance_results: bool,
+    /// A node budget for each game, shared between its moves, in place of the fixed per-move playout caps.
+    pub game_node_budget: Option<u64>,
+    /// The folder to write the data to.
+    pub save_folder: String,
+    /// A name to tag output with, when several runs share the terminal.
+    pub label: Option<String>,
+    /// The settings the workers search with, handed out to them in turn. Empty uses the defaults.
+    pub profiles: &'a [Profile],
+    /// Where the network is evaluated, and the most positions evaluated at once.
+    pub executor_options: ExecutorOptions,
+}
+
+/// Counters shared between the threads of a single run.
+#[derive(Default)]
+struct RunCounters {
+    games_generated: AtomicUsize,
+    positions_generated: AtomicUsize,
+    first_player_wins: AtomicUsize,
+    second_player_wins: AtomicUsize,
+    draws: AtomicUsize,
+    /// Games left out of the written data to balance the decisive results.
+    games_skipped: AtomicUsize,
+}
 
 const PLAYOUT_CAP_RANDOMISATION_FREQ: f64 = 0.25;
 const HI_PLAYOUT_CAP: u64 = 800;
 const LO_PLAYOUT_CAP: u64 = 200;
+/// The number of root moves that Gumbel sequential halving chooses between, which gives better policy targets
+/// than PUCT visit counts at self-play budgets.
+const GUMBEL_ROOT_MOVES: usize = 16;
+/// Searches stop early once the best move holds this percentage of the root visits...
+const EARLY_EXIT_SHARE_PERCENT: u64 = 90;
+/// ...after at least this many nodes.
+const EARLY_EXIT_PROBE_NODES: u64 = 100;
+/// The most unused nodes that can be banked for later contested positions.
+const MAX_BANKED_NODES: u64 = 4 * HI_PLAYOUT_CAP;
+/// The game length assumed when spreading a game's node budget, until games have been played to measure it.
+const DEFAULT_EXPECTED_GAME_LENGTH: f64 = 60.0;
+/// The fewest moves a game's remaining node budget is spread over, so that late moves are not starved.
+const MIN_EXPECTED_MOVES_LEFT: f64 = 10.0;
+/// The fewest nodes searched for any move under a game budget.
+const MIN_BUDGETED_NODES: u64 = 32;
+
+/// The playout cap for a move under a whole-game node budget, with `remaining` nodes left in that budget.
+/// The budget is spread evenly over the moves expected to remain, and high-quality moves take the same
+/// multiple of that share as they do of the average cap under fixed playout caps.
+#[allow(clippy::cast_precision_loss, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn budgeted_playout_cap(remaining: u64, ply: usize, expected_length: f64, high_quality_move: bool) -> u64 {
+    let moves_left = (expected_length - ply as f64).max(MIN_EXPECTED_MOVES_LEFT);
+    let share = remaining as f64 / moves_left;
+    let average_cap = PLAYOUT_CAP_RANDOMISATION_FREQ
+        .mul_add(HI_PLAYOUT_CAP as f64, (1.0 - PLAYOUT_CAP_RANDOMISATION_FREQ) * LO_PLAYOUT_CAP as f64);
+    let cap = if high_quality_move { HI_PLAYOUT_CAP } else { LO_PLAYOUT_CAP };
+    ((share * cap as f64 / average_cap) as u64).max(MIN_BUDGETED_NODES)
+}
+
+/// Decides whether to write a game, downsampling whichever player's wins are over-represented
+/// in the data written so far. Draws are always kept.
+struct ResultBalancer {
+    /// The number of first- and second-player wins written.
+    written: [u64; 2],
+}
+
+impl ResultBalancer {
+    fn accept(&mut self, outcome: Player, rng: &mut impl Rng) -> bool {
+        let (ours, theirs) = match outcome {
+            Player::None => return true,
+            Player::First => (0, 1),
+            Player::Second => (1, 0),
+        };
+        // keep the winning class with probability (theirs + 1) / (ours + 1), capped at one.
+        #[allow(clippy::cast_precision_loss)]
+        let keep_probability = ((self.written[theirs] + 1) as f64 / (self.written[ours] + 1) as f64).min(1.0);
+        let keep = rng.gen_bool(keep_probability);
+        if keep {
+            self.written[ours] += 1;
+        }
+        keep
+    }
+}
 
 fn game_record_writer_thread<G: GameImpl>(
     save_folder: &str,
     recv: std::sync::mpsc::Receiver<GameRecord<G>>,
+    balance_results: bool,
+    counters: &RunCounters,
 ) -> anyhow::Result<()> {
     let mut positions = BufWriter::new(File::create(format!("{save_folder}/positions.csv"))?);
     let mut policy_tgt = BufWriter::new(File::create(format!("{save_folder}/policy-target.csv"))?);
     let mut value_tgt = BufWriter::new(File::create(format!("{save_folder}/value-target.csv"))?);
+    let mut ownership_tgt = BufWriter::new(File::create(format!("{save_folder}/ownership-target.csv"))?);
+    let mut moves_left_tgt = BufWriter::new(File::create(format!("{save_folder}/moves-left-target.csv"))?);
+    // the profile that played each position, for filtering or weighting the data by its source.
+    let mut profiles = BufWriter::new(File::create(format!("{save_folder}/profiles.csv"))?);
+    // the games themselves, for building opening books.
+    let mut games = BufWriter::new(File::create(format!("{save_folder}/games.pgn"))?);
+
+    let mut balancer = balance_results.then_some(ResultBalancer { written: [0; 2] });
+    let mut rng = rand::thread_rng();
 
     for game in recv {
+        let outcome = game.outcome.expect("game record has no outcome");
+        if let Some(balancer) = &mut balancer {
+            if !balancer.accept(outcome, &mut rng) {
+                counters.games_skipped.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                continue;
+            }
+        }
+
+        let moves = game.move_list.iter().map(|(mv, _, _)| *mv).collect::<Vec<_>>();
+        pgn::write_game(&mut games, &format!("datagen {}", game.profile), &game.root, &moves, outcome)?;
+
+        // replay the game to find who owns each square at the end.
+        let mut final_board = game.root;
+        for (mv, _, _) in &game.move_list {
+            final_board.make_move(*mv);
+        }
+        let mut final_ownership = vec![Player::None; G::ownership_dim()];
+        final_board.fill_ownership(|index, player| final_ownership[index] = player);
+
+        let game_length = game.move_list.len();
         let mut board = game.root;
-        for (best_move, root_dist, hq_move) in game.move_list {
+        for (ply, (best_move, policy_target, hq_move)) in game.move_list.into_iter().enumerate() {
             if !hq_move {
                 // don't save positions from low quality moves
                 board.make_move(best_move);
@@ -59,10 +281,10 @@ fn game_record_writer_thread<G: GameImpl>(
             }
             writeln!(positions)?;
             // write out the policy target
-            assert_eq!(root_dist.len(), G::POLICY_DIM);
-            for (i, p) in root_dist.iter().enumerate() {
+            assert_eq!(policy_target.len(), G::policy_dim());
+            for (i, p) in policy_target.iter().enumerate() {
                 write!(policy_tgt, "{:.3}", *p)?;
-                if i < root_dist.len() - 1 {
+                if i < policy_target.len() - 1 {
                     write!(policy_tgt, ",")?;
                 }
             }
@@ -80,53 +302,119 @@ fn game_record_writer_thread<G: GameImpl>(
                 None => unreachable!(),
             };
             writeln!(value_tgt, "{value_target}")?;
+            // write out the ownership target, from the side to move's perspective
+            for (i, owner) in final_ownership.iter().enumerate() {
+                let ownership = match *owner {
+                    Player::None => 0,
+                    player if player == to_move => 1,
+                    _ => -1,
+                };
+                write!(ownership_tgt, "{ownership}")?;
+                if i < final_ownership.len() - 1 {
+                    write!(ownership_tgt, ",")?;
+                }
+            }
+            writeln!(ownership_tgt)?;
+            // write out the moves-left target, the number of plies until the game ends
+            writeln!(moves_left_tgt, "{}", game_length - ply)?;
+            writeln!(profiles, "{}", game.profile)?;
             board.make_move(best_move);
-            POSITIONS_GENERATED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            counters.positions_generated.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         }
 
         positions.flush()?;
         policy_tgt.flush()?;
         value_tgt.flush()?;
+        ownership_tgt.flush()?;
+        moves_left_tgt.flush()?;
+        profiles.flush()?;
+        games.flush()?;
     }
 
     positions.flush()?;
     policy_tgt.flush()?;
     value_tgt.flush()?;
+    ownership_tgt.flush()?;
+    moves_left_tgt.flush()?;
+    profiles.flush()?;
+    games.flush()?;
 
     Ok(())
 }
 
 static STDOUT_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
 
-#[allow(clippy::too_many_lines)]
+/// Statistics gathered by a single self-play worker.
+#[derive(Debug, Default)]
+struct WorkerStats {
+    /// The number of games completed.
+    games: u64,
+    /// The number of moves searched across all completed games.
+    moves: u64,
+    /// The total time spent searching.
+    search_time: Duration,
+    /// The total time spent blocked waiting for the executor.
+    executor_wait: Duration,
+    /// The name of the profile the worker played with.
+    profile: Arc<str>,
+}
+
+impl WorkerStats {
+    #[allow(clippy::cast_precision_loss)]
+    fn print_row(&self, thread_id: usize) {
+        let avg_length = if self.games == 0 { 0.0 } else { self.moves as f64 / self.games as f64 };
+        let avg_move_ms =
+            if self.moves == 0 { 0.0 } else { self.search_time.as_secs_f64() * 1000.0 / self.moves as f64 };
+        let wait_share = if self.search_time.is_zero() {
+            0.0
+        } else {
+            self.executor_wait.as_secs_f64() / self.search_time.as_secs_f64() * 100.0
+        };
+        println!(
+            "{thread_id:>6} {:>8} {avg_length:>11.1} {avg_move_ms:>14.1} {:>14.1} {wait_share:>7.1}%  {}",
+            self.games,
+            self.executor_wait.as_secs_f64(),
+            self.profile
+        );
+    }
+}
+
+#[allow(clippy::too_many_lines, clippy::too_many_arguments)]
 fn self_play_worker_thread<G: GameImpl>(
     time_allocated_millis: u128,
     thread_id: usize,
     executor: ExecutorHandle<G>,
     send: std::sync::mpsc::Sender<GameRecord<G>>,
-) -> anyhow::Result<()> {
+    counters: &RunCounters,
+    label: &str,
+    game_node_budget: Option<u64>,
+    profile: &Profile,
+    starting_position: G,
+) -> anyhow::Result<WorkerStats> {
     #![allow(clippy::cast_precision_loss)]
     let start_time = std::time::Instant::now();
-    let default_params = Params::default();
+    let default_params = profile.params();
     let default_limits = "nodes 800".parse()?;
-    let starting_position = G::default();
     let mut engine = Engine::new(default_params, default_limits, &starting_position, executor);
 
     let mut rng = rand::thread_rng();
+    let mut stats = WorkerStats { profile: profile.name.as_str().into(), ..WorkerStats::default() };
 
     while start_time.elapsed().as_millis() < time_allocated_millis {
-        GAMES_GENERATED.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        counters.games_generated.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
 
         let stdout_lock = STDOUT_LOCK.lock().unwrap();
         print!(
-            "\rGenerated {} games at {:.2} pos/sec",
-            GAMES_GENERATED.load(std::sync::atomic::Ordering::Relaxed),
-            POSITIONS_GENERATED.load(std::sync::atomic::Ordering::Relaxed) as f64 / start_time.elapsed().as_secs_f64()
+            "\r{label}Generated {} games at {:.2} pos/sec",
+            counters.games_generated.load(std::sync::atomic::Ordering::Relaxed),
+            counters.positions_generated.load(std::sync::atomic::Ordering::Relaxed) as f64
+                / start_time.elapsed().as_secs_f64()
         );
         std::io::stdout().flush()?;
         drop(stdout_lock);
 
-        let mut board = G::default();
+        let mut board = starting_position;
+        let mut opening_plies = 0;
         for _ in 0..8 + rng.gen_range(0..=1) {
             let mut moves = Vec::new();
             board.generate_moves(|mv| {
@@ -137,26 +425,75 @@ fn self_play_worker_thread<G: GameImpl>(
                 continue;
             };
             board.make_move(mv);
+            opening_plies += 1;
         }
-        let mut game = GameRecord { root: board, move_list: Vec::new(), outcome: None };
-
+        let mut game =
+            GameRecord { root: board, move_list: Vec::new(), outcome: None, profile: Arc::clone(&stats.profile) };
+
+        // nodes saved by stopping early on obvious moves, to be spent on high-quality searches.
+        let mut banked_nodes = 0;
+        // what is left of this game's node budget, if it has one.
+        let mut remaining_budget = game_node_budget;
+        let expected_length = if stats.games == 0 {
+            DEFAULT_EXPECTED_GAME_LENGTH
+        } else {
+            stats.moves as f64 / stats.games as f64
+        };
         while board.outcome().is_none() {
+            // forced moves need no search, and make for useless training positions.
+            if let Some(forced) = board.only_move() {
+                let mut policy_target = vec![0.0; G::policy_dim()];
+                policy_target[forced.policy_index()] = 1.0;
+                board.make_move(forced);
+                game.move_list.push((forced, policy_target, false));
+                continue;
+            }
             engine.set_position(&board);
+            engine.params_mut().game_ply = opening_plies + game.move_list.len();
             let high_quality_move = rng.gen_bool(PLAYOUT_CAP_RANDOMISATION_FREQ);
-            let playout_cap = if high_quality_move { HI_PLAYOUT_CAP } else { LO_PLAYOUT_CAP };
-            engine.set_limits(Limits::nodes(playout_cap));
-            let SearchResults { best_move, root_dist } = engine.go()?;
-            assert_eq!(root_dist.len(), G::POLICY_DIM);
+            let playout_cap = if let Some(remaining) = remaining_budget {
+                budgeted_playout_cap(remaining, game.move_list.len(), expected_length, high_quality_move)
+            } else {
+                let bonus = if high_quality_move { std::mem::take(&mut banked_nodes).min(HI_PLAYOUT_CAP) } else { 0 };
+                let cap = if high_quality_move { HI_PLAYOUT_CAP } else { LO_PLAYOUT_CAP };
+                cap + bonus
+            };
+            engine.set_limits(
+                Limits::nodes(playout_cap).with_early_exit(EARLY_EXIT_PROBE_NODES, EARLY_EXIT_SHARE_PERCENT),
+            );
+            // a reused tree comes with visits of its own, which were paid for by earlier searches.
+            let inherited_nodes = engine.root_stats().iter().map(|root_move| root_move.visits).sum::<u64>();
+            let search_start = Instant::now();
+            let SearchResults { best_move, root_dist, policy_target, .. } = engine.go()?;
+            stats.search_time += search_start.elapsed();
+            let nodes_used = root_dist.iter().sum::<u64>().saturating_sub(inherited_nodes);
+            banked_nodes = (banked_nodes + playout_cap.saturating_sub(nodes_used)).min(MAX_BANKED_NODES);
+            // under a game budget, nodes saved by stopping early stay in the budget for later moves.
+            remaining_budget = remaining_budget.map(|remaining| remaining.saturating_sub(nodes_used));
+            assert_eq!(root_dist.len(), G::policy_dim());
+            // without Gumbel root selection, the visit counts are the target.
+            #[allow(clippy::cast_precision_loss)]
+            let policy_target =
+                policy_target.unwrap_or_else(|| root_dist.iter().map(|&visits| visits as f64).collect());
             board.make_move(best_move);
-            game.move_list.push((best_move, root_dist, high_quality_move));
+            game.move_list.push((best_move, policy_target, high_quality_move));
         }
 
         if let Some(outcome) = board.outcome() {
             game.outcome = Some(outcome);
+            let counter = match outcome {
+                Player::None => &counters.draws,
+                Player::First => &counters.first_player_wins,
+                Player::Second => &counters.second_player_wins,
+            };
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         } else {
             anyhow::bail!("Game ended without outcome in position {:?}. move sequence was {:?}", board, game.move_list);
         }
 
+        stats.games += 1;
+        stats.moves += game.move_list.len() as u64;
+        stats.executor_wait = engine.eval_wait_time();
         send.send(game)?;
     }
 
@@ -166,59 +503,167 @@ fn self_play_worker_thread<G: GameImpl>(
 
     std::mem::drop(send);
 
-    Ok(())
+    Ok(stats)
 }
 
-pub fn run_data_generation<G: GameImpl>(
-    num_threads: usize,
-    time_allocated_millis: u128,
-    model_path: Option<&str>,
-) -> anyhow::Result<()> {
-    let date = chrono::Local::now().format("%Y-%m-%d-%H-%M-%S");
-    let save_folder = format!("data/{date}");
-    std::fs::create_dir_all(&save_folder).unwrap();
-
-    println!("Running data generation with {num_threads} threads");
+pub fn run_data_generation<G: GameImpl>(starting_position: G, config: &DatagenConfig) -> anyhow::Result<()> {
+    let DatagenConfig {
+        num_threads,
+        time_allocated_millis,
+        model_path,
+        balance_results,
+        game_node_budget,
+        ref save_folder,
+        ref label,
+        profiles,
+        executor_options,
+    } = *config;
+    std::fs::create_dir_all(save_folder).unwrap();
+    let label: Arc<str> = label.as_ref().map_or_else(String::new, |label| format!("[{label}] ")).into();
+
+    println!("{label}Running data generation with {num_threads} threads");
+    let default_profile = [Profile::default()];
+    let profiles = if profiles.is_empty() { &default_profile[..] } else { profiles };
     let mut threads = Vec::new();
 
-    // Load an onnx file into a Graph.
-    let raw_graph = kn_graph::onnx::load_graph_from_onnx_path(model_path.unwrap_or("model.onnx"), false).unwrap();
-    // Optimise the graph.
-    let graph = kn_graph::optimizer::optimize_graph(&raw_graph, OptimizerSettings::default());
-    // Deallocate the raw graph.
-    std::mem::drop(raw_graph);
-
-    let executor_handles = batching::executor::<G>(&graph, num_threads)?;
+    let network = batching::load_network::<G>(model_path.unwrap_or("model.onnx"))?;
+    let executor_handles = batching::executor_on::<G>(&network, num_threads, executor_options)?;
 
     let (send, recv) = std::sync::mpsc::channel();
+    let counters = Arc::new(RunCounters::default());
 
     let save_folder_p = save_folder.clone();
+    let counters_p = Arc::clone(&counters);
     threads.push(
         std::thread::Builder::new()
             .name("game_record_writer".to_string())
-            .spawn(move || game_record_writer_thread(&save_folder_p, recv))?,
+            .spawn(move || game_record_writer_thread(&save_folder_p, recv, balance_results, &counters_p))?,
     );
 
+    let mut workers = Vec::new();
     for (thread_id, executor) in executor_handles.into_iter().enumerate() {
         let send = send.clone();
-        threads.push(
-            std::thread::Builder::new()
-                .name(format!("self_play_worker_{thread_id}"))
-                .spawn(move || self_play_worker_thread(time_allocated_millis, thread_id, executor, send))?,
-        );
+        let counters = Arc::clone(&counters);
+        let label = Arc::clone(&label);
+        let profile = profiles[thread_id % profiles.len()].clone();
+        workers.push(std::thread::Builder::new().name(format!("self_play_worker_{thread_id}")).spawn(move || {
+            self_play_worker_thread(
+                time_allocated_millis,
+                thread_id,
+                executor,
+                send,
+                &counters,
+                &label,
+                game_node_budget,
+                &profile,
+                starting_position,
+            )
+        })?);
     }
 
     std::mem::drop(send);
 
     log::trace!("Waiting for threads to finish...");
+    let mut worker_stats = Vec::new();
+    for worker in workers {
+        log::trace!("Joining {}", worker.thread().name().unwrap_or("unnamed"));
+        match worker.join() {
+            Ok(Ok(stats)) => worker_stats.push(Some(stats)),
+            Ok(Err(e)) => {
+                log::error!("self-play worker failed: {e:#}");
+                worker_stats.push(None);
+            }
+            // we don't care if the thread panicked
+            Err(_) => worker_stats.push(None),
+        }
+    }
     for thread in threads {
         log::trace!("Joining {}", thread.thread().name().unwrap_or("unnamed"));
         // we don't care if the thread panicked
         let _ = thread.join();
     }
 
-    println!("Data generation complete! (saved to {save_folder})");
-    println!("Generated {} games.", GAMES_GENERATED.load(std::sync::atomic::Ordering::Relaxed));
+    // hold the lock so that concurrent runs don't interleave their summaries.
+    let stdout_lock = STDOUT_LOCK.lock().unwrap();
+    println!("{label}Data generation complete! (saved to {save_folder})");
+    println!("{label}Generated {} games.", counters.games_generated.load(std::sync::atomic::Ordering::Relaxed));
+    print_result_distribution(&counters, &label);
+    if balance_results {
+        println!(
+            "{label}Skipped {} games to balance the results.",
+            counters.games_skipped.load(std::sync::atomic::Ordering::Relaxed)
+        );
+    }
+    println!("worker    games  avg length  avg move (ms)  exec wait (s)   wait %  profile");
+    for (thread_id, stats) in worker_stats.iter().enumerate() {
+        match stats {
+            Some(stats) => stats.print_row(thread_id),
+            None => println!("{thread_id:>6} failed"),
+        }
+    }
+    drop(stdout_lock);
 
     Ok(())
 }
+
+/// Returns a fresh folder to save a run's data in, named for the current time.
+pub fn default_save_folder() -> String {
+    let date = chrono::Local::now().format("%Y-%m-%d-%H-%M-%S");
+    format!("data/{date}")
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn print_result_distribution(counters: &RunCounters, label: &str) {
+    let p1 = counters.first_player_wins.load(std::sync::atomic::Ordering::Relaxed);
+    let p2 = counters.second_player_wins.load(std::sync::atomic::Ordering::Relaxed);
+    let draws = counters.draws.load(std::sync::atomic::Ordering::Relaxed);
+    let total = (p1 + p2 + draws).max(1) as f64;
+    println!(
+        "{label}Results: p1 wins {p1} ({:.1}%), p2 wins {p2} ({:.1}%), draws {draws} ({:.1}%)",
+        p1 as f64 / total * 100.0,
+        p2 as f64 / total * 100.0,
+        draws as f64 / total * 100.0
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn game_budgets_are_spread_over_the_expected_moves() {
+        // an average move takes the even share of the budget.
+        let low = budgeted_playout_cap(35_000, 0, 100.0, false);
+        let high = budgeted_playout_cap(35_000, 0, 100.0, true);
+        assert_eq!((low, high), (200, 800));
+        // late in a long game, what is left is spread over a minimum number of moves.
+        assert_eq!(budgeted_playout_cap(3_500, 150, 100.0, false), 200);
+        assert_eq!(budgeted_playout_cap(0, 10, 100.0, true), MIN_BUDGETED_NODES);
+    }
+
+    #[test]
+    fn profiles_override_only_the_settings_they_name() {
+        let profile = "explorer cpuct=3.5 noise=0.25 movetemp=1".parse::<Profile>().unwrap();
+        assert_eq!(profile.name, "explorer");
+        assert!((profile.c_puct - 3.5).abs() < f64::EPSILON);
+        assert!((profile.root_noise_weight - 0.25).abs() < f64::EPSILON);
+        assert!((profile.move_temperature - 1.0).abs() < f64::EPSILON);
+        assert!((profile.policy_temperature - Profile::default().policy_temperature).abs() < f32::EPSILON);
+        assert!("explorer cpuct".parse::<Profile>().is_err());
+        assert!("explorer depth=3".parse::<Profile>().is_err());
+        assert!("explorer temp=hot".parse::<Profile>().is_err());
+        assert_eq!(profile.gumbel_root_moves, Some(GUMBEL_ROOT_MOVES));
+        assert_eq!("puct gumbel=0".parse::<Profile>().unwrap().gumbel_root_moves, None);
+        assert!(("rollouts blend=0.25".parse::<Profile>().unwrap().value_blend - 0.25).abs() < f32::EPSILON);
+        assert!("rollouts blend=2".parse::<Profile>().is_err());
+        assert_eq!("sharpening decay=60".parse::<Profile>().unwrap().cpuct_decay_plies, 60);
+    }
+
+    #[test]
+    fn profile_noise_is_only_added_at_the_root() {
+        let params = "explorer noise=0.25".parse::<Profile>().unwrap().params();
+        assert!((params.noise_weight_at(0) - 0.25).abs() < f64::EPSILON);
+        assert!(params.noise_weight_at(1) == 0.0);
+        assert!(params.noise_weight_at(7) == 0.0);
+    }
+}
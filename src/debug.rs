@@ -1 +1,104 @@
+//! Consistency checks on the search tree, for catching corruption close to where it happens.
 
+use crate::{arena::Handle, game::GameImpl, node::Node};
+
+/// Walks the tree rooted at index 0, whose position is `root`, and describes every broken invariant found:
+/// - every node is reached exactly once, so sibling lists are acyclic and no subtree is shared or leaked,
+/// - every child points back at its parent, and at a distinct edge that exists in its parent's edge list,
+/// - no node has fewer visits than its children put together,
+/// - a node is marked terminal only if its position has ended, and always is once it has been visited,
+/// - no visit is left in flight, as every batch of leaves is evaluated before the next round begins.
+///
+/// An empty list means the tree is sound.
+pub fn validate<G: GameImpl>(root: &G, tree: &[Node<G>]) -> Vec<String> {
+    let mut problems = Vec::new();
+    if tree.is_empty() {
+        return problems;
+    }
+    let mut reached = vec![false; tree.len()];
+    reached[0] = true;
+    let mut stack = vec![(0, *root)];
+    while let Some((node_idx, pos)) = stack.pop() {
+        let node = &tree[node_idx];
+        match (node.is_terminal(), pos.outcome().is_some()) {
+            (true, false) => problems.push(format!("node {node_idx} is marked terminal, but {} is not", pos.fen())),
+            (false, true) if node.visits() > 0 => {
+                problems.push(format!("node {node_idx} has been visited, but not marked terminal at {}", pos.fen()));
+            }
+            _ => {}
+        }
+        if node.in_flight() > 0 {
+            problems.push(format!("node {node_idx} has {} visits still in flight", node.in_flight()));
+        }
+
+        let num_edges = node.edges().map_or(0, <[_]>::len);
+        let mut edge_used = vec![false; num_edges];
+        let mut child_visits = 0;
+        let mut child = node.first_child();
+        while !child.is_null() {
+            let child_idx = child.index();
+            if child_idx >= tree.len() {
+                problems.push(format!("node {node_idx} has a child at {child_idx}, past the end of the tree"));
+                break;
+            }
+            if std::mem::replace(&mut reached[child_idx], true) {
+                problems.push(format!("node {child_idx} is reached twice, the second time from node {node_idx}"));
+                break;
+            }
+            let child_node = &tree[child_idx];
+            if child_node.non_null_parent(tree) != Some(Handle::from_index(node_idx, tree)) {
+                problems.push(format!("node {child_idx} is a child of node {node_idx}, but does not point back at it"));
+            }
+            child_visits += u64::from(child_node.visits());
+            let edge_idx = child_node.edge_index();
+            match node.edges().and_then(|edges| edges.get(edge_idx)) {
+                None => problems.push(format!(
+                    "node {child_idx} claims edge {edge_idx} of node {node_idx}, which has only {num_edges} edges"
+                )),
+                Some(_) if edge_used[edge_idx] => {
+                    problems.push(format!("edge {edge_idx} of node {node_idx} has more than one child"));
+                }
+                Some(edge) => {
+                    edge_used[edge_idx] = true;
+                    let mut child_pos = pos;
+                    child_pos.make_move(edge.get_move(false));
+                    stack.push((child_idx, child_pos));
+                }
+            }
+            child = child_node.sibling();
+        }
+        if child_visits > u64::from(node.visits()) {
+            problems.push(format!(
+                "node {node_idx} has {} visits, but its children have {child_visits} between them",
+                node.visits()
+            ));
+        }
+    }
+
+    let unreached = reached.iter().filter(|&&reached| !reached).count();
+    if unreached > 0 {
+        problems.push(format!("{unreached} nodes cannot be reached from the root"));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::Player, uttt::Board};
+
+    #[test]
+    fn finds_broken_visit_counts() {
+        let root = Board::default();
+        let mut tree = vec![Node::new(Handle::null(), 0, Player::Second)];
+        tree[0].expand(root, &[], true);
+        tree[0].add_visit(0.5);
+        tree.push(Node::new(Handle::from_index(0, &tree), 3, Player::First));
+        *tree[0].first_child_mut() = Handle::from_index(1, &tree);
+        tree[1].add_visit(0.5);
+        assert!(validate(&root, &tree).is_empty());
+
+        tree[1].add_visit(0.5);
+        assert_eq!(validate(&root, &tree), ["node 0 has 1 visits, but its children have 2 between them"]);
+    }
+}
@@ -1,23 +1,122 @@
 // use gomokugen::board::{Board, Move, Player};
 use log::{debug, trace};
 // use std::io::Write;
-use std::{sync::atomic::Ordering, time::Instant};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::Path,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    arena::Handle,
-    batching::ExecutorHandle,
-    game::{GameImpl, Player},
-    node::Node,
-    params::Params,
-    timemgmt::Limits,
-    ugi,
+    arena::{self, Arena, Handle},
+    batching::{Evaluation, ExecutorHandle},
+    book::Book,
+    debug,
+    game::{GameImpl, MovePolicyIndex, Player},
+    gumbel::{self, MoveStats, SequentialHalving},
+    node::{Edge, Node},
+    params::{Exploration, Params},
+    snapshot::SnapshotSink,
+    telemetry::GpuMonitor,
+    timemgmt::{Limits, TimeManager},
+    watchdog::{self, Watchdog},
 };
 
+/// How many plies below the old root a new root is looked for, so that its subtree can be reused.
+/// Two covers both our move and the opponent's reply.
+const MAX_REUSE_DEPTH: usize = 2;
+
+/// How far proven wins are raised above, and proven losses lowered below, their visit counts
+/// when choosing a move to play.
+const PROVEN_BONUS: f64 = 1e12;
+
+/// The number of standard errors below a move's value at which its lower confidence bound is taken.
+const LCB_Z: f64 = 1.96;
+/// The least share of the most-visited move's visits that a move needs to be chosen by its lower confidence bound.
+const LCB_MIN_VISIT_SHARE: f64 = 0.1;
+
+/// The most leaves that may be selected for evaluation together, which keeps the visits in flight
+/// through a node countable in its 16 bits.
+pub const MAX_BATCH_LEAVES: usize = 1024;
+
+/// The nodes searched before a move holding `Params::easy_move_share` of the root visits is played.
+const EASY_MOVE_PROBE_NODES: u64 = 256;
+
+/// The edges a tree file has room for per node. Edges that do not fit stay on the heap.
+const EDGES_PER_NODE: usize = 32;
+
 pub struct SearchResults<G: GameImpl> {
     /// The best move found.
     pub best_move: G::Move,
     /// The root rollout distribution.
     pub root_dist: Vec<u64>,
+    /// The estimated probability that the side to move wins.
+    pub value: f64,
+    /// The lines of best play from the most promising root moves, best first, as many as `MultiPV` asks for.
+    pub lines: Vec<PvLine<G>>,
+    /// What the search found of each root move, most-visited first.
+    pub root_moves: Vec<RootMove<G>>,
+    /// The policy improved by the search, indexed like `root_dist`, when the root moves were chosen by
+    /// Gumbel sequential halving, whose visit counts make a poor training target.
+    pub policy_target: Option<Vec<f64>>,
+}
+
+/// A root move and the line of best play after it.
+pub struct PvLine<G: GameImpl> {
+    /// The root move, followed by the line of best play after it.
+    pub moves: Vec<G::Move>,
+    /// The visits to the root move.
+    pub visits: u64,
+    /// The score of the root move, for the side to move at the root.
+    pub score: Score,
+}
+
+/// A root move, with its statistics at the end of a search.
+pub struct RootMove<G: GameImpl> {
+    pub mv: G::Move,
+    pub visits: u64,
+    /// The value of the move for the side to move at the root, if it has been visited.
+    pub q: Option<f64>,
+    /// The policy's probability of the move.
+    pub prior: f64,
+    /// The exploration bonus of the move in selection, as of the end of the search.
+    pub u: f64,
+    /// The line of best play after the move.
+    pub pv: Vec<G::Move>,
+}
+
+/// How a position is scored for a player, as reported in info lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Score {
+    /// The estimated probability that the player wins.
+    Q(f64),
+    /// A proven win for the player, in this number of plies with best play.
+    Win(usize),
+    /// A proven loss for the player, in this number of plies with best play.
+    Loss(usize),
+}
+
+impl Score {
+    /// The same score for the other player.
+    pub fn flipped(self) -> Self {
+        match self {
+            Self::Q(value) => Self::Q(1.0 - value),
+            Self::Win(plies) => Self::Loss(plies),
+            Self::Loss(plies) => Self::Win(plies),
+        }
+    }
+}
+
+impl std::fmt::Display for Score {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Q(value) => write!(f, "q {:.1}", value * 100.0),
+            Self::Win(plies) => write!(f, "win {plies}"),
+            Self::Loss(plies) => write!(f, "loss {plies}"),
+        }
+    }
 }
 
 /// The MCTS engine's state.
@@ -27,67 +126,662 @@ pub struct Engine<'a, G: GameImpl> {
     /// Limits on the search - time, nodes, etc.
     limits: Limits,
     /// The storage for the search tree.
-    tree: Vec<Node<G>>,
+    tree: Arena<Node<G>>,
     /// The root position.
     root: G,
     /// Interface to the CUDA executor.
     eval_pipe: ExecutorHandle<G>,
+    /// Whether the root has been sent to the executor and its evaluation not yet received.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    root_eval_pending: bool,
+    /// The evaluation of the root, if it has been received but not yet used to expand the root.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    root_eval: Option<Evaluation>,
+    /// Where to stream snapshots of the tree during search, if anywhere.
+    snapshots: Option<SnapshotSink>,
+    /// The opening book to play from while the root is in it, if there is one.
+    book: Option<Book>,
+    /// The repetition keys of the positions played before the root, oldest first.
+    history: Vec<u64>,
+    /// The position the game started from, and the moves played from it to reach the root,
+    /// so that they can be taken back.
+    start: G,
+    moves: Vec<G::Move>,
 }
 
 enum SelectionResult<G: GameImpl> {
-    NonTerminal { node_index: usize, edge_index: usize, board_state: G },
-    Terminal { node_index: usize, board_state: G },
+    NonTerminal {
+        node_index: usize,
+        edge_index: usize,
+        board_state: G,
+        /// The transposition key of the position, if evaluations are cached.
+        key: Option<u64>,
+    },
+    /// A node whose outcome is known, because it is terminal or has been proven.
+    Terminal { node_index: usize, outcome: Player },
+    /// A node whose position already occurred earlier in the game or on the way down, so is a draw.
+    Repetition { node_index: usize },
+    /// A node still waiting on the evaluation requested when it was selected earlier in the same batch.
+    InFlight,
+    /// A node with enough visits for progressive widening to give more of its moves edges.
+    Widen { node_index: usize, board_state: G },
+}
+
+/// The positions expanded during a search, by transposition key, so that a position reached again by
+/// another move order is given the policy and value of the node first expanded for it, rather than being
+/// evaluated again. The two nodes stay separate, each with its own visits.
+#[derive(Default)]
+struct EvalCache {
+    /// The key of the root, from which the keys of the positions below it are kept up to date move by move,
+    /// or `None` if evaluations are not cached.
+    root_key: Option<u64>,
+    /// The node first expanded for each key.
+    nodes: HashMap<u64, usize>,
+}
+
+impl EvalCache {
+    fn new<G: GameImpl>(root: &G, params: &Params) -> Self {
+        let root_key = if params.eval_cache { root.transposition_key() } else { None };
+        Self { root_key, nodes: HashMap::new() }
+    }
+}
+
+/// A newly-expanded leaf, which has been selected but is waiting on its evaluation.
+struct PendingLeaf<G: GameImpl> {
+    /// The node of the leaf.
+    node: Handle,
+    /// The position at the leaf.
+    board: G,
+    /// The player who moved into the leaf.
+    mover: Player,
+    /// The transposition key of the position, if it is recorded in the table once the leaf is expanded.
+    key: Option<u64>,
+    /// The number of plies from the root to the leaf, which are the first moves of `path`.
+    depth: usize,
+    /// The moves made on the way down, and then by any rollout from the leaf, if RAVE needs them.
+    path: Vec<(Player, usize)>,
+}
+
+/// What became of a selection.
+enum Selected<G: GameImpl> {
+    /// The value of the leaf was known, and has been backpropagated.
+    Backpropagated,
+    /// The leaf needs an evaluation.
+    Pending(PendingLeaf<G>),
+    /// The selection ran into a leaf that is still waiting on its evaluation.
+    Collided,
+    /// The selection stopped at a node that needs its policy again to widen, at the given position.
+    Widen { node_index: usize, board: G },
+}
+
+/// What a search has gathered about the tree it has grown, for its info lines and its time management.
+#[derive(Debug, Default)]
+struct SearchStats {
+    /// The sum of the depths of the nodes selected, and the number of selections.
+    total_depth: u64,
+    selections: u64,
+    /// The depth of the deepest node selected.
+    max_depth: usize,
+    /// The sum of the network's estimates of the plies left in the game, counting the plies from the root
+    /// to each leaf, and the number of estimates. These are averaged for the root alone, rather than in
+    /// every node, which keeps nodes at 40 bytes.
+    total_plies_left: f64,
+    plies_left_estimates: u32,
+}
+
+impl SearchStats {
+    /// Records the selection of a node `depth` plies below the root.
+    fn add_selection(&mut self, depth: usize) {
+        self.total_depth += depth as u64;
+        self.selections += 1;
+        self.max_depth = self.max_depth.max(depth);
+    }
+
+    /// Records the network's estimate of the plies left after a leaf `depth` plies below the root.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    #[allow(clippy::cast_precision_loss)]
+    fn add_plies_left(&mut self, depth: usize, plies_left: f32) {
+        self.total_plies_left += f64::from(plies_left) + depth as f64;
+        self.plies_left_estimates += 1;
+    }
+
+    /// The average depth of the nodes selected, rounded to the nearest ply.
+    fn average_depth(&self) -> u64 {
+        if self.selections == 0 {
+            return 0;
+        }
+        (self.total_depth + self.selections / 2) / self.selections
+    }
+
+    /// The average estimate of the plies left from the root, if the network gave any.
+    fn plies_left(&self) -> Option<f64> {
+        (self.plies_left_estimates > 0).then(|| self.total_plies_left / f64::from(self.plies_left_estimates))
+    }
 }
 
 impl<'a, G: GameImpl> Engine<'a, G> {
     /// Creates a new engine.
     pub const fn new(params: Params<'a>, limits: Limits, root: &G, eval_pipe: ExecutorHandle<G>) -> Self {
-        Self { params, limits, tree: Vec::new(), root: *root, eval_pipe }
+        Self {
+            params,
+            limits,
+            tree: Arena::new(),
+            root: *root,
+            eval_pipe,
+            root_eval_pending: false,
+            root_eval: None,
+            snapshots: None,
+            book: None,
+            history: Vec::new(),
+            start: *root,
+            moves: Vec::new(),
+        }
     }
 
     pub const fn root(&self) -> G {
         self.root
     }
 
+    /// The moves played since the position was set, which lead from where the game started to the root.
+    pub fn moves(&self) -> &[G::Move] {
+        &self.moves
+    }
+
     /// Sets the limits on the search.
     pub fn set_limits(&mut self, limits: Limits) {
         self.limits = limits;
     }
 
+    /// Returns the total time this engine has spent waiting on the executor.
+    pub fn eval_wait_time(&self) -> Duration {
+        self.eval_pipe.wait_time()
+    }
+
+    /// Returns a description of the backend evaluating positions for this engine.
+    pub fn backend(&self) -> String {
+        self.eval_pipe.backend()
+    }
+
+    /// Replaces the handle to the executor, such as after the backend is reloaded.
+    /// The position and search tree are kept.
+    pub fn set_eval_pipe(&mut self, eval_pipe: ExecutorHandle<G>) {
+        self.eval_pipe = eval_pipe;
+        // an evaluation requested from the old executor will never arrive, so ask the new one.
+        #[cfg(not(feature = "pure-mcts"))]
+        if std::mem::replace(&mut self.root_eval_pending, false) {
+            self.root_eval_pending = self.eval_pipe.send(self.root).is_ok();
+        }
+    }
+
+    /// Replaces the handle to the executor with one running a different network. The search tree and any
+    /// evaluation of the root hold the old network's opinions, so they are discarded.
+    pub fn set_network(&mut self, eval_pipe: ExecutorHandle<G>) {
+        self.tree.clear();
+        self.root_eval = None;
+        self.set_eval_pipe(eval_pipe);
+    }
+
+    /// Checks the search tree for corruption, describing every broken invariant found.
+    pub fn validate_tree(&self) -> Vec<String> {
+        debug::validate(&self.root, &self.tree)
+    }
+
     /// Get access to the parameters of the search.
     pub fn params_mut(&mut self) -> &mut Params<'a> {
         &mut self.params
     }
 
-    /// Sets the position to search from.
-    /// This clears the search tree, but could in future be altered to retain some subtree.
+    /// Backs the search tree with a memory-mapped file at `path`, holding up to `MaxTreeNodes` nodes
+    /// and their edges. This clears the search tree.
+    pub fn set_tree_file(&mut self, path: &Path) -> anyhow::Result<()> {
+        let capacity = self.params.max_tree_nodes.min(arena::CAPACITY);
+        // the file is sparse, so room for edges that are never made costs nothing.
+        let edge_bytes = capacity.saturating_mul(std::mem::size_of::<Edge<G>>() * EDGES_PER_NODE);
+        // drop the old tree first, as it may be mapped from the same file.
+        self.tree = Arena::new();
+        self.tree = Arena::mapped(path, capacity, edge_bytes)?;
+        Ok(())
+    }
+
+    /// Returns the search tree to ordinary heap memory, clearing it.
+    pub fn clear_tree_file(&mut self) {
+        self.tree = Arena::new();
+    }
+
+    /// Streams snapshots of the tree to `sink` during each search, or stops streaming if it is `None`.
+    pub fn set_snapshot_sink(&mut self, sink: Option<SnapshotSink>) {
+        self.snapshots = sink;
+    }
+
+    /// Plays from `book` without searching while the root is in it, or stops playing from a book if it is `None`.
+    pub fn set_book(&mut self, book: Option<Book>) {
+        self.book = book;
+    }
+
+    /// Sets the position to search from, reached by playing `moves` from `start`.
+    /// The positions along the way are remembered, so that returning to one in search counts as a draw.
+    pub fn set_game(&mut self, start: &G, moves: &[G::Move]) {
+        let mut board = *start;
+        let mut history = Vec::new();
+        for &mv in moves {
+            history.extend(board.repetition_key());
+            board.make_move(mv);
+        }
+        self.set_position(&board);
+        self.history = history;
+        self.params.game_ply = moves.len();
+        self.start = *start;
+        self.moves = moves.to_vec();
+    }
+
+    /// Plays `mv` from the root, remembering the old root as part of the game.
+    pub fn make_move(&mut self, mv: G::Move) {
+        let mut history = std::mem::take(&mut self.history);
+        history.extend(self.root.repetition_key());
+        let mut moves = std::mem::take(&mut self.moves);
+        moves.push(mv);
+        let (start, ply) = (self.start, self.params.game_ply);
+        let mut root = self.root;
+        root.make_move(mv);
+        self.set_position(&root);
+        self.history = history;
+        self.params.game_ply = ply + 1;
+        self.start = start;
+        self.moves = moves;
+    }
+
+    /// Takes back the last move of the game, returning it, or `None` if no moves have been played since
+    /// the position was set. The old root is not in the tree below the new one, so the tree is rebuilt.
+    pub fn undo(&mut self) -> Option<G::Move> {
+        let mv = self.moves.pop()?;
+        let moves = std::mem::take(&mut self.moves);
+        let start = self.start;
+        self.set_game(&start, &moves);
+        Some(mv)
+    }
+
+    /// Sets the position to search from, forgetting any earlier moves of the game.
+    /// If the position is already in the tree, close below the old root, the tree is re-rooted on it and its
+    /// statistics kept; otherwise the tree is cleared, and the new root is sent to the executor straight away,
+    /// so its evaluation overlaps with whatever happens before the next `go`.
     pub fn set_position(&mut self, root: &G) {
+        let total = (self.tree.len(), self.tree.first().map_or(0, |node| u64::from(node.visits())));
+        // root noise is drawn when the root is expanded, so a reused root would go without it.
+        let reused = if self.params.reuse_tree && self.params.noise_weight_at(0) <= 0.0 {
+            self.find_descendant(root)
+        } else {
+            None
+        };
         self.root = *root;
+        self.history.clear();
+        self.params.game_ply = 0;
+        self.start = *root;
+        self.moves.clear();
+        self.root_eval = None;
+        #[cfg(not(feature = "pure-mcts"))]
+        {
+            // discard the evaluation of any previous root that was never searched.
+            if self.root_eval_pending {
+                self.root_eval_pending = false;
+                if self.eval_pipe.recv().is_err() {
+                    self.tree.clear();
+                    return;
+                }
+            }
+        }
+
+        if let Some(new_root) = reused {
+            Self::reroot(&mut self.tree, new_root);
+            // the new root was expanded below the root, so its policy has the temperature of an inner node.
+            #[cfg(not(feature = "pure-mcts"))]
+            if new_root != 0 {
+                let ratio =
+                    f64::from(self.params.policy_softmax_temp) / f64::from(self.params.root_policy_softmax_temp);
+                self.tree[0].retemper_policy(ratio);
+            }
+            if self.params.do_stdout {
+                Self::report_reuse((self.tree.len(), u64::from(self.tree[0].visits())), total);
+            }
+            return;
+        }
+        if self.params.do_stdout && !self.tree.is_empty() {
+            Self::report_reuse((0, 0), total);
+        }
         self.tree.clear();
+        #[cfg(not(feature = "pure-mcts"))]
+        {
+            // if this fails, `prepare_root` will retry the request and report the error.
+            self.root_eval_pending = self.eval_pipe.send(*root).is_ok();
+        }
+    }
+
+    /// Finds the expanded node whose position is `target`, at most `MAX_REUSE_DEPTH` plies below the root.
+    fn find_descendant(&self, target: &G) -> Option<usize> {
+        // only an expanded root has anything below it to reuse.
+        self.tree.first().and_then(Node::edges)?;
+        let target_fen = target.fen();
+        if self.root.fen() == target_fen {
+            return Some(0);
+        }
+        let mut frontier = vec![(0, self.root)];
+        for _ in 0..MAX_REUSE_DEPTH {
+            let mut next = Vec::new();
+            for (node_idx, pos) in frontier {
+                let Some(edges) = self.tree[node_idx].edges() else {
+                    continue;
+                };
+                let mut child = self.tree[node_idx].first_child();
+                while !child.is_null() {
+                    let node = &self.tree[child.index()];
+                    let mut child_pos = pos;
+                    child_pos.make_move(edges[node.edge_index()].get_move(false));
+                    if node.edges().is_some() && child_pos.fen() == target_fen {
+                        return Some(child.index());
+                    }
+                    next.push((child.index(), child_pos));
+                    child = node.sibling();
+                }
+            }
+            frontier = next;
+        }
+        None
+    }
+
+    /// Makes the node at `new_root` the root of the tree, dropping every node outside its subtree.
+    fn reroot(tree: &mut Arena<Node<G>>, new_root: usize) {
+        trace!("Engine::reroot(tree, new_root = {new_root})");
+        if new_root == 0 {
+            return;
+        }
+
+        // parents are always pushed before their children, so one forward pass finds the subtree,
+        // and the new root comes first of it.
+        let mut keep = vec![false; tree.len()];
+        keep[new_root] = true;
+        for index in new_root + 1..tree.len() {
+            let parent = tree[index].non_null_parent(tree).expect("non-root node has no parent");
+            keep[index] = keep[parent.index()];
+        }
+        let mut new_index = vec![usize::MAX; tree.len()];
+        for (next, (index, _)) in keep.iter().enumerate().filter(|(_, &k)| k).enumerate() {
+            new_index[index] = next;
+        }
+        let remap = |handle: Handle| if handle.is_null() { None } else { Some(new_index[handle.index()]) };
+        let mut links = Vec::new();
+        for (index, node) in tree.iter().enumerate().filter(|&(index, _)| keep[index]) {
+            // the new root loses its parent and siblings, and everything else keeps its relatives.
+            if index == new_root {
+                links.push((None, remap(node.first_child()), None));
+            } else {
+                let parent = node.non_null_parent(tree).map(|parent| new_index[parent.index()]);
+                links.push((parent, remap(node.first_child()), remap(node.sibling())));
+            }
+        }
+
+        Self::release_edges(tree, &keep);
+        let mut index = 0;
+        tree.retain(|_| {
+            index += 1;
+            keep[index - 1]
+        });
+        let handle = |index: Option<usize>, tree: &[Node<G>]| {
+            index.map_or_else(Handle::null, |index| Handle::from_index(index, tree))
+        };
+        for (index, (parent, child, sibling)) in links.into_iter().enumerate() {
+            let (parent, child, sibling) = (handle(parent, tree), handle(child, tree), handle(sibling, tree));
+            tree[index].relink(parent, child, sibling);
+        }
+    }
+
+    /// Prints how many nodes and visits of the old tree were kept for the new one.
+    /// Both arguments are pairs of (nodes, visits).
+    #[allow(clippy::cast_precision_loss)]
+    fn report_reuse((kept_nodes, kept_visits): (usize, u64), (total_nodes, total_visits): (usize, u64)) {
+        let share = |kept: f64, total: f64| if total == 0.0 { 0.0 } else { kept / total * 100.0 };
+        println!(
+            "info string reused {kept_nodes} nodes ({:.0}%), {kept_visits} visits ({:.0}%), discarded {} nodes",
+            share(kept_nodes as f64, total_nodes as f64),
+            share(kept_visits as f64, total_visits as f64),
+            total_nodes - kept_nodes
+        );
+    }
+
+    /// Creates and expands the root node, unless it already exists.
+    /// This only waits on the executor if the root was not already evaluated.
+    fn prepare_root(&mut self) -> anyhow::Result<()> {
+        trace!("Engine::prepare_root()");
+
+        if self.tree.is_empty() {
+            self.tree.push(Node::new(Handle::null(), 0, self.root.to_move().opposite()));
+        }
+        if self.tree[0].edges().is_some() {
+            return Ok(());
+        }
+        #[cfg(feature = "pure-mcts")]
+        {
+            self.tree[0].expand(self.root, &[], true);
+        }
+        #[cfg(not(feature = "pure-mcts"))]
+        {
+            let Evaluation { mut policy, .. } = self.take_root_eval()?;
+            self.params.adjust_logits(&mut policy, 0);
+            self.tree[0].expand(self.root, &policy, false);
+        }
+        Self::store_edges(&mut self.tree, 0);
+        let noise_weight = self.params.noise_weight_at(0);
+        if noise_weight > 0.0 {
+            self.tree[0].add_noise(noise_weight, self.params.noise_alpha);
+        }
+        Ok(())
+    }
+
+    /// Returns the evaluation of the root, waiting for it if it was sent early, or requesting it otherwise.
+    #[cfg(not(feature = "pure-mcts"))]
+    fn take_root_eval(&mut self) -> anyhow::Result<Evaluation> {
+        if let Some(eval) = self.root_eval.take() {
+            return Ok(eval);
+        }
+        if !std::mem::replace(&mut self.root_eval_pending, false) {
+            self.eval_pipe.send(self.root)?;
+        }
+        self.eval_pipe.recv()
+    }
+
+    /// Returns the raw value of the root from the network, for the side to move.
+    /// The evaluation is kept for the next search, so this costs nothing extra.
+    pub fn raw_eval(&mut self) -> anyhow::Result<f32> {
+        self.raw_evaluation().map(|eval| eval.value)
+    }
+
+    /// Returns the network's evaluation of the root, as the raw policy logits and the value for the side to move.
+    /// The evaluation is kept for the next search, so this costs nothing extra.
+    #[cfg_attr(feature = "pure-mcts", allow(clippy::unused_self, clippy::needless_pass_by_ref_mut))]
+    pub fn raw_evaluation(&mut self) -> anyhow::Result<Evaluation> {
+        #[cfg(feature = "pure-mcts")]
+        {
+            anyhow::bail!("there is no network in pure MCTS mode")
+        }
+        #[cfg(not(feature = "pure-mcts"))]
+        {
+            let eval = self.take_root_eval()?;
+            self.root_eval = Some(eval.clone());
+            Ok(eval)
+        }
+    }
+
+    /// The statistics of every root move in the tree, most-visited first, or none if the root is unexpanded.
+    pub fn root_stats(&self) -> Vec<RootMove<G>> {
+        Self::root_moves(&self.tree, &self.params)
     }
 
-    /// Runs the engine.
+    /// Runs the engine, failing if the game is already over, as there is no move to find.
     pub fn go(&mut self) -> anyhow::Result<SearchResults<G>> {
-        trace!("Engine::go()");
+        self.go_received(Instant::now())
+    }
+
+    /// Runs the engine for a `go` command that arrived at `received`, from which the hard time limit is counted.
+    pub fn go_received(&mut self, received: Instant) -> anyhow::Result<SearchResults<G>> {
+        trace!("Engine::go_received(received)");
+
+        anyhow::ensure!(self.root.outcome().is_none(), "the game is already over");
+        // with a single legal move there is nothing to decide, so play it at once.
+        if let Some(forced) = self.root.only_move() {
+            if self.params.do_stdout {
+                println!("info string {forced} is the only legal move");
+            }
+            return Ok(self.forced_result(forced));
+        }
+        // the search runs on whichever thread calls this, so its random numbers are seeded here.
+        if let Some(seed) = self.params.seed {
+            fastrand::seed(seed);
+        }
+        if let Some(book_move) = self.book.as_ref().and_then(|book| book.probe(&self.root)) {
+            if self.params.do_stdout {
+                println!("info string {book_move} is a book move");
+            }
+            return Ok(self.forced_result(book_move));
+        }
 
-        Self::search(&self.eval_pipe, &self.root, &mut self.tree, &self.params, &self.limits)?;
+        // the watchdog is armed before the root is evaluated, as that waits on the executor too.
+        self.arm_watchdog(received);
+        let fast_move = self.fast_move_limits();
+        // a fast move may search nothing at all, leaving only the network's opinion of the root.
+        let raw_value =
+            if fast_move.is_some() && cfg!(not(feature = "pure-mcts")) { Some(self.raw_eval()?) } else { None };
+        self.prepare_root()?;
+        if let Some(watchdog) = self.params.watchdog {
+            watchdog.report_best_move(Self::best_move(&self.tree, self.params.lcb_selection).to_string());
+        }
+        let limits = fast_move.unwrap_or(self.limits);
+        let limits = self.params.skill_node_cap().map_or(limits, |cap| limits.with_node_cap(cap));
+        // sequential halving needs to know the budget it is sharing out.
+        let mut halving = match (self.params.gumbel_root_moves, limits.node_limit()) {
+            (Some(num_moves), Some(budget)) if self.tree[0].proven_outcome().is_none() => {
+                Some(SequentialHalving::new(&Self::root_move_stats(&self.tree), num_moves, budget))
+            }
+            _ => None,
+        };
+        Self::search(
+            &self.eval_pipe,
+            &self.root,
+            &self.history,
+            &mut self.tree,
+            &self.params,
+            &limits,
+            &mut self.snapshots,
+            halving.as_mut(),
+        )?;
 
-        let (edge_idx, _) = Self::rollouts_best(&self.tree, 0);
-        let edge = self.tree[0].edges().expect("node has no edges").get(edge_idx).expect("edge index out of bounds");
-        let best_move = edge.get_move(false);
+        // a proven root must play a move that keeps the proven result, so is never sampled.
+        let proven = self.tree[0].proven_outcome();
+        let best_move = if let (Some(halving), None) = (&halving, proven) {
+            let edge_idx = halving.best_move(&Self::root_move_stats(&self.tree));
+            self.tree[0].edges().expect("root has no edges")[edge_idx].get_move(false)
+        } else if proven.is_none() && self.params.skill.is_some() && fastrand::f64() < self.params.blunder_chance() {
+            Self::sample_prior(&self.tree)
+        } else if self.params.move_temperature() > 0.0 && proven.is_none() {
+            Self::sample_move(&self.tree, self.params.move_temperature())
+                .unwrap_or_else(|| Self::best_move(&self.tree, self.params.lcb_selection))
+        } else {
+            Self::best_move(&self.tree, self.params.lcb_selection)
+        };
+        if let (true, Some(outcome)) = (self.params.do_stdout, proven) {
+            let length = Self::proof_length(&self.tree, 0);
+            match outcome {
+                Player::None => println!("info string proven draw"),
+                winner if winner == self.root.to_move() => println!("info string proven win in {length} plies"),
+                _ => println!("info string proven loss in {length} plies"),
+            }
+        }
 
         let root_dist = self.tree[0].dist(&self.tree);
 
-        Ok(SearchResults { best_move, root_dist })
+        // the root's value is from the point of view of the player who moved into it.
+        let value = match raw_value {
+            Some(raw_value) if self.tree[0].visits() == 0 => f64::from(raw_value),
+            _ => 1.0 - self.tree[0].winrate(),
+        };
+
+        let lines = Self::top_lines(&self.tree, self.params.multi_pv.max(1));
+        let root_moves = Self::root_moves(&self.tree, &self.params);
+
+        let policy_target = halving.map(|_| {
+            let improved = gumbel::improved_policy(&Self::root_move_stats(&self.tree), value);
+            let mut policy_target = vec![0.0; G::policy_dim()];
+            for (edge, p) in self.tree[0].edges().expect("root has no edges").iter().zip(improved) {
+                policy_target[edge.get_move(false).policy_index()] = p;
+            }
+            policy_target
+        });
+
+        Ok(SearchResults { best_move, root_dist, value, lines, root_moves, policy_target })
+    }
+
+    /// Arms the watchdog, if there is one, with the hard limit of the clock counted from `received`, telling it
+    /// the first legal move to play until the root has been expanded. A fast move still has the clock's hard limit.
+    fn arm_watchdog(&self, received: Instant) {
+        let is_p1 = self.root.to_move() == Player::First;
+        let hard_limit = Self::time_manager(&self.params, &self.limits, is_p1, false).hard_limit();
+        let (Some(watchdog), Some(hard_limit)) = (self.params.watchdog, hard_limit) else {
+            return;
+        };
+        watchdog.arm(received + Duration::from_millis(hard_limit) + watchdog::GRACE);
+        self.root.generate_moves(|mv| {
+            watchdog.report_best_move(mv.to_string());
+            true
+        });
+    }
+
+    /// The results of a search that played the only legal move without searching, from whatever
+    /// the tree already knows about the root.
+    fn forced_result(&self, forced: G::Move) -> SearchResults<G> {
+        let root = self.tree.first().filter(|root| root.visits() > 0);
+        SearchResults {
+            best_move: forced,
+            root_dist: root.map_or_else(|| vec![0; G::policy_dim()], |root| root.dist(&self.tree)),
+            value: root.map_or(0.5, |root| 1.0 - root.winrate()),
+            lines: Vec::new(),
+            root_moves: root.map_or_else(Vec::new, |_| Self::root_moves(&self.tree, &self.params)),
+            policy_target: None,
+        }
     }
 
-    /// Repeat the search loop until the time limit is reached.
+    /// The limits to search with if this move is a fast move, because fast moves are forced or
+    /// our clock has run too low for a full search to be worth the risk of losing on time.
+    fn fast_move_limits(&self) -> Option<Limits> {
+        let is_p1 = self.root.to_move() == Player::First;
+        let short_of_time =
+            self.limits.remaining_time(is_p1).is_some_and(|remaining| remaining < self.params.fast_move_threshold);
+        if !self.params.policy_only && !short_of_time {
+            return None;
+        }
+        // pure MCTS has no policy to play from, so it must visit every root move at least once.
+        let mut min_nodes = 0;
+        if cfg!(feature = "pure-mcts") {
+            self.root.generate_moves(|_| {
+                min_nodes += 1;
+                false
+            });
+        }
+        let nodes = self.params.fast_move_nodes.max(min_nodes);
+        if self.params.do_stdout {
+            println!("info string playing a fast move with a search of {nodes} nodes");
+        }
+        Some(Limits::nodes(nodes))
+    }
+
+    /// Repeat the search loop until the time limit is reached, or the stop flag is set.
+    /// With `halving`, the root moves are chosen by Gumbel sequential halving rather than by PUCT.
+    #[allow(clippy::too_many_arguments)]
     fn search(
         executor: &ExecutorHandle<G>,
         root: &G,
-        tree: &mut Vec<Node<G>>,
+        history: &[u64],
+        tree: &mut Arena<Node<G>>,
         params: &Params,
         limits: &Limits,
+        snapshots: &mut Option<SnapshotSink>,
+        mut halving: Option<&mut SequentialHalving>,
     ) -> anyhow::Result<()> {
         #![allow(clippy::cast_precision_loss)]
         trace!("Engine::search(root, tree, params, limits)");
@@ -98,146 +792,717 @@ impl<'a, G: GameImpl> Engine<'a, G> {
         let mut nodes_searched = 0;
         let mut elapsed = 0;
 
-        if tree.is_empty() {
-            // create the root node
-            tree.push(Node::new(Handle::null(), 0));
-            #[cfg(feature = "pure-mcts")]
-            {
-                tree[0].expand(*root, &[], true);
+        // let mut log = std::io::BufWriter::new(std::fs::File::create("log.txt").unwrap());
+
+        let mut time_manager = Self::time_manager(params, limits, is_p1, halving.is_some());
+        // when the last GPU sample reported was taken.
+        let mut last_gpu_sample = None;
+        let mut last_best_move_index = Self::rollouts_best(tree, 0).0;
+        // the node first expanded for each position keyed during this search.
+        let mut eval_cache = EvalCache::new(root, params);
+        let mut stats = SearchStats::default();
+        // which moves of the policy a player made in a simulation, reused by every AMAF update.
+        let mut amaf_seen = Vec::new();
+        while !time_manager.is_out_of_time(nodes_searched, elapsed) {
+            // the watchdog has already answered for us, so stop at once
+            if params.watchdog.is_some_and(Watchdog::fired) {
+                break;
             }
-            #[cfg(not(feature = "pure-mcts"))]
-            {
-                // send the root to the executor
-                executor.sender.send(*root)?;
-                // wait for the result
-                let (mut policy, _value) = executor.receiver.recv()?;
-                // apply root softmax temperature
-                for p in &mut policy {
-                    // these are logits, so we can just divide by the temperature
-                    *p /= params.root_policy_softmax_temp;
+            if params.stop.is_some_and(|stop| stop.load(Ordering::SeqCst)) {
+                break;
+            }
+            // nothing is left to learn about a proven root.
+            if params.solver && tree[0].proven_outcome().is_some() {
+                break;
+            }
+
+            // if the tree is full, throw away the least-visited subtrees to make room.
+            // this also keeps the tree within what handles can address.
+            let max_tree_nodes = Self::tree_capacity(tree, params);
+            let batch_leaves = params.batch_leaves.clamp(1, MAX_BATCH_LEAVES);
+            if tree.len() + batch_leaves > max_tree_nodes {
+                let before = tree.len();
+                Self::compact_tree(tree, max_tree_nodes / 2);
+                // compaction moves nodes, so the indices in the table are stale.
+                eval_cache.nodes.clear();
+                debug!("compacted tree from {before} to {} nodes", tree.len());
+                #[cfg(debug_assertions)]
+                {
+                    let problems = debug::validate(root, tree);
+                    assert!(problems.is_empty(), "compaction corrupted the tree: {problems:?}");
+                }
+            }
+
+            // perform a round of selection, expansion, simulation, and backpropagation
+            let simulations = Self::do_sesb(
+                executor,
+                root,
+                history,
+                tree,
+                params,
+                &mut eval_cache,
+                &mut stats,
+                &mut amaf_seen,
+                halving.as_deref_mut(),
+            )?;
+
+            // update elapsed time and print stats
+            let (curr_bm, curr_bm_child) = Self::rollouts_best(tree, 0);
+            let bm_changed = curr_bm != last_best_move_index;
+            last_best_move_index = curr_bm;
+            if let (true, Some(watchdog)) = (bm_changed, params.watchdog) {
+                watchdog.report_best_move(Self::best_move(tree, params.lcb_selection).to_string());
+            }
+            let curr_bm_visits = if curr_bm_child.is_null() { 0 } else { tree[curr_bm_child.index()].visits() };
+            time_manager.report(nodes_searched, elapsed, curr_bm, curr_bm_visits, tree[0].visits());
+            if params.easy_move {
+                time_manager.report_runner_up(Self::runner_up_visits(tree, curr_bm_child));
+            }
+            time_manager.report_moves_left(stats.plies_left());
+            if params.do_stdout && (nodes_searched % 100 < simulations || bm_changed) {
+                let hashfull = Self::hashfull(tree.len(), Self::tree_capacity(tree, params));
+                Self::print_info(tree, params, &stats, nodes_searched, elapsed, hashfull);
+                if let Some((sample, taken)) = params.gpu_monitor.and_then(GpuMonitor::latest) {
+                    if last_gpu_sample != Some(taken) {
+                        last_gpu_sample = Some(taken);
+                        println!(
+                            "info string gpu utilization {}% memory {}/{} MiB",
+                            sample.utilization, sample.memory_used, sample.memory_total
+                        );
+                    }
                 }
-                tree[0].expand(*root, &policy, false);
             }
+            Self::send_snapshot(snapshots, params, tree, nodes_searched, elapsed, false);
+            elapsed = u64::try_from(start_time.elapsed().as_millis()).expect("elapsed time overflow");
+            // write the root rollout distribution to log.txt
+            // let root_dist = tree[0].dist(tree);
+            // for visit_count in root_dist {
+            //     write!(log, "{visit_count},").unwrap();
+            // }
+            // writeln!(log).unwrap();
+
+            // update nodes searched
+            nodes_searched += simulations;
         }
 
-        // let mut log = std::io::BufWriter::new(std::fs::File::create("log.txt").unwrap());
+        Self::send_snapshot(snapshots, params, tree, nodes_searched, elapsed, true);
+        if params.do_stdout {
+            let megabytes = Self::tree_bytes(tree) as f64 / f64::from(1 << 20);
+            println!("info string tree holds {} nodes in {megabytes:.1} MB", tree.len());
+        }
 
-        let mut stopped_by_stdin = false;
-        let mut last_best_move_index = Self::rollouts_best(tree, 0).0;
-        while !limits.is_out_of_time(nodes_searched, elapsed, is_p1) && !stopped_by_stdin {
-            // perform one iteration of selection, expansion, simulation, and backpropagation
-            Self::do_sesb(executor, root, tree, params)?;
+        trace!("Engine::search: finished search loop with {} entries in tree.", tree.len());
+
+        Ok(())
+    }
+
+    /// The most nodes the tree may hold before it is compacted.
+    fn tree_capacity(tree: &Arena<Node<G>>, params: &Params) -> usize {
+        params.max_tree_nodes.min(arena::CAPACITY).min(tree.capacity_limit().unwrap_or(usize::MAX))
+    }
+
+    /// How full a tree of `len` nodes is, in permille of `capacity`.
+    fn hashfull(len: usize, capacity: usize) -> u64 {
+        (len * 1000 / capacity.max(1)).min(1000) as u64
+    }
+
+    /// The memory used by the tree, in bytes: its nodes, and the edges each of them owns.
+    fn tree_bytes(tree: &[Node<G>]) -> usize {
+        std::mem::size_of_val(tree) + tree.iter().map(Node::edge_bytes).sum::<usize>()
+    }
+
+    /// Prints the periodic info lines of a search, one for each line of best play reported.
+    #[allow(clippy::cast_precision_loss)]
+    fn print_info(
+        tree: &[Node<G>],
+        params: &Params,
+        stats: &SearchStats,
+        nodes_searched: u64,
+        elapsed: u64,
+        hashfull: u64,
+    ) {
+        let nps = nodes_searched as f64 / (elapsed as f64 / 1000.0);
+        let mut info = format!(
+            "depth {} seldepth {} nodes {nodes_searched} time {elapsed} nps {nps:.0} hashfull {hashfull}",
+            stats.average_depth(),
+            stats.max_depth
+        );
+        if let Some(plies) = stats.plies_left() {
+            write!(info, " movesleft {plies:.0}").unwrap();
+        }
+        if params.multi_pv > 1 {
+            for (rank, line) in Self::top_lines(tree, params.multi_pv).iter().enumerate() {
+                println!("info multipv {} {info} score {} pv {}", rank + 1, line.score, Self::format_line(&line.moves));
+            }
+        } else {
+            println!(
+                "info {info} score {} pv {}",
+                Self::score(tree, 0, 0).flipped(),
+                Self::format_line(&Self::principal_variation(tree, 0))
+            );
+        }
+    }
+
+    /// Builds the time manager of a search, with the stopping rules set by `params`.
+    fn time_manager(params: &Params, limits: &Limits, is_p1: bool, halving: bool) -> TimeManager {
+        let limits =
+            params.easy_move_share.map_or(*limits, |share| limits.with_early_exit(EASY_MOVE_PROBE_NODES, share));
+        // sequential halving decides the move played, so the visits cannot tell when it is settled.
+        TimeManager::new(limits, is_p1, params.panic_time_multiplier)
+            .with_max_move_time(params.max_move_time)
+            .with_easy_move(params.easy_move && !halving)
+    }
+
+    /// The most visits to any child of the root other than `best`.
+    fn runner_up_visits(tree: &[Node<G>], best: Handle) -> u32 {
+        let mut runner_up = 0;
+        let mut child = tree[0].first_child();
+        while !child.is_null() {
+            if child != best {
+                runner_up = runner_up.max(tree[child.index()].visits());
+            }
+            child = tree[child.index()].sibling();
+        }
+        runner_up
+    }
+
+    /// Sends a snapshot of the tree to the sink, if there is one, dropping the sink if it fails.
+    fn send_snapshot(
+        snapshots: &mut Option<SnapshotSink>,
+        params: &Params,
+        tree: &[Node<G>],
+        nodes: u64,
+        elapsed: u64,
+        force: bool,
+    ) {
+        if let Some(Err(e)) = snapshots.as_mut().map(|sink| sink.send(params, tree, nodes, elapsed, force)) {
+            println!("info string stopped streaming tree snapshots: {e}");
+            *snapshots = None;
+        }
+    }
+
+    /// The move at the root with the most rollouts, or with `lcb`, the move whose value has the best
+    /// lower confidence bound, if enough moves have been searched to compare. A proven win is always played,
+    /// the quickest first.
+    fn best_move(tree: &[Node<G>], lcb: bool) -> G::Move {
+        let lcb_best = if lcb { Self::lcb_best(tree) } else { None };
+        let edge_idx = Self::proven_best(tree).or(lcb_best).unwrap_or_else(|| Self::rollouts_best(tree, 0).0);
+        let edge = tree[0].edges().expect("node has no edges").get(edge_idx).expect("edge index out of bounds");
+        edge.get_move(false)
+    }
+
+    /// The edge of the root move whose value has the best lower confidence bound, among the moves with at
+    /// least `LCB_MIN_VISIT_SHARE` of the visits of the most-visited move, so that a move is not played on the
+    /// strength of a few lucky visits. Returns `None` if fewer than two moves qualify.
+    fn lcb_best(tree: &[Node<G>]) -> Option<usize> {
+        let mut children = Vec::new();
+        let mut child = tree.first()?.first_child();
+        while !child.is_null() {
+            let node = &tree[child.index()];
+            children.push(node);
+            child = node.sibling();
+        }
+        let most_visits = children.iter().map(|node| node.visits()).max()?;
+        let candidates = children
+            .into_iter()
+            .filter(|node| {
+                node.visits() >= 2 && f64::from(node.visits()) >= LCB_MIN_VISIT_SHARE * f64::from(most_visits)
+            })
+            .map(|node| {
+                let visits = f64::from(node.visits());
+                (node.edge_index(), LCB_Z.mul_add(-(node.variance() / visits).sqrt(), node.winrate()))
+            })
+            .collect::<Vec<_>>();
+        if candidates.len() < 2 {
+            return None;
+        }
+        candidates.into_iter().max_by(|(_, a), (_, b)| a.total_cmp(b)).map(|(edge_idx, _)| edge_idx)
+    }
+
+    /// Samples a root move in proportion to its prior, however much it was searched.
+    fn sample_prior(tree: &[Node<G>]) -> G::Move {
+        let edges = tree[0].edges().expect("root has no edges");
+        let mut target = fastrand::f64() * edges.iter().map(|edge| edge.probability()).sum::<f64>();
+        for edge in edges {
+            if target < edge.probability() {
+                return edge.get_move(false);
+            }
+            target -= edge.probability();
+        }
+        edges[edges.len() - 1].get_move(false)
+    }
+
+    /// Samples a root move in proportion to its visits raised to the power `1 / temperature`,
+    /// or returns `None` if no root move has been visited.
+    fn sample_move(tree: &[Node<G>], temperature: f64) -> Option<G::Move> {
+        let root = &tree[0];
+        let edges = root.edges()?;
+        let mut visits = Vec::new();
+        let mut child = root.first_child();
+        while !child.is_null() {
+            let node = &tree[child.index()];
+            visits.push((node.edge_index(), f64::from(node.visits())));
+            child = node.sibling();
+        }
+        // scaling by the most visits keeps low temperatures from overflowing.
+        let most_visits = visits.iter().map(|&(_, visits)| visits).fold(0.0, f64::max);
+        if most_visits == 0.0 {
+            return None;
+        }
+        let weights =
+            visits.iter().map(|&(edge_idx, visits)| (edge_idx, (visits / most_visits).powf(1.0 / temperature)));
+        let weights = weights.collect::<Vec<_>>();
+        let mut target = fastrand::f64() * weights.iter().map(|&(_, weight)| weight).sum::<f64>();
+        let mut chosen = weights[0].0;
+        for (edge_idx, weight) in weights {
+            chosen = edge_idx;
+            if target < weight && weight > 0.0 {
+                break;
+            }
+            target -= weight;
+        }
+        Some(edges[chosen].get_move(false))
+    }
+
+    /// Performs a round of selection, expansion, simulation, and backpropagation, returning the number of
+    /// simulations made. With `batch_leaves` above one, the leaves of several selections are evaluated together,
+    /// with virtual losses on their paths until then, so that each selection is steered away from the others.
+    /// `eval_cache` maps the keys of positions expanded so far to their nodes, `stats` gathers the depths
+    /// of the selections and the network's estimates of the plies left, and `halving`, if there is one,
+    /// chooses the root move of each selection.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(feature = "pure-mcts", allow(clippy::unnecessary_wraps))]
+    fn do_sesb(
+        executor: &ExecutorHandle<G>,
+        root: &G,
+        history: &[u64],
+        tree: &mut Arena<Node<G>>,
+        params: &Params,
+        eval_cache: &mut EvalCache,
+        stats: &mut SearchStats,
+        amaf_seen: &mut Vec<bool>,
+        mut halving: Option<&mut SequentialHalving>,
+    ) -> anyhow::Result<u64> {
+        trace!("Engine::do_sesb(root, tree, params)");
+
+        // rollouts are run where they are selected, so there is nothing to batch for pure MCTS.
+        let batch_leaves = if cfg!(feature = "pure-mcts") { 1 } else { params.batch_leaves.clamp(1, MAX_BATCH_LEAVES) };
+        let mut leaves = Vec::new();
+        let mut simulations = 0;
+        while simulations < batch_leaves {
+            let root_edge = halving.as_deref_mut().map(|halving| halving.next_move(&Self::root_move_stats(tree)));
+            match Self::select_leaf(root, history, tree, params, eval_cache, root_edge, stats, amaf_seen) {
+                Selected::Backpropagated => {}
+                Selected::Pending(leaf) => {
+                    Self::add_virtual_loss(tree, leaf.node);
+                    leaves.push(leaf);
+                }
+                // a selection that runs into another is left for the next round.
+                Selected::Collided => break,
+                // widening is not a simulation, so select again once it is done.
+                Selected::Widen { node_index, board } => {
+                    let old_edges = tree[node_index].mapped_edges();
+                    Self::widen(executor, tree, params, node_index, board)?;
+                    if let (Some((edges, len)), (_, Some(mut spare))) = (old_edges, tree.with_spare()) {
+                        spare.free(edges, len);
+                    }
+                    Self::store_edges(tree, node_index);
+                    continue;
+                }
+            }
+            simulations += 1;
+        }
+        if leaves.is_empty() {
+            return Ok(simulations as u64);
+        }
+
+        #[cfg(feature = "pure-mcts")]
+        for mut leaf in leaves {
+            // if we're doing pure MCTS, we do a random rollout.
+            let value = if params.rave_equivalence.is_some() {
+                leaf.board.rollout_recording(|player, mv| leaf.path.push((player, mv.policy_index())))
+            } else {
+                leaf.board.rollout()
+            };
+            Self::remove_virtual_loss(tree, leaf.node);
+            Self::finish_leaf(tree, params, eval_cache, &leaf, &[], value, true, amaf_seen);
+            Self::store_edges(tree, leaf.node.index());
+        }
+        #[cfg(not(feature = "pure-mcts"))]
+        {
+            // send the boards to the executor, and wait for the results
+            executor.send_batch(leaves.iter().map(|leaf| leaf.board).collect())?;
+            for leaf in leaves {
+                let Evaluation { mut policy, mut value, plies_left, .. } = executor.recv()?;
+                // a weak network's value is steadied by mixing in the result of a random rollout.
+                if params.value_blend < 1.0 {
+                    let rollout = leaf.board.rollout();
+                    value = params.value_blend.mul_add(value - rollout, rollout);
+                }
+                // a leaf is never the root, so takes the interior temperature.
+                params.adjust_logits(&mut policy, 1);
+                Self::remove_virtual_loss(tree, leaf.node);
+                if let Some(plies_left) = plies_left {
+                    stats.add_plies_left(leaf.depth, plies_left);
+                }
+                Self::finish_leaf(tree, params, eval_cache, &leaf, &policy, value, false, amaf_seen);
+                Self::store_edges(tree, leaf.node.index());
+            }
+        }
+
+        Ok(simulations as u64)
+    }
+
+    /// Selects a leaf, through `root_edge` if given, and expands an edge to it,
+    /// backpropagating at once if its value is already known. The depth of the selection is recorded in `stats`.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(not(feature = "rave"), allow(unused_variables, clippy::needless_pass_by_ref_mut))]
+    fn select_leaf(
+        root: &G,
+        history: &[u64],
+        tree: &mut Arena<Node<G>>,
+        params: &Params,
+        eval_cache: &EvalCache,
+        root_edge: Option<usize>,
+        stats: &mut SearchStats,
+        amaf_seen: &mut Vec<bool>,
+    ) -> Selected<G> {
+        // select, recording the moves made if RAVE needs them
+        let rave = params.rave_equivalence.is_some();
+        let mut path = Vec::new();
+        let mut seen = Vec::new();
+        let selection = Self::select(root, history, tree, params, eval_cache.root_key, root_edge, &mut path, &mut seen);
+
+        match selection {
+            SelectionResult::NonTerminal {
+                node_index: best_node,
+                edge_index: edge_to_expand,
+                mut board_state,
+                key,
+            } => {
+                // expand
+                let mover = board_state.to_move();
+                let new_node = Self::expand(tree, params, best_node, edge_to_expand, mover);
+
+                // make the move
+                let edge = &tree[best_node].edges().unwrap()[edge_to_expand];
+                let mv = edge.get_move(false);
+                let before = board_state;
+                board_state.make_move(mv);
+                if rave {
+                    path.push((mover, mv.policy_index()));
+                }
+                let leaf_depth = Self::depth(tree, new_node);
+                stats.add_selection(leaf_depth);
+
+                // a move that ends the game needs no evaluation, as its exact result is known.
+                if let Some(outcome) = board_state.outcome() {
+                    tree[new_node.index()].check_game_over(&board_state);
+                    if params.solver {
+                        Self::propagate_proof(tree, new_node);
+                    }
+                    let value = Self::terminal_value(outcome, mover, params.draw_value(root, mover));
+                    Self::backpropagate(tree, new_node, value);
+                    #[cfg(feature = "rave")]
+                    if rave {
+                        Self::update_amaf(tree, new_node, leaf_depth, &path, value, amaf_seen);
+                    }
+                    return Selected::Backpropagated;
+                }
+
+                // returning to an earlier position is a draw, so there is nothing to evaluate.
+                if Self::is_repetition(&board_state, history, &mut seen) {
+                    let value = params.draw_value(root, mover);
+                    Self::backpropagate(tree, new_node, value);
+                    #[cfg(feature = "rave")]
+                    if rave {
+                        Self::update_amaf(tree, new_node, leaf_depth, &path, value, amaf_seen);
+                    }
+                    return Selected::Backpropagated;
+                }
+
+                // a position reached by another move order takes the evaluation it was given before.
+                let key = key.and_then(|key| before.transposition_key_after(key, mv, &board_state));
+                if let Some(&other) = key.and_then(|key| eval_cache.nodes.get(&key)) {
+                    let (policy, value) = Self::cached_evaluation(&tree[other], mover);
+                    Self::expand_leaf(&mut tree[new_node.index()], params, board_state, &policy, false);
+                    Self::store_edges(tree, new_node.index());
+                    Self::backpropagate(tree, new_node, value);
+                    #[cfg(feature = "rave")]
+                    if rave {
+                        Self::update_amaf(tree, new_node, leaf_depth, &path, value, amaf_seen);
+                    }
+                    return Selected::Backpropagated;
+                }
+
+                Selected::Pending(PendingLeaf {
+                    node: new_node,
+                    board: board_state,
+                    mover,
+                    key,
+                    depth: leaf_depth,
+                    path,
+                })
+            }
+            SelectionResult::Terminal { node_index: best_node, outcome } => {
+                // if the node's outcome is known, we don't need to expand it.
+                // we just need to backpropagate the result.
+                let mover = tree[best_node].mover();
+                let value = Self::terminal_value(outcome, mover, params.draw_value(root, mover));
+                let node = Handle::from_index(best_node, tree);
+                stats.add_selection(Self::depth(tree, node));
+                Self::backpropagate(tree, node, value);
+                #[cfg(feature = "rave")]
+                if rave {
+                    Self::update_amaf(tree, node, path.len(), &path, value, amaf_seen);
+                }
+                Selected::Backpropagated
+            }
+            SelectionResult::Repetition { node_index } => {
+                let node = Handle::from_index(node_index, tree);
+                let value = params.draw_value(root, tree[node_index].mover());
+                stats.add_selection(Self::depth(tree, node));
+                Self::backpropagate(tree, node, value);
+                #[cfg(feature = "rave")]
+                if rave {
+                    Self::update_amaf(tree, node, path.len(), &path, value, amaf_seen);
+                }
+                Selected::Backpropagated
+            }
+            SelectionResult::InFlight => Selected::Collided,
+            SelectionResult::Widen { node_index, board_state } => Selected::Widen { node_index, board: board_state },
+        }
+    }
+
+    /// Expands a leaf with its evaluation, and backpropagates its value, which is from the point of view
+    /// of the side to move at the leaf.
+    #[allow(clippy::too_many_arguments)]
+    #[cfg_attr(not(feature = "rave"), allow(unused_variables, clippy::needless_pass_by_ref_mut))]
+    fn finish_leaf(
+        tree: &mut [Node<G>],
+        params: &Params,
+        eval_cache: &mut EvalCache,
+        leaf: &PendingLeaf<G>,
+        policy: &[f32],
+        value: f32,
+        uniform: bool,
+        amaf_seen: &mut Vec<bool>,
+    ) {
+        // expand this node
+        let node = leaf.node;
+        Self::expand_leaf(&mut tree[node.index()], params, leaf.board, policy, uniform);
+        let noise_weight = params.noise_weight_at(leaf.depth);
+        if noise_weight > 0.0 {
+            tree[node.index()].add_noise(noise_weight, params.noise_alpha);
+        }
+        if let Some(key) = leaf.key {
+            eval_cache.nodes.insert(key, node.index());
+        }
+
+        // backpropagate
+        // the value is from the point of view of the side to move, which is usually
+        // the opponent of the mover, but not always: a turn can span several moves.
+        let value = f64::from(value);
+        let value = if leaf.board.to_move() == leaf.mover { value } else { 1.0 - value };
+        Self::backpropagate(tree, node, value);
+        #[cfg(feature = "rave")]
+        if params.rave_equivalence.is_some() {
+            Self::update_amaf(tree, node, leaf.depth, &leaf.path, value, amaf_seen);
+        }
+    }
+
+    /// Moves the edges of the node at `node_idx` into the tree file, if the tree is memory-mapped.
+    fn store_edges(tree: &mut Arena<Node<G>>, node_idx: usize) {
+        if let (nodes, Some(mut spare)) = tree.with_spare() {
+            nodes[node_idx].move_edges_into(&mut spare);
+        }
+    }
 
-            // update elapsed time and print stats
-            let curr_bm = Self::rollouts_best(tree, 0).0;
-            let bm_changed = curr_bm != last_best_move_index;
-            last_best_move_index = curr_bm;
-            if params.do_stdout && (nodes_searched % 100 == 0 || bm_changed) {
-                print!(
-                    "info nodes {} time {} nps {:.0} score q {:.1} pv",
-                    nodes_searched,
-                    elapsed,
-                    nodes_searched as f64 / (elapsed as f64 / 1000.0),
-                    (1.0 - tree[0].winrate()) * 100.0
-                );
-                Self::print_pv(root, tree);
+    /// Gives the tree file back the room taken by the edges of the nodes about to be dropped, which are
+    /// those not marked in `keep`.
+    fn release_edges(tree: &mut Arena<Node<G>>, keep: &[bool]) {
+        if let (nodes, Some(mut spare)) = tree.with_spare() {
+            for (node, _) in nodes.iter_mut().zip(keep).filter(|(_, &keep)| !keep) {
+                node.release_edges(&mut spare);
             }
-            stopped_by_stdin = if let Some(Ok(cmd)) = params.stdin_rx.map(|m| m.lock().unwrap().try_recv()) {
-                let cmd = cmd.trim();
-                if cmd == "quit" {
-                    ugi::QUIT.store(true, Ordering::SeqCst);
-                }
-                debug!("received command: {}", cmd);
-                true
-            } else {
-                false
-            };
-            elapsed = u64::try_from(start_time.elapsed().as_millis()).expect("elapsed time overflow");
-            // write the root rollout distribution to log.txt
-            // let root_dist = tree[0].dist(tree);
-            // for visit_count in root_dist {
-            //     write!(log, "{visit_count},").unwrap();
-            // }
-            // writeln!(log).unwrap();
-
-            // update nodes searched
-            nodes_searched += 1;
         }
+    }
 
-        trace!("Engine::search: finished search loop with {} entries in tree.", tree.len());
+    /// Gives the legal moves of a newly-selected leaf their edges, or under progressive widening,
+    /// only its most probable moves.
+    fn expand_leaf(node: &mut Node<G>, params: &Params, pos: G, policy: &[f32], uniform: bool) {
+        match params.widening_edges(0) {
+            Some(max_edges) => node.expand_partially(pos, policy, uniform, max_edges),
+            None => node.expand(pos, policy, uniform),
+        }
+    }
 
+    /// Gives more of the moves of the partially-expanded node at `node_idx`, whose position is `pos`,
+    /// their edges, as many as its visits allow. The node's policy is evaluated again to find them.
+    #[cfg_attr(feature = "pure-mcts", allow(clippy::unnecessary_wraps, unused_variables))]
+    fn widen(
+        executor: &ExecutorHandle<G>,
+        tree: &mut [Node<G>],
+        params: &Params,
+        node_idx: usize,
+        pos: G,
+    ) -> anyhow::Result<()> {
+        let max_edges = params.widening_edges(tree[node_idx].visits()).unwrap_or(usize::MAX);
+        #[cfg(feature = "pure-mcts")]
+        {
+            tree[node_idx].widen(pos, &[], true, max_edges);
+        }
+        #[cfg(not(feature = "pure-mcts"))]
+        {
+            executor.send(pos)?;
+            let Evaluation { mut policy, .. } = executor.recv()?;
+            // the depth only matters at the root, which is never widened.
+            params.adjust_logits(&mut policy, 1);
+            tree[node_idx].widen(pos, &policy, false, max_edges);
+        }
         Ok(())
     }
 
-    /// Performs one iteration of selection, expansion, simulation, and backpropagation.
-    fn do_sesb(executor: &ExecutorHandle<G>, root: &G, tree: &mut Vec<Node<G>>, params: &Params) -> anyhow::Result<()> {
-        trace!("Engine::do_sesb(root, tree, params)");
+    /// The policy of an expanded node, as logits, and its value from the point of view of `mover`,
+    /// for a node elsewhere in the tree with the same position to start from.
+    #[allow(clippy::cast_possible_truncation)]
+    fn cached_evaluation(node: &Node<G>, mover: Player) -> (Vec<f32>, f64) {
+        let mut policy = vec![f32::NEG_INFINITY; G::policy_dim()];
+        for edge in node.edges().expect("cached node has not been expanded") {
+            policy[edge.get_move(false).policy_index()] = (edge.probability() as f32).ln();
+        }
+        let value = if node.mover() == mover { node.winrate() } else { 1.0 - node.winrate() };
+        (policy, value)
+    }
 
-        // select
-        let selection = Self::select(root, tree, params, 0);
+    /// Marks the ancestors of `node`, whose outcome is known, that are proven by it.
+    fn propagate_proof(tree: &mut [Node<G>], node: Handle) {
+        let mut parent = tree[node.index()].non_null_parent(tree);
+        while let Some(node) = parent {
+            let Some(outcome) = Self::proven_by_children(tree, node.index()) else {
+                break;
+            };
+            tree[node.index()].set_proven(outcome);
+            parent = tree[node.index()].non_null_parent(tree);
+        }
+    }
 
-        match selection {
-            SelectionResult::NonTerminal { node_index: best_node, edge_index: edge_to_expand, mut board_state } => {
-                // expand
-                let new_node = Self::expand(tree, params, best_node, edge_to_expand);
+    /// The outcome of a node with best play, if its children prove it: a node is won for the side to
+    /// move if any child is, and otherwise is proven once every legal move has a proven child,
+    /// as a draw if any of them is drawn, and as a loss if not.
+    fn proven_by_children(tree: &[Node<G>], node_idx: usize) -> Option<Player> {
+        let node = &tree[node_idx];
+        let num_edges = node.edges()?.len();
+        let mut num_proven = 0;
+        let mut best = None;
+        let mut child = node.first_child();
+        while !child.is_null() {
+            let child_node = &tree[child.index()];
+            match child_node.proven_outcome() {
+                Some(outcome) if outcome == child_node.mover() => return Some(outcome),
+                Some(outcome) => {
+                    num_proven += 1;
+                    if best != Some(Player::None) {
+                        best = Some(outcome);
+                    }
+                }
+                None => {}
+            }
+            child = child_node.sibling();
+        }
+        // moves left to progressive widening might yet be better than the proven ones.
+        best.filter(|_| num_proven == num_edges && !node.is_partial())
+    }
 
-                // make the move
-                let edge = &tree[best_node].edges().unwrap()[edge_to_expand];
-                let mv = edge.get_move(false);
-                board_state.make_move(mv);
+    /// The number of plies to the end of the game from a proven node with best play,
+    /// which wins as quickly as possible, and loses as slowly as possible.
+    fn proof_length(tree: &[Node<G>], node_idx: usize) -> usize {
+        let node = &tree[node_idx];
+        let Some(outcome) = node.proven_outcome().filter(|_| !node.is_terminal()) else {
+            return 0;
+        };
+        let mut lengths = Vec::new();
+        let mut winning = false;
+        let mut child = node.first_child();
+        while !child.is_null() {
+            let child_node = &tree[child.index()];
+            if child_node.proven_outcome() == Some(outcome) {
+                winning = outcome == child_node.mover();
+                lengths.push(Self::proof_length(tree, child.index()));
+            }
+            child = child_node.sibling();
+        }
+        let length = if winning || outcome == Player::None { lengths.iter().min() } else { lengths.iter().max() };
+        1 + length.copied().unwrap_or(0)
+    }
 
-                // simulate
-                let (policy, value, uniform);
-                #[cfg(feature = "pure-mcts")]
-                {
-                    // if we're doing pure MCTS, we do a random rollout.
-                    value = board_state.rollout();
-                    policy = [];
-                    uniform = true;
+    /// The score of the node at `node_idx`, `depth` plies below the root, for the player who moved into it:
+    /// its proven result, with the plies from the root to the end of the game, if it has one, or its value.
+    fn score(tree: &[Node<G>], node_idx: usize, depth: usize) -> Score {
+        let node = &tree[node_idx];
+        match node.proven_outcome() {
+            Some(Player::None) | None => Score::Q(node.winrate()),
+            Some(outcome) if outcome == node.mover() => Score::Win(depth + Self::proof_length(tree, node_idx)),
+            Some(_) => Score::Loss(depth + Self::proof_length(tree, node_idx)),
+        }
+    }
+
+    /// The edge of the quickest proven win at the root, or if the root is a proven loss, of the slowest loss,
+    /// so that a won game is finished off and a lost one drawn out.
+    fn proven_best(tree: &[Node<G>]) -> Option<usize> {
+        let mut quickest_win = None;
+        let mut slowest_loss = None;
+        let mut child = tree.first()?.first_child();
+        while !child.is_null() {
+            let node = &tree[child.index()];
+            match Self::score(tree, child.index(), 1) {
+                Score::Win(plies) if quickest_win.is_none_or(|(_, quickest)| plies < quickest) => {
+                    quickest_win = Some((node.edge_index(), plies));
                 }
-                #[cfg(not(feature = "pure-mcts"))]
-                {
-                    // send the board to the executor
-                    executor.sender.send(board_state)?;
-                    // wait for the result
-                    (policy, value) = executor.receiver.recv()?;
-                    uniform = false;
+                Score::Loss(plies) if slowest_loss.is_none_or(|(_, slowest)| plies > slowest) => {
+                    slowest_loss = Some((node.edge_index(), plies));
                 }
-
-                // expand this node
-                tree[new_node.index()].expand(board_state, &policy, uniform);
-
-                // backpropagate
-                Self::backpropagate(tree, new_node, 1.0 - f64::from(value));
-            }
-            SelectionResult::Terminal { node_index: best_node, board_state } => {
-                // if the node is terminal, we don't need to expand it.
-                // we just need to backpropagate the result.
-                let value = match board_state.outcome() {
-                    None => unreachable!("terminal node has no outcome"),
-                    Some(Player::None) => 0.5, // draw
-                    Some(p) => {
-                        if p == board_state.to_move() {
-                            0.0
-                        } else {
-                            1.0
-                        }
-                    }
-                };
-                let node = Handle::from_index(best_node, tree);
-                Self::backpropagate(tree, node, value);
+                _ => {}
             }
-        };
+            child = node.sibling();
+        }
+        let root_lost = tree[0].proven_outcome() == Some(tree[0].mover());
+        quickest_win.or_else(|| slowest_loss.filter(|_| root_lost)).map(|(edge_idx, _)| edge_idx)
+    }
 
-        Ok(())
+    /// The exact value of a finished game, from the point of view of `mover`, who values a draw at `draw_value`.
+    fn terminal_value(outcome: Player, mover: Player, draw_value: f64) -> f64 {
+        match outcome {
+            Player::None => draw_value,
+            winner if winner == mover => 1.0,
+            _ => 0.0,
+        }
     }
 
-    /// Descends the tree, selecting the best node at each step.
+    /// Descends the tree, selecting the best node at each step, except that `first_edge`, if given,
+    /// is taken from the first node.
     /// Returns the index of a node, and the index of the edge to be expanded.
-    fn select(root: &G, tree: &mut [Node<G>], params: &Params, mut node_idx: usize) -> SelectionResult<G> {
-        trace!("Engine::select(root, tree, params, node_idx = {node_idx})");
+    /// If RAVE is enabled, the moves made on the way down are appended to `path`.
+    /// The repetition keys of the positions passed through are appended to `seen`, and the transposition
+    /// key of the root, `root_key`, is kept up to date on the way down.
+    #[allow(clippy::too_many_arguments)]
+    fn select(
+        root: &G,
+        history: &[u64],
+        tree: &mut [Node<G>],
+        params: &Params,
+        root_key: Option<u64>,
+        first_edge: Option<usize>,
+        path: &mut Vec<(Player, usize)>,
+        seen: &mut Vec<u64>,
+    ) -> SelectionResult<G> {
+        trace!("Engine::select(root, tree, params)");
 
+        let mut node_idx = 0;
         let mut pos = *root;
+        let mut key = root_key;
+        seen.extend(pos.repetition_key());
+        let mut depth = 0;
         loop {
             // if the node has had a single visit, expand it
             // here, "expand" means adding all the legal moves to the node
@@ -246,64 +1511,199 @@ impl<'a, G: GameImpl> Engine<'a, G> {
                 tree[node_idx].check_game_over(&pos);
             }
 
-            // if the node is terminal, return it
-            if tree[node_idx].is_terminal() {
-                trace!("Engine::select: terminal node reached: index {node_idx}, position {}", pos.fen());
-                return SelectionResult::Terminal { node_index: node_idx, board_state: pos };
+            // if the node's outcome is known, return it
+            if let Some(outcome) = tree[node_idx].proven_outcome() {
+                trace!("Engine::select: proven node reached: index {node_idx}, position {}", pos.fen());
+                return SelectionResult::Terminal { node_index: node_idx, outcome };
+            }
+
+            // a leaf selected earlier in the batch has no edges until its evaluation arrives.
+            let Some(num_edges) = tree[node_idx].edges().map(<[_]>::len) else {
+                return SelectionResult::InFlight;
+            };
+            // progressive widening gives more moves edges as the node's visits grow.
+            if tree[node_idx].is_partial()
+                && params.widening_edges(tree[node_idx].visits()).is_some_and(|max_edges| max_edges > num_edges)
+            {
+                return SelectionResult::Widen { node_index: node_idx, board_state: pos };
             }
 
-            let (edge_idx, child_idx) = Self::uct_best(tree, params, node_idx);
+            let (edge_idx, child_idx) = first_edge.filter(|_| depth == 0).map_or_else(
+                || Self::uct_best(tree, params, node_idx, depth),
+                |edge_idx| (edge_idx, Self::child_at_edge(tree, node_idx, edge_idx)),
+            );
             // if the node has no children, return it, because we can't descend any further.
             if child_idx.is_null() {
-                return SelectionResult::NonTerminal { node_index: node_idx, edge_index: edge_idx, board_state: pos };
+                return SelectionResult::NonTerminal {
+                    node_index: node_idx,
+                    edge_index: edge_idx,
+                    board_state: pos,
+                    key,
+                };
             }
 
             // it's *not* unexpanded, so we can descend
             trace!("Engine::select: descending to child {}", child_idx.index());
             let edge = &tree[node_idx].edges().unwrap()[edge_idx];
             let mv = edge.get_move(false);
+            if params.rave_equivalence.is_some() {
+                path.push((pos.to_move(), mv.policy_index()));
+            }
+            let before = pos;
             pos.make_move(mv);
+            key = key.and_then(|key| before.transposition_key_after(key, mv, &pos));
+            if Self::is_repetition(&pos, history, seen) {
+                return SelectionResult::Repetition { node_index: child_idx.index() };
+            }
 
             // descend
             node_idx = child_idx.index();
+            depth += 1;
         }
     }
 
-    /// Prints out the current line of best play.
-    pub fn print_pv(root: &G, tree: &[Node<G>]) {
-        let mut node_idx = Handle::from_index(0, tree);
-        let mut pos = *root;
+    /// The child of a node through the edge at `edge_idx`, or null if that edge has not been expanded.
+    fn child_at_edge(tree: &[Node<G>], node_idx: usize, edge_idx: usize) -> Handle {
+        let mut child = tree[node_idx].first_child();
+        while !child.is_null() && tree[child.index()].edge_index() != edge_idx {
+            child = tree[child.index()].sibling();
+        }
+        child
+    }
+
+    /// What the search knows about each root move, in edge order, for Gumbel root selection.
+    fn root_move_stats(tree: &[Node<G>]) -> Vec<MoveStats> {
+        let root = &tree[0];
+        let mut moves = root
+            .edges()
+            .expect("root has no edges")
+            .iter()
+            .map(|edge| MoveStats { prior: edge.probability(), visits: 0, value: None })
+            .collect::<Vec<_>>();
+        let mut child = root.first_child();
+        while !child.is_null() {
+            let node = &tree[child.index()];
+            let stats = &mut moves[node.edge_index()];
+            stats.visits = node.visits_with_in_flight();
+            // the child's value is from the point of view of the player who moved into it, the side to move here.
+            stats.value = (node.visits() > 0).then(|| node.winrate());
+            child = node.sibling();
+        }
+        moves
+    }
+
+    /// Whether `pos` occurred earlier in the game or earlier on the current path, which is recorded in `seen`.
+    fn is_repetition(pos: &G, history: &[u64], seen: &mut Vec<u64>) -> bool {
+        let Some(key) = pos.repetition_key() else {
+            return false;
+        };
+        if history.contains(&key) || seen.contains(&key) {
+            return true;
+        }
+        seen.push(key);
+        false
+    }
+
+    /// The current line of best play from the node at `node_idx`, which need not be the root.
+    fn principal_variation(tree: &[Node<G>], node_idx: usize) -> Vec<G::Move> {
+        let mut line = Vec::new();
+        let mut node_idx = Handle::from_index(node_idx, tree);
         while !node_idx.is_null() {
-            if tree[node_idx.index()].edges().is_none() {
+            let Some(edges) = tree[node_idx.index()].edges() else {
                 break;
-            }
+            };
             let (edge_idx, child_idx) = Self::rollouts_best(tree, node_idx.index());
-            let Some(edge) = tree[node_idx.index()].edges().expect("node has no edges").get(edge_idx) else {
+            let Some(edge) = edges.get(edge_idx) else {
                 break;
             };
-            let best_move = edge.get_move(false);
-            print!(" {best_move}");
-            pos.make_move(best_move);
+            line.push(edge.get_move(false));
             node_idx = child_idx;
         }
-        println!();
+        line
+    }
+
+    /// The `count` visited root moves that would be played first, each with its line of best play.
+    fn top_lines(tree: &[Node<G>], count: usize) -> Vec<PvLine<G>> {
+        let Some(edges) = tree.first().and_then(Node::edges) else {
+            return Vec::new();
+        };
+        let mut children = Vec::new();
+        let mut child = tree[0].first_child();
+        while !child.is_null() {
+            let node = &tree[child.index()];
+            if node.visits() > 0 {
+                // use probability to break ties, as when choosing the move to play.
+                children.push((child.index(), Self::play_value(node) + edges[node.edge_index()].probability()));
+            }
+            child = node.sibling();
+        }
+        children.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        children.truncate(count);
+        children
+            .into_iter()
+            .map(|(child_idx, _)| {
+                let node = &tree[child_idx];
+                let mut moves = vec![edges[node.edge_index()].get_move(false)];
+                moves.extend(Self::principal_variation(tree, child_idx));
+                PvLine { moves, visits: u64::from(node.visits()), score: Self::score(tree, child_idx, 1) }
+            })
+            .collect()
+    }
+
+    /// The statistics of every root move, most-visited first, with the exploration bonus `params` gives each.
+    fn root_moves(tree: &[Node<G>], params: &Params) -> Vec<RootMove<G>> {
+        let Some(edges) = tree.first().and_then(Node::edges) else {
+            return Vec::new();
+        };
+        let root_visits = tree[0].visits();
+        let exploration_factor = params.c_puct_at(0, root_visits) * f64::from(root_visits + 1).sqrt();
+        let mut moves = edges
+            .iter()
+            .map(|edge| RootMove {
+                mv: edge.get_move(false),
+                visits: 0,
+                q: None,
+                prior: edge.probability(),
+                u: exploration_factor * edge.probability(),
+                pv: Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let mut child = tree[0].first_child();
+        while !child.is_null() {
+            let node = &tree[child.index()];
+            let root_move = &mut moves[node.edge_index()];
+            root_move.visits = u64::from(node.visits());
+            // the child's value is from the point of view of the player who moved into it, the side to move here.
+            root_move.q = (node.visits() > 0).then(|| node.winrate());
+            root_move.u /= 1.0 + f64::from(node.visits());
+            if params.exploration == Exploration::VarianceScaled {
+                root_move.u *= Self::variance_scale(node, f64::from(root_visits + 1).ln());
+            }
+            root_move.pv = Self::principal_variation(tree, child.index());
+            child = node.sibling();
+        }
+        moves.sort_by_key(|root_move| std::cmp::Reverse(root_move.visits));
+        moves
+    }
+
+    /// Writes out a line of moves, separated by spaces.
+    fn format_line(moves: &[G::Move]) -> String {
+        moves.iter().map(ToString::to_string).collect::<Vec<_>>().join(" ")
     }
 
-    /// Selects the best immediate edge of a node according to UCT.
+    /// Selects the best immediate edge of a node `depth` plies below the root according to UCT.
     /// Returns the index of the edge, and a nullable handle to the child.
-    fn uct_best(tree: &[Node<G>], params: &Params, node_idx: usize) -> (usize, Handle) {
-        trace!("Engine::uct_best(tree, params, node_idx = {node_idx})");
+    fn uct_best(tree: &[Node<G>], params: &Params, node_idx: usize, depth: usize) -> (usize, Handle) {
+        trace!("Engine::uct_best(tree, params, node_idx = {node_idx}, depth = {depth})");
 
         let node = &tree[node_idx];
 
-        let exploration_factor = params.c_puct * f64::from(node.visits() + 1).sqrt();
+        // visits waiting on an evaluation count as losses, steering the rest of a batch elsewhere.
+        let visits = node.visits_with_in_flight();
+        let exploration_factor = params.c_puct_at(depth, visits) * f64::from(visits + 1).sqrt();
+        let parent_log_visits = f64::from(visits + 1).ln();
         trace!(" [uct_best] exploration_factor = {exploration_factor}");
 
-        #[cfg(feature = "pure-mcts")]
-        let first_play_urgency = f64::INFINITY;
-        #[cfg(not(feature = "pure-mcts"))]
-        let first_play_urgency = 0.5;
-
         let mut best_idx = 0;
         let mut best_value = f64::NEG_INFINITY;
         let mut best_child = Handle::null();
@@ -316,15 +1716,35 @@ impl<'a, G: GameImpl> Engine<'a, G> {
         // This is slightly problematic because we have to do linked list stuff where
         // only some of the edges have corresponding nodes.
         // The simplest solution is just to have an array that we fill in.
-        let mut values = vec![None; G::POLICY_DIM];
+        let mut values = vec![None; G::policy_dim()];
+        let mut visited_policy = 0.0;
         while !child.is_null() {
             let node = &tree[child.index()];
+            // selection is bound by memory latency, so start fetching the next sibling early.
+            arena::prefetch(tree, node.sibling());
             let edge = &edges[node.edge_index()];
-            let q = node.winrate();
-            let u = exploration_factor * edge.probability() / (1.0 + f64::from(node.visits()));
-            values[node.edge_index()] = Some((child, q + u));
+            let visits = node.visits_with_in_flight();
+            let q = Self::rave_blend(params, edge, node.winrate_with_in_flight(), visits);
+            let mut u = exploration_factor * edge.probability() / (1.0 + f64::from(visits));
+            if params.exploration == Exploration::VarianceScaled {
+                u *= Self::variance_scale(node, parent_log_visits);
+            }
+            // a move proven to lose is not worth searching while any other move might not.
+            let proven_loss =
+                node.proven_outcome().is_some_and(|outcome| outcome != node.mover() && outcome != Player::None);
+            let value = if params.solver && proven_loss { f64::MIN } else { q + u };
+            values[node.edge_index()] = Some((child, value));
+            visited_policy += edge.probability();
             child = node.sibling();
         }
+        // the node's value is from the point of view of the player who moved into it.
+        let parent_q = (node.visits() > 0).then(|| 1.0 - node.winrate());
+        let first_play_urgency = params.first_play_urgency_at(depth, parent_q, visited_policy);
+        // epsilon-greedy selection: sometimes ignore the scores and pick any move.
+        if params.epsilon > 0.0 && fastrand::f64() < params.epsilon {
+            let idx = fastrand::usize(..edges.len());
+            return (idx, values[idx].map_or_else(Handle::null, |(handle, _)| handle));
+        }
         for (idx, value) in values.into_iter().take(edges.len()).enumerate() {
             if let Some((handle, value)) = value {
                 trace!(" [expanded] edge = {idx}, value = {value}");
@@ -334,6 +1754,11 @@ impl<'a, G: GameImpl> Engine<'a, G> {
                     best_child = handle;
                 }
             } else {
+                // an unvisited move takes its AMAF value, when RAVE has one for it.
+                let first_play_urgency = match (params.rave_equivalence, edges[idx].amaf_winrate()) {
+                    (Some(_), Some(amaf_q)) => amaf_q,
+                    _ => first_play_urgency,
+                };
                 let value = exploration_factor.mul_add(edges[idx].probability(), first_play_urgency);
                 trace!(
                     " [dangling] edge = {idx}, value = {value}, fpu = {first_play_urgency}, p(edge) = {}",
@@ -350,6 +1775,26 @@ impl<'a, G: GameImpl> Engine<'a, G> {
         (best_idx, best_child)
     }
 
+    /// Blends the value of a child with the AMAF value of its edge, if RAVE is enabled.
+    /// The AMAF value dominates for rarely-visited children, and fades out as visits accumulate.
+    fn rave_blend(params: &Params, edge: &Edge<G>, q: f64, visits: u32) -> f64 {
+        match (params.rave_equivalence, edge.amaf_winrate()) {
+            (Some(k), Some(amaf_q)) => {
+                let beta = (k / 3.0f64.mul_add(f64::from(visits), k)).sqrt();
+                beta.mul_add(amaf_q - q, q)
+            }
+            _ => q,
+        }
+    }
+
+    /// Scales the exploration term of a child by its observed value variance, following `UCB1-Tuned`.
+    /// Values lie in [0, 1], so the variance is at most 1/4, at which point the scale is one.
+    fn variance_scale(child: &Node<G>, parent_log_visits: f64) -> f64 {
+        let visits = f64::from(child.visits());
+        let variance_bound = child.variance() + (2.0 * parent_log_visits / visits).sqrt();
+        2.0 * variance_bound.min(0.25).sqrt()
+    }
+
     /// Selects the best immediate edge of a node according to rollout count.
     /// Returns the index of the edge, and a nullable handle to the child.
     fn rollouts_best(tree: &[Node<G>], node_idx: usize) -> (usize, Handle) {
@@ -369,11 +1814,10 @@ impl<'a, G: GameImpl> Engine<'a, G> {
         // This is slightly problematic because we have to do linked list stuff where
         // only some of the edges have corresponding nodes.
         // The simplest solution is just to have an array that we fill in.
-        let mut values = vec![None; G::POLICY_DIM];
+        let mut values = vec![None; G::policy_dim()];
         while !child.is_null() {
             let node = &tree[child.index()];
-            let r = node.visits();
-            values[node.edge_index()] = Some((child, f64::from(r)));
+            values[node.edge_index()] = Some((child, Self::play_value(node)));
             child = node.sibling();
         }
         for (idx, value) in values.into_iter().take(edges.len()).enumerate() {
@@ -401,8 +1845,25 @@ impl<'a, G: GameImpl> Engine<'a, G> {
         (best_idx, best_child)
     }
 
+    /// How strongly a visited child is preferred when choosing a move to play: by its visits, except that
+    /// proven wins are played whatever their visits, and proven losses only if nothing else is left.
+    fn play_value(node: &Node<G>) -> f64 {
+        let visits = f64::from(node.visits());
+        match node.proven_outcome() {
+            Some(Player::None) | None => visits,
+            Some(outcome) if outcome == node.mover() => visits + PROVEN_BONUS,
+            Some(_) => visits - PROVEN_BONUS,
+        }
+    }
+
     /// Expands an edge of a given node, returning a handle to the new node.
-    fn expand(tree: &mut Vec<Node<G>>, _params: &Params, node_idx: usize, edge_index: usize) -> Handle {
+    fn expand(
+        tree: &mut Arena<Node<G>>,
+        _params: &Params,
+        node_idx: usize,
+        edge_index: usize,
+        mover: Player,
+    ) -> Handle {
         trace!("Engine::expand(tree, params, node_idx = {node_idx}, edge_idx = {edge_index})");
 
         let last_child_of_expanding_node = {
@@ -421,7 +1882,7 @@ impl<'a, G: GameImpl> Engine<'a, G> {
 
         // allocate a new node
         let parent_handle = Handle::from_index(node_idx, tree);
-        let new_node = Node::new(parent_handle, edge_index);
+        let new_node = Node::new(parent_handle, edge_index, mover);
 
         // write the new node to the tree
         tree.push(new_node);
@@ -441,16 +1902,596 @@ impl<'a, G: GameImpl> Engine<'a, G> {
         handle
     }
 
+    /// Compacts the tree to at most `target` nodes by dropping the least-visited subtrees.
+    /// Surviving nodes keep their relative order, so the root stays at index 0 and every
+    /// parent still precedes its children. The visits of dropped subtrees remain counted in
+    /// their ancestors, and their edges become unexpanded again.
+    fn compact_tree(tree: &mut Arena<Node<G>>, target: usize) {
+        trace!("Engine::compact_tree(tree, target = {target})");
+
+        // find the smallest visit threshold that brings the tree under the target size.
+        // parents are always pushed before their children, so one forward pass suffices.
+        let mut keep = vec![false; tree.len()];
+        let mut threshold = 1;
+        loop {
+            keep[0] = true;
+            for index in 1..tree.len() {
+                let parent = tree[index].non_null_parent(tree).expect("non-root node has no parent");
+                keep[index] = keep[parent.index()] && tree[index].visits() >= threshold;
+            }
+            if keep.iter().filter(|&&k| k).count() <= target.max(1) || threshold > tree[0].visits() {
+                break;
+            }
+            threshold *= 2;
+        }
+
+        // assign new indices to the surviving nodes.
+        let mut new_index = vec![usize::MAX; tree.len()];
+        for (next, (index, _)) in keep.iter().enumerate().filter(|(_, &k)| k).enumerate() {
+            new_index[index] = next;
+        }
+        let first_kept = |mut handle: Handle| {
+            while !handle.is_null() && !keep[handle.index()] {
+                handle = tree[handle.index()].sibling();
+            }
+            handle
+        };
+        let remap = |handle: Handle| if handle.is_null() { None } else { Some(new_index[handle.index()]) };
+        let mut links = Vec::new();
+        for (_, node) in tree.iter().enumerate().filter(|&(index, _)| keep[index]) {
+            let parent = node.non_null_parent(tree).map(|parent| new_index[parent.index()]);
+            links.push((parent, remap(first_kept(node.first_child())), remap(first_kept(node.sibling()))));
+        }
+
+        // drop the pruned nodes and rewrite the handles of the survivors.
+        Self::release_edges(tree, &keep);
+        let mut index = 0;
+        tree.retain(|_| {
+            index += 1;
+            keep[index - 1]
+        });
+        let handle = |index: Option<usize>, tree: &[Node<G>]| {
+            index.map_or_else(Handle::null, |index| Handle::from_index(index, tree))
+        };
+        for (index, (parent, child, sibling)) in links.into_iter().enumerate() {
+            let (parent, child, sibling) = (handle(parent, tree), handle(child, tree), handle(sibling, tree));
+            tree[index].relink(parent, child, sibling);
+        }
+    }
+
+    /// Updates the AMAF statistics of every node above `leaf`, which lies `leaf_depth` moves below the root.
+    /// `moves` holds the moves of the whole simulation from the root, with the players who made them,
+    /// and `value` is from the point of view of the player who moved into `leaf`. `seen` is scratch space.
+    #[cfg(feature = "rave")]
+    fn update_amaf(
+        tree: &mut [Node<G>],
+        mut node: Handle,
+        leaf_depth: usize,
+        moves: &[(Player, usize)],
+        mut value: f64,
+        seen: &mut Vec<bool>,
+    ) {
+        trace!("Engine::update_amaf(tree, node, leaf_depth = {leaf_depth}, moves, value)");
+
+        let mut depth = leaf_depth;
+        seen.resize(G::policy_dim(), false);
+        while let Some(parent) = tree[node.index()].non_null_parent(tree) {
+            depth -= 1;
+            // the edges of the parent are moves by the player who moved into this node.
+            let player = tree[node.index()].mover();
+            seen.fill(false);
+            for &(mover, index) in &moves[depth..] {
+                if mover == player {
+                    seen[index] = true;
+                }
+            }
+            for edge in tree[parent.index()].edges_mut().expect("parent node has no edges") {
+                if seen[edge.get_move(false).policy_index()] {
+                    edge.add_amaf_visit(value);
+                }
+            }
+            if tree[parent.index()].mover() != player {
+                value = 1.0 - value;
+            }
+            node = parent;
+        }
+    }
+
+    /// Counts a visit in flight through `node` and each of its ancestors, until its evaluation arrives.
+    fn add_virtual_loss(tree: &mut [Node<G>], node: Handle) {
+        let mut node = Some(node);
+        while let Some(handle) = node {
+            tree[handle.index()].add_in_flight();
+            node = tree[handle.index()].non_null_parent(tree);
+        }
+    }
+
+    /// Removes the visit in flight counted by `add_virtual_loss` once its evaluation has arrived.
+    fn remove_virtual_loss(tree: &mut [Node<G>], node: Handle) {
+        let mut node = Some(node);
+        while let Some(handle) = node {
+            tree[handle.index()].remove_in_flight();
+            node = tree[handle.index()].non_null_parent(tree);
+        }
+    }
+
+    /// The number of plies from the root down to `node`.
+    fn depth(tree: &[Node<G>], mut node: Handle) -> usize {
+        let mut depth = 0;
+        while let Some(parent) = tree[node.index()].non_null_parent(tree) {
+            depth += 1;
+            node = parent;
+        }
+        depth
+    }
+
     /// Backpropagates the value up the tree.
     fn backpropagate(tree: &mut [Node<G>], mut node: Handle, mut value: f64) {
         trace!("Engine::backpropagate(tree, node, value)");
 
-        // backpropagate the value up the tree
+        // backpropagate the value up the tree, flipping it whenever the mover changes
         tree[node.index()].add_visit(value);
         while let Some(parent) = tree[node.index()].non_null_parent(tree) {
-            value = 1.0 - value;
+            if tree[parent.index()].mover() != tree[node.index()].mover() {
+                value = 1.0 - value;
+            }
             tree[parent.index()].add_visit(value);
             node = parent;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uttt::Board;
+
+    /// Builds a root with three children, the first of which has a child of its own.
+    fn small_tree() -> Arena<Node<Board>> {
+        let params = Params::default();
+        let root = Board::default();
+        let mut tree = Arena::new();
+        tree.push(Node::new(Handle::null(), 0, Player::Second));
+        tree[0].expand(root, &[], true);
+        for (edge, visits) in [(0, 8), (1, 1), (2, 4)] {
+            let child = Engine::expand(&mut tree, &params, 0, edge, Player::First);
+            for _ in 0..visits {
+                tree[child.index()].add_visit(0.5);
+            }
+        }
+        let mut pos = root;
+        pos.make_move(tree[0].edges().unwrap()[0].get_move(false));
+        tree[1].expand(pos, &[], true);
+        let grandchild = Engine::expand(&mut tree, &params, 1, 3, Player::Second);
+        tree[grandchild.index()].add_visit(0.5);
+        for _ in 0..13 {
+            tree[0].add_visit(0.5);
+        }
+        tree
+    }
+
+    /// Builds an engine searching from `root` with a small seeded network, evaluated on its own executor.
+    fn test_engine<'a>(params: Params<'a>, limits: Limits, root: &Board) -> Engine<'a, Board> {
+        use crate::batching::{self, Network};
+        let network = Network::Native(crate::native::NativeNet::seeded::<Board>(1, 16));
+        let eval_pipe = batching::executor::<Board>(&network, 1).unwrap().into_iter().next().unwrap();
+        Engine::new(params, limits, root, eval_pipe)
+    }
+
+    #[test]
+    fn edges_are_kept_in_the_tree_file() {
+        let path = std::env::temp_dir().join(format!("veritas-tree-file-test-{}", std::process::id()));
+        let mut tree = Arena::mapped(&path, 4, 81 * std::mem::size_of::<Edge<Board>>()).unwrap();
+        tree.push(Node::<Board>::new(Handle::null(), 0, Player::Second));
+        tree[0].expand(Board::default(), &[], true);
+        let edges = tree[0].edges().unwrap().to_vec();
+        Engine::store_edges(&mut tree, 0);
+        assert_eq!(tree[0].edges().unwrap(), edges);
+        // there is no room left for another node's edges, so they stay on the heap.
+        tree.push(Node::new(Handle::null(), 0, Player::First));
+        tree[1].expand(Board::default(), &[], true);
+        Engine::store_edges(&mut tree, 1);
+        assert_eq!(tree[1].edges().unwrap(), edges);
+        drop(tree);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rerooting_gives_back_the_edges_of_dropped_nodes() {
+        let path = std::env::temp_dir().join(format!("veritas-tree-reuse-test-{}", std::process::id()));
+        let mut tree = Arena::mapped(&path, 4, 81 * std::mem::size_of::<Edge<Board>>()).unwrap();
+        tree.push(Node::<Board>::new(Handle::null(), 0, Player::Second));
+        tree[0].expand(Board::default(), &[], true);
+        Engine::store_edges(&mut tree, 0);
+        assert!(tree[0].mapped_edges().is_some());
+        Engine::expand(&mut tree, &Params::default(), 0, 0, Player::First);
+        Engine::reroot(&mut tree, 1);
+        // the old root's edges are gone, so the room they took is free for another node's.
+        tree.push(Node::new(Handle::null(), 0, Player::Second));
+        tree[1].expand(Board::default(), &[], true);
+        Engine::store_edges(&mut tree, 1);
+        assert!(tree[1].mapped_edges().is_some());
+        drop(tree);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn compaction_drops_least_visited_subtrees() {
+        let mut tree = small_tree();
+        assert_eq!(tree.len(), 5);
+        Engine::compact_tree(&mut tree, 3);
+        // the threshold rises to two visits, dropping the second child and the grandchild.
+        assert_eq!(tree.len(), 3);
+        let first = tree[0].first_child();
+        assert_eq!(tree[first.index()].visits(), 8);
+        assert!(tree[first.index()].first_child().is_null());
+        let second = tree[first.index()].sibling();
+        assert_eq!(tree[second.index()].visits(), 4);
+        assert_eq!(tree[second.index()].edge_index(), 2);
+        assert!(tree[second.index()].sibling().is_null());
+        assert_eq!(tree[second.index()].non_null_parent(&tree), Some(Handle::from_index(0, &tree)));
+    }
+
+    #[test]
+    fn compaction_keeps_small_trees_intact() {
+        let mut tree = small_tree();
+        Engine::compact_tree(&mut tree, 10);
+        assert_eq!(tree.len(), 5);
+        let first = tree[0].first_child();
+        let grandchild = tree[first.index()].first_child();
+        assert_eq!(tree[grandchild.index()].edge_index(), 3);
+        assert_eq!(tree[grandchild.index()].non_null_parent(&tree), Some(first));
+    }
+
+    #[test]
+    fn rerooting_keeps_only_the_new_roots_subtree() {
+        let mut tree = small_tree();
+        Engine::reroot(&mut tree, 1);
+        assert_eq!(tree.len(), 2);
+        assert_eq!(tree[0].visits(), 8);
+        assert_eq!(tree[0].non_null_parent(&tree), None);
+        assert!(tree[0].sibling().is_null());
+        let child = tree[0].first_child();
+        assert_eq!(tree[child.index()].edge_index(), 3);
+        assert_eq!(tree[child.index()].non_null_parent(&tree), Some(Handle::from_index(0, &tree)));
+        let mut pos = Board::default();
+        pos.make_move(small_tree()[0].edges().unwrap()[0].get_move(false));
+        assert!(debug::validate(&pos, &tree).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "rave")]
+    fn amaf_credits_moves_played_later_by_the_same_player() {
+        let mut tree = small_tree();
+        let root_move =
+            |edge: usize, tree: &[Node<Board>]| tree[0].edges().unwrap()[edge].get_move(false).policy_index();
+        let reply = tree[1].edges().unwrap()[3].get_move(false).policy_index();
+        let moves =
+            [(Player::First, root_move(0, &tree)), (Player::Second, reply), (Player::First, root_move(2, &tree))];
+        let leaf = Handle::from_index(4, &tree);
+        Engine::update_amaf(&mut tree, leaf, 2, &moves, 1.0, &mut Vec::new());
+        // the reply was a winning move for the second player...
+        assert_eq!(tree[1].edges().unwrap()[3].amaf_winrate(), Some(1.0));
+        // ...and so a loss for the first player, whose two moves are both credited.
+        let root_edges = tree[0].edges().unwrap();
+        assert_eq!(root_edges[0].amaf_winrate(), Some(0.0));
+        assert_eq!(root_edges[2].amaf_winrate(), Some(0.0));
+        assert_eq!(root_edges[1].amaf_winrate(), None);
+    }
+
+    #[test]
+    fn snapshots_list_the_most_visited_children_first() {
+        let tree = small_tree();
+        let json = crate::snapshot::to_json(&tree, 13, 5, 1, 2);
+        assert!(json.starts_with("{\"nodes\":13,\"time\":5,\"root\":{\"n\":13,"));
+        // the width of two leaves out the least-visited child, and the depth of one its grandchild.
+        assert_eq!(json.matches("\"move\"").count(), 2);
+        assert!(json.find("\"n\":8").unwrap() < json.find("\"n\":4").unwrap());
+    }
+
+    #[test]
+    fn temperature_samples_only_visited_moves() {
+        let tree = small_tree();
+        let root_move = |edge: usize| tree[0].edges().unwrap()[edge].get_move(false);
+        // a low temperature all but always plays the most-visited move.
+        assert_eq!(Engine::sample_move(&tree, 0.01), Some(root_move(0)));
+        let mut seen = [false; 3];
+        for _ in 0..1000 {
+            let mv = Engine::sample_move(&tree, 10.0).unwrap();
+            seen[(0..3).position(|edge| root_move(edge) == mv).expect("sampled an unvisited move")] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+    }
+
+    #[test]
+    fn proven_losses_are_avoided() {
+        let mut tree = small_tree();
+        // the reply to the first root move wins for the second player, so that move loses.
+        tree[4].set_proven(Player::Second);
+        let node = Handle::from_index(4, &tree);
+        Engine::propagate_proof(&mut tree, node);
+        assert_eq!(tree[1].proven_outcome(), Some(Player::Second));
+        // the other root moves are unproven, so the root is too.
+        assert_eq!(tree[0].proven_outcome(), None);
+        assert_ne!(Engine::uct_best(&tree, &Params::default(), 0, 0).0, 0);
+        assert_ne!(Engine::rollouts_best(&tree, 0).0, 0);
+        // a proven win is played even with fewer visits.
+        tree[3].set_proven(Player::First);
+        let node = Handle::from_index(3, &tree);
+        Engine::propagate_proof(&mut tree, node);
+        assert_eq!(tree[0].proven_outcome(), Some(Player::First));
+        assert_eq!(Engine::rollouts_best(&tree, 0).0, tree[3].edge_index());
+    }
+
+    #[test]
+    fn finished_and_forced_positions_are_not_searched() {
+        let mut root = Board::default();
+        let mut only_move = None;
+        while root.outcome().is_none() {
+            only_move = root.only_move();
+            if only_move.is_some() {
+                break;
+            }
+            root.make_random_move(|lo, hi| fastrand::usize(lo..hi));
+        }
+        let mut engine = test_engine(Params::default(), Limits::nodes(100), &root);
+        match only_move {
+            Some(forced) => {
+                let results = engine.go().unwrap();
+                assert_eq!(results.best_move, forced);
+                assert!(engine.tree.is_empty());
+            }
+            None => assert!(engine.go().is_err()),
+        }
+    }
+
+    #[test]
+    fn untimed_searches_run_after_the_watchdog_fires() {
+        let watchdog = Watchdog::spawn().unwrap();
+        watchdog.arm(Instant::now());
+        watchdog.report_best_move("e5".into());
+        while !watchdog.fired() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(watchdog.disarm());
+
+        let params = Params::default().with_watchdog(&watchdog);
+        let mut engine = test_engine(params, Limits::nodes(50), &Board::default());
+        let SearchResults { root_dist, .. } = engine.go().unwrap();
+        assert!(root_dist.iter().sum::<u64>() > 1);
+        assert!(!watchdog.disarm());
+    }
+
+    #[test]
+    fn moves_are_taken_back_in_order() {
+        let mut engine = test_engine(Params::default(), Limits::nodes(50), &Board::default());
+        let moves = ["e5", "d4", "a1"].map(|mv| mv.parse().unwrap());
+        engine.set_game(&Board::default(), &moves[..2]);
+        engine.go().unwrap();
+        engine.make_move(moves[2]);
+        for (ply, &mv) in moves.iter().enumerate().rev() {
+            assert_eq!(engine.undo(), Some(mv));
+            assert_eq!(engine.params.game_ply, ply);
+            let mut expected = Board::default();
+            moves[..ply].iter().for_each(|&mv| expected.make_move(mv));
+            assert_eq!(engine.root(), expected);
+        }
+        assert_eq!(engine.undo(), None);
+        engine.make_move(moves[0]);
+        engine.set_position(&engine.root());
+        assert_eq!(engine.undo(), None);
+    }
+
+    #[test]
+    fn tree_memory_is_measured() {
+        assert_eq!(Engine::<Board>::hashfull(0, 100), 0);
+        assert_eq!(Engine::<Board>::hashfull(25, 100), 250);
+        // compaction waits until a batch no longer fits, so the tree can run slightly over.
+        assert_eq!(Engine::<Board>::hashfull(101, 100), 1000);
+        let mut root = Node::<Board>::new(Handle::null(), 0, Player::None);
+        let nodes = std::mem::size_of::<Node<Board>>();
+        assert_eq!(Engine::tree_bytes(std::slice::from_ref(&root)), nodes);
+        root.expand(Board::default(), &[0.0; 81], true);
+        assert_eq!(Engine::tree_bytes(std::slice::from_ref(&root)), nodes + 81 * std::mem::size_of::<Edge<Board>>());
+    }
+
+    #[test]
+    fn root_moves_agree_with_the_visit_distribution() {
+        let root = Board::default();
+        let mut engine = test_engine(Params::default(), Limits::nodes(200), &root);
+        let results = engine.go().unwrap();
+        assert_eq!(results.root_moves.len(), 81);
+        assert!(results.root_moves.windows(2).all(|pair| pair[0].visits >= pair[1].visits));
+        assert!((results.root_moves.iter().map(|root_move| root_move.prior).sum::<f64>() - 1.0).abs() < 1e-4);
+        for root_move in &results.root_moves {
+            assert_eq!(root_move.visits, results.root_dist[root_move.mv.policy_index()]);
+            assert_eq!(root_move.q.is_some(), root_move.visits > 0);
+        }
+    }
+
+    #[test]
+    fn seeded_searches_are_reproducible() {
+        let root = Board::default();
+        let search = || {
+            let params = Params { noise_weight: 0.25, temperature: 1.0, seed: Some(42), ..Params::default() };
+            let mut engine = test_engine(params, Limits::nodes(200), &root);
+            engine.go().unwrap()
+        };
+        let (first, second) = (search(), search());
+        assert_eq!(first.best_move, second.best_move);
+        assert_eq!(first.root_dist, second.root_dist);
+    }
+
+    #[test]
+    fn root_noise_leaves_interior_nodes_alone() {
+        let params = Params { noise_weight: 0.25, ..Params::default() };
+        let root = Board::default();
+        let mut tree = Arena::new();
+        tree.push(Node::new(Handle::null(), 0, Player::Second));
+        tree[0].expand(root, &[], true);
+        let child = Engine::expand(&mut tree, &params, 0, 0, Player::First);
+        let mut board = root;
+        board.make_move(tree[0].edges().unwrap()[0].get_move(false));
+        let leaf = PendingLeaf { node: child, board, mover: Player::First, key: None, depth: 1, path: Vec::new() };
+        let mut eval_cache = EvalCache::new(&root, &params);
+        Engine::finish_leaf(&mut tree, &params, &mut eval_cache, &leaf, &[], 0.5, true, &mut Vec::new());
+        // the uniform policy of the leaf is left as it was.
+        let edges = tree[child.index()].edges().unwrap();
+        assert!(edges.iter().all(|edge| (edge.probability() - edges[0].probability()).abs() < 1e-9));
+    }
+
+    #[test]
+    fn the_quickest_proven_win_is_played() {
+        let mut tree = small_tree();
+        // the first root move wins after the reply, and the second wins at once.
+        tree[4].set_proven(Player::First);
+        tree[1].set_proven(Player::First);
+        tree[2].set_proven(Player::First);
+        assert_eq!(Engine::score(&tree, 1, 1), Score::Win(3));
+        assert_eq!(Engine::score(&tree, 2, 1), Score::Win(2));
+        assert_eq!(Engine::proven_best(&tree), Some(tree[2].edge_index()));
+        assert_eq!(Engine::best_move(&tree, false), tree[0].edges().unwrap()[tree[2].edge_index()].get_move(false));
+        assert_eq!(Score::Win(2).flipped().to_string(), "loss 2");
+        assert_eq!(Score::Q(0.25).flipped().to_string(), "q 75.0");
+    }
+
+    #[test]
+    fn search_stats_count_the_plies_from_the_root() {
+        let tree = small_tree();
+        let mut stats = SearchStats::default();
+        assert_eq!(stats.plies_left(), None);
+        // node 2 is a child of the root, and node 4 a grandchild.
+        for (node, plies_left) in [(2, 5.0), (4, 0.0)] {
+            let depth = Engine::depth(&tree, Handle::from_index(node, &tree));
+            stats.add_selection(depth);
+            stats.add_plies_left(depth, plies_left);
+        }
+        assert_eq!(stats.plies_left(), Some(4.0));
+        assert_eq!(stats.average_depth(), 2);
+        assert_eq!(stats.max_depth, 2);
+    }
+
+    #[test]
+    fn cached_evaluations_copy_policy_and_value() {
+        let tree = small_tree();
+        let (policy, value) = Engine::cached_evaluation(&tree[1], Player::First);
+        assert!((value - tree[1].winrate()).abs() < 1e-9);
+        let mut pos = Board::default();
+        pos.make_move(tree[0].edges().unwrap()[0].get_move(false));
+        let mut node = Node::<Board>::new(Handle::null(), 0, Player::First);
+        node.expand(pos, &policy, false);
+        for (copied, original) in node.edges().unwrap().iter().zip(tree[1].edges().unwrap()) {
+            assert_eq!(copied.get_move(false), original.get_move(false));
+            assert!((copied.probability() - original.probability()).abs() < 1e-6);
+        }
+    }
+
+    #[cfg(not(feature = "pure-mcts"))]
+    #[test]
+    fn batched_leaves_are_spread_out_by_virtual_loss() {
+        let root = Board::default();
+        let params = Params { batch_leaves: 8, ..Params::default() };
+        let mut engine = test_engine(params, Limits::default(), &root);
+        engine.prepare_root().unwrap();
+        let simulations = Engine::do_sesb(
+            &engine.eval_pipe,
+            &root,
+            &[],
+            &mut engine.tree,
+            &engine.params,
+            &mut EvalCache::default(),
+            &mut SearchStats::default(),
+            &mut Vec::new(),
+            None,
+        )
+        .unwrap();
+        // the first leaf's virtual loss makes every other unvisited move look better than a second visit to it.
+        assert_eq!(simulations, 8);
+        assert_eq!(engine.tree.len(), 9);
+        assert_eq!(engine.tree[0].visits(), 8);
+        assert!(engine.validate_tree().is_empty());
+    }
+
+    #[cfg(not(feature = "pure-mcts"))]
+    #[test]
+    fn gumbel_root_selection_shares_the_budget_between_sampled_moves() {
+        let root = Board::default();
+        let params = Params { gumbel_root_moves: Some(8), ..Params::default() };
+        let mut engine = test_engine(params, Limits::nodes(96), &root);
+        let results = engine.go().unwrap();
+        // only the eight sampled moves are searched, and the first phase gives each of them four visits.
+        let visited = results.root_dist.iter().filter(|&&visits| visits > 0).count();
+        assert_eq!(visited, 8);
+        assert!(results.root_dist.iter().all(|&visits| visits == 0 || visits >= 4));
+        let policy_target = results.policy_target.unwrap();
+        assert!((policy_target.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(results.root_dist[results.best_move.policy_index()] > 0);
+    }
+
+    #[test]
+    fn widening_adds_edges_as_visits_grow() {
+        let root = Board::default();
+        let params = Params { widening_min_edges: Some(2), ..Params::default() };
+        let mut engine = test_engine(params, Limits::nodes(300), &root);
+        engine.go().unwrap();
+        assert!(engine.validate_tree().is_empty());
+        // the root has every move, and the nodes below it as many as their visits allow.
+        assert_eq!(engine.tree[0].edges().unwrap().len(), 81);
+        for node in engine.tree.iter().skip(1).filter(|node| node.is_partial()) {
+            let max_edges = engine.params.widening_edges(node.visits()).unwrap();
+            assert!(node.edges().unwrap().len() <= max_edges);
+        }
+        let widened = engine.tree.iter().skip(1).filter(|node| node.edges().is_some_and(|edges| edges.len() > 2));
+        assert!(widened.count() > 0);
+    }
+
+    #[test]
+    fn lines_follow_the_most_visited_moves() {
+        let tree = small_tree();
+        let lines = Engine::top_lines(&tree, 2);
+        assert_eq!(lines.iter().map(|line| line.visits).collect::<Vec<_>>(), [8, 4]);
+        let root_edges = tree[0].edges().unwrap();
+        let reply = tree[1].edges().unwrap()[3].get_move(false);
+        assert_eq!(lines[0].moves, [root_edges[0].get_move(false), reply]);
+        assert_eq!(lines[1].moves, [root_edges[2].get_move(false)]);
+    }
+
+    #[test]
+    fn lcb_prefers_confidently_better_moves() {
+        let mut tree = small_tree();
+        // the third root move now has as many visits as the first, and a clearly better value.
+        for _ in 0..4 {
+            tree[3].add_visit(1.0);
+        }
+        assert_eq!(Engine::lcb_best(&tree), Some(2));
+        assert_eq!(tree[0].edges().unwrap()[2].get_move(false), Engine::best_move(&tree, true));
+        // a few lucky visits give too wide a bound to compete.
+        tree[2].add_visit(1.0);
+        assert_eq!(Engine::lcb_best(&tree), Some(2));
+    }
+
+    #[test]
+    fn full_epsilon_selects_uniformly_at_random() {
+        let tree = small_tree();
+        let params = Params { epsilon: 1.0, ..Params::default() };
+        let mut seen = vec![false; tree[0].edges().unwrap().len()];
+        for _ in 0..1000 {
+            seen[Engine::uct_best(&tree, &params, 0, 0).0] = true;
+        }
+        assert!(seen.iter().all(|&seen| seen));
+    }
+
+    #[test]
+    fn shuffling_back_to_an_earlier_position_is_a_repetition() {
+        let mut pos = crate::ataxx::Board::new(7);
+        let mut seen = Vec::new();
+        assert!(!Engine::is_repetition(&pos, &[], &mut seen));
+        for (i, mv) in ["a7a5", "g7g5", "a5a7", "g5g7"].into_iter().enumerate() {
+            pos.make_move(mv.parse().unwrap());
+            // only the last move returns to the starting position.
+            assert_eq!(Engine::is_repetition(&pos, &[], &mut seen), i == 3);
+        }
+        // uttt positions never recur, so have no key.
+        assert_eq!(Board::default().repetition_key(), None);
+    }
+}
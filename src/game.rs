@@ -5,6 +5,11 @@ use std::{
 
 use smallvec::SmallVec;
 
+use crate::{
+    hash::Fnv1a,
+    notation::{self, ParseError, Squares},
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Player {
     None,
@@ -28,12 +33,201 @@ pub trait MovePolicyIndex {
     fn policy_index(&self) -> usize;
 }
 
+/// The reward of a finished rollout, from the point of view of `to_move`.
+fn rollout_reward(outcome: Player, to_move: Player) -> f32 {
+    let value_x_pov = match outcome {
+        Player::None => 0.5,
+        Player::First => 1.0,
+        Player::Second => 0.0,
+    };
+
+    if to_move == Player::First {
+        value_x_pov
+    } else {
+        1.0 - value_x_pov
+    }
+}
+
+/// Hashes the features and side to move of a position, which together identify it.
+pub fn feature_hash<G: GameImpl>(board: &G) -> u64 {
+    let mut hash = Fnv1a::new().add(u64::from(board.to_move() == Player::First));
+    board.fill_feature_map(|index| hash = hash.add(index as u64));
+    hash.finish()
+}
+
+/// The Zobrist key of a stone of `player` on `square`, drawn from a fixed sequence so that keys are the same
+/// in every run. An empty square has none.
+pub const fn zobrist_stone(square: usize, player: Player) -> u64 {
+    let colour = match player {
+        Player::None => return 0,
+        Player::First => 0,
+        Player::Second => 1,
+    };
+    splitmix64(square as u64 * 2 + colour)
+}
+
+/// The Zobrist key of the second player being to move.
+const ZOBRIST_SECOND_TO_MOVE: u64 = splitmix64(u64::MAX);
+
+/// Scrambles `index` into a key that shares no pattern with the keys of its neighbours.
+const fn splitmix64(index: u64) -> u64 {
+    let mut z = index.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The Zobrist key of a position: the keys of the stones on its board, and of the side to move.
+pub fn zobrist_hash<G: GameImpl>(board: &G) -> u64 {
+    let mut hash = if board.to_move() == Player::Second { ZOBRIST_SECOND_TO_MOVE } else { 0 };
+    board.fill_ownership(|square, player| hash ^= zobrist_stone(square, player));
+    hash
+}
+
+/// The Zobrist key of `after`, reached from `before`, whose key is `key`, by placing a stone with `mv`,
+/// for games in which a move only ever adds a stone, on the square of its policy index.
+fn placement_key_after<G: GameImpl>(before: &G, key: u64, mv: G::Move, after: &G) -> u64 {
+    let side = if before.to_move() == after.to_move() { 0 } else { ZOBRIST_SECOND_TO_MOVE };
+    key ^ zobrist_stone(mv.policy_index(), before.to_move()) ^ side
+}
+
+/// Counts the leaf positions `depth` plies below `board`, for validating move generators.
+/// A finished game has no moves, so it has no positions below it.
+pub fn perft<G: GameImpl>(board: &G, depth: usize) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+    if board.outcome().is_some() {
+        return 0;
+    }
+    let mut count = 0;
+    board.generate_moves(|mv| {
+        let mut child = *board;
+        child.make_move(mv);
+        count += perft(&child, depth - 1);
+        false
+    });
+    count
+}
+
+/// Plays the moves named in `moves` on `board`, for setting up positions in tests.
+/// Panics if any of them is not legal when its turn comes.
+#[cfg(test)]
+pub fn play_moves<G: GameImpl>(board: &mut G, moves: &[&str]) {
+    for name in moves {
+        let mut found = None;
+        board.generate_moves(|mv| {
+            found = (mv.to_string() == *name).then_some(mv);
+            found.is_some()
+        });
+        board.make_move(found.unwrap_or_else(|| panic!("{name} is not a legal move")));
+    }
+}
+
+/// A game that can be played, as it is named on the command line and by the `UGI_Variant` option:
+/// one of a fixed set, or a sized variant such as `ataxx:5` or `mnk:7x6x4`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GameSpec {
+    Ataxx,
+    Gomoku9,
+    Gomoku15,
+    Uttt,
+    Connect6,
+    /// Ataxx on a board of the given side length.
+    SizedAtaxx(usize),
+    /// An m,n,k-game of the given shape.
+    Mnk(crate::mnk::Dimensions),
+}
+
+impl FromStr for GameSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use anyhow::Context;
+        match s.split_once(':') {
+            None => match s {
+                "ataxx" => Ok(Self::Ataxx),
+                "gomoku9" => Ok(Self::Gomoku9),
+                "gomoku15" => Ok(Self::Gomoku15),
+                "uttt" => Ok(Self::Uttt),
+                "connect6" => Ok(Self::Connect6),
+                _ => anyhow::bail!("unknown game {s:?}"),
+            },
+            Some(("ataxx", size)) => {
+                let size = size.parse::<usize>().with_context(|| format!("invalid ataxx board size {size:?}"))?;
+                let (min, max) = (crate::ataxx::MIN_SIZE, crate::ataxx::MAX_SIZE);
+                anyhow::ensure!(
+                    (min..=max).contains(&size),
+                    "ataxx board size must be between {min} and {max}, got {size}"
+                );
+                Ok(Self::SizedAtaxx(size))
+            }
+            Some(("mnk", dimensions)) => Ok(Self::Mnk(dimensions.parse()?)),
+            _ => anyhow::bail!("unknown game {s:?}"),
+        }
+    }
+}
+
+/// Evaluates `$body` with the type `$G` standing for the board of the game named `$game`, and `$start`,
+/// if named, for its starting position, which for a sized variant has the size asked for. `$body` gives an
+/// `anyhow::Result`, and so does the whole, failing without evaluating `$body` if `$game` names no game.
+macro_rules! with_game {
+    ($game:expr, |$G:ident| $body:expr) => {
+        with_game!($game, |$G, _start| $body)
+    };
+    ($game:expr, |$G:ident, $start:ident| $body:expr) => {
+        match $game.parse::<$crate::game::GameSpec>() {
+            Err(e) => Err(e),
+            Ok(spec) => match spec {
+                $crate::game::GameSpec::Ataxx => {
+                    type $G = ataxxgen::Board;
+                    let $start = $G::default();
+                    $body
+                }
+                $crate::game::GameSpec::Gomoku9 => {
+                    type $G = gomokugen::board::Board<9>;
+                    let $start = $G::default();
+                    $body
+                }
+                $crate::game::GameSpec::Gomoku15 => {
+                    type $G = gomokugen::board::Board<15>;
+                    let $start = $G::default();
+                    $body
+                }
+                $crate::game::GameSpec::Uttt => {
+                    type $G = $crate::uttt::Board;
+                    let $start = $G::default();
+                    $body
+                }
+                $crate::game::GameSpec::Connect6 => {
+                    type $G = $crate::connect6::Board;
+                    let $start = $G::default();
+                    $body
+                }
+                $crate::game::GameSpec::SizedAtaxx(size) => {
+                    type $G = $crate::ataxx::Board;
+                    let $start = $G::new(size);
+                    $body
+                }
+                $crate::game::GameSpec::Mnk(dimensions) => {
+                    type $G = $crate::mnk::Board;
+                    let $start = $G::new(dimensions);
+                    $body
+                }
+            },
+        }
+    };
+}
+
 /// A wrapper around a game implementation.
 /// Allows `veritas` to be generic over different game implementations.
 #[allow(clippy::module_name_repetitions)]
 pub trait GameImpl: Default + Display + Debug + Copy + Clone + FromStr + Send + Sync + 'static {
     /// The dimensionality of the policy.
-    const POLICY_DIM: usize;
+    fn policy_dim() -> usize;
+    /// The name the game is selected by, as on the command line. Sized variants, whose boards of every size
+    /// share one shape of network, are named with a placeholder for the size, as in `ataxx:<size>`.
+    fn name() -> String;
     /// The associated move type.
     type Move: Copy + Eq + Display + Debug + FromStr + MovePolicyIndex + Send + Sync + 'static;
     /// Which player is to move.
@@ -41,6 +235,7 @@ pub trait GameImpl: Default + Display + Debug + Copy + Clone + FromStr + Send +
     /// The outcome of the game.
     fn outcome(&self) -> Option<Player>;
     /// Make a move.
+    /// A turn may consist of several moves, in which case `to_move` is unchanged by this.
     fn make_move(&mut self, mv: Self::Move);
     /// Generate a list of legal moves.
     /// Iteration short-circuits if the callback returns `true`.
@@ -51,6 +246,56 @@ pub trait GameImpl: Default + Display + Debug + Copy + Clone + FromStr + Send +
     fn fill_feature_map(&self, index_callback: impl FnMut(usize));
     /// The dimensionality of the tensor representation of the game state.
     fn tensor_dims(batch_size: usize) -> kn_graph::ndarray::IxDyn;
+    /// Fills `input` with the feature maps of `boards`, one after another in rows of `features` values.
+    /// `input` starts zeroed, and holds a row for every board. The executor builds its batches with this,
+    /// so games that can build a whole row faster than one feature at a time should override it.
+    fn fill_feature_batch(boards: &[Self], features: usize, input: &mut [f32]) {
+        for (board, row) in boards.iter().zip(input.chunks_exact_mut(features)) {
+            board.fill_feature_map(|index| row[index] = 1.0);
+        }
+    }
+    /// The number of squares in the ownership target.
+    fn ownership_dim() -> usize;
+    /// Calls the callback with the index and owner of every occupied square.
+    /// The occupancy at the end of a game is recorded as an auxiliary training target.
+    fn fill_ownership(&self, f: impl FnMut(usize, Player));
+    /// The number of files of the board, by which ownership indices are laid out in rows from the first rank.
+    /// Boards are square unless this is overridden.
+    fn board_width() -> usize {
+        (1..=Self::ownership_dim()).find(|width| width * width >= Self::ownership_dim()).unwrap_or(1)
+    }
+    /// The numbers of files and ranks of this board, from the corner at ownership index zero. This is the
+    /// whole of the ownership layout, unless the board is smaller than the largest of its game.
+    fn files_and_ranks(&self) -> (usize, usize) {
+        let width = Self::board_width();
+        (width, Self::ownership_dim().div_ceil(width))
+    }
+    /// Parses a position from its FEN, saying which part of it is wrong if it is invalid.
+    fn parse_fen(fen: &str) -> Result<Self, ParseError> {
+        fen.parse().map_err(|_| ParseError::fen("position", "not a valid position for this game"))
+    }
+    /// Parses a move, saying what is wrong with it if it is invalid.
+    /// The move is not checked for legality, as there is no position to check it against.
+    fn parse_move(text: &str) -> Result<Self::Move, ParseError> {
+        text.parse().map_err(|_| ParseError::mv(text, "not a valid move for this game"))
+    }
+    /// A key identifying the position, for games in which positions can recur and a repetition is a draw.
+    /// Games in which positions never recur return `None`, and no repetitions are looked for.
+    fn repetition_key(&self) -> Option<u64> {
+        None
+    }
+    /// A key identifying the position, so that positions reached by different move orders can share
+    /// what the search has learned about them. Defaults to the repetition key, if the game has one.
+    fn transposition_key(&self) -> Option<u64> {
+        self.repetition_key()
+    }
+    /// The transposition key of `after`, the position reached by playing `mv` here, given `key`, this
+    /// position's own, so that the search can keep keys up to date on its way down the tree.
+    /// Games whose keys change by a little with each move override this; by default the key is recomputed.
+    fn transposition_key_after(&self, key: u64, mv: Self::Move, after: &Self) -> Option<u64> {
+        let _ = (key, mv);
+        after.transposition_key()
+    }
     /// Make a random move.
     fn make_random_move(&mut self, mut rng: impl FnMut(usize, usize) -> usize) {
         let mut moves = SmallVec::<[Self::Move; 265]>::new();
@@ -61,6 +306,21 @@ pub trait GameImpl: Default + Display + Debug + Copy + Clone + FromStr + Send +
         let mv = moves[rng(0, moves.len())];
         self.make_move(mv);
     }
+    /// Returns the only legal move in the position, if there is exactly one.
+    fn only_move(&self) -> Option<Self::Move> {
+        let mut first = None;
+        let mut count = 0;
+        self.generate_moves(|mv| {
+            first = Some(mv);
+            count += 1;
+            count > 1
+        });
+        if count == 1 {
+            first
+        } else {
+            None
+        }
+    }
     /// Perform a rollout from the given state, returning the reward.
     fn rollout(&self) -> f32 {
         let to_move = self.to_move();
@@ -74,21 +334,33 @@ pub trait GameImpl: Default + Display + Debug + Copy + Clone + FromStr + Send +
             state.make_random_move(|lo, hi| rng.usize(lo..hi));
         };
 
-        let value_x_pov = match outcome {
-            Player::None => 0.5,
-            Player::First => 1.0,
-            Player::Second => 0.0,
+        rollout_reward(outcome, to_move)
+    }
+    /// Perform a rollout from the given state, returning the reward.
+    /// The callback is called with each move made, and the player who made it.
+    #[cfg_attr(not(feature = "pure-mcts"), allow(dead_code))]
+    fn rollout_recording(&self, mut on_move: impl FnMut(Player, Self::Move)) -> f32 {
+        let to_move = self.to_move();
+        let mut state = *self;
+        let mut rng = fastrand::Rng::new();
+        let mut moves = SmallVec::<[Self::Move; 265]>::new();
+
+        let outcome = loop {
+            if let Some(outcome) = state.outcome() {
+                break outcome;
+            }
+            moves.clear();
+            state.generate_moves(|mv| {
+                moves.push(mv);
+                false
+            });
+            let mv = moves[rng.usize(0..moves.len())];
+            on_move(state.to_move(), mv);
+            state.make_move(mv);
         };
 
-        if to_move == Player::First {
-            value_x_pov
-        } else {
-            1.0 - value_x_pov
-        }
+        rollout_reward(outcome, to_move)
     }
-    /// Textually substitute p1time/p2time/p1inc/p2inc
-    /// from an alternate representation.
-    fn player_substitute(limits_text: &str) -> String;
 }
 
 impl MovePolicyIndex for gomokugen::board::Move<9> {
@@ -98,8 +370,21 @@ impl MovePolicyIndex for gomokugen::board::Move<9> {
 }
 
 impl GameImpl for gomokugen::board::Board<9> {
-    const POLICY_DIM: usize = 9 * 9;
     type Move = gomokugen::board::Move<9>;
+    fn parse_fen(fen: &str) -> Result<Self, ParseError> {
+        notation::check_board_fen(fen, 9, Squares::Dotted, &["move count"])?;
+        fen.parse().map_err(|_| ParseError::fen("position", "rejected by the gomoku parser"))
+    }
+    fn parse_move(text: &str) -> Result<Self::Move, ParseError> {
+        notation::check_square(text, 9).map_err(|reason| ParseError::mv(text, reason))?;
+        text.parse().map_err(|_| ParseError::mv(text, "rejected by the gomoku parser"))
+    }
+    fn policy_dim() -> usize {
+        9 * 9
+    }
+    fn name() -> String {
+        "gomoku9".into()
+    }
     fn to_move(&self) -> Player {
         match self.turn() {
             gomokugen::board::Player::None => Player::None,
@@ -134,15 +419,30 @@ impl GameImpl for gomokugen::board::Board<9> {
     fn tensor_dims(batch_size: usize) -> kn_graph::ndarray::IxDyn {
         kn_graph::ndarray::IxDyn(&[batch_size, 2 * 9 * 9])
     }
+    fn ownership_dim() -> usize {
+        9 * 9
+    }
+    fn fill_ownership(&self, mut f: impl FnMut(usize, Player)) {
+        self.feature_map(|i, c| {
+            f(
+                i,
+                match c {
+                    gomokugen::board::Player::None => Player::None,
+                    gomokugen::board::Player::X => Player::First,
+                    gomokugen::board::Player::O => Player::Second,
+                },
+            );
+        });
+    }
     fn make_random_move(&mut self, rng: impl FnMut(usize, usize) -> usize) {
         self.make_random_move(rng);
     }
-    fn player_substitute(limits_text: &str) -> String {
-        limits_text
-            .replace("btime", "p1time")
-            .replace("wtime", "p2time")
-            .replace("binc", "p1inc")
-            .replace("winc", "p2inc")
+    fn transposition_key(&self) -> Option<u64> {
+        // stones are never removed, so positions cannot repeat, but move orders transpose freely.
+        Some(zobrist_hash(self))
+    }
+    fn transposition_key_after(&self, key: u64, mv: Self::Move, after: &Self) -> Option<u64> {
+        Some(placement_key_after(self, key, mv, after))
     }
 }
 
@@ -153,8 +453,21 @@ impl MovePolicyIndex for gomokugen::board::Move<15> {
 }
 
 impl GameImpl for gomokugen::board::Board<15> {
-    const POLICY_DIM: usize = 15 * 15;
     type Move = gomokugen::board::Move<15>;
+    fn parse_fen(fen: &str) -> Result<Self, ParseError> {
+        notation::check_board_fen(fen, 15, Squares::Dotted, &["move count"])?;
+        fen.parse().map_err(|_| ParseError::fen("position", "rejected by the gomoku parser"))
+    }
+    fn parse_move(text: &str) -> Result<Self::Move, ParseError> {
+        notation::check_square(text, 15).map_err(|reason| ParseError::mv(text, reason))?;
+        text.parse().map_err(|_| ParseError::mv(text, "rejected by the gomoku parser"))
+    }
+    fn policy_dim() -> usize {
+        15 * 15
+    }
+    fn name() -> String {
+        "gomoku15".into()
+    }
     fn to_move(&self) -> Player {
         match self.turn() {
             gomokugen::board::Player::None => Player::None,
@@ -189,15 +502,30 @@ impl GameImpl for gomokugen::board::Board<15> {
     fn tensor_dims(batch_size: usize) -> kn_graph::ndarray::IxDyn {
         kn_graph::ndarray::IxDyn(&[batch_size, 2 * 15 * 15])
     }
+    fn ownership_dim() -> usize {
+        15 * 15
+    }
+    fn fill_ownership(&self, mut f: impl FnMut(usize, Player)) {
+        self.feature_map(|i, c| {
+            f(
+                i,
+                match c {
+                    gomokugen::board::Player::None => Player::None,
+                    gomokugen::board::Player::X => Player::First,
+                    gomokugen::board::Player::O => Player::Second,
+                },
+            );
+        });
+    }
     fn make_random_move(&mut self, rng: impl FnMut(usize, usize) -> usize) {
         self.make_random_move(rng);
     }
-    fn player_substitute(limits_text: &str) -> String {
-        limits_text
-            .replace("btime", "p1time")
-            .replace("wtime", "p2time")
-            .replace("binc", "p1inc")
-            .replace("winc", "p2inc")
+    fn transposition_key(&self) -> Option<u64> {
+        // stones are never removed, so positions cannot repeat, but move orders transpose freely.
+        Some(zobrist_hash(self))
+    }
+    fn transposition_key_after(&self, key: u64, mv: Self::Move, after: &Self) -> Option<u64> {
+        Some(placement_key_after(self, key, mv, after))
     }
 }
 
@@ -208,10 +536,31 @@ impl MovePolicyIndex for ataxxgen::Move {
 }
 
 impl GameImpl for ataxxgen::Board {
-    const POLICY_DIM: usize = 7 * 7 * 7 * 7;
-
     type Move = ataxxgen::Move;
 
+    fn parse_fen(fen: &str) -> Result<Self, ParseError> {
+        notation::check_board_fen(fen, 7, Squares::RunLength, &["halfmove clock", "fullmove number"])?;
+        fen.parse().map_err(|_| ParseError::fen("position", "rejected by the ataxx parser"))
+    }
+
+    fn parse_move(text: &str) -> Result<Self::Move, ParseError> {
+        let squares = match text.len() {
+            _ if text == "0000" => Ok(()),
+            2 => notation::check_square(text, 7),
+            4 => notation::check_square(&text[..2], 7).and_then(|()| notation::check_square(&text[2..], 7)),
+            _ => Err("expected a square such as b2, two squares such as a1c3 for a jump, or 0000 to pass".into()),
+        };
+        squares.map_err(|reason| ParseError::mv(text, reason))?;
+        text.parse().map_err(|_| ParseError::mv(text, "rejected by the ataxx parser"))
+    }
+
+    fn policy_dim() -> usize {
+        7 * 7 * 7 * 7
+    }
+    fn name() -> String {
+        "ataxx".into()
+    }
+
     fn to_move(&self) -> Player {
         match self.turn() {
             ataxxgen::Player::White => Player::First,
@@ -248,15 +597,310 @@ impl GameImpl for ataxxgen::Board {
         kn_graph::ndarray::IxDyn(&[batch_size, 3 * 7 * 7])
     }
 
+    fn ownership_dim() -> usize {
+        7 * 7
+    }
+
+    fn fill_ownership(&self, mut f: impl FnMut(usize, Player)) {
+        // the first two planes of the feature map hold the side to move's stones and the opponent's.
+        let us = GameImpl::to_move(self);
+        self.feature_map(|index| match index / (7 * 7) {
+            0 => f(index, us),
+            1 => f(index - 7 * 7, us.opposite()),
+            _ => {}
+        });
+    }
+
+    fn repetition_key(&self) -> Option<u64> {
+        // pieces can jump back and forth, so shuffling can repeat positions.
+        Some(feature_hash(self))
+    }
+
     fn make_random_move(&mut self, rng: impl FnMut(usize, usize) -> usize) {
         self.make_random_move(rng);
     }
+}
+
+impl MovePolicyIndex for crate::mnk::Move {
+    fn policy_index(&self) -> usize {
+        self.index()
+    }
+}
+
+impl GameImpl for crate::mnk::Board {
+    type Move = crate::mnk::Move;
+    // the parsers of the games in this crate already explain their errors.
+    fn parse_fen(fen: &str) -> Result<Self, ParseError> {
+        fen.parse().map_err(|e: anyhow::Error| ParseError::fen("position", e.to_string()))
+    }
+    fn parse_move(text: &str) -> Result<Self::Move, ParseError> {
+        text.parse().map_err(|e: anyhow::Error| ParseError::mv(text, e.to_string()))
+    }
+    fn policy_dim() -> usize {
+        crate::mnk::CELLS
+    }
+    fn name() -> String {
+        "mnk:<dimensions>".into()
+    }
+    fn to_move(&self) -> Player {
+        self.turn()
+    }
+    fn outcome(&self) -> Option<Player> {
+        self.outcome()
+    }
+    fn make_move(&mut self, mv: Self::Move) {
+        self.make_move(mv);
+    }
+    fn generate_moves(&self, f: impl FnMut(Self::Move) -> bool) {
+        self.generate_moves(f);
+    }
+    fn fen(&self) -> String {
+        self.fen()
+    }
+    fn fill_feature_map(&self, mut index_callback: impl FnMut(usize)) {
+        use crate::mnk::{CELLS, MAX_SIDE};
+        let to_move = self.turn();
+        self.feature_map(|i, c| {
+            let index = i + usize::from(c != to_move) * CELLS;
+            index_callback(index);
+        });
+        // a plane marking the cells off the board, so that boards of every size can share a network.
+        let crate::mnk::Dimensions { width, height, .. } = self.dimensions();
+        for index in 0..CELLS {
+            if index / MAX_SIDE >= height || index % MAX_SIDE >= width {
+                index_callback(2 * CELLS + index);
+            }
+        }
+    }
+    fn tensor_dims(batch_size: usize) -> kn_graph::ndarray::IxDyn {
+        kn_graph::ndarray::IxDyn(&[batch_size, 3 * crate::mnk::CELLS])
+    }
+    fn ownership_dim() -> usize {
+        crate::mnk::CELLS
+    }
+    fn files_and_ranks(&self) -> (usize, usize) {
+        let crate::mnk::Dimensions { width, height, .. } = self.dimensions();
+        (width, height)
+    }
+    fn fill_ownership(&self, f: impl FnMut(usize, Player)) {
+        self.feature_map(f);
+    }
+    fn transposition_key(&self) -> Option<u64> {
+        // stones are never removed, so positions cannot repeat, but move orders transpose freely.
+        Some(zobrist_hash(self))
+    }
+    fn transposition_key_after(&self, key: u64, mv: Self::Move, after: &Self) -> Option<u64> {
+        Some(placement_key_after(self, key, mv, after))
+    }
+}
+
+impl MovePolicyIndex for crate::ataxx::Move {
+    fn policy_index(&self) -> usize {
+        self.index()
+    }
+}
+
+impl GameImpl for crate::ataxx::Board {
+    type Move = crate::ataxx::Move;
+
+    fn parse_fen(fen: &str) -> Result<Self, ParseError> {
+        fen.parse().map_err(|e: anyhow::Error| ParseError::fen("position", e.to_string()))
+    }
+
+    fn parse_move(text: &str) -> Result<Self::Move, ParseError> {
+        text.parse().map_err(|e: anyhow::Error| ParseError::mv(text, e.to_string()))
+    }
+
+    fn policy_dim() -> usize {
+        crate::ataxx::SQUARES * crate::ataxx::SQUARES
+    }
+    fn name() -> String {
+        "ataxx:<size>".into()
+    }
+
+    fn to_move(&self) -> Player {
+        self.turn()
+    }
+
+    fn outcome(&self) -> Option<Player> {
+        self.outcome()
+    }
+
+    fn make_move(&mut self, mv: Self::Move) {
+        self.make_move(mv);
+    }
+
+    fn generate_moves(&self, f: impl FnMut(Self::Move) -> bool) {
+        self.generate_moves(f);
+    }
+
+    fn fen(&self) -> String {
+        self.fen()
+    }
+
+    fn fill_feature_map(&self, index_callback: impl FnMut(usize)) {
+        self.feature_map(index_callback);
+    }
+
+    fn tensor_dims(batch_size: usize) -> kn_graph::ndarray::IxDyn {
+        kn_graph::ndarray::IxDyn(&[batch_size, 3 * crate::ataxx::SQUARES])
+    }
+
+    fn ownership_dim() -> usize {
+        crate::ataxx::SQUARES
+    }
+
+    fn files_and_ranks(&self) -> (usize, usize) {
+        (self.size(), self.size())
+    }
+
+    fn fill_ownership(&self, f: impl FnMut(usize, Player)) {
+        self.ownership(f);
+    }
+
+    fn repetition_key(&self) -> Option<u64> {
+        Some(feature_hash(self))
+    }
+}
+
+impl MovePolicyIndex for crate::uttt::Move {
+    fn policy_index(&self) -> usize {
+        self.index()
+    }
+}
+
+impl GameImpl for crate::uttt::Board {
+    type Move = crate::uttt::Move;
+
+    fn parse_fen(fen: &str) -> Result<Self, ParseError> {
+        fen.parse().map_err(|e: anyhow::Error| ParseError::fen("position", e.to_string()))
+    }
+
+    fn parse_move(text: &str) -> Result<Self::Move, ParseError> {
+        text.parse().map_err(|e: anyhow::Error| ParseError::mv(text, e.to_string()))
+    }
+
+    fn policy_dim() -> usize {
+        crate::uttt::CELLS
+    }
+    fn name() -> String {
+        "uttt".into()
+    }
+
+    fn to_move(&self) -> Player {
+        self.turn()
+    }
+
+    fn outcome(&self) -> Option<Player> {
+        self.outcome()
+    }
+
+    fn make_move(&mut self, mv: Self::Move) {
+        self.make_move(mv);
+    }
+
+    fn generate_moves(&self, f: impl FnMut(Self::Move) -> bool) {
+        self.generate_moves(f);
+    }
+
+    fn fen(&self) -> String {
+        self.fen()
+    }
+
+    fn fill_feature_map(&self, index_callback: impl FnMut(usize)) {
+        self.feature_map(index_callback);
+    }
+
+    fn tensor_dims(batch_size: usize) -> kn_graph::ndarray::IxDyn {
+        kn_graph::ndarray::IxDyn(&[batch_size, 4 * crate::uttt::CELLS])
+    }
+
+    fn fill_feature_batch(boards: &[Self], features: usize, input: &mut [f32]) {
+        for (board, row) in boards.iter().zip(input.chunks_exact_mut(features)) {
+            board.write_features(row);
+        }
+    }
+
+    fn ownership_dim() -> usize {
+        crate::uttt::CELLS
+    }
+
+    fn fill_ownership(&self, mut f: impl FnMut(usize, Player)) {
+        for index in 0..crate::uttt::CELLS {
+            let stone = self.stone_at(index);
+            if stone != Player::None {
+                f(index, stone);
+            }
+        }
+    }
+}
+
+impl MovePolicyIndex for crate::connect6::Move {
+    fn policy_index(&self) -> usize {
+        self.index()
+    }
+}
+
+impl GameImpl for crate::connect6::Board {
+    type Move = crate::connect6::Move;
+
+    fn parse_fen(fen: &str) -> Result<Self, ParseError> {
+        fen.parse().map_err(|e: anyhow::Error| ParseError::fen("position", e.to_string()))
+    }
+
+    fn parse_move(text: &str) -> Result<Self::Move, ParseError> {
+        text.parse().map_err(|e: anyhow::Error| ParseError::mv(text, e.to_string()))
+    }
+
+    fn policy_dim() -> usize {
+        crate::connect6::CELLS
+    }
+    fn name() -> String {
+        "connect6".into()
+    }
+
+    fn to_move(&self) -> Player {
+        self.turn()
+    }
+
+    fn outcome(&self) -> Option<Player> {
+        self.outcome()
+    }
+
+    fn make_move(&mut self, mv: Self::Move) {
+        self.make_move(mv);
+    }
+
+    fn generate_moves(&self, f: impl FnMut(Self::Move) -> bool) {
+        self.generate_moves(f);
+    }
+
+    fn fen(&self) -> String {
+        self.fen()
+    }
+
+    fn fill_feature_map(&self, mut index_callback: impl FnMut(usize)) {
+        use crate::connect6::CELLS;
+        let to_move = self.turn();
+        self.feature_map(|i, c| {
+            let index = i + usize::from(c != to_move) * CELLS;
+            index_callback(index);
+        });
+        // a constant plane marking that this is the second stone of the turn.
+        if self.is_second_stone() {
+            (2 * CELLS..3 * CELLS).for_each(index_callback);
+        }
+    }
+
+    fn tensor_dims(batch_size: usize) -> kn_graph::ndarray::IxDyn {
+        kn_graph::ndarray::IxDyn(&[batch_size, 3 * crate::connect6::CELLS])
+    }
+
+    fn ownership_dim() -> usize {
+        crate::connect6::CELLS
+    }
 
-    fn player_substitute(limits_text: &str) -> String {
-        limits_text
-            .replace("btime", "p1time")
-            .replace("wtime", "p2time")
-            .replace("binc", "p1inc")
-            .replace("winc", "p2inc")
+    fn fill_ownership(&self, f: impl FnMut(usize, Player)) {
+        self.feature_map(f);
     }
 }
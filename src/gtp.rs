@@ -0,0 +1,273 @@
+//! A front-end speaking the Go Text Protocol, so that the gomoku engines can be driven by the GUIs and
+//! referees that already speak it for Go.
+//!
+//! Black is the first player. Vertices are named as in Go, by a column letter that skips `I` and a row
+//! number, so they are translated to and from the engine's notation, which uses every letter. Gomoku
+//! has no passes, so `pass` is never played or accepted.
+
+use crate::{
+    batching::{self, Backend, Priority},
+    engine::{Engine, SearchResults},
+    game::{GameImpl, Player},
+    notation,
+    params::Params,
+    timemgmt::Limits,
+    NAME, VERSION,
+};
+
+/// The board sizes that have a game to play them.
+pub const SIZES: &[usize] = &[9, 15];
+
+/// The column letters of vertices, which skip `i` so that it cannot be confused with `j`.
+const VERTEX_COLUMNS: &str = "abcdefghjklmnopqrstuvwxyz";
+
+/// The time spent on each generated move.
+const GENMOVE_MILLIS: u64 = 1000;
+
+const COMMANDS: &[&str] = &[
+    "protocol_version",
+    "name",
+    "version",
+    "known_command",
+    "list_commands",
+    "quit",
+    "boardsize",
+    "clear_board",
+    "komi",
+    "time_settings",
+    "play",
+    "genmove",
+    "undo",
+    "showboard",
+    "final_score",
+];
+
+/// Why the protocol loop returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// The controller quit, or closed stdin.
+    Quit,
+    /// The controller asked for a board of another size, which is played by another game.
+    Resize(usize),
+}
+
+/// A game in progress, and the engine that plays it, which keeps the moves played so that they can be
+/// taken back.
+struct Session<'a, G: GameImpl> {
+    engine: Engine<'a, G>,
+}
+
+impl<G: GameImpl> Session<'_, G> {
+    /// The board the game has reached.
+    const fn board(&self) -> G {
+        self.engine.root()
+    }
+
+    /// Carries out a command other than `quit` and `boardsize`, answering with its result or with an error.
+    fn execute(&mut self, command: &str, args: &[&str]) -> Result<String, String> {
+        match command {
+            "protocol_version" => Ok("2".into()),
+            "name" => Ok(NAME.into()),
+            "version" => Ok(VERSION.into()),
+            "known_command" => {
+                let known = args.first().is_some_and(|name| COMMANDS.contains(name));
+                Ok(known.to_string())
+            }
+            "list_commands" => Ok(COMMANDS.join("\n")),
+            "clear_board" => {
+                self.engine.set_position(&G::default());
+                Ok(String::new())
+            }
+            // neither komi nor a clock means anything to the search, so they are accepted and ignored.
+            "komi" | "time_settings" => Ok(String::new()),
+            "play" => {
+                let [colour, vertex] = args else {
+                    return Err("syntax error, expected play <colour> <vertex>".into());
+                };
+                let board = self.board();
+                if parse_colour(colour)? != board.to_move() {
+                    return Err("illegal move, it is not that colour's turn".into());
+                }
+                if board.outcome().is_some() {
+                    return Err("illegal move, the game is already over".into());
+                }
+                let text = parse_vertex(vertex, G::board_width())?;
+                let mv = notation::parse_legal_move(&board, &text, None).map_err(|e| format!("illegal move, {e}"))?;
+                self.engine.make_move(mv);
+                Ok(String::new())
+            }
+            "genmove" => {
+                let [colour] = args else {
+                    return Err("syntax error, expected genmove <colour>".into());
+                };
+                let board = self.board();
+                if parse_colour(colour)? != board.to_move() {
+                    return Err("it is not that colour's turn".into());
+                }
+                if board.outcome().is_some() {
+                    return Err("the game is already over".into());
+                }
+                let SearchResults { best_move, .. } = self.engine.go().map_err(|e| format!("search failed: {e}"))?;
+                self.engine.make_move(best_move);
+                Ok(format_vertex(&best_move.to_string()))
+            }
+            "undo" => {
+                if self.engine.undo().is_none() {
+                    return Err("cannot undo".into());
+                }
+                Ok(String::new())
+            }
+            "showboard" => Ok(format!("\n{}", self.board())),
+            "final_score" => match self.board().outcome() {
+                Some(Player::First) => Ok("B+".into()),
+                Some(Player::Second) => Ok("W+".into()),
+                Some(Player::None) => Ok("0".into()),
+                None => Err("cannot score, the game is not over".into()),
+            },
+            _ => Err("unknown command".into()),
+        }
+    }
+}
+
+/// Runs the protocol loop for a board of the size of `G` until the controller quits or
+/// asks for a board of another size.
+pub fn main_loop<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<Exit> {
+    let network = batching::load_network_or_uniform::<G>(net_path.unwrap_or("./model.onnx"), Backend::default())?;
+    let eval_pipe = batching::executor(&network, 1)?.into_iter().next().unwrap().with_priority(Priority::Interactive);
+    let engine = Engine::new(Params::default(), Limits::movetime(GENMOVE_MILLIS), &G::default(), eval_pipe);
+    let mut session = Session { engine };
+
+    for line in std::io::stdin().lines() {
+        let line = line?;
+        let Some((id, command, args)) = parse_command(&line) else {
+            continue;
+        };
+        let id = id.map_or_else(String::new, |id| id.to_string());
+        let result = match command {
+            "quit" => {
+                println!("={id}\n");
+                return Ok(Exit::Quit);
+            }
+            "boardsize" => match args.first().map(|size| size.parse::<usize>()) {
+                Some(Ok(size)) if size == G::board_width() => session.execute("clear_board", &[]),
+                Some(Ok(size)) if SIZES.contains(&size) => {
+                    println!("={id}\n");
+                    return Ok(Exit::Resize(size));
+                }
+                Some(Ok(_)) => Err("unacceptable size".into()),
+                _ => Err("syntax error, expected boardsize <size>".into()),
+            },
+            _ => session.execute(command, &args),
+        };
+        match result {
+            Ok(response) if response.is_empty() => println!("={id}\n"),
+            Ok(response) => println!("={id} {response}\n"),
+            Err(error) => println!("?{id} {error}\n"),
+        }
+    }
+
+    Ok(Exit::Quit)
+}
+
+/// Splits a line into its optional numeric id, its command, and the command's arguments,
+/// or returns `None` if there is no command on the line.
+fn parse_command(line: &str) -> Option<(Option<u64>, &str, Vec<&str>)> {
+    // everything after a hash is a comment.
+    let line = line.split('#').next().unwrap_or_default();
+    let mut words = line.split_whitespace();
+    let first = words.next()?;
+    let (id, command) = match first.parse() {
+        Ok(id) => (Some(id), words.next()?),
+        Err(_) => (None, first),
+    };
+    Some((id, command, words.collect()))
+}
+
+/// Parses a colour, as either its name or its initial.
+fn parse_colour(text: &str) -> Result<Player, String> {
+    match text.to_ascii_lowercase().as_str() {
+        "b" | "black" => Ok(Player::First),
+        "w" | "white" => Ok(Player::Second),
+        _ => Err(format!("syntax error, invalid colour {text:?}")),
+    }
+}
+
+/// Translates a vertex on a `size`x`size` board into the engine's notation for the same square.
+fn parse_vertex(text: &str, size: usize) -> Result<String, String> {
+    let text = text.to_ascii_lowercase();
+    if text == "pass" {
+        return Err("illegal move, there are no passes in gomoku".into());
+    }
+    let mut chars = text.chars();
+    let column = chars
+        .next()
+        .and_then(|column| VERTEX_COLUMNS.find(column))
+        .and_then(|index| ('a'..='z').nth(index))
+        .ok_or_else(|| format!("syntax error, invalid vertex {text:?}"))?;
+    let square = format!("{column}{}", chars.as_str());
+    notation::check_square(&square, size)
+        .map_err(|reason| format!("syntax error, invalid vertex {text:?}: {reason}"))?;
+    Ok(square)
+}
+
+/// Translates a square in the engine's notation into a vertex.
+fn format_vertex(square: &str) -> String {
+    let mut chars = square.chars();
+    let column = chars
+        .next()
+        .and_then(|column| ('a'..='z').position(|letter| letter == column))
+        .and_then(|index| VERTEX_COLUMNS.chars().nth(index))
+        .unwrap_or('?');
+    format!("{}{}", column.to_ascii_uppercase(), chars.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{batching::Network, native::NativeNet};
+
+    type Gomoku = gomokugen::board::Board<9>;
+
+    fn session<'a>() -> Session<'a, Gomoku> {
+        let network = Network::Native(NativeNet::seeded::<Gomoku>(7, 16));
+        let eval_pipe = batching::executor(&network, 1).unwrap().into_iter().next().unwrap();
+        let engine = Engine::new(Params::default(), Limits::nodes(64), &Gomoku::default(), eval_pipe);
+        Session { engine }
+    }
+
+    #[test]
+    fn commands_have_optional_ids_and_comments() {
+        assert_eq!(parse_command("12 play b E5"), Some((Some(12), "play", vec!["b", "E5"])));
+        assert_eq!(parse_command("genmove w # our turn"), Some((None, "genmove", vec!["w"])));
+        assert_eq!(parse_command("# just a comment"), None);
+        assert_eq!(parse_command("3"), None);
+    }
+
+    #[test]
+    fn vertices_skip_the_letter_i() {
+        assert_eq!(parse_vertex("H8", 15).unwrap(), "h8");
+        assert_eq!(parse_vertex("J8", 15).unwrap(), "i8");
+        assert_eq!(parse_vertex("p15", 15).unwrap(), "o15");
+        assert!(parse_vertex("I8", 15).is_err());
+        assert!(parse_vertex("K1", 9).is_err());
+        assert!(parse_vertex("pass", 9).is_err());
+        for square in ["a1", "h9", "i9", "o15"] {
+            assert_eq!(parse_vertex(&format_vertex(square), 15).unwrap(), square);
+        }
+    }
+
+    #[test]
+    fn moves_are_played_in_turn_and_taken_back() {
+        let mut session = session();
+        assert!(session.execute("play", &["w", "E5"]).is_err());
+        assert_eq!(session.execute("play", &["b", "E5"]), Ok(String::new()));
+        assert!(session.execute("play", &["w", "E5"]).is_err());
+        let reply = session.execute("genmove", &["w"]).unwrap();
+        assert!(parse_vertex(&reply, 9).is_ok(), "genmove replied with {reply:?}");
+        assert_eq!(session.engine.moves().len(), 2);
+        assert_eq!(session.execute("undo", &[]), Ok(String::new()));
+        assert_eq!(session.execute("undo", &[]), Ok(String::new()));
+        assert!(session.execute("undo", &[]).is_err());
+        assert_eq!(session.engine.root().fen(), Gomoku::default().fen());
+    }
+}
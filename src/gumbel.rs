@@ -0,0 +1,149 @@
+//! Gumbel root selection, after Danihelka et al., "Policy improvement by planning with Gumbel" (2022).
+//!
+//! Rather than spreading its visits over the root moves by PUCT, the search samples a few of them without
+//! replacement by the Gumbel-Top-k trick, and shares its budget between them by sequential halving: each
+//! phase gives every remaining move the same number of visits, and then drops the worse half of them, judged
+//! by their perturbed logits and searched values. The move left at the end is played. The network's policy,
+//! improved by the searched values, makes a better training target than the visit counts of a small search.
+
+/// The visits added to the most visits of any root move when values are scaled to logits.
+const C_VISIT: f64 = 50.0;
+/// The scale of values against logits.
+const C_SCALE: f64 = 1.0;
+
+/// What the search knows about a root move.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MoveStats {
+    /// The policy's probability of the move.
+    pub prior: f64,
+    /// The visits to the move, counting those waiting on an evaluation.
+    pub visits: u32,
+    /// The value of the move for the side to move at the root, if it has been visited.
+    pub value: Option<f64>,
+}
+
+/// The state of sequential halving over the sampled root moves during one search.
+#[derive(Debug)]
+pub struct SequentialHalving {
+    /// The moves still in contention, as indices into the root's moves, with their Gumbel-perturbed logits,
+    /// best first.
+    candidates: Vec<(usize, f64)>,
+    /// The visits of each root move before the search, which do not count towards its share.
+    initial_visits: Vec<u32>,
+    /// The visits in this search that each candidate has by the end of the current phase.
+    phase_target: u32,
+    /// The visits to share between the candidates.
+    budget: u64,
+    /// The number of phases in which the candidates are halved down to one.
+    num_phases: u64,
+}
+
+impl SequentialHalving {
+    /// Samples `num_moves` of the root moves, to share `budget` visits between.
+    pub fn new(moves: &[MoveStats], num_moves: usize, budget: u64) -> Self {
+        let mut candidates = moves.iter().enumerate().map(|(i, m)| (i, m.prior.ln() + gumbel())).collect::<Vec<_>>();
+        candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        candidates.truncate(num_moves.max(1));
+        // ceil(log2(m)) phases take m candidates down to one.
+        let num_phases = u64::from(candidates.len().next_power_of_two().trailing_zeros().max(1));
+        let initial_visits = moves.iter().map(|m| m.visits).collect();
+        let phase_target = share(budget, num_phases, candidates.len());
+        Self { candidates, initial_visits, phase_target, budget, num_phases }
+    }
+
+    /// The root move to visit next: the remaining candidate with the fewest visits in this search,
+    /// once the candidates that fall short of this phase's share have been halved away.
+    pub fn next_move(&mut self, moves: &[MoveStats]) -> usize {
+        loop {
+            let (index, visits) = self
+                .candidates
+                .iter()
+                .map(|&(index, _)| (index, moves[index].visits.saturating_sub(self.initial_visits[index])))
+                .min_by_key(|&(_, visits)| visits)
+                .expect("sequential halving has no candidates");
+            if visits < self.phase_target || self.candidates.len() == 1 {
+                return index;
+            }
+            // every candidate has had its share, so keep the better half for the next phase.
+            self.rank(moves);
+            self.candidates.truncate(self.candidates.len().div_ceil(2));
+            self.phase_target += share(self.budget, self.num_phases, self.candidates.len());
+        }
+    }
+
+    /// The root move to play: the best of the remaining candidates.
+    pub fn best_move(&self, moves: &[MoveStats]) -> usize {
+        let max_visits = max_visits(moves);
+        self.candidates
+            .iter()
+            .map(|&(index, perturbed)| (index, perturbed + sigma(moves[index].value.unwrap_or(0.0), max_visits)))
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(index, _)| index)
+    }
+
+    /// Sorts the candidates by their perturbed logits and searched values, best first.
+    fn rank(&mut self, moves: &[MoveStats]) {
+        let max_visits = max_visits(moves);
+        let score =
+            |&(index, perturbed): &(usize, f64)| perturbed + sigma(moves[index].value.unwrap_or(0.0), max_visits);
+        self.candidates.sort_by(|a, b| score(b).total_cmp(&score(a)));
+    }
+}
+
+/// The policy improved by the search: the softmax of the logits raised by the scaled values of the moves,
+/// where an unvisited move takes `root_value`, the value of the root for the side to move.
+pub fn improved_policy(moves: &[MoveStats], root_value: f64) -> Vec<f64> {
+    let max_visits = max_visits(moves);
+    let logits =
+        moves.iter().map(|m| m.prior.ln() + sigma(m.value.unwrap_or(root_value), max_visits)).collect::<Vec<_>>();
+    let max_logit = logits.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let exps = logits.iter().map(|logit| (logit - max_logit).exp()).collect::<Vec<_>>();
+    let total = exps.iter().sum::<f64>();
+    exps.into_iter().map(|e| e / total).collect()
+}
+
+/// The visits each of `candidates` moves gets in one of `num_phases` phases of a `budget`, at least one.
+fn share(budget: u64, num_phases: u64, candidates: usize) -> u32 {
+    let candidates = u64::try_from(candidates).unwrap_or(u64::MAX);
+    u32::try_from(budget / (num_phases * candidates)).unwrap_or(u32::MAX).max(1)
+}
+
+/// The most visits of any root move.
+fn max_visits(moves: &[MoveStats]) -> u32 {
+    moves.iter().map(|m| m.visits).max().unwrap_or(0)
+}
+
+/// Scales a value in [0, 1] to be comparable with logits, trusting it more as the search grows.
+fn sigma(value: f64, max_visits: u32) -> f64 {
+    (C_VISIT + f64::from(max_visits)) * C_SCALE * value
+}
+
+/// Samples the standard Gumbel distribution.
+fn gumbel() -> f64 {
+    -(-(1.0 - fastrand::f64()).ln()).ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halving_shares_the_budget_and_keeps_the_best_move() {
+        // the third move is the best by far, so must survive every halving.
+        let values = [0.2, 0.3, 0.9, 0.1];
+        let mut moves = [0.25; 4].map(|prior| MoveStats { prior, visits: 0, value: None });
+        let mut halving = SequentialHalving::new(&moves, 4, 40);
+        for _ in 0..40 {
+            let index = halving.next_move(&moves);
+            moves[index].visits += 1;
+            moves[index].value = Some(values[index]);
+        }
+        // two phases of twenty visits, shared between four candidates and then the best two.
+        assert_eq!(halving.candidates.len(), 2);
+        assert!(moves.iter().filter(|m| m.visits == 5).count() >= 2);
+        assert_eq!(halving.best_move(&moves), 2);
+        let policy = improved_policy(&moves, 0.5);
+        assert!((policy.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(policy[2] > 0.99);
+    }
+}
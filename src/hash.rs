@@ -0,0 +1,69 @@
+//! Hashes that are stable across builds and platforms, unlike the standard library's hasher,
+//! for keys that are written to files or compared between processes.
+
+use std::{io::Read, path::Path};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+
+/// A 64-bit FNV-1a hash, built up one value at a time.
+#[derive(Clone, Copy, Debug)]
+pub struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fnv1a {
+    pub const fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Mixes `value` into the hash whole, as FNV-1a mixes in a byte.
+    #[must_use]
+    pub const fn add(self, value: u64) -> Self {
+        Self((self.0 ^ value).wrapping_mul(0x0100_0000_01b3))
+    }
+
+    pub const fn finish(self) -> u64 {
+        self.0
+    }
+}
+
+/// The 64-bit FNV-1a hash of `bytes`.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(Fnv1a::new(), |hash, &byte| hash.add(u64::from(byte))).finish()
+}
+
+/// The SHA-256 digest of a file's contents, in lowercase hexadecimal.
+pub fn sha256_file(path: &Path) -> anyhow::Result<String> {
+    let mut file = std::fs::File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 16];
+    loop {
+        let read = file.read(&mut buffer).with_context(|| format!("failed to read {}", path.display()))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_match_published_values() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        let path = std::env::temp_dir().join(format!("veritas-hash-test-{}", std::process::id()));
+        std::fs::write(&path, "abc").unwrap();
+        let digest = sha256_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(digest, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -3,19 +3,46 @@
 
 //! Veritas, a UGI-conformant MCTS-PUCT engine.
 
+use std::collections::VecDeque;
+
 use anyhow::Context;
 
+mod analysis;
 mod arena;
+mod ataxx;
 mod batching;
+mod bench;
+mod book;
+mod connect6;
 mod datagen;
 mod debug;
 mod engine;
+#[macro_use]
 mod game;
+mod gtp;
+mod gumbel;
+mod hash;
+mod matchplay;
+mod mnk;
+mod models;
+mod native;
 mod node;
+mod notation;
+#[cfg(feature = "onnxruntime")]
+mod onnxruntime;
 mod params;
+mod pgn;
 mod pleasant;
+mod pns;
+mod regress;
+mod server;
+mod snapshot;
+mod solver;
+mod telemetry;
 mod timemgmt;
 mod ugi;
+mod uttt;
+mod watchdog;
 
 /// The name of the engine.
 pub static NAME: &str = "Veritas";
@@ -30,70 +57,320 @@ fn main() -> anyhow::Result<()> {
 
     if std::env::args_os().len() == 1 {
         // fast path to UCI:
-        return ugi::main_loop::<ataxxgen::Board>(None);
+        return run_ugi("ataxx", None, batching::ExecutorOptions::default());
     }
 
     let args: Vec<_> = std::env::args_os().collect();
+    if args[1] == "model" {
+        return model_main(&args);
+    }
+    // networks given by URL are fetched into the local store, and their local paths used in their place.
+    let args = args
+        .into_iter()
+        .map(|arg| match arg.to_str() {
+            Some(text) if text.contains("http://") || text.contains("https://") => {
+                models::resolve_argument(text).map(Into::into)
+            }
+            _ => Ok(arg),
+        })
+        .collect::<anyhow::Result<Vec<std::ffi::OsString>>>()?;
 
     match args[1].to_str().unwrap() {
-        "datagen" => {
-            let game = args
-                .get(2)
-                .with_context(|| "did not find <GAME> argument!")?
-                .to_str()
-                .with_context(|| "invalid unicode!")?;
-            let num_threads = args
-                .get(3)
-                .with_context(|| "did not find <NUM_THREADS> argument!")?
-                .to_str()
-                .with_context(|| "invalid unicode!")?
-                .parse()
-                .with_context(|| "num_threads")?;
-            let time_allocated_millis = args
-                .get(4)
-                .with_context(|| "did not find <DATAGEN_MILLIS> argument!")?
-                .to_str()
-                .with_context(|| "invalid unicode!")?
-                .parse()
-                .with_context(|| "time_allocated_millis")?;
-            let model_path = args.get(5).map(|s| s.to_str().unwrap());
-            match game {
-                "ataxx" => {
-                    datagen::run_data_generation::<ataxxgen::Board>(num_threads, time_allocated_millis, model_path)
-                }
-                "gomoku9" => datagen::run_data_generation::<gomokugen::board::Board<9>>(
-                    num_threads,
-                    time_allocated_millis,
-                    model_path,
-                ),
-                "gomoku15" => datagen::run_data_generation::<gomokugen::board::Board<15>>(
-                    num_threads,
-                    time_allocated_millis,
-                    model_path,
-                ),
-                _ => panic!("unknown game"),
+        "datagen" => datagen_main(&args),
+        "analyse" => {
+            let game = arg(&args, 2, "GAME")?;
+            let fen_path = arg(&args, 3, "FEN_FILE")?;
+            let out_path = arg(&args, 4, "OUT_FILE")?;
+            let limits = arg(&args, 5, "LIMITS")?.parse().with_context(|| "limits")?;
+            let model_path = args.get(6).map(|s| s.to_str().unwrap());
+            run_analysis(game, fen_path, out_path, limits, model_path)
+        }
+        "book" => {
+            let game = arg(&args, 2, "GAME")?;
+            let out_path = arg(&args, 3, "OUT_FILE")?;
+            let max_ply = arg(&args, 4, "MAX_PLY")?.parse().with_context(|| "max_ply")?;
+            let pgn_paths = args[5..]
+                .iter()
+                .map(|s| s.to_str().with_context(|| "invalid unicode!"))
+                .collect::<Result<Vec<_>, _>>()?;
+            if pgn_paths.is_empty() {
+                anyhow::bail!("did not find any <PGN_FILE> arguments!");
             }
+            run_book_builder(game, &pgn_paths, out_path, max_ply)
+        }
+        "match" => {
+            let game = arg(&args, 2, "GAME")?;
+            let model_a = arg(&args, 3, "MODEL_A")?;
+            let model_b = arg(&args, 4, "MODEL_B")?;
+            let limits = arg(&args, 5, "LIMITS")?.parse().with_context(|| "limits")?;
+            let pairs = arg(&args, 6, "GAME_PAIRS")?.parse().with_context(|| "game_pairs")?;
+            let openings = args.get(7).map(|s| s.to_str().unwrap());
+            run_match(game, model_a, model_b, limits, pairs, openings)
         }
         "ugi" | "uai" | "uci" => {
             let game = args.get(2).map_or("ataxx", |s| s.to_str().unwrap());
+            let model_path = args.get(3).map(|s| s.to_str().unwrap()).filter(|s| !s.starts_with("--"));
+            run_ugi(game, model_path, executor_options(&args)?)
+        }
+        "gtp" => {
+            let size = args.get(2).map_or(Ok(15), |s| s.to_str().unwrap().parse()).with_context(|| "size")?;
             let model_path = args.get(3).map(|s| s.to_str().unwrap());
-            match game {
-                "ataxx" => ugi::main_loop::<ataxxgen::Board>(model_path),
-                "gomoku9" => ugi::main_loop::<gomokugen::board::Board<9>>(model_path),
-                "gomoku15" => ugi::main_loop::<gomokugen::board::Board<15>>(model_path),
-                _ => panic!("unknown game"),
-            }
+            run_gtp(size, model_path)
+        }
+        "serve" => {
+            let game = arg(&args, 2, "GAME")?;
+            let slots = arg(&args, 3, "SLOTS")?.parse().with_context(|| "slots")?;
+            let model_path = args.get(4).map(|s| s.to_str().unwrap()).filter(|s| !s.starts_with("--"));
+            run_server(game, slots, model_path, executor_options(&args)?)
+        }
+        "regress" => {
+            // "--bless" records the current results as the golden ones.
+            let bless = args.iter().skip(2).any(|s| s == "--bless");
+            regress::main(regress::GOLDEN_PATH, bless)
+        }
+        "bench" => {
+            let game = args.get(2).map_or("ataxx", |s| s.to_str().unwrap());
+            let nodes = args.get(3).map_or(Ok(bench::DEFAULT_NODES), |s| s.to_str().unwrap().parse());
+            let model_path = args.get(4).map(|s| s.to_str().unwrap());
+            run_bench(game, nodes.with_context(|| "nodes")?, model_path)
         }
         "play" => {
             let game = args.get(2).map_or("ataxx", |s| s.to_str().unwrap());
-            let model_path = args.get(3).map(|s| s.to_str().unwrap());
-            match game {
-                "ataxx" => pleasant::play_game_vs_user::<ataxxgen::Board>(model_path),
-                "gomoku9" => pleasant::play_game_vs_user::<gomokugen::board::Board<9>>(model_path),
-                "gomoku15" => pleasant::play_game_vs_user::<gomokugen::board::Board<15>>(model_path),
-                _ => panic!("unknown game"),
-            }
+            let model_path = args.get(3).map(|s| s.to_str().unwrap()).filter(|s| !s.starts_with("--"));
+            // "--ascii" draws the board without colour or Unicode, for terminals that lack them.
+            let ascii = args.iter().skip(3).any(|s| s == "--ascii");
+            run_play(game, model_path, ascii)
         }
         _ => panic!("unknown subcommand"),
     }
 }
+
+/// Runs the UGI loop for the game with the given name, and then for whichever game the `UGI_Variant` option
+/// switches to, until the GUI quits.
+fn run_ugi(game: &str, model_path: Option<&str>, executor_options: batching::ExecutorOptions) -> anyhow::Result<()> {
+    let stdin = ugi::stdin_reader();
+    let mut game = game.to_owned();
+    let mut model = model_path;
+    let mut pending = VecDeque::new();
+    loop {
+        let exit =
+            with_game!(game, |G, start| ugi::main_loop::<G>(&game, start, model, executor_options, &stdin, pending))?;
+        match exit {
+            ugi::Exit::Quit => return Ok(()),
+            ugi::Exit::Switch { game: next_game, pending: next_pending } => {
+                // the model was trained for the old game, so the new one starts with its default.
+                game = next_game;
+                model = None;
+                pending = next_pending;
+            }
+        }
+    }
+}
+
+/// Runs the GTP loop for gomoku on a board of the given size, and then on whichever size the
+/// controller asks for with `boardsize`, until it quits.
+fn run_gtp(mut size: usize, model_path: Option<&str>) -> anyhow::Result<()> {
+    loop {
+        let exit = match size {
+            9 => gtp::main_loop::<gomokugen::board::Board<9>>(model_path)?,
+            15 => gtp::main_loop::<gomokugen::board::Board<15>>(model_path)?,
+            _ => anyhow::bail!("gomoku is not played on a {size}x{size} board, expected one of {:?}", gtp::SIZES),
+        };
+        match exit {
+            gtp::Exit::Quit => return Ok(()),
+            gtp::Exit::Resize(new_size) => size = new_size,
+        }
+    }
+}
+
+/// Runs data generation for the game with the given name.
+fn run_data_generation(game: &str, config: &datagen::DatagenConfig) -> anyhow::Result<()> {
+    with_game!(game, |G, start| datagen::run_data_generation::<G>(start, config))
+}
+
+/// Runs data generation for several games at once, splitting the threads between them.
+fn run_concurrent_data_generation(
+    specs: &[(&str, Option<&str>)],
+    num_threads: usize,
+    time_allocated_millis: u128,
+    balance_results: bool,
+    game_node_budget: Option<u64>,
+    profiles: &[datagen::Profile],
+    executor_options: batching::ExecutorOptions,
+) -> anyhow::Result<()> {
+    let base_folder = datagen::default_save_folder();
+    let threads_per_game = (num_threads / specs.len()).max(1);
+    std::thread::scope(|scope| {
+        let mut runs = Vec::new();
+        for &(game, model_path) in specs {
+            let config = datagen::DatagenConfig {
+                num_threads: threads_per_game,
+                time_allocated_millis,
+                model_path,
+                balance_results,
+                game_node_budget,
+                save_folder: format!("{base_folder}/{}", game.replace(':', "-")),
+                label: Some(game.to_string()),
+                profiles,
+                executor_options,
+            };
+            runs.push(scope.spawn(move || run_data_generation(game, &config)));
+        }
+        runs.into_iter()
+            .try_for_each(|run| run.join().map_err(|_| anyhow::anyhow!("data generation thread panicked"))?)
+    })
+}
+
+/// Runs batch analysis of a file of positions for the game with the given name.
+fn run_analysis(
+    game: &str,
+    fen_path: &str,
+    out_path: &str,
+    limits: timemgmt::Limits,
+    model_path: Option<&str>,
+) -> anyhow::Result<()> {
+    with_game!(game, |G| analysis::analyse_file::<G>(fen_path, out_path, limits, model_path))
+}
+
+/// Runs the analysis server for the game with the given name.
+fn run_server(
+    game: &str,
+    slots: usize,
+    model_path: Option<&str>,
+    executor_options: batching::ExecutorOptions,
+) -> anyhow::Result<()> {
+    with_game!(game, |G, start| server::serve::<G>(start, slots, model_path, executor_options))
+}
+
+/// Benchmarks search speed over the bundled positions of the game with the given name.
+fn run_bench(game: &str, nodes: u64, model_path: Option<&str>) -> anyhow::Result<()> {
+    with_game!(game, |G, start| bench::main::<G>(game, start, nodes, model_path))
+}
+
+/// Plays a game against the user, for the game with the given name.
+fn run_play(game: &str, model_path: Option<&str>, ascii: bool) -> anyhow::Result<()> {
+    with_game!(game, |G, start| pleasant::play_game_vs_user::<G>(start, model_path, ascii))
+}
+
+/// Returns the positional argument at `index`, named `name` in errors.
+fn arg<'a>(args: &'a [std::ffi::OsString], index: usize, name: &str) -> anyhow::Result<&'a str> {
+    args.get(index)
+        .with_context(|| format!("did not find <{name}> argument!"))?
+        .to_str()
+        .with_context(|| "invalid unicode!")
+}
+
+/// Parses the `--backend`, `--device` and `--batch-size` flags, which say with what and where the executor
+/// evaluates networks, and how many positions it evaluates at once.
+fn executor_options(args: &[std::ffi::OsString]) -> anyhow::Result<batching::ExecutorOptions> {
+    let mut options = batching::ExecutorOptions::default();
+    if let Some(i) = args.iter().position(|s| s == "--backend") {
+        options.backend = arg(args, i + 1, "BACKEND")?.parse()?;
+    }
+    if let Some(i) = args.iter().position(|s| s == "--device") {
+        options.device = arg(args, i + 1, "DEVICE")?.parse()?;
+    }
+    if let Some(i) = args.iter().position(|s| s == "--batch-size") {
+        options.batch_size = arg(args, i + 1, "BATCH_SIZE")?.parse().with_context(|| "batch_size")?;
+        anyhow::ensure!(options.batch_size > 0, "the batch size must be at least 1");
+    }
+    Ok(options)
+}
+
+/// Parses the arguments of the `datagen` subcommand and runs data generation.
+fn datagen_main(args: &[std::ffi::OsString]) -> anyhow::Result<()> {
+    let game =
+        args.get(2).with_context(|| "did not find <GAME> argument!")?.to_str().with_context(|| "invalid unicode!")?;
+    let num_threads = args
+        .get(3)
+        .with_context(|| "did not find <NUM_THREADS> argument!")?
+        .to_str()
+        .with_context(|| "invalid unicode!")?
+        .parse()
+        .with_context(|| "num_threads")?;
+    let time_allocated_millis = args
+        .get(4)
+        .with_context(|| "did not find <DATAGEN_MILLIS> argument!")?
+        .to_str()
+        .with_context(|| "invalid unicode!")?
+        .parse()
+        .with_context(|| "time_allocated_millis")?;
+    let balance_results = args.iter().skip(5).any(|s| s == "--balance-results");
+    // "--game-nodes N" shares a budget of N nodes between all the moves of each game.
+    let game_node_budget = args
+        .iter()
+        .position(|s| s == "--game-nodes")
+        .map(|i| arg(args, i + 1, "GAME_NODES")?.parse().with_context(|| "game_nodes"))
+        .transpose()?;
+    // "--profiles FILE" gives the workers search settings from a population, one profile per line.
+    let profiles = args
+        .iter()
+        .position(|s| s == "--profiles")
+        .map(|i| datagen::load_profiles(arg(args, i + 1, "PROFILES_FILE")?))
+        .transpose()?
+        .unwrap_or_default();
+    let model_path = args.get(5).map(|s| s.to_str().unwrap()).filter(|s| !s.starts_with("--"));
+    let executor_options = executor_options(args)?;
+    // several games may be given as a comma-separated list, each optionally with its own model,
+    // as in "ataxx=ataxx.onnx,gomoku9=gomoku.onnx". they run concurrently, splitting the threads.
+    let specs = game
+        .split(',')
+        .map(|spec| spec.split_once('=').map_or((spec, model_path), |(game, model)| (game, Some(model))))
+        .collect::<Vec<_>>();
+    if specs.len() == 1 {
+        let (game, model_path) = specs[0];
+        let config = datagen::DatagenConfig {
+            num_threads,
+            time_allocated_millis,
+            model_path,
+            balance_results,
+            game_node_budget,
+            save_folder: datagen::default_save_folder(),
+            label: None,
+            profiles: &profiles,
+            executor_options,
+        };
+        run_data_generation(game, &config)
+    } else {
+        run_concurrent_data_generation(
+            &specs,
+            num_threads,
+            time_allocated_millis,
+            balance_results,
+            game_node_budget,
+            &profiles,
+            executor_options,
+        )
+    }
+}
+
+/// Parses the arguments of the `model` subcommand, which manages the store of downloaded networks.
+fn model_main(args: &[std::ffi::OsString]) -> anyhow::Result<()> {
+    match arg(args, 2, "ACTION")? {
+        "fetch" => {
+            let url = arg(args, 3, "URL")?;
+            anyhow::ensure!(models::is_url(url), "{url} is not an http(s) URL");
+            let local = models::resolve(url)?;
+            println!("{local} {}", hash::sha256_file(std::path::Path::new(&local))?);
+            Ok(())
+        }
+        action => anyhow::bail!("unknown model action {action:?}, expected \"fetch\""),
+    }
+}
+
+/// Builds an opening book from stored games of the game with the given name.
+fn run_book_builder(game: &str, pgn_paths: &[&str], out_path: &str, max_ply: usize) -> anyhow::Result<()> {
+    with_game!(game, |G| book::build_book::<G>(pgn_paths, out_path, max_ply))
+}
+
+/// Plays a match between two networks at the game with the given name.
+fn run_match(
+    game: &str,
+    model_a: &str,
+    model_b: &str,
+    limits: timemgmt::Limits,
+    pairs: usize,
+    openings: Option<&str>,
+) -> anyhow::Result<()> {
+    with_game!(game, |G, start| matchplay::run_match::<G>(start, model_a, model_b, limits, pairs, openings))
+}
@@ -0,0 +1,115 @@
+//! Engine-vs-engine matches between two networks.
+
+use crate::{
+    batching,
+    engine::{Engine, SearchResults},
+    game::{GameImpl, Player},
+    params::Params,
+    solver,
+    timemgmt::Limits,
+};
+
+/// Positions with at most this many legal moves are handed to the solver for adjudication.
+const SOLVER_MAX_BRANCHING: usize = 8;
+/// The node budget of each adjudication attempt, beyond which the game is played on.
+const SOLVER_NODE_BUDGET: u64 = 200_000;
+
+/// Results of a match, from the point of view of the first engine.
+#[derive(Debug, Default)]
+struct Tally {
+    wins: u32,
+    draws: u32,
+    losses: u32,
+    /// The number of games whose result was decided by the solver.
+    adjudicated: u32,
+}
+
+/// Plays `pairs` pairs of games between the networks at `model_a` and `model_b`, swapping sides within each pair.
+/// Openings are taken in turn from `openings`, one FEN per line, or are `startpos` alone if there are none.
+/// Once few enough moves remain, the exact solver is tried, and the game ends with its result if it succeeds.
+pub fn run_match<G: GameImpl>(
+    startpos: G,
+    model_a: &str,
+    model_b: &str,
+    limits: Limits,
+    pairs: usize,
+    openings: Option<&str>,
+) -> anyhow::Result<()> {
+    let openings = match openings {
+        Some(path) => std::fs::read_to_string(path)?
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| line.trim().parse::<G>().map_err(|_| anyhow::anyhow!("invalid opening fen {line:?}")))
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        None => vec![startpos],
+    };
+    anyhow::ensure!(!openings.is_empty(), "no openings to play from");
+
+    let executor_a = batching::executor(&batching::load_network::<G>(model_a)?, 1)?.into_iter().next().unwrap();
+    let executor_b = batching::executor(&batching::load_network::<G>(model_b)?, 1)?.into_iter().next().unwrap();
+    let mut engines = [
+        Engine::new(Params::default(), limits, &startpos, executor_a),
+        Engine::new(Params::default(), limits, &startpos, executor_b),
+    ];
+
+    let mut tally = Tally::default();
+    for game_index in 0..2 * pairs {
+        let opening = openings[game_index / 2 % openings.len()];
+        // engine A plays first in the first game of each pair.
+        let a_player = if game_index % 2 == 0 { Player::First } else { Player::Second };
+        let (outcome, adjudicated) = play_game(&mut engines, opening, limits, a_player)?;
+        tally.adjudicated += u32::from(adjudicated);
+        match outcome {
+            Player::None => tally.draws += 1,
+            winner if winner == a_player => tally.wins += 1,
+            _ => tally.losses += 1,
+        }
+        println!(
+            "Game {} of {}: {} (+{} ={} -{})",
+            game_index + 1,
+            2 * pairs,
+            if adjudicated { "adjudicated" } else { "played out" },
+            tally.wins,
+            tally.draws,
+            tally.losses
+        );
+    }
+
+    println!(
+        "Match complete: {model_a} vs {model_b}: +{} ={} -{}, {} games adjudicated by the solver",
+        tally.wins, tally.draws, tally.losses, tally.adjudicated
+    );
+
+    Ok(())
+}
+
+/// Plays one game, with `engines[0]` playing as `a_player`.
+/// Returns the result, and whether it was decided by the solver.
+fn play_game<G: GameImpl>(
+    engines: &mut [Engine<'_, G>; 2],
+    opening: G,
+    limits: Limits,
+    a_player: Player,
+) -> anyhow::Result<(Player, bool)> {
+    let mut board = opening;
+    loop {
+        if let Some(outcome) = board.outcome() {
+            return Ok((outcome, false));
+        }
+        let mut num_moves = 0;
+        board.generate_moves(|_| {
+            num_moves += 1;
+            num_moves > SOLVER_MAX_BRANCHING
+        });
+        if num_moves <= SOLVER_MAX_BRANCHING {
+            if let Some(outcome) = solver::solve(&board, SOLVER_NODE_BUDGET) {
+                return Ok((outcome, true));
+            }
+        }
+        let engine = &mut engines[usize::from(board.to_move() != a_player)];
+        engine.set_position(&board);
+        engine.set_limits(limits);
+        let SearchResults { best_move, .. } = engine.go()?;
+        board.make_move(best_move);
+    }
+}
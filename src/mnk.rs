@@ -0,0 +1,454 @@
+//! A generic m,n,k-game: players alternate placing stones on an m×n board,
+//! and the first to get k in a row (horizontally, vertically, or diagonally) wins.
+//!
+//! Boards of every size are laid out on the largest, `MAX_SIDE` cells to a row, so that the policy and
+//! the network's input have the same shape whatever the dimensions, which each position carries with it.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+use crate::game::Player;
+
+/// The largest supported board side length.
+pub const MAX_SIDE: usize = 19;
+/// The number of cells of the largest board, on which every board is laid out.
+pub const CELLS: usize = MAX_SIDE * MAX_SIDE;
+/// The number of 64-bit words needed to store a bit per cell on the largest board.
+const WORDS: usize = CELLS.div_ceil(64);
+
+/// The shape of an m,n,k-game.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dimensions {
+    pub width: usize,
+    pub height: usize,
+    pub k: usize,
+}
+
+impl Dimensions {
+    pub const fn cells(self) -> usize {
+        self.width * self.height
+    }
+}
+
+impl Default for Dimensions {
+    /// The dimensions of freestyle gomoku.
+    fn default() -> Self {
+        Self { width: 15, height: 15, k: 5 }
+    }
+}
+
+impl FromStr for Dimensions {
+    type Err = anyhow::Error;
+
+    /// Parses dimensions of the form `WIDTHxHEIGHTxK`, e.g. `15x15x5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('x');
+        let mut next = |what: &str| -> anyhow::Result<usize> {
+            parts
+                .next()
+                .with_context(|| format!("did not find {what} in m,n,k dimensions {s:?}"))?
+                .parse()
+                .with_context(|| format!("invalid {what} in m,n,k dimensions {s:?}"))
+        };
+        let width = next("width")?;
+        let height = next("height")?;
+        let k = next("k")?;
+        if parts.next().is_some() {
+            anyhow::bail!("too many components in m,n,k dimensions {s:?}");
+        }
+        if !(1..=MAX_SIDE).contains(&width) || !(1..=MAX_SIDE).contains(&height) {
+            anyhow::bail!("m,n,k board sides must be between 1 and {MAX_SIDE}, got {width}x{height}");
+        }
+        if k == 0 || k > width.max(height) {
+            anyhow::bail!("k = {k} is impossible to achieve on a {width}x{height} board");
+        }
+        Ok(Self { width, height, k })
+    }
+}
+
+/// The index of the cell in the given row and column.
+const fn cell(row: usize, col: usize) -> usize {
+    row * MAX_SIDE + col
+}
+
+/// A move in an m,n,k-game, i.e. the cell on which to place a stone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Move {
+    index: u16,
+}
+
+impl Move {
+    /// The index of the cell this move places a stone on.
+    pub const fn index(self) -> usize {
+        self.index as usize
+    }
+}
+
+impl Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = (b'a' + u8::try_from(self.index() % MAX_SIDE).expect("file out of range")) as char;
+        let rank = self.index() / MAX_SIDE + 1;
+        write!(f, "{file}{rank}")
+    }
+}
+
+impl FromStr for Move {
+    type Err = anyhow::Error;
+
+    /// Parses a move on a board of any size, so whether it is on a smaller board is left to its legality.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let file = chars.next().with_context(|| "empty move string")?;
+        if !file.is_ascii_lowercase() {
+            anyhow::bail!("invalid file {file:?} in move {s:?}");
+        }
+        let file = usize::from(file as u8 - b'a');
+        let rank: usize = chars.as_str().parse().with_context(|| format!("invalid rank in move {s:?}"))?;
+        if file >= MAX_SIDE || rank == 0 || rank > MAX_SIDE {
+            anyhow::bail!("move {s:?} is off the largest board, which is {MAX_SIDE}x{MAX_SIDE}");
+        }
+        Ok(Self { index: cell(rank - 1, file).try_into()? })
+    }
+}
+
+/// One bit per cell.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+struct Bitset([u64; WORDS]);
+
+impl Bitset {
+    const fn get(&self, index: usize) -> bool {
+        self.0[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.0[index / 64] |= 1 << (index % 64);
+    }
+}
+
+/// An m,n,k-game position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Board {
+    dimensions: Dimensions,
+    /// The stones of the first and second players.
+    stones: [Bitset; 2],
+    turn: Player,
+    ply: usize,
+    winner: Option<Player>,
+}
+
+impl Default for Board {
+    fn default() -> Self {
+        Self::new(Dimensions::default())
+    }
+}
+
+impl Board {
+    /// Creates an empty board with the given dimensions.
+    pub fn new(dimensions: Dimensions) -> Self {
+        Self { dimensions, stones: [Bitset::default(); 2], turn: Player::First, ply: 0, winner: None }
+    }
+
+    pub const fn dimensions(&self) -> Dimensions {
+        self.dimensions
+    }
+
+    pub const fn turn(&self) -> Player {
+        self.turn
+    }
+
+    /// The indices of the cells of this board, row by row from the first rank.
+    fn cells(&self) -> impl Iterator<Item = usize> {
+        let Dimensions { width, height, .. } = self.dimensions;
+        (0..height).flat_map(move |row| (0..width).map(move |col| cell(row, col)))
+    }
+
+    /// The player whose stone is on the given cell.
+    pub const fn stone_at(&self, index: usize) -> Player {
+        if self.stones[0].get(index) {
+            Player::First
+        } else if self.stones[1].get(index) {
+            Player::Second
+        } else {
+            Player::None
+        }
+    }
+
+    pub const fn outcome(&self) -> Option<Player> {
+        if self.winner.is_some() {
+            self.winner
+        } else if self.ply == self.dimensions.cells() {
+            Some(Player::None)
+        } else {
+            None
+        }
+    }
+
+    pub fn make_move(&mut self, mv: Move) {
+        let index = mv.index();
+        debug_assert_eq!(self.stone_at(index), Player::None, "cell {mv} is already occupied");
+        let side = usize::from(self.turn == Player::Second);
+        self.stones[side].set(index);
+        self.ply += 1;
+        if self.makes_k(index, self.turn) {
+            self.winner = Some(self.turn);
+        }
+        self.turn = self.turn.opposite();
+    }
+
+    /// Whether the stone on the given cell is part of a line of at least k stones.
+    #[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+    fn makes_k(&self, index: usize, player: Player) -> bool {
+        let Dimensions { width, height, k } = self.dimensions;
+        let (row, col) = ((index / MAX_SIDE) as isize, (index % MAX_SIDE) as isize);
+        let in_bounds = |r: isize, c: isize| (0..height as isize).contains(&r) && (0..width as isize).contains(&c);
+        [(0, 1), (1, 0), (1, 1), (1, -1)].into_iter().any(|(dr, dc)| {
+            let mut count = 1;
+            for sign in [1, -1] {
+                let (mut r, mut c) = (row + dr * sign, col + dc * sign);
+                while in_bounds(r, c) && self.stone_at(cell(r as usize, c as usize)) == player {
+                    count += 1;
+                    r += dr * sign;
+                    c += dc * sign;
+                }
+            }
+            count >= k
+        })
+    }
+
+    /// Generate a list of legal moves.
+    /// Iteration short-circuits if the callback returns `true`.
+    pub fn generate_moves(&self, mut f: impl FnMut(Move) -> bool) {
+        if self.outcome().is_some() {
+            return;
+        }
+        for index in self.cells() {
+            if self.stone_at(index) == Player::None {
+                let mv = Move { index: index.try_into().expect("index out of range") };
+                if f(mv) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Calls the callback with the index and owner of every stone on the board.
+    pub fn feature_map(&self, mut f: impl FnMut(usize, Player)) {
+        for index in self.cells() {
+            let stone = self.stone_at(index);
+            if stone != Player::None {
+                f(index, stone);
+            }
+        }
+    }
+
+    pub fn fen(&self) -> String {
+        let Dimensions { width, height, .. } = self.dimensions;
+        let mut fen = String::new();
+        for row in (0..height).rev() {
+            let mut empty = 0;
+            for col in 0..width {
+                let c = match self.stone_at(cell(row, col)) {
+                    Player::None => {
+                        empty += 1;
+                        continue;
+                    }
+                    Player::First => 'x',
+                    Player::Second => 'o',
+                };
+                if empty > 0 {
+                    fen += &empty.to_string();
+                    empty = 0;
+                }
+                fen.push(c);
+            }
+            if empty > 0 {
+                fen += &empty.to_string();
+            }
+            if row > 0 {
+                fen.push('/');
+            }
+        }
+        let side = if self.turn == Player::First { 'x' } else { 'o' };
+        format!("{fen} {side} {}", self.dimensions.k)
+    }
+}
+
+impl Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Dimensions { width, height, .. } = self.dimensions;
+        for row in (0..height).rev() {
+            for col in 0..width {
+                let c = match self.stone_at(cell(row, col)) {
+                    Player::None => '.',
+                    Player::First => 'x',
+                    Player::Second => 'o',
+                };
+                write!(f, "{c}")?;
+            }
+            if row > 0 {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Board {
+    type Err = anyhow::Error;
+
+    /// Parses a FEN of the form `x2/1o1/3 x 3`, whose rows give the width and height of the board,
+    /// and whose last field is the length of line that wins.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_ascii_whitespace();
+        let rows = parts.next().with_context(|| format!("empty fen {s:?}"))?.split('/').collect::<Vec<_>>();
+        let height = rows.len();
+        let mut parsed_rows = Vec::with_capacity(height);
+        for row in &rows {
+            let mut cells = Vec::new();
+            let mut empty = 0;
+            for c in row.chars() {
+                if let Some(digit) = c.to_digit(10) {
+                    empty = empty * 10 + digit as usize;
+                    continue;
+                }
+                cells.resize(cells.len() + empty, Player::None);
+                empty = 0;
+                cells.push(match c {
+                    'x' => Player::First,
+                    'o' => Player::Second,
+                    _ => anyhow::bail!("invalid character {c:?} in fen {s:?}"),
+                });
+            }
+            cells.resize(cells.len() + empty, Player::None);
+            parsed_rows.push(cells);
+        }
+        let width = parsed_rows[0].len();
+        if parsed_rows.iter().any(|row| row.len() != width) {
+            anyhow::bail!("rows of differing lengths in fen {s:?}");
+        }
+        let side = parts.next().with_context(|| format!("no side-to-move in fen {s:?}"))?;
+        let k = parts.next().with_context(|| format!("no win length in fen {s:?}"))?;
+        let dimensions =
+            format!("{width}x{height}x{k}").parse::<Dimensions>().map_err(|e| anyhow::anyhow!("{e}, in fen {s:?}"))?;
+
+        let mut board = Self::new(dimensions);
+        for (row, cells) in parsed_rows.iter().rev().enumerate() {
+            for (col, &stone) in cells.iter().enumerate() {
+                let index = cell(row, col);
+                match stone {
+                    Player::None => continue,
+                    Player::First => board.stones[0].set(index),
+                    Player::Second => board.stones[1].set(index),
+                }
+                board.ply += 1;
+            }
+        }
+        for index in board.cells() {
+            let stone = board.stone_at(index);
+            if stone != Player::None && board.makes_k(index, stone) {
+                board.winner = Some(stone);
+            }
+        }
+        board.turn = match side {
+            "x" => Player::First,
+            "o" => Player::Second,
+            other => anyhow::bail!("invalid side-to-move {other:?} in fen {s:?}"),
+        };
+        Ok(board)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::play_moves;
+
+    #[test]
+    fn tic_tac_toe_win() {
+        let mut board = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        play_moves(&mut board, &["a1", "b1", "b2", "c1", "c3"]);
+        assert_eq!(board.outcome(), Some(Player::First));
+    }
+
+    #[test]
+    fn tic_tac_toe_draw() {
+        let mut board = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        play_moves(&mut board, &["b2", "a1", "c3", "a3", "a2", "c2", "b1", "b3", "c1"]);
+        assert_eq!(board.outcome(), Some(Player::None));
+    }
+
+    #[test]
+    fn fen_round_trip() {
+        let mut board = Board::default();
+        play_moves(&mut board, &["d1", "d2", "e1", "o15"]);
+        let fen = board.fen();
+        assert!(fen.starts_with("14o/15/"), "unexpected fen {fen}");
+        assert!(fen.ends_with("/15/3o11/3xx10 x 5"), "unexpected fen {fen}");
+        let parsed: Board = fen.parse().unwrap();
+        assert_eq!(parsed, board);
+    }
+
+    #[test]
+    fn fens_carry_their_dimensions() {
+        let board = "6o/7/7/7/3o3/3xx2 x 4".parse::<Board>().unwrap();
+        assert_eq!(board.dimensions(), Dimensions { width: 7, height: 6, k: 4 });
+        assert_eq!(board.fen(), "6o/7/7/7/3o3/3xx2 x 4");
+        assert!("6o/7/7/7/3o3/3xx2 x".parse::<Board>().is_err());
+        assert!("6o/7/7/7/3o3/3xx2 x 8".parse::<Board>().is_err());
+    }
+
+    #[test]
+    fn boards_of_every_size_share_a_layout() {
+        let mut small = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        play_moves(&mut small, &["c3"]);
+        assert_eq!("c3".parse::<Move>().unwrap().index(), cell(2, 2));
+        let mut moves = Vec::new();
+        small.generate_moves(|mv| {
+            moves.push(mv.to_string());
+            false
+        });
+        assert_eq!(moves, ["a1", "b1", "c1", "a2", "b2", "c2", "a3", "b3"]);
+        assert!("t1".parse::<Move>().is_err());
+    }
+
+    #[test]
+    fn transposition_keys_are_kept_up_to_date_move_by_move() {
+        use crate::game::GameImpl;
+        let mut board = Board::default();
+        let mut key = GameImpl::transposition_key(&board).unwrap();
+        let mut rng = fastrand::Rng::with_seed(7);
+        while board.outcome().is_none() {
+            let mut moves = Vec::new();
+            board.generate_moves(|mv| {
+                moves.push(mv);
+                false
+            });
+            let mv = moves[rng.usize(0..moves.len())];
+            let before = board;
+            board.make_move(mv);
+            key = before.transposition_key_after(key, mv, &board).unwrap();
+            assert_eq!(Some(key), GameImpl::transposition_key(&board));
+        }
+
+        let mut one_order = Board::default();
+        play_moves(&mut one_order, &["d1", "d2", "e1"]);
+        let mut another = Board::default();
+        play_moves(&mut another, &["e1", "d2", "d1"]);
+        assert_eq!(GameImpl::transposition_key(&one_order), GameImpl::transposition_key(&another));
+        let mut swapped = Board::default();
+        play_moves(&mut swapped, &["d2", "d1", "e1"]);
+        assert_ne!(GameImpl::transposition_key(&one_order), GameImpl::transposition_key(&swapped));
+    }
+
+    #[test]
+    fn dimensions_parsing() {
+        assert_eq!("15x15x5".parse::<Dimensions>().unwrap(), Dimensions { width: 15, height: 15, k: 5 });
+        assert!("15x15".parse::<Dimensions>().is_err());
+        assert!("3x3x4".parse::<Dimensions>().is_err());
+        assert!("20x20x5".parse::<Dimensions>().is_err());
+    }
+}
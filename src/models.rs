@@ -0,0 +1,99 @@
+//! A local store of networks downloaded from URLs.
+
+use std::{path::PathBuf, process::Command};
+
+use anyhow::Context;
+
+use crate::hash;
+
+/// The environment variable naming the directory that downloaded networks are cached in.
+const STORE_VAR: &str = "VERITAS_MODEL_STORE";
+/// The cache directory used if `STORE_VAR` is not set.
+const DEFAULT_STORE: &str = "models";
+
+/// Whether `path` names a network to download, rather than a local file.
+pub fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Returns a local path for the network at `path`. Local paths are returned unchanged,
+/// while URLs are downloaded into the store, unless a copy with a matching checksum is already there.
+/// A URL may end in `#<sha256>`, the hexadecimal SHA-256 digest the download must have.
+pub fn resolve(path: &str) -> anyhow::Result<String> {
+    if !is_url(path) {
+        return Ok(path.to_owned());
+    }
+    let (url, expected) = match path.split_once('#') {
+        Some((url, digest)) => {
+            anyhow::ensure!(
+                digest.len() == 64 && digest.bytes().all(|byte| byte.is_ascii_hexdigit()),
+                "invalid checksum {digest:?}, expected a SHA-256 digest of 64 hexadecimal digits"
+            );
+            (url, Some(digest.to_ascii_lowercase()))
+        }
+        None => (path, None),
+    };
+
+    let store = PathBuf::from(std::env::var(STORE_VAR).unwrap_or_else(|_| DEFAULT_STORE.into()));
+    std::fs::create_dir_all(&store).with_context(|| format!("failed to create model store {}", store.display()))?;
+    let name = url.rsplit('/').next().filter(|name| !name.is_empty()).unwrap_or("model.onnx");
+    let local = store.join(format!("{:016x}-{name}", hash::fnv1a(url.as_bytes())));
+    let sidecar = local.with_extension("checksum");
+
+    // a cached copy is only trusted if it still matches the checksum recorded when it was fetched.
+    if let Ok(recorded) = std::fs::read_to_string(&sidecar) {
+        let recorded = recorded.trim();
+        let matches_file = hash::sha256_file(&local).is_ok_and(|actual| actual == recorded);
+        if matches_file && (expected.is_none() || expected.as_deref() == Some(recorded)) {
+            return Ok(local.to_string_lossy().into_owned());
+        }
+    }
+
+    println!("Downloading {url} to {}", local.display());
+    let partial = local.with_extension("partial");
+    let status = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--output"])
+        .arg(&partial)
+        .arg(url)
+        .status()
+        .with_context(|| "failed to run curl, which is needed to download networks")?;
+    anyhow::ensure!(status.success(), "failed to download {url}: curl exited with {status}");
+    let actual = hash::sha256_file(&partial)?;
+    if let Some(expected) = expected {
+        if actual != expected {
+            std::fs::remove_file(&partial)?;
+            anyhow::bail!("checksum mismatch for {url}: expected {expected}, got {actual}");
+        }
+    }
+    std::fs::rename(&partial, &local)?;
+    std::fs::write(&sidecar, format!("{actual}\n"))?;
+    println!("Fetched {url} with SHA-256 {actual}");
+
+    Ok(local.to_string_lossy().into_owned())
+}
+
+/// Resolves every network named in a command-line argument, which may be a single path
+/// or a comma-separated list of `<game>=<path>` specifications, as taken by datagen.
+pub fn resolve_argument(argument: &str) -> anyhow::Result<String> {
+    let parts = argument
+        .split(',')
+        .map(|part| match part.split_once('=') {
+            Some((game, path)) if is_url(path) => Ok(format!("{game}={}", resolve(path)?)),
+            _ => resolve(part),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(parts.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_paths_are_unchanged() {
+        assert!(!is_url("nets/ataxx.onnx"));
+        assert!(is_url("https://example.com/net.onnx"));
+        assert_eq!(resolve("nets/ataxx.onnx").unwrap(), "nets/ataxx.onnx");
+        assert_eq!(resolve_argument("ataxx=a.onnx,uttt").unwrap(), "ataxx=a.onnx,uttt");
+    }
+}
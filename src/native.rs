@@ -0,0 +1,390 @@
+//! A built-in evaluator for small multilayer perceptrons stored as safetensors,
+//! which runs without the ONNX and CUDA stack.
+//!
+//! The file holds `F32` tensors in the layout of `PyTorch`'s `nn.Linear`, with weights of shape `[out, in]`:
+//! hidden layers `trunk.<i>.weight` and `trunk.<i>.bias`, numbered from zero and each followed by a `ReLU`,
+//! then a policy head `policy.weight` and `policy.bias` giving logits,
+//! and a value head `value.weight` and `value.bias` of width one, followed by a sigmoid.
+
+use std::collections::HashMap;
+
+use anyhow::Context;
+
+use crate::game::GameImpl;
+
+/// A fully-connected layer.
+#[derive(Clone)]
+struct Linear {
+    /// The weights, row-major with one row per output.
+    weight: Vec<f32>,
+    bias: Vec<f32>,
+    inputs: usize,
+}
+
+impl Linear {
+    const fn outputs(&self) -> usize {
+        self.bias.len()
+    }
+
+    fn forward(&self, input: &[f32]) -> Vec<f32> {
+        self.weight
+            .chunks_exact(self.inputs)
+            .zip(&self.bias)
+            .map(|(row, bias)| row.iter().zip(input).fold(*bias, |acc, (w, x)| w.mul_add(*x, acc)))
+            .collect()
+    }
+
+    /// Like `forward`, for an input that is one at the given indices and zero elsewhere,
+    /// as the feature maps are.
+    fn forward_sparse(&self, active: &[usize]) -> Vec<f32> {
+        self.weight
+            .chunks_exact(self.inputs)
+            .zip(&self.bias)
+            .map(|(row, bias)| active.iter().map(|&i| row[i]).sum::<f32>() + bias)
+            .collect()
+    }
+}
+
+/// A network in the native format.
+#[derive(Clone)]
+pub struct NativeNet {
+    trunk: Vec<Linear>,
+    policy: Linear,
+    value: Linear,
+}
+
+impl NativeNet {
+    /// Loads a network from a safetensors file, checking that its shapes suit the game.
+    pub fn load<G: GameImpl>(path: &str) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("failed to read {path}"))?;
+        Self::from_bytes::<G>(&bytes).with_context(|| format!("failed to load native network {path}"))
+    }
+
+    pub fn from_bytes<G: GameImpl>(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut tensors = parse_safetensors(bytes)?;
+        let mut take = |name: &str| tensors.remove(name).with_context(|| format!("missing tensor {name}"));
+
+        let mut trunk = Vec::new();
+        let mut width = G::tensor_dims(1)[1];
+        while let Ok(weight) = take(&format!("trunk.{}.weight", trunk.len())) {
+            let layer = linear(weight, take(&format!("trunk.{}.bias", trunk.len()))?, width)?;
+            width = layer.outputs();
+            trunk.push(layer);
+        }
+        let policy = linear(take("policy.weight")?, take("policy.bias")?, width)?;
+        anyhow::ensure!(
+            policy.outputs() == G::policy_dim(),
+            "policy head has {} outputs, but the game has {} moves",
+            policy.outputs(),
+            G::policy_dim()
+        );
+        let value = linear(take("value.weight")?, take("value.bias")?, width)?;
+        anyhow::ensure!(value.outputs() == 1, "value head has {} outputs, expected 1", value.outputs());
+
+        Ok(Self { trunk, policy, value })
+    }
+
+    /// Builds a network of one hidden layer of `hidden` units, with small weights drawn from `seed`
+    /// by `SplitMix64`. It plays badly, but identically on every machine, so it can stand in for a
+    /// trained network wherever a search has to be reproducible without a model file.
+    pub fn seeded<G: GameImpl>(seed: u64, hidden: usize) -> Self {
+        let mut state = seed;
+        let mut layer = |inputs: usize, outputs: usize| {
+            #[allow(clippy::cast_precision_loss)]
+            let scale = 1.0 / (inputs as f32).sqrt();
+            let mut next = || {
+                state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                let mut z = state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                z ^= z >> 31;
+                // the top 24 bits, as a uniform sample from [-1, 1).
+                #[allow(clippy::cast_precision_loss)]
+                let unit = (z >> 40) as f32 / (1 << 23) as f32 - 1.0;
+                unit * scale
+            };
+            let weight = (0..inputs * outputs).map(|_| next()).collect();
+            let bias = (0..outputs).map(|_| next()).collect();
+            Linear { weight, bias, inputs }
+        };
+        let trunk = vec![layer(G::tensor_dims(1)[1], hidden)];
+        let policy = layer(hidden, G::policy_dim());
+        let value = layer(hidden, 1);
+        Self { trunk, policy, value }
+    }
+
+    /// Evaluates a position, returning the policy logits and the win probability of the side to move.
+    pub fn evaluate<G: GameImpl>(&self, board: &G) -> (Vec<f32>, f32) {
+        let mut active = Vec::new();
+        board.fill_feature_map(|index| active.push(index));
+        let relu = |x: Vec<f32>| x.into_iter().map(|v| v.max(0.0)).collect::<Vec<_>>();
+        let hidden = if let Some((first, rest)) = self.trunk.split_first() {
+            rest.iter().fold(relu(first.forward_sparse(&active)), |x, layer| relu(layer.forward(&x)))
+        } else {
+            // with no hidden layers, the heads see the features directly.
+            let mut x = vec![0.0; self.policy.inputs];
+            for i in active {
+                x[i] = 1.0;
+            }
+            x
+        };
+        let value = self.value.forward(&hidden)[0];
+        (self.policy.forward(&hidden), 1.0 / (1.0 + (-value).exp()))
+    }
+}
+
+/// A tensor read from a safetensors file.
+struct RawTensor {
+    shape: Vec<usize>,
+    data: Vec<f32>,
+}
+
+fn linear(weight: RawTensor, bias: RawTensor, inputs: usize) -> anyhow::Result<Linear> {
+    anyhow::ensure!(
+        weight.shape.len() == 2 && weight.shape[1] == inputs,
+        "weight of shape {:?} does not take {inputs} inputs",
+        weight.shape
+    );
+    anyhow::ensure!(
+        bias.shape == [weight.shape[0]],
+        "bias of shape {:?} does not match weight of shape {:?}",
+        bias.shape,
+        weight.shape
+    );
+    Ok(Linear { weight: weight.data, bias: bias.data, inputs })
+}
+
+/// Reads the `F32` tensors of a safetensors file: a little-endian u64 header length,
+/// a JSON header mapping names to dtypes, shapes, and data offsets, and then the data.
+fn parse_safetensors(bytes: &[u8]) -> anyhow::Result<HashMap<String, RawTensor>> {
+    let header_len = bytes.get(..8).context("file too short")?;
+    let header_len = usize::try_from(u64::from_le_bytes(header_len.try_into()?))?;
+    let header = bytes.get(8..8 + header_len).context("header runs past the end of the file")?;
+    let data = &bytes[8 + header_len..];
+    let header = std::str::from_utf8(header)?;
+    let Json::Object(entries) = Json::parse(header)? else {
+        anyhow::bail!("header is not an object");
+    };
+
+    let mut tensors = HashMap::new();
+    for (name, info) in entries {
+        if name == "__metadata__" {
+            continue;
+        }
+        let field = |key: &str| info.get(key).with_context(|| format!("tensor {name} has no {key}"));
+        anyhow::ensure!(field("dtype")?.as_str() == Some("F32"), "tensor {name} is not F32");
+        let shape = field("shape")?.as_usizes().with_context(|| format!("tensor {name} has a malformed shape"))?;
+        let offsets = field("data_offsets")?.as_usizes();
+        let Some(&[start, end]) = offsets.as_deref() else {
+            anyhow::bail!("tensor {name} has malformed data offsets");
+        };
+        let raw = data.get(start..end).with_context(|| format!("tensor {name} runs past the end of the file"))?;
+        anyhow::ensure!(raw.len() == 4 * shape.iter().product::<usize>(), "tensor {name} has the wrong size");
+        let data = raw.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+        tensors.insert(name, RawTensor { shape, data });
+    }
+    Ok(tensors)
+}
+
+/// Just enough JSON to read safetensors headers.
+enum Json {
+    String(String),
+    Number(f64),
+    Array(Vec<Self>),
+    Object(Vec<(String, Self)>),
+    Other,
+}
+
+impl Json {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut chars = text.chars().peekable();
+        let value = Self::parse_value(&mut chars)?;
+        anyhow::ensure!(chars.all(char::is_whitespace), "trailing characters after JSON");
+        Ok(value)
+    }
+
+    fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> anyhow::Result<Self> {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        match chars.peek().copied().context("unexpected end of JSON")? {
+            '"' => Ok(Self::String(Self::parse_string(chars)?)),
+            '[' => {
+                chars.next();
+                let mut items = Vec::new();
+                loop {
+                    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                    if chars.next_if_eq(&']').is_some() {
+                        return Ok(Self::Array(items));
+                    }
+                    items.push(Self::parse_value(chars)?);
+                    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                    chars.next_if_eq(&',');
+                }
+            }
+            '{' => {
+                chars.next();
+                let mut entries = Vec::new();
+                loop {
+                    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                    if chars.next_if_eq(&'}').is_some() {
+                        return Ok(Self::Object(entries));
+                    }
+                    let key = Self::parse_string(chars)?;
+                    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                    anyhow::ensure!(chars.next() == Some(':'), "expected ':' in JSON object");
+                    entries.push((key, Self::parse_value(chars)?));
+                    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                    chars.next_if_eq(&',');
+                }
+            }
+            _ => {
+                let mut token = String::new();
+                while let Some(c) = chars.next_if(|c| !matches!(c, ',' | ']' | '}') && !c.is_whitespace()) {
+                    token.push(c);
+                }
+                anyhow::ensure!(!token.is_empty(), "unexpected character in JSON");
+                Ok(token.parse().map_or(Self::Other, Self::Number))
+            }
+        }
+    }
+
+    fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> anyhow::Result<String> {
+        anyhow::ensure!(chars.next() == Some('"'), "expected a JSON string");
+        let mut string = String::new();
+        loop {
+            match chars.next().context("unterminated JSON string")? {
+                '"' => return Ok(string),
+                '\\' => string.push(match chars.next().context("unterminated JSON string")? {
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'u' => {
+                        let unit = Self::parse_hex4(chars)?;
+                        // characters outside the basic plane are escaped as a pair of UTF-16 surrogates.
+                        let code = if (0xD800..0xDC00).contains(&unit) {
+                            anyhow::ensure!(
+                                chars.next() == Some('\\') && chars.next() == Some('u'),
+                                "unpaired surrogate in JSON string"
+                            );
+                            let low = Self::parse_hex4(chars)?;
+                            anyhow::ensure!((0xDC00..0xE000).contains(&low), "unpaired surrogate in JSON string");
+                            0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                        } else {
+                            unit
+                        };
+                        char::from_u32(code).context("invalid unicode escape in JSON string")?
+                    }
+                    c @ ('"' | '\\' | '/') => c,
+                    c => anyhow::bail!("invalid escape \\{c} in JSON string"),
+                }),
+                c => string.push(c),
+            }
+        }
+    }
+
+    /// Parses the four hex digits of a `\u` escape.
+    fn parse_hex4(chars: &mut std::iter::Peekable<std::str::Chars>) -> anyhow::Result<u32> {
+        let digits = chars.by_ref().take(4).collect::<String>();
+        anyhow::ensure!(
+            digits.len() == 4 && digits.chars().all(|c| c.is_ascii_hexdigit()),
+            "invalid unicode escape \\u{digits} in JSON string"
+        );
+        Ok(u32::from_str_radix(&digits, 16)?)
+    }
+
+    fn get(&self, key: &str) -> Option<&Self> {
+        let Self::Object(entries) = self else {
+            return None;
+        };
+        entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    fn as_usizes(&self) -> Option<Vec<usize>> {
+        let Self::Array(items) = self else {
+            return None;
+        };
+        items
+            .iter()
+            .map(|item| match item {
+                Self::Number(n) if n.fract() == 0.0 && *n >= 0.0 => Some(*n as usize),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uttt::Board;
+
+    /// Serialises tensors into a safetensors file.
+    fn safetensors(tensors: &[(&str, Vec<usize>, Vec<f32>)]) -> Vec<u8> {
+        let mut header = Vec::new();
+        let mut data = Vec::new();
+        for (name, shape, values) in tensors {
+            let start = data.len();
+            data.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+            let shape = shape.iter().map(ToString::to_string).collect::<Vec<_>>().join(",");
+            header.push(format!(
+                "\"{name}\":{{\"dtype\":\"F32\",\"shape\":[{shape}],\"data_offsets\":[{start},{}]}}",
+                data.len()
+            ));
+        }
+        let header = format!("{{\"__metadata__\":{{\"format\":\"pt\"}},{}}}", header.join(","));
+        let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+        bytes.extend(header.bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn json_strings_are_unescaped() {
+        let Json::String(string) = Json::parse(r#""a\"b\\c\/d\n\t\u00e9\ud83d\ude00""#).unwrap() else {
+            panic!("expected a JSON string");
+        };
+        assert_eq!(string, "a\"b\\c/d\n\t\u{e9}\u{1f600}");
+        assert!(Json::parse(r#""\x""#).is_err());
+        assert!(Json::parse(r#""\ud83d""#).is_err());
+        assert!(Json::parse(r#""\u00""#).is_err());
+    }
+
+    #[test]
+    fn evaluates_a_small_network() {
+        let (features, moves) = (Board::tensor_dims(1)[1], Board::policy_dim());
+        let bytes = safetensors(&[
+            ("trunk.0.weight", vec![2, features], vec![1.0; 2 * features]),
+            ("trunk.0.bias", vec![2], vec![0.0, -1000.0]),
+            ("policy.weight", vec![moves, 2], vec![0.0; 2 * moves]),
+            ("policy.bias", vec![moves], (0..moves).map(|i| f32::from(u16::try_from(i).unwrap())).collect()),
+            ("value.weight", vec![1, 2], vec![0.0, 1.0]),
+            ("value.bias", vec![1], vec![0.0]),
+        ]);
+        let net = NativeNet::from_bytes::<Board>(&bytes).unwrap();
+        let (policy, value) = net.evaluate(&Board::default());
+        assert_eq!(policy.len(), moves);
+        assert!((policy[3] - 3.0).abs() < 1e-6);
+        // the second hidden unit is always switched off by the ReLU, so the value is sigmoid(0).
+        assert!((value - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn rejects_mismatched_shapes() {
+        let bytes = safetensors(&[
+            ("policy.weight", vec![3, 5], vec![0.0; 15]),
+            ("policy.bias", vec![3], vec![0.0; 3]),
+            ("value.weight", vec![1, 5], vec![0.0; 5]),
+            ("value.bias", vec![1], vec![0.0]),
+        ]);
+        assert!(NativeNet::from_bytes::<Board>(&bytes).is_err());
+    }
+}
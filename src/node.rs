@@ -1,9 +1,9 @@
-use std::alloc::Layout;
+use std::ptr::NonNull;
 
 use smallvec::SmallVec;
 
 use crate::{
-    arena::Handle,
+    arena::{Handle, Spare},
     game::{GameImpl, MovePolicyIndex, Player},
 };
 
@@ -14,26 +14,143 @@ pub struct Edge<G: GameImpl> {
     // Probability that this move will be made, from the policy head of the neural
     // network. TODO: leela compresses this into a short.
     probability: f32,
+    /// Number of simulations through the parent in which this move was played later on,
+    /// by the same player. These are the all-moves-as-first (AMAF) statistics used by RAVE.
+    #[cfg(feature = "rave")]
+    amaf_visits: u32,
+    /// Sum of the values of those simulations, from the point of view of the player making this move.
+    #[cfg(feature = "rave")]
+    amaf_wl: f32,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
-enum Terminal {
-    /// This node is not terminal.
-    NonTerminal,
-    /// This node is terminal.
-    Terminal,
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
 enum GameResult {
     /// The game is ongoing.
-    Ongoing,
+    Ongoing = 0,
     /// The game is a draw.
-    Draw,
+    Draw = 1,
     /// The game is a win for the first player.
-    FirstPlayerWin,
+    FirstPlayerWin = 2,
     /// The game is a win for the second player.
-    SecondPlayerWin,
+    SecondPlayerWin = 3,
+}
+
+impl GameResult {
+    const fn from_outcome(outcome: Player) -> Self {
+        match outcome {
+            Player::None => Self::Draw,
+            Player::First => Self::FirstPlayerWin,
+            Player::Second => Self::SecondPlayerWin,
+        }
+    }
+
+    const fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::Ongoing,
+            1 => Self::Draw,
+            2 => Self::FirstPlayerWin,
+            _ => Self::SecondPlayerWin,
+        }
+    }
+
+    const fn outcome(self) -> Option<Player> {
+        match self {
+            Self::Ongoing => None,
+            Self::Draw => Some(Player::None),
+            Self::FirstPlayerWin => Some(Player::First),
+            Self::SecondPlayerWin => Some(Player::Second),
+        }
+    }
+}
+
+/// Whether a node is terminal, and its best and worst possible outcomes, packed into a byte.
+/// Bit 0 is the terminal flag, bits 1-2 the upper bound, bits 3-4 the lower bound, and bit 5 is set while
+/// progressive widening has left some legal moves without edges.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Status(u8);
+
+impl Status {
+    const TERMINAL: u8 = 1;
+    const UPPER_SHIFT: u8 = 1;
+    const LOWER_SHIFT: u8 = 3;
+    const PARTIAL: u8 = 1 << 5;
+
+    /// A non-terminal node with unknown bounds.
+    const fn new() -> Self {
+        Self(((GameResult::Ongoing as u8) << Self::UPPER_SHIFT) | ((GameResult::Ongoing as u8) << Self::LOWER_SHIFT))
+    }
+
+    /// A terminal node with the given result.
+    const fn terminal(result: GameResult) -> Self {
+        Self(Self::TERMINAL | ((result as u8) << Self::UPPER_SHIFT) | ((result as u8) << Self::LOWER_SHIFT))
+    }
+
+    /// A non-terminal node whose outcome with best play has been proven.
+    const fn proven(result: GameResult) -> Self {
+        Self(((result as u8) << Self::UPPER_SHIFT) | ((result as u8) << Self::LOWER_SHIFT))
+    }
+
+    const fn is_terminal(self) -> bool {
+        self.0 & Self::TERMINAL != 0
+    }
+
+    const fn is_partial(self) -> bool {
+        self.0 & Self::PARTIAL != 0
+    }
+
+    const fn set_partial(&mut self, partial: bool) {
+        if partial {
+            self.0 |= Self::PARTIAL;
+        } else {
+            self.0 &= !Self::PARTIAL;
+        }
+    }
+
+    /// The outcome of the node, if its bounds meet.
+    const fn proven_result(self) -> Option<GameResult> {
+        let upper = GameResult::from_bits(self.0 >> Self::UPPER_SHIFT);
+        let lower = GameResult::from_bits(self.0 >> Self::LOWER_SHIFT);
+        if upper as u8 == lower as u8 && !matches!(upper, GameResult::Ongoing) {
+            Some(upper)
+        } else {
+            None
+        }
+    }
+}
+
+/// Samples a symmetric Dirichlet distribution over `n` outcomes with concentration `alpha`.
+fn dirichlet(n: usize, alpha: f64) -> Vec<f64> {
+    let mut sample = (0..n).map(|_| gamma(alpha)).collect::<Vec<_>>();
+    let total = sample.iter().sum::<f64>();
+    if total > 0.0 {
+        for x in &mut sample {
+            *x /= total;
+        }
+    }
+    sample
+}
+
+/// Samples a Gamma(`alpha`, 1) distribution, by the method of Marsaglia and Tsang.
+fn gamma(alpha: f64) -> f64 {
+    if alpha < 1.0 {
+        // boost the shape above one, and correct with a uniform power.
+        return gamma(alpha + 1.0) * fastrand::f64().powf(1.0 / alpha);
+    }
+    let shape = alpha - 1.0 / 3.0;
+    let scale = 1.0 / (9.0 * shape).sqrt();
+    loop {
+        // a standard normal, by the Box-Muller transform.
+        let normal = (-2.0 * (1.0 - fastrand::f64()).ln()).sqrt() * (std::f64::consts::TAU * fastrand::f64()).cos();
+        let cube = scale.mul_add(normal, 1.0).powi(3);
+        if cube <= 0.0 {
+            continue;
+        }
+        let uniform = 1.0 - fastrand::f64();
+        if uniform.ln() < (0.5 * normal).mul_add(normal, shape - shape * cube + shape * cube.ln()) {
+            return shape * cube;
+        }
+    }
 }
 
 impl<G: GameImpl> Edge<G> {
@@ -50,6 +167,26 @@ impl<G: GameImpl> Edge<G> {
     pub const fn probability(self) -> f64 {
         self.probability as f64
     }
+
+    /// Returns the AMAF winrate of this edge, if it has been played in any simulation.
+    #[cfg(feature = "rave")]
+    pub fn amaf_winrate(self) -> Option<f64> {
+        (self.amaf_visits > 0).then(|| f64::from(self.amaf_wl) / f64::from(self.amaf_visits))
+    }
+
+    /// Returns the AMAF winrate of this edge, which is never known in builds without RAVE.
+    #[cfg(not(feature = "rave"))]
+    pub const fn amaf_winrate(self) -> Option<f64> {
+        None
+    }
+
+    /// Adds an AMAF visit to this edge.
+    #[cfg(feature = "rave")]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn add_amaf_visit(&mut self, value: f64) {
+        self.amaf_wl += value as f32;
+        self.amaf_visits += 1;
+    }
 }
 
 #[derive(Debug)]
@@ -60,9 +197,13 @@ pub struct Node<G: GameImpl> {
     /// perspective of the player-to-move for the position.
     /// WL stands for "W minus L". Is equal to Q if draw score is 0.
     wl: f64,
-    /// Array of edges from this node.
-    /// TODO: store the allocation length out-of-line, as it should fit in a u8.
-    edges: Option<Box<[Edge<G>]>>,
+    /// Sum of the squares of the values backpropagated through this node,
+    /// from which the variance of its value is estimated.
+    #[cfg(feature = "variance-stats")]
+    wl_squared: f64,
+    /// Array of edges from this node, with its length stored in `num_edges`.
+    /// Storing a thin pointer rather than a boxed slice keeps the node at 40 bytes.
+    edges: Option<NonNull<Edge<G>>>,
     /// Index of the parent node in the tree.
     parent: Handle,
     /// Index to a first child. Null for a leaf node.
@@ -75,26 +216,41 @@ pub struct Node<G: GameImpl> {
     // remaining: f32,
     /// Number of completed visits to this node.
     visits: u32,
-    // How many threads are currently visiting this node.
-    // num_in_flight: u32,
+    /// Number of visits to this node still waiting on an evaluation, which count as losses until it arrives.
+    in_flight: u16,
     /// Index of this node in the parent's edge list.
     index: u16,
+    /// Number of edges from this node, with `MAPPED_EDGES` set if they live in the spare bytes of a
+    /// memory-mapped tree rather than on the heap.
+    num_edges: u16,
+    /// The player who made the move leading to this node.
+    /// This need not alternate between parent and child, as a turn may span several moves.
+    mover: Player,
+    /// Whether this node ends the game, and the bounds on its outcome.
+    status: Status,
+}
+
+/// The bit of `Node::num_edges` marking edges kept in a memory-mapped tree, which the node does not free.
+const MAPPED_EDGES: u16 = 1 << 15;
 
-    // TODO: pack the next three fields into a single u8.
-    /// Whether this node ends the game.
-    terminal_type: Terminal,
-    /// Best possible outcome for this node.
-    upper_bound: GameResult,
-    /// Worst possible outcome for this node.
-    lower_bound: GameResult,
+impl<G: GameImpl> Drop for Node<G> {
+    fn drop(&mut self) {
+        self.free_edges();
+    }
 }
 
+// SAFETY: the node uniquely owns its edges, as a `Box<[Edge<G>]>` would, or uniquely borrows them from the
+// mapping of its tree, which outlives it, so it can move between threads whenever they can.
+unsafe impl<G: GameImpl> Send for Node<G> where Edge<G>: Send {}
+
 impl<G: GameImpl> Node<G> {
-    /// Creates a new node.
-    pub fn new(parent: Handle, edge_index: usize) -> Self {
+    /// Creates a new node, reached by a move made by `mover`.
+    pub fn new(parent: Handle, edge_index: usize, mover: Player) -> Self {
         let index = edge_index.try_into().unwrap_or_else(|_| panic!("edge index {edge_index} too large"));
         Self {
             wl: 0.0,
+            #[cfg(feature = "variance-stats")]
+            wl_squared: 0.0,
             edges: None,
             parent,
             child: Handle::null(),
@@ -102,11 +258,11 @@ impl<G: GameImpl> Node<G> {
             // draw_probability: 0.0,
             // remaining: 0.0,
             visits: 0,
-            // num_in_flight: 0,
+            in_flight: 0,
             index,
-            terminal_type: Terminal::NonTerminal,
-            upper_bound: GameResult::Ongoing,
-            lower_bound: GameResult::Ongoing,
+            num_edges: 0,
+            mover,
+            status: Status::new(),
         }
     }
 
@@ -137,11 +293,10 @@ impl<G: GameImpl> Node<G> {
 
     /// Returns the distribution of visits to the children of this node.
     pub fn dist(&self, tree: &[Self]) -> Vec<u64> {
-        let mut dist = vec![0; G::POLICY_DIM];
+        let mut dist = vec![0; G::policy_dim()];
         let mut edge = self.child;
         while !edge.is_null() {
-            let move_index =
-                self.edges.as_ref().unwrap()[tree[edge.index()].edge_index()].get_move(false).policy_index();
+            let move_index = self.edges().unwrap()[tree[edge.index()].edge_index()].get_move(false).policy_index();
             let visits = u64::from(tree[edge.index()].visits);
             dist[move_index] = visits;
             edge = tree[edge.index()].sibling;
@@ -159,15 +314,159 @@ impl<G: GameImpl> Node<G> {
         self.wl / f64::from(self.visits)
     }
 
+    /// Returns the number of visits to this node that are waiting on an evaluation.
+    pub const fn in_flight(&self) -> u16 {
+        self.in_flight
+    }
+
+    /// Returns the number of visits to this node, counting those waiting on an evaluation.
+    pub fn visits_with_in_flight(&self) -> u32 {
+        self.visits + u32::from(self.in_flight)
+    }
+
+    /// Returns the winrate of this node, counting the visits waiting on an evaluation as losses.
+    pub fn winrate_with_in_flight(&self) -> f64 {
+        self.wl / f64::from(self.visits_with_in_flight())
+    }
+
+    /// Adds a visit that is waiting on an evaluation.
+    pub fn add_in_flight(&mut self) {
+        self.in_flight += 1;
+    }
+
+    /// Removes a visit that was waiting on an evaluation, once it has arrived.
+    pub fn remove_in_flight(&mut self) {
+        self.in_flight -= 1;
+    }
+
+    /// Returns the sample variance of the values backpropagated through this node.
+    #[cfg(feature = "variance-stats")]
+    pub fn variance(&self) -> f64 {
+        let visits = f64::from(self.visits);
+        let mean = self.wl / visits;
+        mean.mul_add(-mean, self.wl_squared / visits).max(0.0)
+    }
+
+    /// Returns a bound on the variance of the values backpropagated through this node.
+    /// Without the sum of their squares, this is the variance of wins and losses with the same mean,
+    /// which no values in [0, 1] can exceed.
+    #[cfg(not(feature = "variance-stats"))]
+    pub fn variance(&self) -> f64 {
+        let mean = self.winrate();
+        mean * (1.0 - mean)
+    }
+
     /// Add a visit to this node.
     pub fn add_visit(&mut self, value: f64) {
         self.wl += value;
+        #[cfg(feature = "variance-stats")]
+        {
+            self.wl_squared += value * value;
+        }
         self.visits += 1;
     }
 
+    /// The number of bytes of edges this node owns, besides its own size.
+    pub fn edge_bytes(&self) -> usize {
+        self.edge_count() * std::mem::size_of::<Edge<G>>()
+    }
+
+    /// The number of edges from this node.
+    fn edge_count(&self) -> usize {
+        usize::from(self.num_edges & !MAPPED_EDGES)
+    }
+
     /// Returns a reference to the edges of this node.
     pub fn edges(&self) -> Option<&[Edge<G>]> {
-        self.edges.as_deref()
+        // SAFETY: the edges were allocated as a slice of `edge_count` elements in `expand`.
+        self.edges.map(|ptr| unsafe { std::slice::from_raw_parts(ptr.as_ptr(), self.edge_count()) })
+    }
+
+    /// Moves the edges of this node out of the heap and into the spare bytes of its memory-mapped tree,
+    /// if they fit. The tree must outlive the node, or be cleared before it is dropped.
+    pub fn move_edges_into(&mut self, spare: &mut Spare) {
+        let Some(ptr) = self.edges.filter(|_| self.num_edges & MAPPED_EDGES == 0) else {
+            return;
+        };
+        let len = self.edge_count();
+        let Some(mapped) = spare.alloc::<Edge<G>>(len) else {
+            return;
+        };
+        // SAFETY: the spare bytes are fresh, so do not overlap the boxed slice of `len` edges allocated by
+        // `set_edges`, which is freed once copied.
+        unsafe {
+            std::ptr::copy_nonoverlapping(ptr.as_ptr(), mapped.as_ptr(), len);
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr.as_ptr(), len)));
+        }
+        self.edges = Some(mapped);
+        self.num_edges |= MAPPED_EDGES;
+    }
+
+    /// The edges of this node and their number, if they live in the spare bytes of a memory-mapped tree.
+    pub fn mapped_edges(&self) -> Option<(NonNull<Edge<G>>, usize)> {
+        self.edges.filter(|_| self.num_edges & MAPPED_EDGES != 0).map(|ptr| (ptr, self.edge_count()))
+    }
+
+    /// Gives the edges of this node back to the spare bytes of its memory-mapped tree, if they live there,
+    /// leaving the node without edges.
+    pub fn release_edges(&mut self, spare: &mut Spare) {
+        if let Some((ptr, len)) = self.mapped_edges() {
+            spare.free(ptr, len);
+            self.edges = None;
+            self.num_edges = 0;
+        }
+    }
+
+    /// Frees the edges of this node, unless they belong to a memory-mapped tree.
+    fn free_edges(&mut self) {
+        if let Some(ptr) = self.edges.take() {
+            if self.num_edges & MAPPED_EDGES == 0 {
+                // SAFETY: the edges were allocated as a boxed slice of `num_edges` elements by `set_edges`.
+                unsafe {
+                    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr.as_ptr(), self.edge_count())));
+                }
+            }
+        }
+        self.num_edges = 0;
+    }
+
+    /// Mixes Dirichlet noise with concentration `alpha` into the policy of this node,
+    /// giving the noise a share of `weight`.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn add_noise(&mut self, weight: f64, alpha: f64) {
+        let Some(edges) = self.edges_mut() else {
+            return;
+        };
+        let noise = dirichlet(edges.len(), alpha);
+        for (edge, noise) in edges.iter_mut().zip(noise) {
+            edge.probability = (1.0 - weight).mul_add(f64::from(edge.probability), weight * noise) as f32;
+        }
+    }
+
+    /// Changes the softmax temperature of the policy of this node by the factor `ratio`, the old temperature
+    /// over the new one, as if its logits had been divided by the new temperature in the first place.
+    /// The edges keep the share of probability they had between them, which is less than one for a
+    /// partially-expanded node.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn retemper_policy(&mut self, ratio: f64) {
+        let Some(edges) = self.edges_mut() else {
+            return;
+        };
+        let mass = edges.iter().map(|edge| f64::from(edge.probability)).sum::<f64>();
+        let tempered = edges.iter().map(|edge| f64::from(edge.probability).powf(ratio)).sum::<f64>();
+        if tempered <= 0.0 {
+            return;
+        }
+        for edge in edges {
+            edge.probability = (f64::from(edge.probability).powf(ratio) / tempered * mass) as f32;
+        }
+    }
+
+    /// Returns a mutable reference to the edges of this node.
+    pub fn edges_mut(&mut self) -> Option<&mut [Edge<G>]> {
+        // SAFETY: the edges were allocated as a slice of `edge_count` elements in `expand`,
+        // and are uniquely borrowed through `self`.
+        self.edges.map(|ptr| unsafe { std::slice::from_raw_parts_mut(ptr.as_ptr(), self.edge_count()) })
     }
 
     /// Returns the first child of this node.
@@ -185,6 +484,11 @@ impl<G: GameImpl> Node<G> {
         self.index as usize
     }
 
+    /// Returns the player who made the move leading to this node.
+    pub const fn mover(&self) -> Player {
+        self.mover
+    }
+
     /// Returns the next sibling of this node.
     pub const fn sibling(&self) -> Handle {
         self.sibling
@@ -195,6 +499,14 @@ impl<G: GameImpl> Node<G> {
         &mut self.sibling
     }
 
+    /// Overwrites the parent, first-child and sibling handles of this node.
+    /// Used when the tree is compacted and nodes move to new indices.
+    pub fn relink(&mut self, parent: Handle, child: Handle, sibling: Handle) {
+        self.parent = parent;
+        self.child = child;
+        self.sibling = sibling;
+    }
+
     /// Returns the parent of the node.
     pub const fn non_null_parent(&self, _tree: &[Self]) -> Option<Handle> {
         if self.parent.is_null() {
@@ -206,6 +518,51 @@ impl<G: GameImpl> Node<G> {
 
     /// Expands this node, adding the legal moves and their policies.
     pub fn expand(&mut self, pos: G, policy: &[f32], uniform: bool) {
+        assert!(self.edges.is_none(), "attempted to expand a node twice");
+        self.set_edges(Self::legal_edges(pos, policy, uniform));
+        self.check_game_over(&pos);
+    }
+
+    /// Expands this node with only the `max_edges` most probable legal moves, most probable first,
+    /// leaving the rest for `widen` to add as the node's visits grow.
+    pub fn expand_partially(&mut self, pos: G, policy: &[f32], uniform: bool, max_edges: usize) {
+        assert!(self.edges.is_none(), "attempted to expand a node twice");
+        let mut moves = Self::legal_edges(pos, policy, uniform);
+        moves.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+        let max_edges = max_edges.max(1);
+        let partial = moves.len() > max_edges;
+        moves.truncate(max_edges);
+        self.set_edges(moves);
+        self.check_game_over(&pos);
+        self.status.set_partial(partial);
+    }
+
+    /// Adds the next most probable legal moves to a partially-expanded node, up to `max_edges` edges in all.
+    /// The existing edges keep their places and statistics, so the children still refer to the right ones.
+    pub fn widen(&mut self, pos: G, policy: &[f32], uniform: bool, max_edges: usize) {
+        let mut moves = SmallVec::<[Edge<G>; 2600]>::new();
+        moves.extend(self.edges().expect("attempted to widen an unexpanded node").iter().copied());
+        let mut present = vec![false; G::policy_dim()];
+        for edge in &moves {
+            present[edge.pov_move.policy_index()] = true;
+        }
+        let mut rest = Self::legal_edges(pos, policy, uniform);
+        rest.retain(|edge| !present[edge.pov_move.policy_index()]);
+        rest.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+        let room = max_edges.saturating_sub(moves.len());
+        let partial = rest.len() > room;
+        moves.extend(rest.into_iter().take(room));
+        self.set_edges(moves);
+        self.status.set_partial(partial);
+    }
+
+    /// Whether some legal moves of this node have no edge yet, because they are left to progressive widening.
+    pub const fn is_partial(&self) -> bool {
+        self.status.is_partial()
+    }
+
+    /// The legal moves of `pos`, with their probabilities under the softmax of the policy logits.
+    fn legal_edges(pos: G, policy: &[f32], uniform: bool) -> SmallVec<[Edge<G>; 2600]> {
         // TODO: FIX GENERIC SIZE SOMEHOW
         let mut moves = SmallVec::<[Edge<G>; 2600]>::new();
         let mut max_logit = -1000.0;
@@ -214,7 +571,14 @@ impl<G: GameImpl> Node<G> {
             if logit > max_logit {
                 max_logit = logit;
             }
-            moves.push(Edge { pov_move: m, probability: logit });
+            moves.push(Edge {
+                pov_move: m,
+                probability: logit,
+                #[cfg(feature = "rave")]
+                amaf_visits: 0,
+                #[cfg(feature = "rave")]
+                amaf_wl: 0.0,
+            });
             false
         });
         // normalize the probabilities
@@ -235,49 +599,156 @@ impl<G: GameImpl> Node<G> {
                 edge.probability
             );
         }
+        moves
+    }
 
+    /// Replaces the edge list of this node, freeing the old one.
+    fn set_edges(&mut self, moves: SmallVec<[Edge<G>; 2600]>) {
+        self.free_edges();
         // allocate the edge list and copy the moves into it
-        unsafe {
-            let layout = Layout::array::<Edge<G>>(moves.len()).unwrap();
-            // cast_ptr_alignment is fine because we're allocating using the Edge layout
-            #[allow(clippy::cast_ptr_alignment)]
-            let ptr = std::alloc::alloc(layout).cast::<Edge<G>>();
-            if ptr.is_null() {
-                std::alloc::handle_alloc_error(layout);
-            }
-            // copy the moves into the edge list
-            ptr.copy_from_nonoverlapping(moves.as_ptr(), moves.len());
-            let boxed_slice = Box::from_raw(std::slice::from_raw_parts_mut(ptr, moves.len()));
-            self.edges = Some(boxed_slice);
-        }
-
-        if let Some(result) = pos.outcome() {
-            self.terminal_type = Terminal::Terminal;
-            let game_result = match result {
-                Player::None => GameResult::Draw,
-                Player::First => GameResult::FirstPlayerWin,
-                Player::Second => GameResult::SecondPlayerWin,
-            };
-            self.upper_bound = game_result;
-            self.lower_bound = game_result;
-        }
+        self.num_edges = moves.len().try_into().ok().filter(|&len| len < MAPPED_EDGES).expect("too many legal moves");
+        let boxed_slice: Box<[Edge<G>]> = moves.into_iter().collect();
+        self.edges = NonNull::new(Box::into_raw(boxed_slice).cast::<Edge<G>>());
     }
 
     pub fn check_game_over(&mut self, pos: &G) {
         if let Some(result) = pos.outcome() {
-            self.terminal_type = Terminal::Terminal;
-            let game_result = match result {
-                Player::None => GameResult::Draw,
-                Player::First => GameResult::FirstPlayerWin,
-                Player::Second => GameResult::SecondPlayerWin,
-            };
-            self.upper_bound = game_result;
-            self.lower_bound = game_result;
+            self.status = Status::terminal(GameResult::from_outcome(result));
         }
     }
 
     /// Whether this node is terminal.
-    pub fn is_terminal(&self) -> bool {
-        self.terminal_type == Terminal::Terminal
+    pub const fn is_terminal(&self) -> bool {
+        self.status.is_terminal()
+    }
+
+    /// The outcome of the game from this node with best play, if it is known:
+    /// either because the node is terminal, or because it has been proven by its children.
+    pub const fn proven_outcome(&self) -> Option<Player> {
+        match self.status.proven_result() {
+            Some(result) => result.outcome(),
+            None => None,
+        }
+    }
+
+    /// Records that best play from this non-terminal node leads to `outcome`.
+    pub const fn set_proven(&mut self, outcome: Player) {
+        let partial = self.status.is_partial();
+        self.status = Status::proven(GameResult::from_outcome(outcome));
+        self.status.set_partial(partial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(not(any(feature = "huge-trees", feature = "variance-stats")))]
+    fn node_is_compact() {
+        assert_eq!(std::mem::size_of::<Node<crate::uttt::Board>>(), 40);
+    }
+
+    #[test]
+    #[cfg(not(feature = "variance-stats"))]
+    fn variance_is_bounded_by_that_of_wins_and_losses() {
+        let mut node = Node::<crate::uttt::Board>::new(Handle::null(), 0, Player::First);
+        for value in [0.0, 1.0, 0.5, 0.5] {
+            node.add_visit(value);
+        }
+        assert!((node.variance() - 0.25).abs() < 1e-9);
+        node.add_visit(1.0);
+        assert!((node.variance() - 0.24).abs() < 1e-9);
+    }
+
+    #[test]
+    #[cfg(feature = "variance-stats")]
+    fn variance_of_backpropagated_values() {
+        let mut node = Node::<crate::uttt::Board>::new(Handle::null(), 0, Player::First);
+        for value in [0.0, 1.0, 0.0, 1.0] {
+            node.add_visit(value);
+        }
+        assert!((node.variance() - 0.25).abs() < 1e-9);
+        let mut node = Node::<crate::uttt::Board>::new(Handle::null(), 0, Player::First);
+        node.add_visit(0.5);
+        node.add_visit(0.5);
+        assert!(node.variance().abs() < 1e-9);
+    }
+
+    #[test]
+    fn retempering_matches_expanding_at_the_new_temperature() {
+        let pos = crate::uttt::Board::default();
+        let mut logits = vec![0.0; crate::uttt::Board::policy_dim()];
+        let mut logit = 0.0;
+        pos.generate_moves(|mv| {
+            logits[mv.policy_index()] = logit;
+            logit += 0.25;
+            false
+        });
+        let cooled = logits.iter().map(|logit| logit / 2.0).collect::<Vec<_>>();
+        let mut node = Node::<crate::uttt::Board>::new(Handle::null(), 0, Player::First);
+        node.expand(pos, &logits, false);
+        node.retemper_policy(0.5);
+        let mut expected = Node::<crate::uttt::Board>::new(Handle::null(), 0, Player::First);
+        expected.expand(pos, &cooled, false);
+        for (edge, expected) in node.edges().unwrap().iter().zip(expected.edges().unwrap()) {
+            assert!((edge.probability() - expected.probability()).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn status_packing() {
+        assert!(!Status::new().is_terminal());
+        assert!(Status::terminal(GameResult::Draw).is_terminal());
+        assert_eq!(Status::terminal(GameResult::SecondPlayerWin).0, 0b1_1111);
+        assert_eq!(Status::new().proven_result(), None);
+        let proven = Status::proven(GameResult::FirstPlayerWin);
+        assert!(!proven.is_terminal());
+        assert_eq!(proven.proven_result(), Some(GameResult::FirstPlayerWin));
+    }
+
+    #[test]
+    fn widening_keeps_existing_edges_in_place() {
+        let pos = crate::uttt::Board::default();
+        let mut policy = vec![0.0; crate::uttt::Board::policy_dim()];
+        // each move is more probable than the one generated before it.
+        let mut moves = Vec::new();
+        let mut logit = 0.0;
+        pos.generate_moves(|mv| {
+            policy[mv.policy_index()] = logit;
+            logit += 1.0;
+            moves.push(mv);
+            false
+        });
+        let mut node = Node::<crate::uttt::Board>::new(Handle::null(), 0, Player::First);
+        node.expand_partially(pos, &policy, false, 2);
+        assert!(node.is_partial());
+        let first = node.edges().unwrap().iter().map(|edge| edge.get_move(false)).collect::<Vec<_>>();
+        assert_eq!(first, [moves[moves.len() - 1], moves[moves.len() - 2]]);
+        #[cfg(feature = "rave")]
+        node.edges_mut().unwrap()[1].add_amaf_visit(1.0);
+
+        node.widen(pos, &policy, false, 5);
+        assert!(node.is_partial());
+        let edges = node.edges().unwrap();
+        assert_eq!(edges.len(), 5);
+        assert_eq!(edges[..2].iter().map(|edge| edge.get_move(false)).collect::<Vec<_>>(), first);
+        #[cfg(feature = "rave")]
+        assert_eq!(edges[1].amaf_winrate(), Some(1.0));
+        assert_eq!(edges[2].get_move(false), moves[moves.len() - 3]);
+
+        node.widen(pos, &policy, false, usize::MAX);
+        assert!(!node.is_partial());
+        assert_eq!(node.edges().unwrap().len(), moves.len());
+    }
+
+    #[test]
+    fn noise_keeps_the_policy_normalised() {
+        let mut node = Node::<crate::uttt::Board>::new(Handle::null(), 0, Player::First);
+        node.expand(crate::uttt::Board::default(), &[], true);
+        node.add_noise(0.25, 0.3);
+        let total = node.edges().unwrap().iter().map(|edge| edge.probability()).sum::<f64>();
+        assert!((total - 1.0).abs() < 1e-4);
+        assert!(node.edges().unwrap().iter().all(|edge| edge.probability() >= 0.0));
     }
 }
@@ -0,0 +1,222 @@
+//! Parsing of positions and moves from protocol commands, with errors that say exactly what is wrong.
+//!
+//! The external game crates only report that a FEN or move failed to parse, so their notations are
+//! checked here field by field before they are handed over, and every move of a move list is parsed
+//! and checked for legality before any of it is applied.
+
+use std::fmt::{self, Display};
+
+use crate::game::GameImpl;
+
+/// Why a position or move could not be parsed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A field of a FEN is malformed.
+    Fen { field: String, reason: String },
+    /// A move is malformed.
+    Move { text: String, reason: String },
+    /// A well-formed move cannot be played in the position it was given for,
+    /// which is reached at `ply` of a move list, if it was part of one.
+    IllegalMove { text: String, ply: Option<usize>, fen: String },
+    /// The command around the position or move is malformed.
+    Command(String),
+}
+
+impl ParseError {
+    pub fn fen(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        Self::Fen { field: field.into(), reason: reason.into() }
+    }
+
+    pub fn mv(text: &str, reason: impl Into<String>) -> Self {
+        Self::Move { text: text.to_owned(), reason: reason.into() }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fen { field, reason } => write!(f, "invalid fen: {field}: {reason}"),
+            Self::Move { text, reason } => write!(f, "invalid move \"{text}\": {reason}"),
+            Self::IllegalMove { text, ply: Some(ply), fen } => {
+                write!(f, "illegal move \"{text}\" at ply {ply} of the move list, in position {fen}")
+            }
+            Self::IllegalMove { text, ply: None, fen } => write!(f, "illegal move \"{text}\" in position {fen}"),
+            Self::Command(reason) => write!(f, "invalid command: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// How the squares of a row are written in a FEN board field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Squares {
+    /// Runs of empty squares are counted by a digit, and `-` marks a blocked square, as in ataxx.
+    RunLength,
+    /// Every empty square is written as `.`, as in gomoku.
+    Dotted,
+}
+
+/// Checks the shape of a FEN of the form `<rows> <side> <counters...>`, where `rows` lists the ranks of a
+/// `size`x`size` board from the top down, separated by `/`, with stones written as `x` and `o`, and the
+/// side to move is `x` or `o`. Each of the trailing `counters`, named by this list, is optional.
+pub fn check_board_fen(fen: &str, size: usize, squares: Squares, counters: &[&str]) -> Result<(), ParseError> {
+    let mut fields = fen.split_ascii_whitespace();
+    let board = fields.next().ok_or_else(|| ParseError::fen("board", "the fen is empty"))?;
+    let rows = board.split('/').collect::<Vec<_>>();
+    if rows.len() != size {
+        return Err(ParseError::fen("board", format!("expected {size} ranks separated by '/', found {}", rows.len())));
+    }
+    for (i, row) in rows.iter().enumerate() {
+        let rank = size - i;
+        let mut count = 0;
+        for (column, c) in row.chars().enumerate() {
+            count += match (squares, c) {
+                (_, 'x' | 'o') | (Squares::RunLength, '-') | (Squares::Dotted, '.') => 1,
+                (Squares::RunLength, '1'..='9') => c.to_digit(10).unwrap() as usize,
+                (Squares::RunLength, _) => {
+                    let expected = format!("x, o, -, or a count of empty squares from 1 to {size}");
+                    return Err(ParseError::fen(
+                        format!("rank {rank}"),
+                        format!("unexpected {c:?} at character {}, expected {expected}", column + 1),
+                    ));
+                }
+                (Squares::Dotted, _) => {
+                    return Err(ParseError::fen(
+                        format!("rank {rank}"),
+                        format!("unexpected {c:?} at character {}, expected x, o, or .", column + 1),
+                    ));
+                }
+            };
+        }
+        if count != size {
+            return Err(ParseError::fen(
+                format!("rank {rank}"),
+                format!("{row:?} has {count} squares, expected {size}"),
+            ));
+        }
+    }
+    match fields.next() {
+        Some("x" | "o") => {}
+        Some(other) => return Err(ParseError::fen("side to move", format!("expected x or o, found {other:?}"))),
+        None => return Err(ParseError::fen("side to move", "missing, expected x or o after the board")),
+    }
+    for &field in counters {
+        let Some(counter) = fields.next() else {
+            break;
+        };
+        if counter.parse::<u32>().is_err() {
+            return Err(ParseError::fen(field, format!("expected a non-negative integer, found {counter:?}")));
+        }
+    }
+    if let Some(extra) = fields.next() {
+        return Err(ParseError::fen("end", format!("unexpected trailing {extra:?}")));
+    }
+    Ok(())
+}
+
+/// Checks that `text` names a square of a `size`x`size` board, as a file letter followed by a rank number.
+pub fn check_square(text: &str, size: usize) -> Result<(), String> {
+    let mut chars = text.chars();
+    let last_file = char::from(b'a' + u8::try_from(size - 1).expect("board too large to name squares"));
+    match chars.next() {
+        Some(file) if ('a'..=last_file).contains(&file) => {}
+        Some(file) => return Err(format!("file {file:?} is not between a and {last_file}")),
+        None => return Err("expected a square".into()),
+    }
+    match chars.as_str().parse::<usize>() {
+        Ok(rank) if (1..=size).contains(&rank) => Ok(()),
+        Ok(rank) => Err(format!("rank {rank} is not between 1 and {size}")),
+        Err(_) => Err(format!("expected a rank from 1 to {size} after the file, found {:?}", chars.as_str())),
+    }
+}
+
+/// Parses `text` as a move and checks that it is legal in `board`, which is at `ply` of a move list, if any.
+pub fn parse_legal_move<G: GameImpl>(board: &G, text: &str, ply: Option<usize>) -> Result<G::Move, ParseError> {
+    let mv = G::parse_move(text)?;
+    let mut legal = false;
+    board.generate_moves(|legal_mv| {
+        legal = legal_mv == mv;
+        legal
+    });
+    if !legal {
+        return Err(ParseError::IllegalMove { text: text.to_owned(), ply, fen: board.fen() });
+    }
+    Ok(mv)
+}
+
+/// Parses the arguments of a `position` command: `startpos`, which is `startpos` here, or `fen <FEN>`, then
+/// optionally `moves ...`. Returns the starting position and the moves played from it, every one of which is legal.
+pub fn parse_position<G: GameImpl>(text: &str, startpos: G) -> Result<(G, Vec<G::Move>), ParseError> {
+    let (board_part, moves_part) = text.split_once("moves").unwrap_or((text, ""));
+    let start = match board_part.trim() {
+        "startpos" => startpos,
+        board_part => {
+            let fen = board_part
+                .strip_prefix("fen ")
+                .ok_or_else(|| ParseError::Command(format!("expected startpos or fen <FEN>, found {board_part:?}")))?;
+            G::parse_fen(fen.trim())?
+        }
+    };
+    let mut board = start;
+    let mut moves = Vec::new();
+    for (ply, text) in moves_part.split_ascii_whitespace().enumerate() {
+        let mv = parse_legal_move(&board, text, Some(ply + 1))?;
+        board.make_move(mv);
+        moves.push(mv);
+    }
+    Ok((start, moves))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fen_errors_name_the_field() {
+        let check = |fen| check_board_fen(fen, 7, Squares::RunLength, &["halfmove clock", "fullmove number"]);
+        assert_eq!(check("x5o/7/7/7/7/7/o5x x 0 1"), Ok(()));
+        assert_eq!(check("x5o/7/7/7/7/7/o5x x"), Ok(()));
+        assert_eq!(
+            check("x5o/7/7/7/7/7/o5x").unwrap_err().to_string(),
+            "invalid fen: side to move: missing, expected x or o after the board"
+        );
+        assert_eq!(
+            check("x5o/7/7/7/7/7/o5x w 0 1").unwrap_err().to_string(),
+            "invalid fen: side to move: expected x or o, found \"w\""
+        );
+        assert_eq!(
+            check("x5o/7/7/8/7/7/o5x x").unwrap_err(),
+            ParseError::fen("rank 4", "\"8\" has 8 squares, expected 7")
+        );
+        assert_eq!(
+            check("x5o/7/7/3z3/7/7/o5x x").unwrap_err(),
+            ParseError::fen(
+                "rank 4",
+                "unexpected 'z' at character 2, expected x, o, -, or a count of empty squares from 1 to 7"
+            )
+        );
+        assert_eq!(
+            check("x5o/7/7/7/7/7/o5x x 0 one").unwrap_err(),
+            ParseError::fen("fullmove number", "expected a non-negative integer, found \"one\"")
+        );
+        let dotted = check_board_fen(".x./.../... o 2", 3, Squares::Dotted, &["ply"]);
+        assert_eq!(dotted, Ok(()));
+    }
+
+    #[test]
+    fn moves_are_checked_for_legality_before_applying() {
+        use crate::uttt::Board;
+        let (_, moves) = parse_position("startpos moves e5 d4", Board::default()).unwrap();
+        assert_eq!(moves.len(), 2);
+        // e5 sends the opponent to the centre board, so a1 is not available.
+        assert!(matches!(
+            parse_position("startpos moves e5 a1", Board::default()),
+            Err(ParseError::IllegalMove { ply: Some(2), .. })
+        ));
+        assert!(matches!(parse_position("startpos moves e5 z9", Board::default()), Err(ParseError::Move { .. })));
+        assert!(matches!(parse_position("fen", Board::default()), Err(ParseError::Command(_))));
+        assert_eq!(check_square("c7", 7), Ok(()));
+        assert!(check_square("h1", 7).is_err());
+    }
+}
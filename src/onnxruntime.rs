@@ -0,0 +1,83 @@
+//! An inference backend built on ONNX Runtime, for networks with operators that Kyanite cannot run
+//! and for devices it does not support, such as DirectML and ROCm GPUs.
+//!
+//! The runtime does its own graph optimisation and accepts any batch size, so unlike the CUDA backend,
+//! inputs are never padded.
+
+use kn_graph::ndarray::Dimension;
+use ort::{
+    execution_providers::{
+        CPUExecutionProvider, CUDAExecutionProvider, DirectMLExecutionProvider, ExecutionProviderDispatch,
+        ROCmExecutionProvider,
+    },
+    session::{builder::GraphOptimizationLevel, Session},
+    value::{Tensor, ValueType},
+};
+
+use crate::{
+    batching::{self, Device, Evaluation, Heads},
+    game::GameImpl,
+};
+
+/// A network loaded into ONNX Runtime, bound to one device.
+pub struct OnnxRuntime {
+    session: Session,
+    /// Where the heads of the network are among its outputs.
+    heads: Heads,
+}
+
+impl OnnxRuntime {
+    /// Loads the model at `path` onto `device`, failing if the runtime was built without support for it,
+    /// or if the model does not fit the features and moves of `G`.
+    pub fn load<G: GameImpl>(path: &str, device: Device) -> anyhow::Result<Self> {
+        let provider: ExecutionProviderDispatch = match device {
+            Device::Cpu => CPUExecutionProvider::default().build(),
+            Device::Cuda(ordinal) => CUDAExecutionProvider::default().with_device_id(ordinal).build(),
+            Device::DirectMl(ordinal) => DirectMLExecutionProvider::default().with_device_id(ordinal).build(),
+            Device::Rocm(ordinal) => ROCmExecutionProvider::default().with_device_id(ordinal).build(),
+        };
+        // a missing provider would otherwise fall back to the CPU without a word.
+        let session = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .with_execution_providers([provider.error_on_failure()])?
+            .commit_from_file(path)
+            .map_err(|e| anyhow::anyhow!("failed to load onnx network {path} into onnxruntime on {device}: {e}"))?;
+        let names = session.outputs.iter().map(|output| output.name.clone()).collect::<Vec<_>>();
+        let heads =
+            Heads::from_names(&names).map_err(|e| anyhow::anyhow!("failed to find the heads of {path}: {e}"))?;
+        // shapes are checked without the batch dimension, and a dimension left free in the model fits nothing.
+        let dims = |value_type: &ValueType| match value_type {
+            ValueType::Tensor { dimensions, .. } => {
+                dimensions.iter().skip(1).map(|&dim| usize::try_from(dim).unwrap_or(0)).collect()
+            }
+            _ => Vec::new(),
+        };
+        let inputs = session.inputs.iter().map(|input| dims(&input.input_type)).collect::<Vec<_>>();
+        let outputs = session.outputs.iter().map(|output| dims(&output.output_type)).collect::<Vec<_>>();
+        batching::check_shapes::<G>(path, &inputs, &outputs, &heads)?;
+        Ok(Self { session, heads })
+    }
+
+    /// Evaluates the boards, at most `batch_size` at a time.
+    pub fn evaluate<G: GameImpl>(&self, boards: &[G], batch_size: usize) -> anyhow::Result<Vec<Evaluation>> {
+        let features = G::tensor_dims(1).size();
+        let mut results = Vec::with_capacity(boards.len());
+        for chunk in boards.chunks(batch_size) {
+            let mut input = vec![0.0; chunk.len() * features];
+            G::fill_feature_batch(chunk, features, &mut input);
+            let shape = G::tensor_dims(chunk.len()).slice().to_vec();
+            let session_outputs = self.session.run(ort::inputs![Tensor::from_array((shape, input))?]?)?;
+            let outputs = (0..session_outputs.len())
+                .map(|index| session_outputs[index].try_extract_raw_tensor::<f32>().map(|(_, data)| data))
+                .collect::<Result<Vec<_>, _>>()?;
+            for batch_index in 0..chunk.len() {
+                let rows = outputs.iter().map(|output| {
+                    let width = output.len() / chunk.len();
+                    output[batch_index * width..(batch_index + 1) * width].to_vec()
+                });
+                results.push(self.heads.read(rows.collect()));
+            }
+        }
+        Ok(results)
+    }
+}
@@ -1,26 +1,438 @@
-use std::sync::{mpsc, Mutex};
+use std::sync::atomic::AtomicBool;
 
+use crate::{
+    game::{GameImpl, Player},
+    telemetry::GpuMonitor,
+    watchdog::Watchdog,
+};
+
+/// The skill level of full strength. Lower levels play weaker, for humans to play against.
+pub const MAX_SKILL: u32 = 20;
+/// The nodes searched at skill level zero, doubled every two levels above it.
+const SKILL_MIN_NODES: u64 = 16;
+/// The Elo ratings that the lowest and full skill levels roughly correspond to, with the levels
+/// between spread evenly over the range. These are guesses, not measurements.
+pub const MIN_ELO: u32 = 500;
+pub const MAX_ELO: u32 = 2500;
+
+/// The skill level that plays at roughly the given Elo rating.
+pub fn skill_for_elo(elo: u32) -> u32 {
+    (elo.clamp(MIN_ELO, MAX_ELO) - MIN_ELO) * MAX_SKILL / (MAX_ELO - MIN_ELO)
+}
+
+/// The formula used to trade off exploration against exploitation during selection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exploration {
+    /// Plain PUCT, as in `AlphaZero`.
+    Puct,
+    /// PUCT with the exploration term scaled by the observed variance of each child's value,
+    /// in the style of `UCB1-Tuned`. Builds without the `variance-stats` feature use a bound on the variance.
+    VarianceScaled,
+}
+
+/// How the value of moves that have not yet been visited is chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FpuStrategy {
+    /// A fixed value, the first-play urgency.
+    Absolute,
+    /// The value of the parent, less the FPU reduction scaled by the square root of the policy
+    /// of the moves already visited, as in Leela Chess Zero.
+    Reduction,
+    /// The value of a loss, so that unvisited moves are tried only on the strength of their policy.
+    Loss,
+}
+
+/// Settings that take different values at the root than in the interior of the tree,
+/// as the root benefits from more exploration. `None` uses the interior value.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RootOverrides {
+    pub c_puct: Option<f64>,
+    pub first_play_urgency: Option<f64>,
+}
+
+#[allow(clippy::struct_excessive_bools)]
 pub struct Params<'a> {
     pub c_puct: f64,
+    /// How quickly c-PUCT grows with the visits to a node, as in `AlphaZero`: at a node with `N` visits,
+    /// it is `c_puct + cpuct_factor * ln((N + cpuct_base + 1) / cpuct_base)`. Zero keeps c-PUCT constant.
+    pub cpuct_factor: f64,
+    /// The number of visits over which c-PUCT grows by roughly `cpuct_factor * ln 2`.
+    pub cpuct_base: f64,
+    /// The ply of the game by which c-PUCT has decayed linearly to `cpuct_decay_floor` of its value, so that
+    /// early moves explore more and later ones play sharper. Zero keeps c-PUCT the same all game.
+    pub cpuct_decay_plies: usize,
+    /// The share of c-PUCT that is left once it has fully decayed.
+    pub cpuct_decay_floor: f64,
+    /// The number of moves played in the game before the root, which c-PUCT decays over.
+    /// The engine keeps this up to date as moves are played.
+    pub game_ply: usize,
+    /// How the value of moves that have not yet been visited is chosen.
+    pub fpu_strategy: FpuStrategy,
+    /// The value assumed of moves that have not yet been visited, under `FpuStrategy::Absolute`.
+    pub first_play_urgency: f64,
+    /// How far below the parent's value unvisited moves are assumed to be, under `FpuStrategy::Reduction`.
+    pub fpu_reduction: f64,
+    /// The share of the root's policy replaced by Dirichlet noise on expansion.
+    pub noise_weight: f64,
+    /// The share of the policy of every other node replaced by Dirichlet noise on expansion, which is zero
+    /// unless asked for, as noise below the root only dilutes the search.
+    pub interior_noise_weight: f64,
+    /// The concentration of the Dirichlet noise.
+    pub noise_alpha: f64,
+    /// Values of the above that apply at the root instead.
+    pub root: RootOverrides,
+    /// The exploration formula to use in selection.
+    pub exploration: Exploration,
+    /// The equivalence parameter of RAVE: the number of visits at which a child's own value and its
+    /// all-moves-as-first value are weighted equally. `None` disables RAVE, which builds without the `rave`
+    /// feature do not keep the statistics for.
+    pub rave_equivalence: Option<f64>,
+    /// The number of root moves sampled by Gumbel-Top-k, between which a search with a node limit shares
+    /// its visits by sequential halving, in place of PUCT at the root. `None` selects root moves by PUCT.
+    pub gumbel_root_moves: Option<usize>,
+    /// The number of most probable moves given edges when a node below the root is expanded, under progressive
+    /// widening, which adds more as the node's visits grow. `None` gives every legal move an edge at once.
+    pub widening_min_edges: Option<usize>,
+    /// How fast progressive widening adds edges: a node with `N` visits has `widening_min_edges * (N + 1)^exponent`.
+    pub widening_exponent: f64,
+    /// The value of a draw to the side to move at the root, from 0, as bad as a loss, to 1, as good as a win.
+    /// Below a half, the engine avoids draws, and above it, seeks them; the opponent is taken to value draws
+    /// the other way round.
+    pub draw_score: f64,
+    /// The probability that selection picks a uniformly random child rather than the best-scoring one.
+    /// This is a baseline for exploration experiments, and is zero in play.
+    pub epsilon: f64,
+    /// The temperature of the softmax that turns the policy logits of the root into move probabilities.
     pub root_policy_softmax_temp: f32,
-    /// A handle to a receiver for stdin.
-    pub stdin_rx: Option<&'a Mutex<mpsc::Receiver<String>>>,
+    /// The temperature of the policy softmax below the root. Above one flattens an overconfident policy,
+    /// and below one sharpens an underconfident one.
+    pub policy_softmax_temp: f32,
+    /// The least logit a legal move is given before the softmax, so that no legal move a network has learned
+    /// to all but rule out is left unsearchable. `None` leaves the logits as they are.
+    pub policy_logit_floor: Option<f32>,
+    /// The weight of the network's value in the evaluation of a leaf, with the rest given to the result of a
+    /// random rollout from it. One trusts the network alone, and zero uses only rollouts, guided by the policy.
+    pub value_blend: f32,
+    /// The temperature at which the move played is sampled from the root visit distribution, with each
+    /// move chosen in proportion to its visits raised to the power `1 / temperature`.
+    /// Zero always plays the most-visited move.
+    pub temperature: f64,
+    /// The skill level below `MAX_SKILL` at which to play weaker than full strength, by capping the nodes searched,
+    /// raising the temperature of move selection, and now and then playing a move on the strength of its prior alone.
+    /// `None` plays at full strength.
+    pub skill: Option<u32>,
+    /// The seed of the random numbers each search draws, for noise, move sampling, and rollouts, so that a search
+    /// from the same tree with the same node limit is reproduced exactly. `None` seeds every search differently.
+    pub seed: Option<u64>,
+    /// Multiplier on the soft time limit when the best move changes late in the search.
+    pub panic_time_multiplier: f64,
+    /// The most time, in milliseconds, to spend on a single move under dynamic time controls.
+    pub max_move_time: Option<u64>,
+    /// Whether to stop searching once the most-visited root move leads the runner-up by more visits
+    /// than the search has left to spend.
+    pub easy_move: bool,
+    /// The percentage of the root visits at which the most-visited move is played without searching further.
+    /// `None` searches on however dominant a move becomes.
+    pub easy_move_share: Option<u64>,
+    /// Our remaining clock time, in milliseconds, below which moves are fast moves: played from the
+    /// policy after at most a small verification search, as a full search would risk losing on time.
+    pub fast_move_threshold: u64,
+    /// Whether to make every move a fast move, whatever the clock.
+    pub policy_only: bool,
+    /// The nodes of the verification search of a fast move. With none, the policy's top move is played.
+    pub fast_move_nodes: u64,
+    /// Whether a new position keeps the subtree of the old tree that is rooted at it, if there is one.
+    pub reuse_tree: bool,
+    /// Whether to propagate proven wins, losses, and draws up the tree, so that proven nodes are no
+    /// longer searched, and the search ends once the root is proven.
+    pub solver: bool,
+    /// Whether a position reached by another move order than a node already searched takes that node's
+    /// policy and value rather than being evaluated again. The nodes are not merged, so this is a cache of
+    /// evaluations rather than a search of a graph.
+    pub eval_cache: bool,
+    /// The number of root moves whose lines of best play are reported.
+    pub multi_pv: usize,
+    /// Whether to play the move whose value has the best lower confidence bound, rather than the most-visited move,
+    /// so that barely-searched moves are not played on the strength of a few lucky visits.
+    pub lcb_selection: bool,
+    /// The number of leaves selected, under virtual loss, and sent to the executor together in each round
+    /// of the search. One evaluates every leaf before the next is selected.
+    pub batch_leaves: usize,
+    /// The number of nodes the tree may hold before it is compacted.
+    pub max_tree_nodes: usize,
+    /// The least time, in milliseconds, between streamed snapshots of the tree.
+    pub snapshot_interval: u64,
+    /// The number of plies below the root included in each snapshot.
+    pub snapshot_depth: usize,
+    /// The number of most-visited children of each node included in each snapshot.
+    pub snapshot_width: usize,
+    /// A flag that stops the search as soon as it is set.
+    pub stop: Option<&'a AtomicBool>,
     /// Whether to print search info.
     pub do_stdout: bool,
+    /// A watchdog to arm with the hard time limit of each search.
+    pub watchdog: Option<&'a Watchdog>,
+    /// A monitor whose GPU samples are reported alongside the search info.
+    pub gpu_monitor: Option<&'a GpuMonitor>,
 }
 
 impl Default for Params<'_> {
     fn default() -> Self {
-        Self { c_puct: 2.50, root_policy_softmax_temp: 1.3, stdin_rx: None, do_stdout: false }
+        Self {
+            c_puct: 2.50,
+            cpuct_factor: 0.0,
+            cpuct_base: 19652.0,
+            cpuct_decay_plies: 0,
+            cpuct_decay_floor: 0.5,
+            game_ply: 0,
+            // pure MCTS has no policy to order unvisited moves, so it tries each of them before any twice.
+            fpu_strategy: FpuStrategy::Absolute,
+            first_play_urgency: if cfg!(feature = "pure-mcts") { f64::INFINITY } else { 0.5 },
+            fpu_reduction: 0.3,
+            noise_weight: 0.0,
+            interior_noise_weight: 0.0,
+            noise_alpha: 0.3,
+            root: RootOverrides::default(),
+            exploration: Exploration::Puct,
+            rave_equivalence: None,
+            gumbel_root_moves: None,
+            widening_min_edges: None,
+            widening_exponent: 0.5,
+            draw_score: 0.5,
+            epsilon: 0.0,
+            root_policy_softmax_temp: 1.3,
+            policy_softmax_temp: 1.0,
+            policy_logit_floor: None,
+            value_blend: 1.0,
+            temperature: 0.0,
+            skill: None,
+            seed: None,
+            panic_time_multiplier: 1.5,
+            max_move_time: None,
+            easy_move: false,
+            easy_move_share: None,
+            fast_move_threshold: 100,
+            policy_only: false,
+            fast_move_nodes: 0,
+            reuse_tree: true,
+            solver: true,
+            eval_cache: false,
+            multi_pv: 1,
+            lcb_selection: false,
+            batch_leaves: 1,
+            max_tree_nodes: 20_000_000,
+            snapshot_interval: 250,
+            snapshot_depth: 3,
+            snapshot_width: 4,
+            stop: None,
+            do_stdout: false,
+            watchdog: None,
+            gpu_monitor: None,
+        }
     }
 }
 
 impl<'a> Params<'a> {
-    pub const fn with_stdin_rx(self, stdin_rx: &'a Mutex<mpsc::Receiver<String>>) -> Self {
-        Self { stdin_rx: Some(stdin_rx), ..self }
+    pub const fn with_stop(self, stop: &'a AtomicBool) -> Self {
+        Self { stop: Some(stop), ..self }
     }
 
     pub const fn with_stdout(self, do_stdout: bool) -> Self {
         Self { do_stdout, ..self }
     }
+
+    pub const fn with_watchdog(self, watchdog: &'a Watchdog) -> Self {
+        Self { watchdog: Some(watchdog), ..self }
+    }
+
+    pub const fn with_gpu_monitor(self, gpu_monitor: &'a GpuMonitor) -> Self {
+        Self { gpu_monitor: Some(gpu_monitor), ..self }
+    }
+
+    /// Applies the logit floor and the policy softmax temperature of a node `depth` plies below the root
+    /// to its policy logits.
+    #[cfg_attr(feature = "pure-mcts", allow(dead_code))]
+    pub fn adjust_logits(&self, logits: &mut [f32], depth: usize) {
+        let temperature = if depth == 0 { self.root_policy_softmax_temp } else { self.policy_softmax_temp };
+        for logit in logits {
+            if let Some(floor) = self.policy_logit_floor {
+                *logit = logit.max(floor);
+            }
+            // these are logits, so we can just divide by the temperature
+            *logit /= temperature;
+        }
+    }
+
+    /// The number of edges a node with `visits` visits may have under progressive widening, if it is enabled.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_precision_loss, clippy::cast_sign_loss)]
+    pub fn widening_edges(&self, visits: u32) -> Option<usize> {
+        let min_edges = self.widening_min_edges?;
+        Some((min_edges as f64 * f64::from(visits + 1).powf(self.widening_exponent)).ceil() as usize)
+    }
+
+    /// The c-PUCT of a node `depth` plies below the root with `visits` visits.
+    pub fn c_puct_at(&self, depth: usize, visits: u32) -> f64 {
+        let c_init = if depth == 0 { self.root.c_puct.unwrap_or(self.c_puct) } else { self.c_puct };
+        let c_puct = if self.cpuct_factor == 0.0 {
+            c_init
+        } else {
+            let growth = ((f64::from(visits) + self.cpuct_base + 1.0) / self.cpuct_base).ln();
+            self.cpuct_factor.mul_add(growth, c_init)
+        };
+        c_puct * self.cpuct_decay()
+    }
+
+    /// The factor c-PUCT is scaled by at the current ply of the game, falling linearly from one at the start
+    /// of the game to `cpuct_decay_floor` at `cpuct_decay_plies`.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn cpuct_decay(&self) -> f64 {
+        if self.cpuct_decay_plies == 0 {
+            return 1.0;
+        }
+        let progress = (self.game_ply as f64 / self.cpuct_decay_plies as f64).min(1.0);
+        progress.mul_add(self.cpuct_decay_floor - 1.0, 1.0)
+    }
+
+    /// The first-play urgency of nodes `depth` plies below the root, whose value for the side to move
+    /// is `parent_q`, if it has been visited, and whose visited children hold `visited_policy` of its policy.
+    pub fn first_play_urgency_at(&self, depth: usize, parent_q: Option<f64>, visited_policy: f64) -> f64 {
+        let absolute = if depth == 0 {
+            self.root.first_play_urgency.unwrap_or(self.first_play_urgency)
+        } else {
+            self.first_play_urgency
+        };
+        match (self.fpu_strategy, parent_q) {
+            (FpuStrategy::Absolute, _) | (FpuStrategy::Reduction, None) => absolute,
+            (FpuStrategy::Reduction, Some(parent_q)) => self.fpu_reduction.mul_add(-visited_policy.sqrt(), parent_q),
+            (FpuStrategy::Loss, _) => 0.0,
+        }
+    }
+
+    /// How far below full strength the skill level is, from zero at full strength to one at skill level zero.
+    fn weakness(&self) -> f64 {
+        self.skill.map_or(0.0, |skill| f64::from(MAX_SKILL - skill.min(MAX_SKILL)) / f64::from(MAX_SKILL))
+    }
+
+    /// The most nodes a search may visit at the skill level, if it is limited.
+    pub fn skill_node_cap(&self) -> Option<u64> {
+        self.skill.filter(|&skill| skill < MAX_SKILL).map(|skill| SKILL_MIN_NODES << (skill / 2))
+    }
+
+    /// The temperature at which the move played is sampled, which is at least as high as the skill level demands.
+    pub fn move_temperature(&self) -> f64 {
+        self.temperature.max(self.weakness())
+    }
+
+    /// The chance that the move played is sampled from the root's policy instead of from its visits,
+    /// so that weaker skill levels sometimes play moves that merely look plausible.
+    pub fn blunder_chance(&self) -> f64 {
+        self.weakness() / 4.0
+    }
+
+    /// The value of a draw to `mover`, when searching from `root`.
+    pub fn draw_value<G: GameImpl>(&self, root: &G, mover: Player) -> f64 {
+        if mover == root.to_move() {
+            self.draw_score
+        } else {
+            1.0 - self.draw_score
+        }
+    }
+
+    /// The noise weight of nodes `depth` plies below the root.
+    pub fn noise_weight_at(&self, depth: usize) -> f64 {
+        if depth == 0 {
+            self.noise_weight
+        } else {
+            self.interior_noise_weight
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fpu_strategies_value_unvisited_moves() {
+        let params = Params { first_play_urgency: 0.5, fpu_reduction: 0.2, ..Params::default() };
+        assert!((params.first_play_urgency_at(3, Some(0.9), 0.25) - 0.5).abs() < 1e-9);
+        let reduction = Params { fpu_strategy: FpuStrategy::Reduction, ..params };
+        assert!((reduction.first_play_urgency_at(3, Some(0.9), 0.25) - 0.8).abs() < 1e-9);
+        // an unvisited parent has no value to reduce from.
+        assert!((reduction.first_play_urgency_at(3, None, 0.0) - 0.5).abs() < 1e-9);
+        let loss = Params { fpu_strategy: FpuStrategy::Loss, ..params };
+        assert!(loss.first_play_urgency_at(0, Some(0.9), 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn noise_is_only_added_below_the_root_when_asked_for() {
+        let root_only = Params { noise_weight: 0.25, ..Params::default() };
+        assert!((root_only.noise_weight_at(0) - 0.25).abs() < 1e-9);
+        assert!(root_only.noise_weight_at(1) == 0.0);
+        let everywhere = Params { interior_noise_weight: 0.1, ..root_only };
+        assert!((everywhere.noise_weight_at(3) - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn draws_are_valued_from_the_roots_point_of_view() {
+        let root = crate::uttt::Board::default();
+        let params = Params { draw_score: 0.3, ..Params::default() };
+        assert!((params.draw_value(&root, root.to_move()) - 0.3).abs() < 1e-9);
+        assert!((params.draw_value(&root, root.to_move().opposite()) - 0.7).abs() < 1e-9);
+    }
+
+    #[test]
+    fn logits_are_floored_then_tempered() {
+        let params = Params { policy_softmax_temp: 2.0, policy_logit_floor: Some(-4.0), ..Params::default() };
+        let mut logits = [2.0, -1.0, -30.0];
+        params.adjust_logits(&mut logits, 1);
+        for (logit, expected) in logits.into_iter().zip([1.0, -0.5, -2.0]) {
+            assert!((logit - expected).abs() < 1e-6);
+        }
+        let mut root_logits = [1.3, -30.0];
+        Params::default().adjust_logits(&mut root_logits, 0);
+        assert!((root_logits[0] - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cpuct_grows_with_visits() {
+        let constant = Params { c_puct: 2.0, ..Params::default() };
+        assert!((constant.c_puct_at(1, 1_000_000) - 2.0).abs() < 1e-9);
+        let growing = Params { cpuct_factor: 1.0, cpuct_base: 100.0, ..constant };
+        assert!((growing.c_puct_at(1, 0) - 2.0).abs() < 0.01);
+        // N + base + 1 = e * base
+        assert!((growing.c_puct_at(1, 171) - 3.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn cpuct_decays_over_the_game() {
+        let decaying = Params { c_puct: 2.0, cpuct_decay_plies: 40, cpuct_decay_floor: 0.25, ..Params::default() };
+        assert!((decaying.c_puct_at(1, 10) - 2.0).abs() < 1e-9);
+        let midgame = Params { game_ply: 20, ..decaying };
+        assert!((midgame.c_puct_at(0, 10) - 1.25).abs() < 1e-9);
+        let endgame = Params { game_ply: 100, ..decaying };
+        assert!((endgame.c_puct_at(1, 10) - 0.5).abs() < 1e-9);
+        // with no schedule, the ply makes no difference.
+        let constant = Params { cpuct_decay_plies: 0, ..endgame };
+        assert!((constant.c_puct_at(1, 10) - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn lower_skill_levels_play_weaker() {
+        let full = Params::default();
+        assert_eq!(full.skill_node_cap(), None);
+        assert!(full.move_temperature() == 0.0 && full.blunder_chance() == 0.0);
+        let weakest = Params { skill: Some(0), ..Params::default() };
+        let stronger = Params { skill: Some(10), ..Params::default() };
+        assert_eq!(weakest.skill_node_cap(), Some(SKILL_MIN_NODES));
+        assert!(stronger.skill_node_cap() > weakest.skill_node_cap());
+        assert!(stronger.move_temperature() < weakest.move_temperature());
+        assert!(stronger.blunder_chance() < weakest.blunder_chance());
+        // an explicit temperature above the skill level's is kept.
+        let hot = Params { temperature: 2.0, ..stronger };
+        assert!((hot.move_temperature() - 2.0).abs() < 1e-9);
+        assert_eq!(skill_for_elo(0), 0);
+        assert_eq!(skill_for_elo(1500), MAX_SKILL / 2);
+        assert_eq!(skill_for_elo(MAX_ELO), MAX_SKILL);
+    }
 }
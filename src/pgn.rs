@@ -0,0 +1,152 @@
+//! Reading and writing games in the PGN-like format used by match runners such as cutegames.
+
+use std::io::Write;
+
+use crate::game::{GameImpl, Player};
+
+/// A game parsed from a PGN file.
+pub struct PgnGame<G: GameImpl> {
+    /// The position the game started from.
+    pub root: G,
+    /// The moves of the game, in order.
+    pub moves: Vec<G::Move>,
+    /// The winner of the game, or `None` if the result is not recorded.
+    pub outcome: Option<Player>,
+}
+
+/// Writes a game, with its starting position and result in the tags.
+pub fn write_game<G: GameImpl>(
+    out: &mut impl Write,
+    event: &str,
+    root: &G,
+    moves: &[G::Move],
+    outcome: Player,
+) -> std::io::Result<()> {
+    let result = match outcome {
+        Player::First => "1-0",
+        Player::Second => "0-1",
+        Player::None => "1/2-1/2",
+    };
+    writeln!(out, "[Event \"{event}\"]")?;
+    writeln!(out, "[Result \"{result}\"]")?;
+    writeln!(out, "[FEN \"{}\"]", root.fen())?;
+    writeln!(out, "[PlyCount \"{}\"]", moves.len())?;
+    writeln!(out)?;
+    for mv in moves {
+        write!(out, "{mv} ")?;
+    }
+    writeln!(out, "{result}")?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Parses every game in the text, skipping games with unparseable or illegal moves, and failing on a game
+/// whose `FEN` tag holds no position of the game. Games without a `FEN` tag start from the default position.
+pub fn parse_games<G: GameImpl>(text: &str) -> anyhow::Result<Vec<PgnGame<G>>> {
+    let mut games = Vec::new();
+    let mut root = None;
+    let mut outcome = None;
+    let mut in_movetext = false;
+    let mut movetext = String::new();
+    for line in text.lines().map(str::trim) {
+        if let Some(tag) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            if in_movetext {
+                // a new game's tags have begun.
+                games.extend(finish_game(root.take(), outcome.take(), &movetext));
+                movetext.clear();
+                in_movetext = false;
+            }
+            let Some((name, value)) = tag.split_once(' ') else {
+                continue;
+            };
+            let value = value.trim().trim_matches('"');
+            match name {
+                "FEN" => {
+                    root = Some(G::parse_fen(value).map_err(|e| anyhow::anyhow!("invalid FEN tag {value:?}: {e}"))?);
+                }
+                "Result" => outcome = parse_result(value),
+                _ => {}
+            }
+        } else if !line.is_empty() {
+            in_movetext = true;
+            movetext.push_str(line);
+            movetext.push(' ');
+        }
+    }
+    if in_movetext {
+        games.extend(finish_game(root, outcome, &movetext));
+    }
+    Ok(games)
+}
+
+fn parse_result(result: &str) -> Option<Player> {
+    match result {
+        "1-0" => Some(Player::First),
+        "0-1" => Some(Player::Second),
+        "1/2-1/2" => Some(Player::None),
+        _ => None,
+    }
+}
+
+fn finish_game<G: GameImpl>(root: Option<G>, outcome: Option<Player>, movetext: &str) -> Option<PgnGame<G>> {
+    let root = root.unwrap_or_default();
+    let mut board = root;
+    let mut moves = Vec::new();
+    for token in movetext.split_ascii_whitespace() {
+        // skip move numbers such as "1." and "1...", and the result.
+        if token.ends_with('.') || parse_result(token).is_some() || token == "*" {
+            continue;
+        }
+        let mv = token.parse::<G::Move>().ok()?;
+        let mut legal = false;
+        board.generate_moves(|legal_mv| {
+            legal = legal_mv == mv;
+            legal
+        });
+        if !legal {
+            return None;
+        }
+        board.make_move(mv);
+        moves.push(mv);
+    }
+    Some(PgnGame { root, moves, outcome })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uttt::Board;
+
+    #[test]
+    fn written_games_parse_back() {
+        let root = Board::default();
+        let mut board = root;
+        let mut moves = Vec::new();
+        for _ in 0..3 {
+            let mut first = None;
+            board.generate_moves(|mv| {
+                first = Some(mv);
+                true
+            });
+            let mv = first.unwrap();
+            board.make_move(mv);
+            moves.push(mv);
+        }
+        let mut text = Vec::new();
+        write_game(&mut text, "test", &root, &moves, Player::Second).unwrap();
+        write_game(&mut text, "test", &root, &moves[..1], Player::None).unwrap();
+
+        let games = parse_games::<Board>(std::str::from_utf8(&text).unwrap()).unwrap();
+        assert_eq!(games.len(), 2);
+        assert_eq!(games[0].moves, moves);
+        assert_eq!(games[0].outcome, Some(Player::Second));
+        assert_eq!(games[1].moves, moves[..1]);
+        assert_eq!(games[1].outcome, Some(Player::None));
+    }
+
+    #[test]
+    fn games_with_invalid_fens_are_rejected() {
+        let text = "[Result \"1-0\"]\n[FEN \"not a position\"]\n\n1-0\n";
+        assert!(parse_games::<Board>(text).is_err());
+    }
+}
@@ -1,41 +1,39 @@
-use kn_graph::optimizer::OptimizerSettings;
+use std::fmt::Write;
 
 use crate::{
-    batching,
+    batching::{self, Backend, Priority},
     engine::SearchResults,
     game::{GameImpl, Player},
     params::Params,
     timemgmt::Limits,
 };
 
-pub fn play_game_vs_user<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<()> {
-    // Load an onnx file into a Graph.
-    let raw_graph = kn_graph::onnx::load_graph_from_onnx_path(net_path.unwrap_or("./model.onnx"), false).unwrap();
-    // Optimise the graph.
-    let graph = kn_graph::optimizer::optimize_graph(&raw_graph, OptimizerSettings::default());
-    // Deallocate the raw graph.
-    std::mem::drop(raw_graph);
+/// Plays a game against the user in the terminal, from `starting_position`.
+/// The board is drawn with coloured Unicode stones, unless `ascii` is set.
+pub fn play_game_vs_user<G: GameImpl>(starting_position: G, net_path: Option<&str>, ascii: bool) -> anyhow::Result<()> {
+    let network = batching::load_network_or_uniform::<G>(net_path.unwrap_or("./model.onnx"), Backend::default())?;
 
-    let starting_position = G::default();
     // clear the screen
     print!("\x1B[2J\x1B[1;1H");
-    println!("{starting_position}");
+    println!("{}", render(&starting_position, None, ascii));
 
     let mut response = String::new();
     println!("Would you like to move first? (y/n)");
     std::io::stdin().read_line(&mut response).unwrap();
     let user_goes_first = response.trim().to_lowercase() == "y";
-    let mut user_to_move = user_goes_first;
+    let user_player = if user_goes_first { Player::First } else { Player::Second };
 
     let params = Params::default();
     let limits = Limits::movetime(1000);
-    let executor = batching::executor(&graph, 1)?;
-    let mut engine =
-        crate::engine::Engine::new(params, limits, &starting_position, executor.into_iter().next().unwrap());
+    let executor = batching::executor(&network, 1)?.into_iter().next().unwrap().with_priority(Priority::Interactive);
+    let mut engine = crate::engine::Engine::new(params, limits, &starting_position, executor);
     let mut board = starting_position;
+    // the user's chance of winning, as estimated by each of the engine's searches.
+    let mut history = Vec::new();
 
     loop {
-        if user_to_move {
+        // turns can span several moves, so ask the board whose turn it is.
+        if board.to_move() == user_player {
             println!("Your move:");
             let mut user_move = String::new();
             std::io::stdin().read_line(&mut user_move).unwrap();
@@ -43,6 +41,10 @@ pub fn play_game_vs_user<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<
             if user_move == "quit" {
                 return Ok(());
             }
+            if user_move == "graph" {
+                print_history(&history);
+                continue;
+            }
             if let Ok(m) = user_move.parse() {
                 let mut legal = false;
                 board.generate_moves(|l| {
@@ -52,12 +54,12 @@ pub fn play_game_vs_user<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<
                     legal
                 });
                 if legal {
+                    let before = board;
                     board.make_move(m);
                     engine.set_position(&board);
                     // clear the screen
                     print!("\x1B[2J\x1B[1;1H");
-                    println!("{}", engine.root());
-                    user_to_move = false;
+                    println!("{}", render(&board, Some(&before), ascii));
                 } else {
                     println!("Illegal move: {user_move}");
                 }
@@ -65,13 +67,14 @@ pub fn play_game_vs_user<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<
                 println!("Invalid move: {user_move}");
             }
         } else {
-            let SearchResults { best_move, .. } = engine.go()?;
+            let SearchResults { best_move, value, .. } = engine.go()?;
+            history.push(1.0 - value);
+            let before = board;
             board.make_move(best_move);
             engine.set_position(&board);
             // clear the screen
             print!("\x1B[2J\x1B[1;1H");
-            println!("{}", engine.root());
-            user_to_move = true;
+            println!("{}", render(&board, Some(&before), ascii));
         }
 
         if engine.root().outcome().is_some() {
@@ -87,6 +90,103 @@ pub fn play_game_vs_user<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<
         Player::Second => println!("You lose!"),
         Player::None => println!("Draw!"),
     }
+    print_history(&history);
 
     Ok(())
 }
+
+/// Draws the board with file letters and rank numbers, highlighting the squares that changed
+/// since `previous`, which are those the last move touched.
+/// In ASCII mode, stones are `x` and `o`, and highlighted stones are capitalised.
+fn render<G: GameImpl>(board: &G, previous: Option<&G>, ascii: bool) -> String {
+    let owners = |board: &G| {
+        let mut owners = vec![Player::None; G::ownership_dim()];
+        board.fill_ownership(|index, player| owners[index] = player);
+        owners
+    };
+    let now = owners(board);
+    let before = previous.map_or_else(|| now.clone(), owners);
+    let width = G::board_width();
+    let (files, ranks) = board.files_and_ranks();
+    let label_width = ranks.to_string().len();
+
+    let mut out = String::new();
+    for rank in (0..ranks).rev() {
+        write!(out, "{:>label_width$} ", rank + 1).unwrap();
+        for file in 0..files {
+            let index = rank * width + file;
+            let Some(&owner) = now.get(index) else {
+                break;
+            };
+            let changed = before[index] != owner;
+            let cell = match (ascii, owner, changed) {
+                (true, Player::First, false) => "x".to_string(),
+                (true, Player::First, true) => "X".to_string(),
+                (true, Player::Second, false) => "o".to_string(),
+                (true, Player::Second, true) => "O".to_string(),
+                (true, Player::None, _) => ".".to_string(),
+                (false, player, changed) => {
+                    let (glyph, colour) = match player {
+                        Player::First => ('●', "\x1B[31m"),
+                        Player::Second => ('○', "\x1B[34m"),
+                        Player::None => ('·', "\x1B[2m"),
+                    };
+                    let highlight = if changed { "\x1B[43m" } else { "" };
+                    format!("{highlight}{colour}{glyph}\x1B[0m")
+                }
+            };
+            out.push_str(&cell);
+            out.push(' ');
+        }
+        out.push('\n');
+    }
+    out.push_str(&" ".repeat(label_width + 1));
+    for file in 0..files {
+        // files run out of letters on very wide boards, so carry on into the rest of ASCII.
+        out.push(char::from(b'a'.saturating_add(u8::try_from(file).unwrap_or(u8::MAX))));
+        out.push(' ');
+    }
+    out.truncate(out.trim_end().len());
+    out
+}
+
+/// Prints the user's estimated chance of winning over the course of the game.
+fn print_history(history: &[f64]) {
+    let (Some(first), Some(last)) = (history.first(), history.last()) else {
+        println!("No evaluations yet.");
+        return;
+    };
+    println!("Your chance of winning, over the engine's moves:");
+    println!("{:>3.0}% {} {:.0}%", first * 100.0, sparkline(history), last * 100.0);
+}
+
+/// Renders probabilities as a row of bars, one per value, from empty at 0 to full at 1.
+fn sparkline(values: &[f64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    values.iter().map(|&v| BARS[(v.clamp(0.0, 1.0) * 7.0).round() as usize]).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_boards_mark_the_last_move() {
+        let before = crate::uttt::Board::default();
+        let mut after = before;
+        after.make_move("b1".parse().unwrap());
+        let rendered = render(&after, Some(&before), true);
+        let lines = rendered.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(lines[0], "9 . . . . . . . . . ");
+        assert_eq!(lines[8], "1 . X . . . . . . . ");
+        assert_eq!(lines[9], "  a b c d e f g h i");
+    }
+
+    #[test]
+    fn sparkline_spans_the_full_range() {
+        assert_eq!(sparkline(&[0.0, 0.5, 1.0, 1.5]), "▁▅██");
+        assert_eq!(sparkline(&[]), "");
+    }
+}
@@ -0,0 +1,182 @@
+//! Proof-number search, for proving forced wins.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use crate::{
+    game::{GameImpl, Player},
+    timemgmt::{Limits, TimeManager},
+};
+
+/// A proof or disproof number that can never be reached.
+const INFINITY: u64 = u64::MAX;
+
+/// The most memory the proof tree may take, whatever the limits, as it is never pruned.
+const MAX_TREE_BYTES: usize = 1 << 30;
+
+/// The outcome of an attempt to prove a forced win.
+pub enum Proof<G: GameImpl> {
+    /// The side to move wins by force, with this line of play.
+    Win(Vec<G::Move>),
+    /// The side to move cannot force a win.
+    NoWin,
+    /// The search ran out of budget before deciding either way.
+    Unknown,
+}
+
+struct PnsNode<G: GameImpl> {
+    board: G,
+    /// The move leading to this node. Unused at the root.
+    mv: Option<G::Move>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    /// The number of leaves that must be proven to prove a win for the attacker here.
+    proof: u64,
+    /// The number of leaves that must be disproven to refute a win for the attacker here.
+    disproof: u64,
+}
+
+impl<G: GameImpl> PnsNode<G> {
+    fn new(board: G, mv: Option<G::Move>, parent: Option<usize>, attacker: Player) -> Self {
+        let (proof, disproof) = match board.outcome() {
+            Some(winner) if winner == attacker => (0, INFINITY),
+            Some(_) => (INFINITY, 0),
+            None => (1, 1),
+        };
+        Self { board, mv, parent, children: Vec::new(), proof, disproof }
+    }
+}
+
+/// Searches for a forced win for the side to move, within the given limits, until `stop` is set,
+/// and for no more than `MAX_TREE_BYTES` of nodes. Nodes where the attacker is to move are OR nodes,
+/// and all others are AND nodes, as a turn may span several moves.
+pub fn prove_win<G: GameImpl>(root: &G, limits: Limits, stop: Option<&AtomicBool>) -> Proof<G> {
+    let attacker = root.to_move();
+    let start = Instant::now();
+    let time_manager = TimeManager::new(limits, attacker == Player::First, 1.0);
+    let mut tree = vec![PnsNode::new(*root, None, None, attacker)];
+    let mut expansions = 0;
+    // each node is also listed among its parent's children.
+    let max_nodes = MAX_TREE_BYTES / (std::mem::size_of::<PnsNode<G>>() + std::mem::size_of::<usize>());
+
+    while tree[0].proof != 0 && tree[0].disproof != 0 {
+        let elapsed = u64::try_from(start.elapsed().as_millis()).expect("elapsed time overflow");
+        if time_manager.is_out_of_time(expansions, elapsed)
+            || tree.len() >= max_nodes
+            || stop.is_some_and(|stop| stop.load(Ordering::SeqCst))
+        {
+            return Proof::Unknown;
+        }
+        let leaf = most_proving(&tree, attacker);
+        expand(&mut tree, leaf, attacker);
+        update_ancestors(&mut tree, leaf, attacker);
+        expansions += 1;
+    }
+
+    if tree[0].disproof == 0 {
+        return Proof::NoWin;
+    }
+    // follow the proof: any proven move at OR nodes, and every reply in turn at AND nodes
+    // holds a proof, so take the one the opponent would hold out longest with.
+    let mut line = Vec::new();
+    let mut node = 0;
+    while !tree[node].children.is_empty() {
+        let children = tree[node].children.iter().copied();
+        node = if tree[node].board.to_move() == attacker {
+            children.filter(|&child| tree[child].proof == 0).min_by_key(|&child| subtree_size(&tree, child))
+        } else {
+            children.max_by_key(|&child| subtree_size(&tree, child))
+        }
+        .expect("proven node has no proven child");
+        line.push(tree[node].mv.expect("non-root node has no move"));
+    }
+    Proof::Win(line)
+}
+
+/// Descends from the root to the leaf whose expansion most helps to decide the root.
+fn most_proving<G: GameImpl>(tree: &[PnsNode<G>], attacker: Player) -> usize {
+    let mut node = 0;
+    while !tree[node].children.is_empty() {
+        let children = tree[node].children.iter().copied();
+        node = if tree[node].board.to_move() == attacker {
+            children.min_by_key(|&child| tree[child].proof)
+        } else {
+            children.min_by_key(|&child| tree[child].disproof)
+        }
+        .expect("internal node has no children");
+    }
+    node
+}
+
+fn expand<G: GameImpl>(tree: &mut Vec<PnsNode<G>>, node: usize, attacker: Player) {
+    let board = tree[node].board;
+    let mut children = Vec::new();
+    board.generate_moves(|mv| {
+        let mut child = board;
+        child.make_move(mv);
+        children.push(tree.len());
+        tree.push(PnsNode::new(child, Some(mv), Some(node), attacker));
+        false
+    });
+    tree[node].children = children;
+}
+
+/// Recomputes the proof and disproof numbers of `node` and its ancestors.
+fn update_ancestors<G: GameImpl>(tree: &mut [PnsNode<G>], node: usize, attacker: Player) {
+    let mut current = Some(node);
+    while let Some(node) = current {
+        let children = &tree[node].children;
+        let proofs = children.iter().map(|&child| tree[child].proof);
+        let disproofs = children.iter().map(|&child| tree[child].disproof);
+        let (proof, disproof) = if tree[node].board.to_move() == attacker {
+            (proofs.min().unwrap_or(INFINITY), disproofs.fold(0, u64::saturating_add))
+        } else {
+            (proofs.fold(0, u64::saturating_add), disproofs.min().unwrap_or(INFINITY))
+        };
+        tree[node].proof = proof;
+        tree[node].disproof = disproof;
+        current = tree[node].parent;
+    }
+}
+
+fn subtree_size<G: GameImpl>(tree: &[PnsNode<G>], node: usize) -> usize {
+    1 + tree[node].children.iter().map(|&child| subtree_size(tree, child)).sum::<usize>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        game::play_moves,
+        mnk::{Board, Dimensions},
+    };
+
+    #[test]
+    fn proves_a_win_against_an_edge_reply() {
+        let mut board = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        play_moves(&mut board, &["b2", "a2"]);
+        // o answered the centre with an edge, which loses by force.
+        let Proof::Win(line) = prove_win(&board, Limits::nodes(100_000), None) else {
+            panic!("failed to prove a forced win");
+        };
+        for mv in line {
+            board.make_move(mv);
+        }
+        assert_eq!(board.outcome(), Some(Player::First));
+    }
+
+    #[test]
+    fn disproves_a_drawn_position() {
+        let board = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        assert!(matches!(prove_win(&board, Limits::nodes(1_000_000), None), Proof::NoWin));
+    }
+
+    #[test]
+    fn a_stopped_proof_gives_up() {
+        let board = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        let stop = AtomicBool::new(true);
+        assert!(matches!(prove_win(&board, Limits::infinite(), Some(&stop)), Proof::Unknown));
+    }
+}
@@ -0,0 +1,188 @@
+//! A regression suite for the search, so that changes to the engine or the tree cannot alter search
+//! behaviour unnoticed.
+//!
+//! Each case searches a bundled position for a fixed number of nodes, evaluated by a seeded native
+//! network so that no model file is needed, and compares the best move and the root visit distribution
+//! with the golden result recorded alongside the position. Searches are deterministic apart from noise
+//! and epsilon-greedy selection, which the default parameters turn off.
+//!
+//! The golden file has one case per line, with tab-separated fields:
+//! `<game> <nodes> <fen> [<best move> <visits>]`, where the visits are listed as `<move>:<count>`,
+//! separated by commas, for each root move that was visited.
+//! A case without a result is new, and fails until it is blessed.
+
+use std::fmt::Write;
+
+use anyhow::Context;
+
+use crate::{
+    batching::{self, Network},
+    engine::{Engine, SearchResults},
+    game::{GameImpl, MovePolicyIndex},
+    native::NativeNet,
+    params::Params,
+    timemgmt::Limits,
+};
+
+/// The golden results, relative to the root of the repository.
+pub const GOLDEN_PATH: &str = "regress/golden.txt";
+
+/// The seed and width of the network that evaluates every case.
+const NETWORK_SEED: u64 = 0x7665_7269_7461_7321;
+const NETWORK_WIDTH: usize = 32;
+
+/// A position to search, and how far.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Case {
+    game: String,
+    nodes: u64,
+    fen: String,
+}
+
+/// What a search of a case found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Outcome {
+    best_move: String,
+    /// The visits to each visited root move, in move generation order.
+    visits: Vec<(String, u64)>,
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let visits = self.visits.iter().map(|(mv, visits)| format!("{mv}:{visits}")).collect::<Vec<_>>().join(",");
+        write!(f, "{}\t{visits}", self.best_move)
+    }
+}
+
+/// Parses a line of the golden file into a case and its golden result, if it has one.
+fn parse_line(line: &str) -> anyhow::Result<(Case, Option<Outcome>)> {
+    let mut fields = line.split('\t');
+    let (Some(game), Some(nodes), Some(fen)) = (fields.next(), fields.next(), fields.next()) else {
+        anyhow::bail!("expected a game, a node count, and a fen, separated by tabs");
+    };
+    let nodes = nodes.parse().with_context(|| format!("invalid node count {nodes:?}"))?;
+    let case = Case { game: game.to_owned(), nodes, fen: fen.to_owned() };
+    let result = fields.collect::<Vec<_>>();
+    let outcome = match result[..] {
+        [] => None,
+        [best_move, visits] => Some(Outcome {
+            best_move: best_move.to_owned(),
+            visits: visits.split(',').map(parse_visits).collect::<anyhow::Result<_>>()?,
+        }),
+        _ => anyhow::bail!("expected a best move and a visit distribution after the fen"),
+    };
+    Ok((case, outcome))
+}
+
+/// Parses the visits to one move, as `<move>:<visits>`.
+fn parse_visits(text: &str) -> anyhow::Result<(String, u64)> {
+    let (mv, visits) = text.split_once(':').with_context(|| format!("expected <move>:<visits>, found {text:?}"))?;
+    Ok((mv.to_owned(), visits.parse().with_context(|| format!("invalid visit count in {text:?}"))?))
+}
+
+/// Searches a case with the named game.
+fn run_case(case: &Case) -> anyhow::Result<Outcome> {
+    with_game!(case.game, |G| search::<G>(case))
+}
+
+fn search<G: GameImpl>(case: &Case) -> anyhow::Result<Outcome> {
+    let root = G::parse_fen(&case.fen)?;
+    anyhow::ensure!(root.outcome().is_none(), "the game is already over");
+    let network = Network::Native(NativeNet::seeded::<G>(NETWORK_SEED, NETWORK_WIDTH));
+    let eval_pipe = batching::executor::<G>(&network, 1)?.into_iter().next().unwrap();
+    let mut engine = Engine::new(Params::default(), Limits::nodes(case.nodes), &root, eval_pipe);
+    let SearchResults { best_move, root_dist, .. } = engine.go()?;
+    let mut visits = Vec::new();
+    root.generate_moves(|mv| {
+        let count = root_dist[mv.policy_index()];
+        if count > 0 {
+            visits.push((mv.to_string(), count));
+        }
+        false
+    });
+    Ok(Outcome { best_move: best_move.to_string(), visits })
+}
+
+/// Runs every case of the golden file `golden`, returning a description of each that did not
+/// reproduce its golden result.
+pub fn check(golden: &str) -> anyhow::Result<Vec<String>> {
+    let mut failures = Vec::new();
+    for (line_number, line) in golden.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (case, expected) =
+            parse_line(line).with_context(|| format!("line {} of the golden file", line_number + 1))?;
+        let outcome = run_case(&case).with_context(|| format!("failed to search {} {}", case.game, case.fen))?;
+        match expected {
+            Some(expected) if expected == outcome => {}
+            Some(expected) => failures.push(format!("{} {}: expected {expected}, got {outcome}", case.game, case.fen)),
+            None => failures.push(format!("{} {}: no golden result, got {outcome}", case.game, case.fen)),
+        }
+    }
+    Ok(failures)
+}
+
+/// Reruns every case of the golden file `golden`, returning the file with the results replaced.
+pub fn bless(golden: &str) -> anyhow::Result<String> {
+    let mut blessed = String::with_capacity(golden.len());
+    for (line_number, line) in golden.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            blessed.push_str(line);
+        } else {
+            let (case, _) = parse_line(line).with_context(|| format!("line {} of the golden file", line_number + 1))?;
+            let outcome = run_case(&case).with_context(|| format!("failed to search {} {}", case.game, case.fen))?;
+            write!(blessed, "{}\t{}\t{}\t{outcome}", case.game, case.nodes, case.fen)?;
+        }
+        blessed.push('\n');
+    }
+    Ok(blessed)
+}
+
+/// Runs the suite against the golden file at `path`, failing if any case changed,
+/// or with `bless`, records the current results as the golden ones.
+pub fn main(path: &str, bless_results: bool) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !cfg!(feature = "pure-mcts"),
+        "the regression suite searches with the network evaluator, which pure-mcts builds do not use"
+    );
+    let golden = std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+    if bless_results {
+        std::fs::write(path, bless(&golden)?).with_context(|| format!("failed to write {path}"))?;
+        println!("blessed {path}");
+        return Ok(());
+    }
+    let failures = check(&golden)?;
+    for failure in &failures {
+        println!("FAIL {failure}");
+    }
+    anyhow::ensure!(failures.is_empty(), "{} regression cases changed", failures.len());
+    println!("all regression cases match");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn golden_lines_parse() {
+        let (case, outcome) = parse_line("uttt\t100\tstartpos-fen\te5\td4:3,e5:97").unwrap();
+        assert_eq!(case, Case { game: "uttt".into(), nodes: 100, fen: "startpos-fen".into() });
+        let visits = vec![("d4".into(), 3), ("e5".into(), 97)];
+        assert_eq!(outcome, Some(Outcome { best_move: "e5".into(), visits }));
+        assert_eq!(outcome.unwrap().to_string(), "e5\td4:3,e5:97");
+        assert_eq!(parse_line("uttt\t100\tstartpos-fen").unwrap().1, None);
+        assert!(parse_line("uttt\t100").is_err());
+        assert!(parse_line("uttt\tmany\tstartpos-fen").is_err());
+        assert!(parse_line("uttt\t100\tstartpos-fen\te5\t97").is_err());
+    }
+
+    #[cfg(not(feature = "pure-mcts"))]
+    #[test]
+    fn search_matches_golden_results() {
+        let golden = include_str!("../regress/golden.txt");
+        let failures = check(golden).unwrap();
+        assert!(failures.is_empty(), "search behaviour changed:\n{}", failures.join("\n"));
+    }
+}
@@ -0,0 +1,186 @@
+//! A server for several independent analyses at once, one per slot, sharing a single executor.
+//!
+//! Every command is prefixed by the number of the slot it is for, as in `1 position startpos moves b2`,
+//! and is answered by lines with the same prefix. Each slot searches on its own thread, so a long
+//! analysis in one slot does not hold up the others, while all of their evaluations are batched together.
+//! Slots understand `position`, `go`, and `stop`, and `quit` shuts the server down.
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    mpsc,
+};
+
+use crate::{
+    batching::{self, ExecutorHandle, ExecutorOptions, Priority},
+    engine::{Engine, SearchResults},
+    game::GameImpl,
+    notation,
+    params::Params,
+    timemgmt::Limits,
+};
+
+/// The stop flag of a slot's searches, which the main thread sets while the slot is busy searching.
+struct Stop {
+    flag: AtomicBool,
+    /// The number of `go` commands sent to the slot before the latest `stop`, so that a slot starting a search
+    /// can tell whether it was already stopped, or whether the flag was left over from the search before it.
+    stopped_gos: AtomicU64,
+}
+
+impl Stop {
+    const fn new() -> Self {
+        Self { flag: AtomicBool::new(false), stopped_gos: AtomicU64::new(0) }
+    }
+
+    /// Stops the search in progress, and any of the first `gos_sent` searches that have not yet started.
+    fn stop(&self, gos_sent: u64) {
+        // the count is raised first, so that a slot that clears the flag afterwards sees it and sets it again.
+        self.stopped_gos.fetch_max(gos_sent, Ordering::SeqCst);
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Readies the flag for the search of the `go`th `go` command, which stays stopped if it already was.
+    fn start(&self, go: u64) {
+        self.flag.store(false, Ordering::SeqCst);
+        if self.stopped_gos.load(Ordering::SeqCst) >= go {
+            self.flag.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Runs the analysis server with `num_slots` slots until stdin closes or `quit` is received,
+/// evaluating with the network at `net_path` as `options` say. `startpos` is the starting position of the game.
+pub fn serve<G: GameImpl>(
+    startpos: G,
+    num_slots: usize,
+    net_path: Option<&str>,
+    options: ExecutorOptions,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(num_slots > 0, "the server needs at least one slot");
+    let network = batching::load_network_with::<G>(net_path.unwrap_or("./model.onnx"), options.backend)?;
+    let handles = batching::executor_on::<G>(&network, num_slots, options)?;
+    let stops = (0..num_slots).map(|_| Stop::new()).collect::<Vec<_>>();
+    // the number of `go` commands sent to each slot.
+    let mut gos_sent = vec![0; num_slots];
+
+    std::thread::scope(|scope| {
+        let mut senders = Vec::with_capacity(num_slots);
+        for ((id, handle), stop) in handles.into_iter().enumerate().zip(&stops) {
+            let (sender, receiver) = mpsc::channel();
+            senders.push(sender);
+            std::thread::Builder::new()
+                .name(format!("slot-{id}"))
+                .spawn_scoped(scope, move || run_slot(id, startpos, handle, stop, &receiver))?;
+        }
+        println!("info string serving {num_slots} analysis slots");
+
+        for line in std::io::stdin().lines() {
+            let line = line?;
+            let line = line.trim();
+            if line == "quit" {
+                break;
+            }
+            let Some((slot, command)) = parse_command(line, num_slots) else {
+                println!("info string expected a slot number below {num_slots} followed by a command");
+                continue;
+            };
+            // stop is handled here, as the slot cannot read commands while it searches.
+            match command {
+                "stop" => stops[slot].stop(gos_sent[slot]),
+                go if go.starts_with("go") => {
+                    gos_sent[slot] += 1;
+                    senders[slot].send(command.to_owned())?;
+                }
+                _ => senders[slot].send(command.to_owned())?,
+            }
+        }
+
+        // end every search in progress or queued, and let the slots finish once their queued commands are done.
+        for stop in &stops {
+            stop.stop(u64::MAX);
+        }
+        drop(senders);
+        Ok(())
+    })
+}
+
+/// Splits a line into the slot it addresses and the command for that slot.
+fn parse_command(line: &str, num_slots: usize) -> Option<(usize, &str)> {
+    let (slot, command) = line.split_once(' ')?;
+    let slot = slot.parse().ok().filter(|&slot| slot < num_slots)?;
+    Some((slot, command.trim()))
+}
+
+/// Handles the commands sent to one slot, in order.
+fn run_slot<G: GameImpl>(
+    id: usize,
+    startpos: G,
+    eval_pipe: ExecutorHandle<G>,
+    stop: &Stop,
+    commands: &mpsc::Receiver<String>,
+) {
+    let params = Params::default().with_stop(&stop.flag);
+    let eval_pipe = eval_pipe.with_priority(Priority::Interactive);
+    let mut engine = Engine::new(params, Limits::default(), &startpos, eval_pipe);
+    // the number of `go` commands received, counted as the main thread counts those it sends.
+    let mut gos = 0;
+    for command in commands {
+        if let Some(position) = command.strip_prefix("position ") {
+            match notation::parse_position(position, startpos) {
+                Ok((start, moves)) => engine.set_game(&start, &moves),
+                Err(e) => println!("{id} info string {e}"),
+            }
+        } else if let Some(limits) = command.strip_prefix("go") {
+            gos += 1;
+            stop.start(gos);
+            let limits = match limits.trim().parse::<Limits>() {
+                Ok(limits) => limits,
+                Err(e) => {
+                    println!("{id} info string invalid go command: {e}");
+                    continue;
+                }
+            };
+            if engine.root().outcome().is_some() {
+                println!("{id} info string the game is already over");
+                continue;
+            }
+            engine.set_limits(limits);
+            match engine.go() {
+                Ok(SearchResults { best_move, root_dist, value, lines, .. }) => {
+                    let nodes = root_dist.iter().sum::<u64>();
+                    let pv = lines
+                        .first()
+                        .map_or_else(Vec::new, |line| line.moves.iter().map(ToString::to_string).collect());
+                    println!("{id} info nodes {nodes} score q {:.1} pv {}", value * 100.0, pv.join(" "));
+                    println!("{id} bestmove {best_move}");
+                }
+                Err(e) => println!("{id} info string search failed: {e}"),
+            }
+        } else {
+            println!("{id} info string unknown command {command:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_address_slots() {
+        assert_eq!(parse_command("1 go nodes 100", 2), Some((1, "go nodes 100")));
+        assert_eq!(parse_command("2 go nodes 100", 2), None);
+        assert_eq!(parse_command("go nodes 100", 2), None);
+    }
+
+    #[test]
+    fn stops_are_only_cleared_for_searches_sent_after_them() {
+        let stop = Stop::new();
+        // go infinite, stop, go nodes 100, all sent while an earlier search was still running.
+        stop.stop(1);
+        stop.start(1);
+        assert!(stop.flag.load(Ordering::SeqCst));
+        stop.start(2);
+        assert!(!stop.flag.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,99 @@
+//! Streaming snapshots of the search tree, for external visualizers.
+
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    game::GameImpl,
+    node::{Edge, Node},
+    params::Params,
+};
+
+/// A destination for tree snapshots.
+pub struct SnapshotSink {
+    out: Box<dyn Write + Send>,
+    last_sent: Option<Instant>,
+}
+
+impl SnapshotSink {
+    /// Opens a sink at `target`, which is either `tcp:<host>:<port>` to connect to a listening visualizer,
+    /// or a file path, which is truncated.
+    pub fn open(target: &str) -> anyhow::Result<Self> {
+        let out: Box<dyn Write + Send> = match target.strip_prefix("tcp:") {
+            Some(address) => Box::new(BufWriter::new(TcpStream::connect(address)?)),
+            None => Box::new(BufWriter::new(File::create(target)?)),
+        };
+        Ok(Self { out, last_sent: None })
+    }
+
+    /// Sends a snapshot shaped by `params`, if their interval has passed since the last one or `force` is set.
+    pub fn send<G: GameImpl>(
+        &mut self,
+        params: &Params,
+        tree: &[Node<G>],
+        nodes: u64,
+        elapsed: u64,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let interval = Duration::from_millis(params.snapshot_interval);
+        if !force && self.last_sent.is_some_and(|last| last.elapsed() < interval) {
+            return Ok(());
+        }
+        self.last_sent = Some(Instant::now());
+        writeln!(self.out, "{}", to_json(tree, nodes, elapsed, params.snapshot_depth, params.snapshot_width))?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// Renders the top of the tree as one line of JSON:
+/// `{"nodes":N,"time":T,"root":{"n":..,"q":..,"children":[{"move":..,"n":..,"q":..,"p":..,"children":[..]}]}}`.
+/// `q` is the value of a node for the player who moved into it, and `p` is the policy prior of its move.
+/// Children are ordered by visits, and unvisited children are left out.
+pub fn to_json<G: GameImpl>(tree: &[Node<G>], nodes: u64, elapsed: u64, depth: usize, width: usize) -> String {
+    let mut json = format!("{{\"nodes\":{nodes},\"time\":{elapsed},\"root\":");
+    write_node(&mut json, tree, 0, None, depth, width);
+    json.push('}');
+    json
+}
+
+fn write_node<G: GameImpl>(
+    json: &mut String,
+    tree: &[Node<G>],
+    node_idx: usize,
+    edge: Option<&Edge<G>>,
+    depth: usize,
+    width: usize,
+) {
+    let node = &tree[node_idx];
+    json.push('{');
+    if let Some(edge) = edge {
+        write!(json, "\"move\":\"{}\",\"p\":{:.4},", edge.get_move(false), edge.probability()).unwrap();
+    }
+    let q = if node.visits() == 0 { 0.5 } else { node.winrate() };
+    write!(json, "\"n\":{},\"q\":{q:.4},\"children\":[", node.visits()).unwrap();
+    if depth > 0 {
+        let mut children = Vec::new();
+        let mut child = node.first_child();
+        while !child.is_null() {
+            if tree[child.index()].visits() > 0 {
+                children.push(child.index());
+            }
+            child = tree[child.index()].sibling();
+        }
+        children.sort_by_key(|&child| std::cmp::Reverse(tree[child].visits()));
+        for (i, &child) in children.iter().take(width).enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let edge = node.edges().map(|edges| &edges[tree[child].edge_index()]);
+            write_node(json, tree, child, edge, depth - 1, width);
+        }
+    }
+    json.push_str("]}");
+}
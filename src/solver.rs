@@ -0,0 +1,79 @@
+//! An exact solver for small endgames.
+
+use smallvec::SmallVec;
+
+use crate::game::{GameImpl, Player};
+
+/// Solves the position by exhaustive alpha-beta search, visiting at most `budget` nodes.
+/// Returns the result under perfect play, with `Player::None` for a draw,
+/// or `None` if the position could not be solved within the budget.
+pub fn solve<G: GameImpl>(board: &G, budget: u64) -> Option<Player> {
+    let mut budget = budget;
+    let score = negamax(board, -1, 1, &mut budget)?;
+    Some(match score {
+        0 => Player::None,
+        1 => board.to_move(),
+        _ => board.to_move().opposite(),
+    })
+}
+
+/// Returns the score of the position for the side to move: 1 for a win, 0 for a draw, and -1 for a loss.
+fn negamax<G: GameImpl>(board: &G, mut alpha: i8, beta: i8, budget: &mut u64) -> Option<i8> {
+    if let Some(outcome) = board.outcome() {
+        return Some(match outcome {
+            Player::None => 0,
+            winner if winner == board.to_move() => 1,
+            _ => -1,
+        });
+    }
+    *budget = budget.checked_sub(1)?;
+
+    let mut moves = SmallVec::<[G::Move; 64]>::new();
+    board.generate_moves(|mv| {
+        moves.push(mv);
+        false
+    });
+    let mut best = -1;
+    for mv in moves {
+        let mut child = *board;
+        child.make_move(mv);
+        // a turn may span several moves, in which case the score is not negated.
+        let score = if child.to_move() == board.to_move() {
+            negamax(&child, alpha, beta, budget)?
+        } else {
+            -negamax(&child, -beta, -alpha, budget)?
+        };
+        best = best.max(score);
+        alpha = alpha.max(score);
+        if alpha >= beta {
+            break;
+        }
+    }
+    Some(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mnk::{Board, Dimensions};
+
+    #[test]
+    fn tic_tac_toe_is_a_draw() {
+        let board = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        assert_eq!(solve(&board, 1_000_000), Some(Player::None));
+    }
+
+    #[test]
+    fn small_budgets_give_up() {
+        let board = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        assert_eq!(solve(&board, 10), None);
+    }
+
+    #[test]
+    fn finds_a_forced_win() {
+        let mut board = Board::new(Dimensions { width: 3, height: 3, k: 3 });
+        crate::game::play_moves(&mut board, &["a1", "c1", "a2", "c2"]);
+        // x threatens a3, and o threatens c3, but x is to move.
+        assert_eq!(solve(&board, 1_000_000), Some(Player::First));
+    }
+}
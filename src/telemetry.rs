@@ -0,0 +1,151 @@
+//! Sampling of GPU utilization and memory, to tell a search-bound NPS from a device-bound one.
+//!
+//! Samples come from `nvidia-smi`, which is far too slow to run from the search loop, so a
+//! background thread refreshes the latest sample at a fixed interval while a device is being
+//! monitored, and the search reports each new sample as it appears.
+
+use std::{
+    process::Command,
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+/// How often the GPU is sampled while it is monitored.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// One reading of a GPU's load.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GpuSample {
+    /// The percentage of time over the last sample period in which a kernel was running.
+    pub utilization: u32,
+    /// Memory in use, in MiB.
+    pub memory_used: u64,
+    /// Total memory, in MiB.
+    pub memory_total: u64,
+}
+
+#[derive(Default)]
+struct State {
+    /// The CUDA ordinal of the device to sample, or `None` while monitoring is off.
+    device: Option<i32>,
+    /// The latest sample, and when it was taken.
+    latest: Option<(GpuSample, Instant)>,
+    /// Whether the sampling thread should exit.
+    shutdown: bool,
+}
+
+pub struct GpuMonitor {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl GpuMonitor {
+    /// Starts the sampling thread, initially monitoring no device.
+    pub fn spawn() -> anyhow::Result<Self> {
+        let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let thread_shared = Arc::clone(&shared);
+        let thread = std::thread::Builder::new().name("gpu-monitor".into()).spawn(move || monitor(&thread_shared))?;
+        Ok(Self { shared, thread: Some(thread) })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.shared.0.lock().expect("gpu monitor lock poisoned")
+    }
+
+    /// Starts monitoring the CUDA device with the given ordinal, or stops monitoring if it is `None`.
+    pub fn set_device(&self, device: Option<i32>) {
+        let mut state = self.state();
+        state.device = device;
+        state.latest = None;
+        drop(state);
+        self.shared.1.notify_one();
+    }
+
+    /// The latest sample, and when it was taken.
+    pub fn latest(&self) -> Option<(GpuSample, Instant)> {
+        self.state().latest
+    }
+}
+
+impl Drop for GpuMonitor {
+    fn drop(&mut self) {
+        self.state().shutdown = true;
+        self.shared.1.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn monitor(shared: &(Mutex<State>, Condvar)) {
+    while let Some(device) = next_device(shared) {
+        match sample(device) {
+            Ok(sample) => record(shared, device, sample),
+            Err(e) => log::debug!("failed to sample GPU {device}: {e}"),
+        }
+        // wait out the interval, unless the device changes or the monitor shuts down first.
+        let (lock, condvar) = shared;
+        let state = lock.lock().expect("gpu monitor lock poisoned");
+        let _ = condvar.wait_timeout_while(state, INTERVAL, |state| !state.shutdown && state.device == Some(device));
+    }
+}
+
+/// Waits until there is a device to sample, returning `None` once the monitor shuts down.
+fn next_device(shared: &(Mutex<State>, Condvar)) -> Option<i32> {
+    let (lock, condvar) = shared;
+    let state = lock.lock().expect("gpu monitor lock poisoned");
+    let state = condvar
+        .wait_while(state, |state| !state.shutdown && state.device.is_none())
+        .expect("gpu monitor lock poisoned");
+    state.device.filter(|_| !state.shutdown)
+}
+
+/// Stores a sample of `device`, unless the device changed while it was being taken.
+fn record(shared: &(Mutex<State>, Condvar), device: i32, sample: GpuSample) {
+    let mut state = shared.0.lock().expect("gpu monitor lock poisoned");
+    if state.device == Some(device) {
+        state.latest = Some((sample, Instant::now()));
+    }
+}
+
+/// Reads the load of the CUDA device with the given ordinal from `nvidia-smi`.
+fn sample(device: i32) -> anyhow::Result<GpuSample> {
+    let output = Command::new("nvidia-smi")
+        .args(["--query-gpu=utilization.gpu,memory.used,memory.total", "--format=csv,noheader,nounits"])
+        .arg(format!("--id={device}"))
+        .output()
+        .with_context(|| "failed to run nvidia-smi")?;
+    anyhow::ensure!(output.status.success(), "nvidia-smi exited with {}", output.status);
+    parse_sample(String::from_utf8_lossy(&output.stdout).trim())
+}
+
+/// Parses a line of `nvidia-smi` CSV output, such as `87, 2048, 8192`.
+fn parse_sample(line: &str) -> anyhow::Result<GpuSample> {
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+    let &[utilization, memory_used, memory_total] = fields.as_slice() else {
+        anyhow::bail!("expected three fields from nvidia-smi, got {line:?}");
+    };
+    let invalid = || format!("invalid nvidia-smi output {line:?}");
+    Ok(GpuSample {
+        utilization: utilization.parse().with_context(invalid)?,
+        memory_used: memory_used.parse().with_context(invalid)?,
+        memory_total: memory_total.parse().with_context(invalid)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nvidia_smi_output_parses() {
+        let sample = parse_sample("87, 2048, 8192").unwrap();
+        assert_eq!(sample, GpuSample { utilization: 87, memory_used: 2048, memory_total: 8192 });
+        // a GPU that does not report utilization prints "[N/A]".
+        assert!(parse_sample("[N/A], 2048, 8192").is_err());
+        assert!(parse_sample("87, 2048").is_err());
+    }
+}
@@ -9,31 +9,69 @@ enum Clock {
 }
 
 impl Clock {
-    fn time_limit(self, is_p1: bool) -> u64 {
+    /// The time we would like to spend on this move, sharing our clock between `moves_to_go` moves,
+    /// with the dynamic allocation multiplied by `scale`. This is never more than the hard limit.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    fn soft_limit(self, is_p1: bool, scale: f64, moves_to_go: u64) -> u64 {
         match self {
             Self::Fixed { millis } => millis,
+            Self::Dynamic { .. } => {
+                let (our_base, our_increment) = self.our_clock(is_p1);
+                let optimum = (our_base / moves_to_go + 3 * our_increment / 4) as f64 * scale;
+                (optimum as u64).min(self.hard_limit(is_p1))
+            }
+        }
+    }
+
+    /// The time we must never exceed on this move.
+    fn hard_limit(self, is_p1: bool) -> u64 {
+        match self {
+            Self::Fixed { millis } => millis,
+            Self::Dynamic { .. } => {
+                let (our_base, our_increment) = self.our_clock(is_p1);
+                (our_base / 5 + our_increment).min(our_base.saturating_sub(50))
+            }
+        }
+    }
+
+    /// Our remaining time and increment.
+    const fn our_clock(self, is_p1: bool) -> (u64, u64) {
+        match self {
+            Self::Fixed { millis } => (millis, 0),
             Self::Dynamic { p1_base, p1_inc, p2_base, p2_inc } => {
-                let (our_base, our_increment, _, _) =
-                    if is_p1 { (p1_base, p1_inc, p2_base, p2_inc) } else { (p2_base, p2_inc, p1_base, p1_inc) };
-                (our_base / 20 + 3 * our_increment / 4).min(our_base - 50)
+                if is_p1 {
+                    (p1_base, p1_inc)
+                } else {
+                    (p2_base, p2_inc)
+                }
             }
         }
     }
 }
 
+/// Stops a search once a single move clearly dominates the root.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EarlyExit {
+    /// The number of nodes to search before considering stopping.
+    probe_nodes: u64,
+    /// The percentage of root visits the best move must hold to stop the search.
+    min_share_percent: u64,
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Limits {
     nodes: Option<u64>,
     time: Option<Clock>,
+    early_exit: Option<EarlyExit>,
 }
 
 impl Limits {
     pub const fn movetime(millis: u64) -> Self {
-        Self { nodes: None, time: Some(Clock::Fixed { millis }) }
+        Self { nodes: None, time: Some(Clock::Fixed { millis }), early_exit: None }
     }
 
     pub const fn nodes(nodes: u64) -> Self {
-        Self { nodes: Some(nodes), time: None }
+        Self { nodes: Some(nodes), time: None, early_exit: None }
     }
 
     const fn time(our_base: u64, our_increment: u64, their_base: u64, their_increment: u64) -> Self {
@@ -45,25 +83,220 @@ impl Limits {
                 p2_base: their_base,
                 p2_inc: their_increment,
             }),
+            early_exit: None,
         }
     }
 
     pub const fn infinite() -> Self {
-        Self { nodes: None, time: None }
+        Self { nodes: None, time: None, early_exit: None }
+    }
+
+    /// The most nodes the search may visit, if it is limited by nodes.
+    pub const fn node_limit(&self) -> Option<u64> {
+        self.nodes
+    }
+
+    /// Our remaining clock time, in milliseconds, if the search is played under a game clock.
+    pub const fn remaining_time(&self, is_p1: bool) -> Option<u64> {
+        match self.time {
+            Some(clock @ Clock::Dynamic { .. }) => Some(clock.our_clock(is_p1).0),
+            _ => None,
+        }
+    }
+
+    /// Limits the search to at most `cap` nodes, as well as to whatever limits it already has.
+    pub fn with_node_cap(self, cap: u64) -> Self {
+        Self { nodes: Some(self.nodes.map_or(cap, |nodes| nodes.min(cap))), ..self }
+    }
+
+    /// Stops the search early if, after `probe_nodes` nodes, the best move holds
+    /// at least `min_share_percent` percent of the root visits.
+    pub const fn with_early_exit(self, probe_nodes: u64, min_share_percent: u64) -> Self {
+        Self { early_exit: Some(EarlyExit { probe_nodes, min_share_percent }), ..self }
+    }
+}
+
+/// The number of nodes to search before trusting the root statistics enough to rescale the time allocation.
+const MIN_NODES_FOR_ADAPTATION: u64 = 256;
+/// Bounds on the multiplier applied to the dynamic time allocation.
+const MIN_TIME_SCALE: f64 = 0.4;
+const MAX_TIME_SCALE: f64 = 1.8;
+/// A best-move change after this fraction of the soft limit has elapsed triggers panic time.
+const PANIC_THRESHOLD: f64 = 0.5;
+/// The number of our moves between which the clock is shared, unless the network estimates the moves left.
+const DEFAULT_MOVES_TO_GO: u64 = 20;
+/// Bounds on the number of our moves between which the clock is shared, by the network's estimate.
+const MIN_MOVES_TO_GO: u64 = 5;
+const MAX_MOVES_TO_GO: u64 = 40;
+
+/// Decides when a search should stop, adapting dynamic time controls
+/// to how settled the search at the root appears to be.
+#[derive(Clone, Debug)]
+pub struct TimeManager {
+    limits: Limits,
+    is_p1: bool,
+    /// Multiplier on the dynamic time allocation.
+    scale: f64,
+    /// Multiplier on the soft limit applied when the best move changes late in the search.
+    panic_multiplier: f64,
+    /// Whether the soft limit has been extended by `panic_multiplier`.
+    panicking: bool,
+    /// An upper bound on the dynamic time allocation for a single move.
+    max_move_time: Option<u64>,
+    /// Index of the best root edge at the last report.
+    best_edge: Option<usize>,
+    /// The node count at which the best root edge last changed.
+    last_best_edge_change: u64,
+    /// The visits to the best root edge and to the root at the last report.
+    best_visits: u32,
+    root_visits: u32,
+    /// The number of our moves between which the clock is shared.
+    moves_to_go: u64,
+    /// Whether to stop once the best move can no longer be overtaken within the budget left.
+    easy_move: bool,
+    /// The visits to the second most-visited root edge at the last report.
+    runner_up_visits: u32,
+}
+
+impl TimeManager {
+    pub const fn new(limits: Limits, is_p1: bool, panic_multiplier: f64) -> Self {
+        Self {
+            limits,
+            is_p1,
+            scale: 1.0,
+            panic_multiplier,
+            panicking: false,
+            max_move_time: None,
+            best_edge: None,
+            last_best_edge_change: 0,
+            best_visits: 0,
+            root_visits: 0,
+            moves_to_go: DEFAULT_MOVES_TO_GO,
+            easy_move: false,
+            runner_up_visits: 0,
+        }
+    }
+
+    /// Caps the time spent on any single move under dynamic time controls.
+    pub const fn with_max_move_time(self, max_move_time: Option<u64>) -> Self {
+        Self { max_move_time, ..self }
+    }
+
+    /// Stops the search once the best move leads the runner-up by more visits than are left in the budget,
+    /// so that nothing more could change the move played.
+    pub const fn with_easy_move(self, easy_move: bool) -> Self {
+        Self { easy_move, ..self }
+    }
+
+    /// The soft limit, after any panic extension and the per-move cap.
+    fn soft_limit(&self, clock: Clock) -> u64 {
+        let soft_scale = if self.panicking { self.scale * self.panic_multiplier } else { self.scale };
+        let soft_limit = clock.soft_limit(self.is_p1, soft_scale, self.moves_to_go);
+        match (clock, self.max_move_time) {
+            (Clock::Dynamic { .. }, Some(max_move_time)) => soft_limit.min(max_move_time),
+            _ => soft_limit,
+        }
+    }
+
+    /// Feeds the current state of the root back into the time manager.
+    ///
+    /// A best move that has held for most of the search and owns most of the root visits
+    /// shrinks the time allocation, while a recently-changed best move or visits split between
+    /// several candidates extends it. A best move change late in the search additionally
+    /// extends the soft limit by the panic multiplier, up to the hard limit.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn report(&mut self, nodes_searched: u64, elapsed: u64, best_edge: usize, best_visits: u32, root_visits: u32) {
+        if self.best_edge != Some(best_edge) {
+            if self.best_edge.is_some() {
+                if let Some(clock) = self.limits.time {
+                    let soft_limit = self.soft_limit(clock) as f64;
+                    if elapsed as f64 >= soft_limit * PANIC_THRESHOLD {
+                        self.panicking = true;
+                    }
+                }
+            }
+            self.best_edge = Some(best_edge);
+            self.last_best_edge_change = nodes_searched;
+        }
+        self.best_visits = best_visits;
+        self.root_visits = root_visits;
+
+        if nodes_searched < MIN_NODES_FOR_ADAPTATION || root_visits == 0 {
+            self.scale = 1.0;
+            return;
+        }
+
+        // fraction of the search for which the best move has not changed.
+        let stability = (nodes_searched - self.last_best_edge_change) as f64 / nodes_searched as f64;
+        // fraction of the root visits that went to the best move.
+        let sharpness = f64::from(best_visits) / f64::from(root_visits);
+
+        let stability_factor = 0.5f64.mul_add(-stability, 1.25);
+        let sharpness_factor = 1.5 - sharpness;
+        self.scale = (stability_factor * sharpness_factor).clamp(MIN_TIME_SCALE, MAX_TIME_SCALE);
+    }
+
+    /// Feeds the network's estimate of the plies left in the game back into the time manager, so that
+    /// the clock is shared between the moves we are expected to have left rather than a fixed number.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn report_moves_left(&mut self, plies_left: Option<f64>) {
+        self.moves_to_go = plies_left.map_or(DEFAULT_MOVES_TO_GO, |plies_left| {
+            // we make every other move.
+            ((plies_left / 2.0).ceil() as u64).clamp(MIN_MOVES_TO_GO, MAX_MOVES_TO_GO)
+        });
+    }
+
+    /// Feeds the visits to the second most-visited root edge back into the time manager.
+    pub const fn report_runner_up(&mut self, runner_up_visits: u32) {
+        self.runner_up_visits = runner_up_visits;
+    }
+
+    /// The nodes the search can still visit before its soft budget runs out, if it has one. Under a clock,
+    /// this is estimated from the speed of the search so far, once it has run for long enough to measure.
+    fn remaining_nodes(&self, nodes_searched: u64, elapsed: u64) -> Option<u64> {
+        let by_nodes = self.limits.nodes.map(|nodes| nodes.saturating_sub(nodes_searched));
+        let by_time = self
+            .limits
+            .time
+            .filter(|_| nodes_searched >= MIN_NODES_FOR_ADAPTATION && elapsed > 0)
+            .map(|clock| self.soft_limit(clock).saturating_sub(elapsed).saturating_mul(nodes_searched) / elapsed);
+        match (by_nodes, by_time) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// The time, in milliseconds, that this search must never exceed, if it is timed.
+    pub fn hard_limit(&self) -> Option<u64> {
+        self.limits.time.map(|clock| clock.hard_limit(self.is_p1))
     }
 
-    pub fn is_out_of_time(&self, nodes_searched: u64, elapsed: u64, is_p1: bool) -> bool {
-        if let Some(nodes) = self.nodes {
+    pub fn is_out_of_time(&self, nodes_searched: u64, elapsed: u64) -> bool {
+        if let Some(nodes) = self.limits.nodes {
             if nodes_searched >= nodes {
                 return true;
             }
         }
-        if let Some(clock) = self.time {
-            let time_limit = clock.time_limit(is_p1);
+        if let Some(clock) = self.limits.time {
+            let time_limit = self.soft_limit(clock);
             if elapsed >= time_limit {
                 return true;
             }
         }
+        if let Some(EarlyExit { probe_nodes, min_share_percent }) = self.limits.early_exit {
+            if nodes_searched >= probe_nodes
+                && self.root_visits > 0
+                && u64::from(self.best_visits) * 100 >= u64::from(self.root_visits) * min_share_percent
+            {
+                return true;
+            }
+        }
+        if self.easy_move && self.best_visits > 0 {
+            let lead = u64::from(self.best_visits.saturating_sub(self.runner_up_visits));
+            if self.remaining_nodes(nodes_searched, elapsed).is_some_and(|remaining| lead > remaining) {
+                return true;
+            }
+        }
         false
     }
 }
@@ -81,6 +314,7 @@ impl std::ops::Add for Limits {
         Self {
             nodes: if rhs.nodes.is_some() { rhs.nodes } else { self.nodes },
             time: if rhs.time.is_some() { rhs.time } else { self.time },
+            early_exit: if rhs.early_exit.is_some() { rhs.early_exit } else { self.early_exit },
         }
     }
 }
@@ -93,12 +327,14 @@ impl FromStr for Limits {
         // "nodes [nodes]" => Self::nodes(nodes)
         // "movetime [ms]" => Self::movetime(ms)
         // "p1time [ms] p2time [ms] p1inc [ms] p2inc [ms]" => Self::time(p1time, p1inc, p2time, p2inc)
+        // "wtime [ms] btime [ms] winc [ms] binc [ms]" => Self::time(btime, binc, wtime, winc), in any order
         // "infinite" => Self::infinite()
         // "nodes [nodes] movetime [ms]" => Self { nodes: Some(nodes), time: Some(Self::movetime(ms)) }
         // "nodes [nodes] p1time [ms] p2time [ms] p1inc [ms] p2inc [ms]" => Self { nodes: Some(nodes), time: Some(Self::time(p1time, p1inc, p2time, p2inc)) }
 
         let mut words = s.split_ascii_whitespace();
         let mut components = Vec::with_capacity(4);
+        let (mut p1time, mut p2time, mut p1inc, mut p2inc) = (None, None, None, None);
         while let Some(word) = words.next() {
             match word {
                 "nodes" => {
@@ -109,24 +345,18 @@ impl FromStr for Limits {
                     let millis = words.next().with_context(|| "nothing after \"movetime\" token!")?.parse()?;
                     components.push(Self::movetime(millis));
                 }
-                "p1time" => {
-                    let p1time = words.next().with_context(|| "nothing after \"p1time\" token!")?.parse()?;
-                    let t = words.next().with_context(|| "did not find \"p2time\" token!")?;
-                    if t != "p2time" {
-                        anyhow::bail!("expected \"p2time\" token, found {:?}", t);
-                    }
-                    let p2time = words.next().with_context(|| "nothing after \"p2time\" token!")?.parse()?;
-                    let t = words.next().with_context(|| "did not find \"p1inc\" token!")?;
-                    if t != "p1inc" {
-                        anyhow::bail!("expected \"p2time\" token, found {:?}", t);
-                    }
-                    let p1inc = words.next().with_context(|| "nothing after \"p1inc\" token!")?.parse()?;
-                    let t = words.next().with_context(|| "did not find \"p2inc\" token!")?;
-                    if t != "p2inc" {
-                        anyhow::bail!("expected \"p2time\" token, found {:?}", t);
-                    }
-                    let p2inc = words.next().with_context(|| "nothing after \"p2inc\" token!")?.parse()?;
-                    components.push(Self::time(p1time, p1inc, p2time, p2inc));
+                // clock tokens may come in any order, and in the chess-style family, where
+                // black moves first. missing increments are zero, and a missing time is taken
+                // to be the same as the other player's.
+                "p1time" | "btime" | "p2time" | "wtime" | "p1inc" | "binc" | "p2inc" | "winc" => {
+                    let value = words.next().with_context(|| format!("nothing after {word:?} token!"))?.parse()?;
+                    let slot = match word {
+                        "p1time" | "btime" => &mut p1time,
+                        "p2time" | "wtime" => &mut p2time,
+                        "p1inc" | "binc" => &mut p1inc,
+                        _ => &mut p2inc,
+                    };
+                    *slot = Some(value);
                 }
                 "infinite" => {
                     components.push(Self::infinite());
@@ -135,6 +365,13 @@ impl FromStr for Limits {
             }
         }
 
+        if p1time.is_some() || p2time.is_some() {
+            let (p1time, p2time) = (p1time.or(p2time).unwrap_or(0), p2time.or(p1time).unwrap_or(0));
+            components.push(Self::time(p1time, p1inc.unwrap_or(0), p2time, p2inc.unwrap_or(0)));
+        } else if p1inc.is_some() || p2inc.is_some() {
+            anyhow::bail!("increments given without any clock times");
+        }
+
         Ok(components.into_iter().fold(Self::infinite(), |acc, x| acc + x))
     }
 }
@@ -159,6 +396,43 @@ mod tests {
         assert_eq!(Limits::time(100, 10, 200, 20), "p1time 100 p2time 200 p1inc 10 p2inc 20".parse().unwrap());
     }
 
+    #[test]
+    fn go_time_any_order() {
+        assert_eq!(Limits::time(100, 10, 200, 20), "p2inc 20 p1time 100 p1inc 10 p2time 200".parse().unwrap());
+    }
+
+    #[test]
+    fn go_chess_style_time() {
+        assert_eq!(Limits::time(100, 10, 200, 20), "wtime 200 btime 100 winc 20 binc 10".parse().unwrap());
+        assert_eq!(Limits::time(100, 10, 200, 20), "btime 100 binc 10 p2time 200 winc 20".parse().unwrap());
+    }
+
+    #[test]
+    fn node_caps_keep_the_lower_limit() {
+        assert_eq!(Limits::nodes(100).with_node_cap(50), Limits::nodes(50));
+        assert_eq!(Limits::nodes(30).with_node_cap(50), Limits::nodes(30));
+        assert_eq!(Limits::infinite().with_node_cap(50).node_limit(), Some(50));
+        assert_eq!(Limits::movetime(100).with_node_cap(50), Limits { nodes: Some(50), ..Limits::movetime(100) });
+    }
+
+    #[test]
+    fn go_partial_time() {
+        // missing increments are zero, and a missing clock mirrors the other.
+        assert_eq!(Limits::time(100, 0, 100, 0), "btime 100".parse().unwrap());
+        assert_eq!(Limits::time(300, 0, 300, 5), "wtime 300 winc 5".parse().unwrap());
+        assert!("binc 10".parse::<Limits>().is_err());
+        assert!("btime".parse::<Limits>().is_err());
+    }
+
+    #[test]
+    fn remaining_time_is_read_from_game_clocks() {
+        let limits: Limits = "p1time 40 p2time 900".parse().unwrap();
+        assert_eq!(limits.remaining_time(true), Some(40));
+        assert_eq!(limits.remaining_time(false), Some(900));
+        // a fixed move time is not a clock that can run out.
+        assert_eq!(Limits::movetime(40).remaining_time(true), None);
+    }
+
     #[test]
     fn go_infinite() {
         assert_eq!(Limits::infinite(), "infinite".parse().unwrap());
@@ -189,4 +463,108 @@ mod tests {
             "nodes 100 movetime 100 p1time 100 p2time 200 p1inc 10 p2inc 20".parse().unwrap()
         );
     }
+
+    // time allocation
+    #[test]
+    fn stable_sharp_search_stops_early() {
+        let limits = Limits::time(20_000, 0, 20_000, 0);
+        let mut tm = TimeManager::new(limits, true, 1.0);
+        tm.report(10, 0, 3, 5, 10);
+        tm.report(10_000, 500, 3, 9_500, 10_000);
+        assert!(tm.scale < 1.0);
+        assert!(tm.is_out_of_time(10_000, 900));
+    }
+
+    #[test]
+    fn unstable_split_search_extends() {
+        let limits = Limits::time(20_000, 0, 20_000, 0);
+        let mut tm = TimeManager::new(limits, true, 1.0);
+        tm.report(10, 0, 3, 5, 10);
+        tm.report(10_000, 500, 4, 3_000, 10_000);
+        assert!(tm.scale > 1.0);
+        assert!(!tm.is_out_of_time(10_000, 1_100));
+    }
+
+    #[test]
+    fn movetime_is_not_scaled() {
+        let mut tm = TimeManager::new(Limits::movetime(1000), true, 2.0);
+        tm.report(10, 0, 3, 5, 10);
+        tm.report(10_000, 900, 4, 3_000, 10_000);
+        assert!(!tm.is_out_of_time(10_000, 999));
+        assert!(tm.is_out_of_time(10_000, 1000));
+    }
+
+    #[test]
+    fn late_best_move_change_extends_to_hard_limit() {
+        let limits = Limits::time(20_000, 0, 20_000, 0);
+        let mut tm = TimeManager::new(limits, true, 1.5);
+        tm.report(10, 0, 3, 5, 10);
+        assert!(tm.is_out_of_time(10, 1_000));
+        tm.report(20, 900, 4, 5, 20);
+        assert!(!tm.is_out_of_time(20, 1_000));
+        assert!(tm.is_out_of_time(20, 1_500));
+        // the extension can never exceed the hard limit
+        let mut tm = TimeManager::new(limits, true, 100.0);
+        tm.report(10, 0, 3, 5, 10);
+        tm.report(20, 900, 4, 5, 20);
+        assert!(tm.is_out_of_time(20, 4_000));
+    }
+
+    #[test]
+    fn moves_left_shares_the_clock() {
+        let limits = Limits::time(20_000, 0, 20_000, 0);
+        let mut tm = TimeManager::new(limits, true, 1.0);
+        assert!(tm.is_out_of_time(0, 1_000));
+        // a game expected to end soon leaves fewer moves to share the clock between.
+        tm.report_moves_left(Some(16.0));
+        assert!(!tm.is_out_of_time(0, 2_400));
+        assert!(tm.is_out_of_time(0, 2_500));
+        tm.report_moves_left(Some(500.0));
+        assert!(tm.is_out_of_time(0, 500));
+        tm.report_moves_left(None);
+        assert!(!tm.is_out_of_time(0, 999));
+    }
+
+    #[test]
+    fn easy_move_stops_once_the_best_move_cannot_be_overtaken() {
+        let mut tm = TimeManager::new(Limits::nodes(1_000), true, 1.0).with_easy_move(true);
+        tm.report(600, 0, 3, 400, 600);
+        tm.report_runner_up(150);
+        assert!(!tm.is_out_of_time(600, 0));
+        tm.report(700, 0, 3, 500, 700);
+        tm.report_runner_up(150);
+        assert!(tm.is_out_of_time(700, 0));
+        // under a clock, the nodes left are estimated from the speed so far.
+        let limits = Limits::time(20_000, 0, 20_000, 0);
+        let mut tm = TimeManager::new(limits, true, 1.0).with_easy_move(true);
+        tm.report(1_000, 400, 3, 950, 1_000);
+        tm.report_runner_up(30);
+        assert!(tm.is_out_of_time(1_000, 400));
+        assert!(!tm.clone().with_easy_move(false).is_out_of_time(1_000, 400));
+    }
+
+    #[test]
+    fn max_move_time_clamps_dynamic_allocation() {
+        let limits = Limits::time(600_000, 0, 600_000, 0);
+        let tm = TimeManager::new(limits, true, 1.0).with_max_move_time(Some(2_000));
+        assert!(!tm.is_out_of_time(0, 1_999));
+        assert!(tm.is_out_of_time(0, 2_000));
+        // fixed movetime is left alone
+        let tm = TimeManager::new(Limits::movetime(5_000), true, 1.0).with_max_move_time(Some(2_000));
+        assert!(!tm.is_out_of_time(0, 4_999));
+    }
+
+    #[test]
+    fn early_exit_on_dominant_move() {
+        let limits = Limits::nodes(800).with_early_exit(100, 90);
+        let mut tm = TimeManager::new(limits, true, 1.0);
+        // dominant, but not yet probed enough
+        tm.report(50, 0, 3, 48, 50);
+        assert!(!tm.is_out_of_time(50, 0));
+        // probed, but contested
+        tm.report(100, 0, 3, 80, 100);
+        assert!(!tm.is_out_of_time(100, 0));
+        tm.report(150, 0, 3, 140, 150);
+        assert!(tm.is_out_of_time(150, 0));
+    }
 }
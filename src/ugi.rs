@@ -1,26 +1,53 @@
 //! The Universal Game Interface (UGI) implementation.
 
 use std::{
-    ops::ControlFlow,
+    collections::VecDeque,
+    path::Path,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc, Mutex,
+        mpsc,
     },
+    time::{Duration, Instant},
 };
 
-use kn_graph::optimizer::OptimizerSettings;
 use log::info;
 
 use crate::{
-    batching,
-    engine::{Engine, SearchResults},
-    game::{GameImpl, Player},
-    params::Params,
+    batching::{self, Device, ExecutorHandle, ExecutorOptions, Priority},
+    bench,
+    book::Book,
+    engine::{Engine, SearchResults, MAX_BATCH_LEAVES},
+    game::{self, GameImpl, GameSpec, MovePolicyIndex, Player},
+    hash, models,
+    notation::{self, ParseError},
+    params::{self, Exploration, FpuStrategy, Params, MAX_ELO, MAX_SKILL, MIN_ELO},
+    pns,
+    snapshot::SnapshotSink,
+    telemetry::GpuMonitor,
     timemgmt::Limits,
+    watchdog::Watchdog,
     NAME, VERSION,
 };
 
-fn stdin_reader() -> mpsc::Receiver<String> {
+/// The node budget of `go mate` when no limits are given.
+const MATE_DEFAULT_NODES: u64 = 1_000_000;
+
+/// The largest value advertised for spin options without a natural bound, the largest that GUIs
+/// reliably accept.
+const SPIN_MAX: u64 = 2_147_483_647;
+
+/// The number of most probable moves the `eval` command prints by default.
+const EVAL_DEFAULT_TOP: usize = 5;
+/// The number of root moves the `tree` command prints by default.
+const TREE_DEFAULT_TOP: usize = 10;
+/// The length to which the `tree` command cuts each line of best play by default.
+const TREE_DEFAULT_DEPTH: usize = 8;
+
+/// How long to wait for a command before checking again whether a search has finished.
+const SEARCH_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Starts reading commands from stdin, once for the whole process, as the UGI loop may run several times.
+pub fn stdin_reader() -> mpsc::Receiver<String> {
     let (sender, receiver) = mpsc::channel();
     std::thread::Builder::new()
         .name("stdin-reader".into())
@@ -60,33 +87,84 @@ fn stdin_reader_worker(sender: mpsc::Sender<String>) {
     std::mem::drop(sender);
 }
 
-/// The main loop of the Universal Game Interface (UGI).
+/// How the UGI loop ended.
+pub enum Exit {
+    /// The GUI quit, or stdin was closed.
+    Quit,
+    /// The `UGI_Variant` option asked for another game, which starts with its default model
+    /// by handling the commands that were already queued.
+    Switch { game: String, pending: VecDeque<String> },
+}
+
+/// The main loop of the Universal Game Interface (UGI), reading commands from `stdin` once those in
+/// `pending` have been handled. `game` is the name the game was selected by, for reporting, and
+/// `starting_position` is where its games start.
 #[allow(clippy::too_many_lines)]
-pub fn main_loop<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<()> {
-    let stdin = Mutex::new(stdin_reader());
+pub fn main_loop<G: GameImpl>(
+    game: &str,
+    starting_position: G,
+    net_path: Option<&str>,
+    executor_options: ExecutorOptions,
+    stdin: &mpsc::Receiver<String>,
+    pending: VecDeque<String>,
+) -> anyhow::Result<Exit> {
+    // set to end the search running on the worker thread.
+    let stop = AtomicBool::new(false);
 
     let version_extension = if cfg!(feature = "final-release") { "" } else { "-dev" };
     println!("{NAME} {VERSION}{version_extension} by Cosmo");
 
-    // Load an onnx file into a Graph.
-    let raw_graph = kn_graph::onnx::load_graph_from_onnx_path(net_path.unwrap_or("./model.onnx"), false).unwrap();
-    // Optimise the graph.
-    let graph = kn_graph::optimizer::optimize_graph(&raw_graph, OptimizerSettings::default());
-    // Deallocate the raw graph.
-    std::mem::drop(raw_graph);
+    // the model in use, which the Model option can change.
+    let mut net_path = net_path.unwrap_or("./model.onnx").to_owned();
+    let mut model_hash = hash::sha256_file(Path::new(&net_path));
+    // the device and batch size the backend was last started with, kept for reloads.
+    let mut executor_options = executor_options;
+    // whether to report GPU samples during search.
+    let mut gpu_telemetry = false;
 
-    let executor_handles = batching::executor(&graph, 1)?;
-
-    let default_params = Params::default().with_stdin_rx(&stdin).with_stdout(true);
+    let watchdog = Watchdog::spawn()?;
+    let gpu_monitor = GpuMonitor::spawn()?;
+    let default_params =
+        Params::default().with_stop(&stop).with_stdout(true).with_watchdog(&watchdog).with_gpu_monitor(&gpu_monitor);
     let default_limits = Limits::default();
-    let starting_position = G::default();
-    let mut engine =
-        Engine::new(default_params, default_limits, &starting_position, executor_handles.into_iter().next().unwrap());
+    // a model that cannot be loaded, such as one for another game, leaves the engine playing without one.
+    let eval_pipe = match start_executor::<G>(&net_path, executor_options) {
+        Ok(eval_pipe) => eval_pipe,
+        Err(e) => {
+            println!("info string failed to load {net_path} for {game}, playing without a model: {e:#}");
+            let handles = batching::executor_on(&batching::Network::Uniform, 1, executor_options)?;
+            handles.into_iter().next().unwrap().with_priority(Priority::Interactive)
+        }
+    };
+    let mut engine = Engine::new(default_params, default_limits, &starting_position, eval_pipe);
+    // the root value of the last search, for the side to move.
+    let mut last_winrate = None;
+    // whether to search the position without limits after every position or play command.
+    let mut analysis_mode = false;
+    // whether an analysis is under way, paused while the commands that interrupted it are handled.
+    let mut analysing = false;
+    // the skill level, and the Elo rating that overrides it while strength is limited.
+    let mut skill_level = MAX_SKILL;
+    let mut limit_strength = false;
+    let mut elo = MAX_ELO;
+    // commands that arrived during a search, to be handled before reading any more.
+    let mut pending = pending;
 
     loop {
         std::io::Write::flush(&mut std::io::stdout()).expect("couldn't flush stdout");
-        let Ok(line) = stdin.lock().expect("failed to take lock on stdin").recv() else {
-            break;
+        if analysing && analysis_mode && pending.is_empty() {
+            last_winrate = analyse(&mut engine, stdin, &stop, &mut pending)?.or(last_winrate);
+            // a stop, which is never queued, or a new position or search ends it, and anything else pauses it.
+            analysing = pending.front().is_some_and(|cmd| !ends_analysis(cmd));
+            continue;
+        }
+        let line = if let Some(cmd) = pending.pop_front() {
+            cmd
+        } else {
+            let Ok(line) = stdin.recv() else {
+                break;
+            };
+            line
         };
         let input = line.trim();
 
@@ -97,24 +175,181 @@ pub fn main_loop<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<()> {
                 break;
             }
             "isready" => println!("readyok"),
+            "about" => {
+                let features = [
+                    ("pure-mcts", cfg!(feature = "pure-mcts")),
+                    ("huge-trees", cfg!(feature = "huge-trees")),
+                    ("variance-stats", cfg!(feature = "variance-stats")),
+                    ("rave", cfg!(feature = "rave")),
+                    ("onnxruntime", cfg!(feature = "onnxruntime")),
+                    ("onnxruntime-cuda", cfg!(feature = "onnxruntime-cuda")),
+                    ("onnxruntime-directml", cfg!(feature = "onnxruntime-directml")),
+                    ("onnxruntime-rocm", cfg!(feature = "onnxruntime-rocm")),
+                    ("final-release", cfg!(feature = "final-release")),
+                ];
+                let features = features.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect::<Vec<_>>();
+                println!("info string {NAME} {VERSION}{version_extension}");
+                println!("info string commit {}", env!("VERITAS_GIT_COMMIT"));
+                println!("info string features {}", features.join(" "));
+                if cfg!(feature = "pure-mcts") {
+                    println!("info string backend none (random rollouts)");
+                } else {
+                    println!("info string backend {}", engine.backend());
+                }
+                println!("info string model {net_path}");
+                match &model_hash {
+                    Ok(hash) => println!("info string model sha256 {hash}"),
+                    Err(e) => println!("info string model hash unavailable: {e}"),
+                }
+                println!("info string game {game}");
+            }
             protocol @ ("ugi" | "uai" | "uci") => {
                 println!("id name {NAME} {VERSION}{version_extension}");
                 println!("id author Cosmo");
+                print_options(game, &net_path, executor_options);
                 println!("{protocol}ok");
             }
             "uginewgame" | "ucinewgame" | "uainewgame" => {
-                engine.set_position(&G::default());
+                engine.set_position(&starting_position);
             }
-            "show" => {
+            "show" | "d" => {
                 println!("info string position fen {}", engine.root().fen());
                 let board_string = engine.root().to_string();
                 let prefixed =
                     board_string.lines().map(|line| format!("info string {line}")).collect::<Vec<_>>().join("\n");
                 println!("{prefixed}");
+                if engine.root().outcome().is_none() {
+                    print_move_table(&mut engine);
+                }
+            }
+            reload if reload == "reload" || reload.starts_with("reload ") => {
+                // rebuild the backend from scratch, perhaps on another device, keeping the game but not the tree.
+                let spec = reload.trim_start_matches("reload").trim();
+                let new_device = if spec.is_empty() {
+                    executor_options.device
+                } else {
+                    match spec.parse() {
+                        Ok(new_device) => new_device,
+                        Err(e) => {
+                            println!("info string invalid reload command: {e}");
+                            continue;
+                        }
+                    }
+                };
+                let new_options = ExecutorOptions { device: new_device, ..executor_options };
+                match start_executor::<G>(&net_path, new_options) {
+                    Ok(eval_pipe) => {
+                        // the file may have been replaced since it was loaded, so nothing it said is kept.
+                        engine.set_network(eval_pipe);
+                        executor_options = new_options;
+                        if gpu_telemetry {
+                            gpu_monitor.set_device(cuda_ordinal(&net_path, executor_options.device));
+                        }
+                        model_hash = hash::sha256_file(Path::new(&net_path));
+                        println!("info string reloaded backend {}", engine.backend());
+                    }
+                    Err(e) => println!("info string failed to reload backend, keeping the old one: {e}"),
+                }
+            }
+            eval if eval == "eval" || eval.starts_with("eval ") => {
+                let top_k = eval.trim_start_matches("eval").trim();
+                let top_k = if top_k.is_empty() { Ok(EVAL_DEFAULT_TOP) } else { top_k.parse() };
+                let Ok(top_k) = top_k else {
+                    println!("info string invalid eval command, expected eval [<k>]");
+                    continue;
+                };
+                if engine.root().outcome().is_some() {
+                    println!("info string the game is already over");
+                    continue;
+                }
+                match engine.raw_evaluation() {
+                    Ok(eval) => {
+                        println!("info string value {:.4}", eval.value);
+                        if let Some(plies_left) = eval.plies_left {
+                            println!("info string plies left {plies_left:.1}");
+                        }
+                        let mut priors = legal_priors(&engine.root(), &eval.policy);
+                        priors.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+                        for (mv, prior) in priors.into_iter().take(top_k) {
+                            println!("info string policy {mv} {:.2}%", prior * 100.0);
+                        }
+                    }
+                    Err(e) => println!("info string failed to evaluate the root: {e}"),
+                }
+            }
+            command if command == "bench" || command.starts_with("bench ") => {
+                let nodes = command.trim_start_matches("bench").trim();
+                let nodes = if nodes.is_empty() { Ok(bench::DEFAULT_NODES) } else { nodes.parse() };
+                let Ok(nodes) = nodes else {
+                    println!("info string invalid bench command, expected bench [<nodes>]");
+                    continue;
+                };
+                // the benchmark has an executor of its own, so the game in progress is left as it is.
+                let report = batching::load_network_or_uniform::<G>(&net_path, executor_options.backend)
+                    .and_then(|network| batching::executor_on(&network, 1, executor_options))
+                    .and_then(|handles| {
+                        bench::run(
+                            starting_position,
+                            handles.into_iter().next().unwrap(),
+                            bench::positions(game),
+                            nodes,
+                        )
+                    });
+                match report {
+                    Ok(report) => println!("info string bench {report}"),
+                    Err(e) => println!("info string bench failed: {e:#}"),
+                }
+            }
+            perft if perft == "perft" || perft.starts_with("perft ") => {
+                let Ok(depth) = perft.trim_start_matches("perft").trim().parse::<usize>() else {
+                    println!("info string invalid perft command, expected perft <depth>");
+                    continue;
+                };
+                let root = engine.root();
+                let start = Instant::now();
+                let mut total = 0;
+                // split the count by root move, so that a discrepancy can be narrowed down.
+                if depth > 0 && root.outcome().is_none() {
+                    root.generate_moves(|mv| {
+                        let mut child = root;
+                        child.make_move(mv);
+                        let count = game::perft(&child, depth - 1);
+                        println!("info string {mv}: {count}");
+                        total += count;
+                        false
+                    });
+                } else {
+                    total = game::perft(&root, depth);
+                }
+                let elapsed = start.elapsed();
+                println!(
+                    "info string perft {depth} nodes {total} time {} nps {}",
+                    elapsed.as_millis(),
+                    u128::from(total) * 1000 / elapsed.as_millis().max(1)
+                );
             }
-            "stop" => {
-                // engine.stop();
+            tree if tree == "tree" || tree.starts_with("tree ") => {
+                let Some((top_k, depth)) = parse_tree_command(tree) else {
+                    println!("info string invalid tree command, expected tree [top <k>] [depth <plies>]");
+                    continue;
+                };
+                print_tree(&engine, top_k, depth);
             }
+            "undo" | "takeback" => match engine.undo() {
+                Some(mv) => println!("info string took back {mv}"),
+                None => println!("info string there is no move to take back"),
+            },
+            "validate" => {
+                let problems = engine.validate_tree();
+                if problems.is_empty() {
+                    println!("info string tree is valid");
+                }
+                for problem in problems {
+                    println!("info string invalid tree: {problem}");
+                }
+            }
+            // a search is only ever stopped while it runs, so there is nothing to stop here.
+            "stop" => {}
             query if query.starts_with("query ") => match query.trim_start_matches("query ").trim() {
                 "gameover" => {
                     println!("response {}", engine.root().outcome().is_some());
@@ -133,42 +368,124 @@ pub fn main_loop<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<()> {
                         }
                     );
                 }
+                "moves" => {
+                    let mut moves = Vec::new();
+                    engine.root().generate_moves(|mv| {
+                        moves.push(mv.to_string());
+                        false
+                    });
+                    // a finished game has no legal moves.
+                    if moves.is_empty() {
+                        println!("response none");
+                    } else {
+                        println!("response {}", moves.join(" "));
+                    }
+                }
+                "fen" => println!("response {}", engine.root().fen()),
+                "winrate" => match last_winrate {
+                    Some(winrate) => println!("response {winrate:.4}"),
+                    None => println!("response none"),
+                },
+                "eval" => match engine.raw_eval() {
+                    Ok(value) => println!("response {value:.4}"),
+                    Err(e) => println!("response none ({e})"),
+                },
                 _ => println!("response unknown query: {query}"),
             },
             go if go.starts_with("go") => {
+                // the clock runs from when the command arrived, not from when the search starts.
+                let received = Instant::now();
                 let limits_text = go.trim_start_matches("go").trim();
-                let limits_text = G::player_substitute(limits_text);
-                let limits: Limits = if let Ok(limits) = limits_text.parse() {
+                let (prove, limits_text) = match limits_text.split_once(' ').unwrap_or((limits_text, "")) {
+                    ("mate" | "win", rest) => (true, rest.trim()),
+                    _ => (false, limits_text),
+                };
+                let mut limits: Limits = if let Ok(limits) = limits_text.parse() {
                     limits
                 } else {
                     println!("info string invalid go command");
                     continue;
                 };
+                // there is no move to make in a finished game, so answer with the null move.
+                if engine.root().outcome().is_some() {
+                    println!("info string the game is already over");
+                    println!("bestmove 0000");
+                    continue;
+                }
+                if prove {
+                    // a proof search without limits would run until its tree fills, so cap it.
+                    let proof_limits = if limits_text.is_empty() { Limits::nodes(MATE_DEFAULT_NODES) } else { limits };
+                    let root = engine.root();
+                    let proof = run_in_background(stdin, &stop, &mut pending, false, || {
+                        pns::prove_win(&root, proof_limits, Some(&stop))
+                    })?;
+                    // a proof search cut short leaves no time for another, so play the network's choice at once.
+                    if stop.load(Ordering::SeqCst) {
+                        limits = Limits::nodes(1);
+                    }
+                    match proof {
+                        pns::Proof::Win(line) if !line.is_empty() => {
+                            let line = line.iter().map(ToString::to_string).collect::<Vec<_>>();
+                            println!("info string proven win pv {}", line.join(" "));
+                            println!("bestmove {}", line[0]);
+                            continue;
+                        }
+                        pns::Proof::NoWin => println!("info string no forced win exists, searching normally"),
+                        pns::Proof::Win(_) | pns::Proof::Unknown => {
+                            println!("info string no forced win found, searching normally");
+                        }
+                    }
+                }
                 engine.set_limits(limits);
-                let SearchResults { best_move, root_dist } = engine.go()?;
+                let SearchResults { best_move, value, lines, root_moves, .. } =
+                    search_in_background(&mut engine, received, stdin, &stop, &mut pending, false)?;
+                last_winrate = Some(value);
+                // the final state of each line, as the last ones printed during search may be out of date.
+                if lines.len() > 1 {
+                    for (rank, line) in lines.iter().enumerate() {
+                        let moves = line.moves.iter().map(ToString::to_string).collect::<Vec<_>>();
+                        println!(
+                            "info multipv {} nodes {} score {} pv {}",
+                            rank + 1,
+                            line.visits,
+                            line.score,
+                            moves.join(" ")
+                        );
+                    }
+                }
                 info!("best move from search: {}", best_move);
-                info!("root rollout distribution: {:?}", root_dist);
-                println!("bestmove {best_move}");
+                for root_move in root_moves.iter().filter(|root_move| root_move.visits > 0) {
+                    let pv = root_move.pv.iter().map(ToString::to_string).collect::<Vec<_>>();
+                    info!(
+                        "root move {}: visits {}, q {:.4}, prior {:.4}, pv {}",
+                        root_move.mv,
+                        root_move.visits,
+                        root_move.q.unwrap_or_default(),
+                        root_move.prior,
+                        pv.join(" ")
+                    );
+                }
+                // if the watchdog fired, it has already printed a best move
+                if !watchdog.disarm() {
+                    println!("bestmove {best_move}");
+                }
             }
             play if play.starts_with("play ") => {
-                if make_move_on_engine(play, &mut engine) == ControlFlow::Break(()) {
+                if let Err(e) = make_move_on_engine(play, &mut engine) {
+                    println!("info string {e}");
                     continue;
                 }
+                analysing = analysis_mode;
             }
             set_position if set_position.starts_with("position ") => {
-                if parse_position(set_position, &mut engine) == ControlFlow::Break(()) {
+                if let Err(e) = parse_position(set_position, starting_position, &mut engine) {
+                    println!("info string {e}");
                     continue;
                 }
+                analysing = analysis_mode;
             }
             set_option if set_option.starts_with("setoption ") => {
-                let mut words = set_option.trim_start_matches("setoption ").split_ascii_whitespace();
-                words.next(); // "name"
-                let Ok(name) = words.next().ok_or(()) else {
-                    println!("info string invalid setoption command");
-                    continue;
-                };
-                words.next(); // "value"
-                let Ok(value) = words.next().ok_or(()) else {
+                let Some((name, value)) = parse_setoption(set_option) else {
                     println!("info string invalid setoption command");
                     continue;
                 };
@@ -180,6 +497,542 @@ pub fn main_loop<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<()> {
                         };
                         engine.params_mut().c_puct = cpuct;
                     }
+                    "FPU" => {
+                        let Ok(fpu) = value.parse() else {
+                            println!("info string invalid FPU value");
+                            continue;
+                        };
+                        engine.params_mut().first_play_urgency = fpu;
+                    }
+                    "CPuctFactor" => {
+                        let Ok(factor) = value.parse() else {
+                            println!("info string invalid CPuctFactor value");
+                            continue;
+                        };
+                        // zero keeps cpuct constant
+                        engine.params_mut().cpuct_factor = factor;
+                    }
+                    "CPuctBase" => {
+                        let Ok(base) = value.parse::<f64>() else {
+                            println!("info string invalid CPuctBase value");
+                            continue;
+                        };
+                        if base <= 0.0 {
+                            println!("info string CPuctBase must be positive");
+                            continue;
+                        }
+                        engine.params_mut().cpuct_base = base;
+                    }
+                    "CPuctDecayPlies" => {
+                        let Ok(plies) = value.parse() else {
+                            println!("info string invalid CPuctDecayPlies value");
+                            continue;
+                        };
+                        // zero keeps cpuct the same all game
+                        engine.params_mut().cpuct_decay_plies = plies;
+                    }
+                    "CPuctDecayFloor" => {
+                        let Ok(floor) = value.parse::<f64>() else {
+                            println!("info string invalid CPuctDecayFloor value");
+                            continue;
+                        };
+                        if !(0.0..=1.0).contains(&floor) {
+                            println!("info string CPuctDecayFloor must be between 0 and 1");
+                            continue;
+                        }
+                        engine.params_mut().cpuct_decay_floor = floor;
+                    }
+                    "FPUStrategy" => {
+                        engine.params_mut().fpu_strategy = match value {
+                            "absolute" => FpuStrategy::Absolute,
+                            "reduction" => FpuStrategy::Reduction,
+                            "loss" => FpuStrategy::Loss,
+                            _ => {
+                                println!(
+                                    "info string invalid FPUStrategy value, expected absolute, reduction, or loss"
+                                );
+                                continue;
+                            }
+                        };
+                    }
+                    "FPUReduction" => {
+                        let Ok(reduction) = value.parse() else {
+                            println!("info string invalid FPUReduction value");
+                            continue;
+                        };
+                        engine.params_mut().fpu_reduction = reduction;
+                    }
+                    "Noise" => {
+                        let Ok(weight) = value.parse::<f64>() else {
+                            println!("info string invalid Noise value");
+                            continue;
+                        };
+                        if !(0.0..=1.0).contains(&weight) {
+                            println!("info string Noise must be between 0 and 1");
+                            continue;
+                        }
+                        engine.params_mut().noise_weight = weight;
+                    }
+                    "InteriorNoise" => {
+                        let Ok(weight) = value.parse::<f64>() else {
+                            println!("info string invalid InteriorNoise value");
+                            continue;
+                        };
+                        if !(0.0..=1.0).contains(&weight) {
+                            println!("info string InteriorNoise must be between 0 and 1");
+                            continue;
+                        }
+                        engine.params_mut().interior_noise_weight = weight;
+                    }
+                    "NoiseAlpha" => {
+                        let Ok(alpha) = value.parse::<f64>() else {
+                            println!("info string invalid NoiseAlpha value");
+                            continue;
+                        };
+                        if alpha <= 0.0 {
+                            println!("info string NoiseAlpha must be positive");
+                            continue;
+                        }
+                        engine.params_mut().noise_alpha = alpha;
+                    }
+                    root @ ("RootCpuct" | "RootFPU") => {
+                        // "<empty>" makes the root use the interior value again
+                        let override_value = if value == "<empty>" {
+                            None
+                        } else if let Ok(value) = value.parse::<f64>() {
+                            Some(value)
+                        } else {
+                            println!("info string invalid {root} value");
+                            continue;
+                        };
+                        let overrides = &mut engine.params_mut().root;
+                        if root == "RootCpuct" {
+                            overrides.c_puct = override_value;
+                        } else {
+                            overrides.first_play_urgency = override_value;
+                        }
+                    }
+                    "Exploration" => {
+                        engine.params_mut().exploration = match value {
+                            "puct" => Exploration::Puct,
+                            "variance" => Exploration::VarianceScaled,
+                            _ => {
+                                println!("info string invalid Exploration value, expected puct or variance");
+                                continue;
+                            }
+                        };
+                    }
+                    "RaveEquivalence" if cfg!(feature = "rave") => {
+                        let Ok(k) = value.parse::<f64>() else {
+                            println!("info string invalid RaveEquivalence value");
+                            continue;
+                        };
+                        // zero disables RAVE
+                        engine.params_mut().rave_equivalence = if k > 0.0 { Some(k) } else { None };
+                    }
+                    "GumbelRootMoves" => {
+                        let Ok(moves) = value.parse::<usize>() else {
+                            println!("info string invalid GumbelRootMoves value");
+                            continue;
+                        };
+                        // zero selects root moves by PUCT
+                        engine.params_mut().gumbel_root_moves = if moves > 0 { Some(moves) } else { None };
+                    }
+                    "PolicyTemperature" => {
+                        let Ok(temperature) = value.parse::<f32>() else {
+                            println!("info string invalid PolicyTemperature value");
+                            continue;
+                        };
+                        if temperature <= 0.0 {
+                            println!("info string PolicyTemperature must be positive");
+                            continue;
+                        }
+                        engine.params_mut().policy_softmax_temp = temperature;
+                    }
+                    "PolicyLogitFloor" => {
+                        // "<empty>" leaves the logits as they are
+                        if value == "<empty>" {
+                            engine.params_mut().policy_logit_floor = None;
+                            continue;
+                        }
+                        let Ok(floor) = value.parse::<f32>() else {
+                            println!("info string invalid PolicyLogitFloor value");
+                            continue;
+                        };
+                        engine.params_mut().policy_logit_floor = Some(floor);
+                    }
+                    "ValueBlend" => {
+                        let Ok(blend) = value.parse::<f32>() else {
+                            println!("info string invalid ValueBlend value");
+                            continue;
+                        };
+                        if !(0.0..=1.0).contains(&blend) {
+                            println!("info string ValueBlend must be between 0 and 1");
+                            continue;
+                        }
+                        // one trusts the network alone, and zero evaluates leaves by rollouts
+                        engine.params_mut().value_blend = blend;
+                    }
+                    "WideningMinEdges" => {
+                        let Ok(edges) = value.parse::<usize>() else {
+                            println!("info string invalid WideningMinEdges value");
+                            continue;
+                        };
+                        // zero gives every move an edge at once
+                        engine.params_mut().widening_min_edges = if edges > 0 { Some(edges) } else { None };
+                    }
+                    "WideningExponent" => {
+                        let Ok(exponent) = value.parse::<f64>() else {
+                            println!("info string invalid WideningExponent value");
+                            continue;
+                        };
+                        if exponent < 0.0 {
+                            println!("info string WideningExponent must not be negative");
+                            continue;
+                        }
+                        engine.params_mut().widening_exponent = exponent;
+                    }
+                    "GpuTelemetry" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid GpuTelemetry value, expected true or false");
+                            continue;
+                        };
+                        gpu_telemetry = enabled;
+                        gpu_monitor.set_device(cuda_ordinal(&net_path, executor_options.device).filter(|_| enabled));
+                    }
+                    "AnalysisMode" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid AnalysisMode value, expected true or false");
+                            continue;
+                        };
+                        analysis_mode = enabled;
+                    }
+                    "Epsilon" => {
+                        let Ok(epsilon) = value.parse::<f64>() else {
+                            println!("info string invalid Epsilon value");
+                            continue;
+                        };
+                        if !(0.0..=1.0).contains(&epsilon) {
+                            println!("info string Epsilon must be between 0 and 1");
+                            continue;
+                        }
+                        engine.params_mut().epsilon = epsilon;
+                    }
+                    "Temperature" => {
+                        let Ok(temperature) = value.parse::<f64>() else {
+                            println!("info string invalid Temperature value");
+                            continue;
+                        };
+                        if temperature < 0.0 {
+                            println!("info string Temperature must not be negative");
+                            continue;
+                        }
+                        // zero always plays the most-visited move
+                        engine.params_mut().temperature = temperature;
+                    }
+                    "SkillLevel" => {
+                        let Ok(level) = value.parse::<u32>() else {
+                            println!("info string invalid SkillLevel value");
+                            continue;
+                        };
+                        if level > MAX_SKILL {
+                            println!("info string SkillLevel must be at most {MAX_SKILL}");
+                            continue;
+                        }
+                        skill_level = level;
+                        engine.params_mut().skill = strength_limit(skill_level, limit_strength, elo);
+                    }
+                    "UGI_LimitStrength" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid UGI_LimitStrength value, expected true or false");
+                            continue;
+                        };
+                        limit_strength = enabled;
+                        engine.params_mut().skill = strength_limit(skill_level, limit_strength, elo);
+                    }
+                    "UGI_Elo" => {
+                        let Ok(rating) = value.parse::<u32>() else {
+                            println!("info string invalid UGI_Elo value");
+                            continue;
+                        };
+                        if !(MIN_ELO..=MAX_ELO).contains(&rating) {
+                            println!("info string UGI_Elo must be between {MIN_ELO} and {MAX_ELO}");
+                            continue;
+                        }
+                        elo = rating;
+                        engine.params_mut().skill = strength_limit(skill_level, limit_strength, elo);
+                    }
+                    "Seed" => {
+                        // "<empty>" seeds every search differently
+                        if value == "<empty>" {
+                            engine.params_mut().seed = None;
+                            continue;
+                        }
+                        let Ok(seed) = value.parse::<u64>() else {
+                            println!("info string invalid Seed value");
+                            continue;
+                        };
+                        engine.params_mut().seed = Some(seed);
+                    }
+                    "DrawScore" => {
+                        let Ok(score) = value.parse::<f64>() else {
+                            println!("info string invalid DrawScore value");
+                            continue;
+                        };
+                        if !(0.0..=1.0).contains(&score) {
+                            println!("info string DrawScore must be between 0 and 1");
+                            continue;
+                        }
+                        engine.params_mut().draw_score = score;
+                    }
+                    "PanicMultiplier" => {
+                        let Ok(multiplier) = value.parse::<f64>() else {
+                            println!("info string invalid PanicMultiplier value");
+                            continue;
+                        };
+                        if multiplier < 1.0 {
+                            println!("info string PanicMultiplier must be at least 1.0");
+                            continue;
+                        }
+                        engine.params_mut().panic_time_multiplier = multiplier;
+                    }
+                    "MaxMoveTime" => {
+                        let Ok(millis) = value.parse::<u64>() else {
+                            println!("info string invalid MaxMoveTime value");
+                            continue;
+                        };
+                        // zero disables the cap
+                        engine.params_mut().max_move_time = if millis == 0 { None } else { Some(millis) };
+                    }
+                    "EasyMove" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid EasyMove value, expected true or false");
+                            continue;
+                        };
+                        engine.params_mut().easy_move = enabled;
+                    }
+                    "EasyMoveShare" => {
+                        let Ok(percent) = value.parse::<u64>() else {
+                            println!("info string invalid EasyMoveShare value");
+                            continue;
+                        };
+                        if percent > 100 {
+                            println!("info string EasyMoveShare must be a percentage");
+                            continue;
+                        }
+                        // zero disables the threshold
+                        engine.params_mut().easy_move_share = if percent == 0 { None } else { Some(percent) };
+                    }
+                    "PolicyOnly" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid PolicyOnly value, expected true or false");
+                            continue;
+                        };
+                        engine.params_mut().policy_only = enabled;
+                    }
+                    "FastMoveThreshold" => {
+                        let Ok(millis) = value.parse::<u64>() else {
+                            println!("info string invalid FastMoveThreshold value");
+                            continue;
+                        };
+                        // zero never makes fast moves for lack of time
+                        engine.params_mut().fast_move_threshold = millis;
+                    }
+                    "FastMoveNodes" => {
+                        let Ok(nodes) = value.parse::<u64>() else {
+                            println!("info string invalid FastMoveNodes value");
+                            continue;
+                        };
+                        engine.params_mut().fast_move_nodes = nodes;
+                    }
+                    "ReuseTree" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid ReuseTree value, expected true or false");
+                            continue;
+                        };
+                        engine.params_mut().reuse_tree = enabled;
+                    }
+                    "Solver" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid Solver value, expected true or false");
+                            continue;
+                        };
+                        engine.params_mut().solver = enabled;
+                    }
+                    "EvalCache" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid EvalCache value, expected true or false");
+                            continue;
+                        };
+                        engine.params_mut().eval_cache = enabled;
+                    }
+                    "MultiPV" => {
+                        let Ok(lines) = value.parse::<usize>() else {
+                            println!("info string invalid MultiPV value");
+                            continue;
+                        };
+                        if lines == 0 {
+                            println!("info string MultiPV must be at least 1");
+                            continue;
+                        }
+                        engine.params_mut().multi_pv = lines;
+                    }
+                    "LCBSelection" => {
+                        let Ok(enabled) = value.parse::<bool>() else {
+                            println!("info string invalid LCBSelection value, expected true or false");
+                            continue;
+                        };
+                        engine.params_mut().lcb_selection = enabled;
+                    }
+                    "BatchLeaves" => {
+                        let Ok(leaves) = value.parse::<usize>() else {
+                            println!("info string invalid BatchLeaves value");
+                            continue;
+                        };
+                        if !(1..=MAX_BATCH_LEAVES).contains(&leaves) {
+                            println!("info string BatchLeaves must be between 1 and {MAX_BATCH_LEAVES}");
+                            continue;
+                        }
+                        engine.params_mut().batch_leaves = leaves;
+                        // the leaves of a round are evaluated together, so the backend is rebuilt to batch them.
+                        let new_options = ExecutorOptions { slots_per_pipe: leaves, ..executor_options };
+                        match start_executor::<G>(&net_path, new_options) {
+                            Ok(eval_pipe) => {
+                                engine.set_eval_pipe(eval_pipe);
+                                executor_options = new_options;
+                            }
+                            Err(e) => println!("info string failed to rebuild the backend for {leaves} leaves: {e:#}"),
+                        }
+                    }
+                    "MaxTreeNodes" => {
+                        let Ok(nodes) = value.parse::<usize>() else {
+                            println!("info string invalid MaxTreeNodes value");
+                            continue;
+                        };
+                        if nodes < 2 {
+                            println!("info string MaxTreeNodes must be at least 2");
+                            continue;
+                        }
+                        engine.params_mut().max_tree_nodes = nodes;
+                    }
+                    "SnapshotTarget" => {
+                        // a file path, or tcp:<host>:<port>. "<empty>" stops streaming
+                        if value == "<empty>" {
+                            engine.set_snapshot_sink(None);
+                            continue;
+                        }
+                        match SnapshotSink::open(value) {
+                            Ok(sink) => engine.set_snapshot_sink(Some(sink)),
+                            Err(e) => println!("info string failed to set SnapshotTarget: {e:#}"),
+                        }
+                    }
+                    "SnapshotInterval" => {
+                        let Ok(millis) = value.parse::<u64>() else {
+                            println!("info string invalid SnapshotInterval value");
+                            continue;
+                        };
+                        engine.params_mut().snapshot_interval = millis;
+                    }
+                    "SnapshotDepth" => {
+                        let Ok(depth) = value.parse::<usize>() else {
+                            println!("info string invalid SnapshotDepth value");
+                            continue;
+                        };
+                        engine.params_mut().snapshot_depth = depth;
+                    }
+                    "SnapshotWidth" => {
+                        let Ok(width) = value.parse::<usize>() else {
+                            println!("info string invalid SnapshotWidth value");
+                            continue;
+                        };
+                        engine.params_mut().snapshot_width = width;
+                    }
+                    "Backend" | "Device" | "BatchSize" => {
+                        let new_options = match name {
+                            "Backend" => value.parse().map(|backend| ExecutorOptions { backend, ..executor_options }),
+                            "Device" => value.parse().map(|device| ExecutorOptions { device, ..executor_options }),
+                            _ => match value.parse::<usize>() {
+                                Ok(batch_size) if batch_size > 0 => {
+                                    Ok(ExecutorOptions { batch_size, ..executor_options })
+                                }
+                                _ => Err(anyhow::anyhow!("expected a batch size of at least 1")),
+                            },
+                        };
+                        let new_options = match new_options {
+                            Ok(new_options) => new_options,
+                            Err(e) => {
+                                println!("info string invalid {name} value: {e}");
+                                continue;
+                            }
+                        };
+                        // the backend is rebuilt straight away, so an unavailable device is reported here.
+                        match start_executor::<G>(&net_path, new_options) {
+                            Ok(eval_pipe) => {
+                                engine.set_eval_pipe(eval_pipe);
+                                executor_options = new_options;
+                                if gpu_telemetry {
+                                    gpu_monitor.set_device(cuda_ordinal(&net_path, executor_options.device));
+                                }
+                            }
+                            Err(e) => println!("info string failed to set {name}, keeping the old backend: {e:#}"),
+                        }
+                    }
+                    "Model" => {
+                        // a URL is downloaded into the model store first, as on the command line.
+                        let loaded = models::resolve(value).and_then(|path| {
+                            let network = batching::load_network_with::<G>(&path, executor_options.backend)?;
+                            Ok((path, batching::executor_on(&network, 1, executor_options)?))
+                        });
+                        match loaded {
+                            Ok((path, handles)) => {
+                                engine.set_network(
+                                    handles.into_iter().next().unwrap().with_priority(Priority::Interactive),
+                                );
+                                model_hash = hash::sha256_file(Path::new(&path));
+                                net_path = path;
+                                if gpu_telemetry {
+                                    gpu_monitor.set_device(cuda_ordinal(&net_path, executor_options.device));
+                                }
+                                println!("info string loaded model {net_path}");
+                            }
+                            Err(e) => println!("info string failed to load Model, keeping the old one: {e:#}"),
+                        }
+                    }
+                    "UGI_Variant" => {
+                        if let Err(e) = value.parse::<GameSpec>() {
+                            println!("info string invalid UGI_Variant value: {e:#}");
+                            continue;
+                        }
+                        // the engine is built for a single game, so another takes a fresh loop, with every
+                        // option back at its default.
+                        if value != game {
+                            println!("info string switching to {value}");
+                            return Ok(Exit::Switch { game: value.to_owned(), pending });
+                        }
+                    }
+                    "BookFile" => {
+                        // "<empty>" stops playing from a book
+                        if value == "<empty>" {
+                            engine.set_book(None);
+                        } else {
+                            match Book::load(value) {
+                                Ok(book) => engine.set_book(Some(book)),
+                                Err(e) => println!("info string failed to set BookFile: {e:#}"),
+                            }
+                        }
+                    }
+                    "TreeFile" => {
+                        // "<empty>" returns the tree to ordinary memory
+                        let result = if value == "<empty>" {
+                            engine.clear_tree_file();
+                            Ok(())
+                        } else {
+                            engine.set_tree_file(Path::new(value))
+                        };
+                        if let Err(e) = result {
+                            println!("info string failed to set TreeFile: {e:#}");
+                        }
+                    }
                     _ => println!("info string unknown option: {name}"),
                 }
             }
@@ -193,59 +1046,422 @@ pub fn main_loop<G: GameImpl>(net_path: Option<&str>) -> anyhow::Result<()> {
 
     STDIN_READER_THREAD_KEEP_RUNNING.store(false, Ordering::SeqCst);
 
-    Ok(())
+    Ok(Exit::Quit)
+}
+
+/// Splits a `setoption name <name> value <value>` command into the option's name and value.
+/// As in UCI, both may span several words: the name runs up to the word `value`, and the value is
+/// everything after it, so that paths with spaces in can be given.
+fn parse_setoption(set_option: &str) -> Option<(&str, &str)> {
+    let rest = set_option.strip_prefix("setoption")?.trim_start().strip_prefix("name")?;
+    let (name, value) = rest.split_once(" value ")?;
+    let (name, value) = (name.trim(), value.trim());
+    if name.is_empty() || value.is_empty() {
+        return None;
+    }
+    Some((name, value))
 }
 
-fn make_move_on_engine<G: GameImpl>(play: &str, engine: &mut Engine<'_, G>) -> ControlFlow<()> {
-    let Ok(mv) = play.trim_start_matches("play ").trim().parse() else {
-        println!("info string invalid move \"{play}\"");
-        return ControlFlow::Break(());
+/// Prints an `option` line for every option that `setoption` accepts, with its default value.
+/// Fractional values have no option type of their own, so they are advertised as strings.
+fn print_options(game: &str, net_path: &str, executor_options: ExecutorOptions) {
+    let params = Params::default();
+    let spin = |name: &str, default: u64, min: u64, max: u64| {
+        println!("option name {name} type spin default {default} min {min} max {max}");
     };
-    let mut root = engine.root();
-    let mut move_legal = false;
-    root.generate_moves(|legal_mv| {
-        if legal_mv == mv {
-            move_legal = true;
-        };
-        move_legal
+    let check = |name: &str, default: bool| println!("option name {name} type check default {default}");
+    let string = |name: &str, default: &dyn std::fmt::Display| {
+        println!("option name {name} type string default {default}");
+    };
+    let combo = |name: &str, default: &str, vars: &[&str]| {
+        let vars = vars.iter().map(|var| format!(" var {var}")).collect::<String>();
+        println!("option name {name} type combo default {default}{vars}");
+    };
+    // an optional value is advertised as empty when it is unset.
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "<empty>".into());
+
+    string("cpuct", &params.c_puct);
+    string("CPuctFactor", &params.cpuct_factor);
+    string("CPuctBase", &params.cpuct_base);
+    spin("CPuctDecayPlies", params.cpuct_decay_plies as u64, 0, SPIN_MAX);
+    string("CPuctDecayFloor", &params.cpuct_decay_floor);
+    let fpu_strategy = match params.fpu_strategy {
+        FpuStrategy::Absolute => "absolute",
+        FpuStrategy::Reduction => "reduction",
+        FpuStrategy::Loss => "loss",
+    };
+    combo("FPUStrategy", fpu_strategy, &["absolute", "reduction", "loss"]);
+    string("FPU", &params.first_play_urgency);
+    string("FPUReduction", &params.fpu_reduction);
+    string("Noise", &params.noise_weight);
+    string("InteriorNoise", &params.interior_noise_weight);
+    string("NoiseAlpha", &params.noise_alpha);
+    string("RootCpuct", &optional(params.root.c_puct.as_ref().map(ToString::to_string)));
+    string("RootFPU", &optional(params.root.first_play_urgency.as_ref().map(ToString::to_string)));
+    let exploration = match params.exploration {
+        Exploration::Puct => "puct",
+        Exploration::VarianceScaled => "variance",
+    };
+    combo("Exploration", exploration, &["puct", "variance"]);
+    if cfg!(feature = "rave") {
+        string("RaveEquivalence", &params.rave_equivalence.unwrap_or(0.0));
+    }
+    spin("GumbelRootMoves", params.gumbel_root_moves.unwrap_or(0) as u64, 0, SPIN_MAX);
+    string("PolicyTemperature", &params.policy_softmax_temp);
+    string("PolicyLogitFloor", &optional(params.policy_logit_floor.as_ref().map(ToString::to_string)));
+    string("ValueBlend", &params.value_blend);
+    spin("WideningMinEdges", params.widening_min_edges.unwrap_or(0) as u64, 0, SPIN_MAX);
+    string("WideningExponent", &params.widening_exponent);
+    check("GpuTelemetry", false);
+    check("AnalysisMode", false);
+    string("Epsilon", &params.epsilon);
+    string("Temperature", &params.temperature);
+    spin("SkillLevel", u64::from(MAX_SKILL), 0, u64::from(MAX_SKILL));
+    check("UGI_LimitStrength", false);
+    spin("UGI_Elo", u64::from(MAX_ELO), u64::from(MIN_ELO), u64::from(MAX_ELO));
+    string("Seed", &optional(params.seed.as_ref().map(ToString::to_string)));
+    string("DrawScore", &params.draw_score);
+    string("PanicMultiplier", &params.panic_time_multiplier);
+    spin("MaxMoveTime", params.max_move_time.unwrap_or(0), 0, SPIN_MAX);
+    check("EasyMove", params.easy_move);
+    spin("EasyMoveShare", params.easy_move_share.unwrap_or(0), 0, 100);
+    check("PolicyOnly", params.policy_only);
+    spin("FastMoveThreshold", params.fast_move_threshold, 0, SPIN_MAX);
+    spin("FastMoveNodes", params.fast_move_nodes, 0, SPIN_MAX);
+    check("ReuseTree", params.reuse_tree);
+    check("Solver", params.solver);
+    check("EvalCache", params.eval_cache);
+    spin("MultiPV", params.multi_pv as u64, 1, SPIN_MAX);
+    check("LCBSelection", params.lcb_selection);
+    spin("BatchLeaves", params.batch_leaves as u64, 1, MAX_BATCH_LEAVES as u64);
+    spin("MaxTreeNodes", params.max_tree_nodes as u64, 2, SPIN_MAX);
+    string("SnapshotTarget", &"<empty>");
+    spin("SnapshotInterval", params.snapshot_interval, 0, SPIN_MAX);
+    spin("SnapshotDepth", params.snapshot_depth as u64, 0, SPIN_MAX);
+    spin("SnapshotWidth", params.snapshot_width as u64, 0, SPIN_MAX);
+    string("TreeFile", &"<empty>");
+    string("BookFile", &"<empty>");
+    combo("Backend", &executor_options.backend.to_string(), &["kyanite", "onnxruntime"]);
+    string("Device", &executor_options.device);
+    spin("BatchSize", executor_options.batch_size as u64, 1, SPIN_MAX);
+    string("Model", &net_path);
+    string("UGI_Variant", &game);
+}
+
+/// The skill level to play at, with the Elo rating taking over from the skill level while strength is limited,
+/// or `None` to play at full strength.
+fn strength_limit(skill_level: u32, limit_strength: bool, elo: u32) -> Option<u32> {
+    let skill = if limit_strength { params::skill_for_elo(elo) } else { skill_level };
+    (skill < MAX_SKILL).then_some(skill)
+}
+
+/// The network's probability of each legal move in `pos`, from its policy logits.
+fn legal_priors<G: GameImpl>(pos: &G, logits: &[f32]) -> Vec<(G::Move, f64)> {
+    let mut moves = Vec::new();
+    pos.generate_moves(|mv| {
+        moves.push((mv, f64::from(logits[mv.policy_index()])));
+        false
     });
-    if !move_legal {
-        println!("info string illegal move \"{mv}\"");
-        return ControlFlow::Break(());
-    }
-    root.make_move(mv);
-    engine.set_position(&root);
-    ControlFlow::Continue(())
-}
-
-fn parse_position<G: GameImpl>(set_position: &str, engine: &mut Engine<'_, G>) -> ControlFlow<()> {
-    let (board_part, moves_part) = set_position.trim_start_matches("position ").trim().split_once("moves").map_or_else(
-        || (set_position.trim_start_matches("position ").trim(), ""),
-        |(board_part, moves_part)| (board_part.trim(), moves_part.trim()),
-    );
-    let mut board = match board_part {
-        "startpos" => G::default(),
-        fen if fen.starts_with("fen ") => {
-            if let Ok(board) = fen.trim_start_matches("fen ").trim().parse() {
-                board
-            } else {
-                println!("info string invalid fen \"{fen}\"");
-                return ControlFlow::Break(());
-            }
+    let max_logit = moves.iter().map(|&(_, logit)| logit).fold(f64::NEG_INFINITY, f64::max);
+    let total = moves.iter().map(|&(_, logit)| (logit - max_logit).exp()).sum::<f64>();
+    moves.into_iter().map(|(mv, logit)| (mv, (logit - max_logit).exp() / total)).collect()
+}
+
+/// Prints the network's value of the root and an aligned table of its legal moves, with the network's prior
+/// of each and, if the root has been searched, their visits and values for the side to move.
+fn print_move_table<G: GameImpl>(engine: &mut Engine<'_, G>) {
+    let root = engine.root();
+    let mut rows = match engine.raw_evaluation() {
+        Ok(eval) => {
+            println!("info string value {:.4}", eval.value);
+            legal_priors(&root, &eval.policy).into_iter().map(|(mv, prior)| (mv, Some(prior))).collect()
         }
-        _ => {
-            println!("info string invalid position command");
-            return ControlFlow::Break(());
+        Err(e) => {
+            println!("info string no priors: {e}");
+            let mut moves = Vec::new();
+            root.generate_moves(|mv| {
+                moves.push((mv, None));
+                false
+            });
+            moves
         }
     };
-    for mv in moves_part.split_ascii_whitespace() {
-        if let Ok(mv) = mv.parse() {
-            board.make_move(mv);
-        } else {
-            println!("info string invalid move \"{mv}\"");
-            continue;
+    let stats = engine.root_stats();
+    let stats_of = |mv: G::Move| stats.iter().find(|root_move| root_move.mv == mv);
+    // the most-searched moves first, and then the most probable.
+    rows.sort_by(|&(a, a_prior), &(b, b_prior)| {
+        let visits = |mv| stats_of(mv).map_or(0, |root_move| root_move.visits);
+        visits(b).cmp(&visits(a)).then(b_prior.unwrap_or(0.0).total_cmp(&a_prior.unwrap_or(0.0)))
+    });
+    let width = rows.iter().map(|(mv, _)| mv.to_string().len()).max().unwrap_or(0).max(4);
+    println!("info string {:<width$} {:>7} {:>8} {:>7}", "move", "prior", "visits", "q");
+    for (mv, prior) in rows {
+        let prior = prior.map_or_else(|| "-".into(), |prior| format!("{:.2}%", prior * 100.0));
+        let (visits, q) = stats_of(mv).map_or((0, None), |root_move| (root_move.visits, root_move.q));
+        let q = q.map_or_else(|| "-".into(), |q| format!("{q:.4}"));
+        println!("info string {:<width$} {prior:>7} {visits:>8} {q:>7}", mv.to_string());
+    }
+}
+
+/// Parses a `tree [top <k>] [depth <plies>]` command into the number of root moves to print
+/// and the length to cut their lines of best play to.
+fn parse_tree_command(tree: &str) -> Option<(usize, usize)> {
+    let (mut top_k, mut depth) = (TREE_DEFAULT_TOP, TREE_DEFAULT_DEPTH);
+    let mut words = tree.strip_prefix("tree")?.split_ascii_whitespace();
+    while let Some(word) = words.next() {
+        let value = words.next()?.parse().ok()?;
+        match word {
+            "top" => top_k = value,
+            "depth" => depth = value,
+            _ => return None,
         }
     }
-    engine.set_position(&board);
-    ControlFlow::Continue(())
+    Some((top_k, depth))
+}
+
+/// Prints the `top_k` most-visited root moves, with their visits, values, priors, and exploration bonuses,
+/// and the first `depth` moves of the line of best play after each, to show why a move was chosen.
+#[allow(clippy::cast_precision_loss)]
+fn print_tree<G: GameImpl>(engine: &Engine<'_, G>, top_k: usize, depth: usize) {
+    let stats = engine.root_stats();
+    if stats.is_empty() {
+        println!("info string the root has not been searched");
+        return;
+    }
+    let total = stats.iter().map(|root_move| root_move.visits).sum::<u64>().max(1);
+    println!("info string root visits {total}");
+    let width = stats.iter().map(|root_move| root_move.mv.to_string().len()).max().unwrap_or(0).max(4);
+    println!("info string {:<width$} {:>8} {:>7} {:>7} {:>7} {:>7}  pv", "move", "n", "share", "q", "p", "u");
+    for root_move in stats.iter().take(top_k) {
+        let share = format!("{:.2}%", root_move.visits as f64 / total as f64 * 100.0);
+        let q = root_move.q.map_or_else(|| "-".into(), |q| format!("{q:.4}"));
+        let prior = format!("{:.2}%", root_move.prior * 100.0);
+        let pv = root_move.pv.iter().take(depth).map(ToString::to_string).collect::<Vec<_>>();
+        println!(
+            "info string {:<width$} {:>8} {share:>7} {q:>7} {prior:>7} {:>7.4}  {}",
+            root_move.mv.to_string(),
+            root_move.visits,
+            root_move.u,
+            pv.join(" ")
+        );
+    }
+}
+
+/// Starts an executor for the network at `net_path` with `options`, returning the engine's handle to it.
+fn start_executor<G: GameImpl>(net_path: &str, options: ExecutorOptions) -> anyhow::Result<ExecutorHandle<G>> {
+    let network = batching::load_network_or_uniform::<G>(net_path, options.backend)?;
+    let handle = batching::executor_on(&network, 1, options)?.into_iter().next().unwrap();
+    Ok(handle.with_priority(Priority::Interactive))
+}
+
+/// The CUDA ordinal of `device`, if it is a GPU that the network at `net_path` runs on, and so can be monitored.
+/// Native networks, the uniform evaluator used when there is no network, and pure MCTS never touch the GPU,
+/// whatever the device.
+fn cuda_ordinal(net_path: &str, device: Device) -> Option<i32> {
+    let on_gpu =
+        cfg!(not(feature = "pure-mcts")) && !net_path.ends_with(".safetensors") && Path::new(net_path).exists();
+    match device {
+        Device::Cuda(ordinal) if on_gpu => Some(ordinal),
+        _ => None,
+    }
+}
+
+/// Runs a search for a command that arrived at `received` on a worker thread, handling the commands that
+/// arrive until it finishes, as `run_in_background` does.
+fn search_in_background<G: GameImpl>(
+    engine: &mut Engine<'_, G>,
+    received: Instant,
+    stdin: &mpsc::Receiver<String>,
+    stop: &AtomicBool,
+    pending: &mut VecDeque<String>,
+    interruptible: bool,
+) -> anyhow::Result<SearchResults<G>> {
+    run_in_background(stdin, stop, pending, interruptible, || engine.go_received(received))?
+}
+
+/// Runs `work`, a search that ends once `stop` is set, on a worker thread, handling the commands that
+/// arrive until it finishes: `isready` is answered at once, `stop` ends the search, and any other command
+/// ends the search and is queued in `pending`, to be handled once it has finished. Unless `interruptible`
+/// is set, as it is for analysis, a `go` or a change of position is refused instead, and the search goes on.
+fn run_in_background<T: Send>(
+    stdin: &mpsc::Receiver<String>,
+    stop: &AtomicBool,
+    pending: &mut VecDeque<String>,
+    interruptible: bool,
+    work: impl FnOnce() -> T + Send,
+) -> anyhow::Result<T> {
+    stop.store(false, Ordering::SeqCst);
+    std::thread::scope(|scope| {
+        let search = std::thread::Builder::new().name("search".into()).spawn_scoped(scope, work)?;
+        while !search.is_finished() {
+            match stdin.recv_timeout(SEARCH_POLL_INTERVAL) {
+                Ok(cmd) => match cmd.trim() {
+                    "isready" => println!("readyok"),
+                    "stop" => stop.store(true, Ordering::SeqCst),
+                    cmd if !interruptible && (cmd == "go" || cmd.starts_with("go ")) => {
+                        println!("info string already searching, send stop before starting another search");
+                    }
+                    cmd if !interruptible && changes_position(cmd) => {
+                        println!("info string cannot change the position during a search, send stop first");
+                    }
+                    cmd => {
+                        if cmd == "quit" {
+                            QUIT.store(true, Ordering::SeqCst);
+                        }
+                        stop.store(true, Ordering::SeqCst);
+                        pending.push_back(cmd.to_owned());
+                    }
+                },
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    stop.store(true, Ordering::SeqCst);
+                    break;
+                }
+            }
+        }
+        Ok(search.join().expect("the search thread panicked"))
+    })
+}
+
+/// Whether a command ends an analysis for good, rather than pausing it until the command has been handled.
+fn ends_analysis(cmd: &str) -> bool {
+    changes_position(cmd) || cmd == "go" || cmd.starts_with("go ") || matches!(cmd, "stop" | "quit")
+}
+
+/// Whether a command sets or changes the position, which must not happen while it is being searched.
+fn changes_position(cmd: &str) -> bool {
+    ["position ", "play "].iter().any(|prefix| cmd.starts_with(prefix))
+        || matches!(cmd, "undo" | "takeback" | "uginewgame" | "ucinewgame" | "uainewgame")
+}
+
+/// Searches the current position until a command arrives, streaming info lines but no best move.
+/// Returns the root value for the side to move, unless the game is already over.
+fn analyse<G: GameImpl>(
+    engine: &mut Engine<'_, G>,
+    stdin: &mpsc::Receiver<String>,
+    stop: &AtomicBool,
+    pending: &mut VecDeque<String>,
+) -> anyhow::Result<Option<f64>> {
+    if engine.root().outcome().is_some() {
+        return Ok(None);
+    }
+    engine.set_limits(Limits::infinite());
+    // analysis is of the position alone, so exploration does not decay with the length of the game.
+    let decay_plies = std::mem::take(&mut engine.params_mut().cpuct_decay_plies);
+    let results = search_in_background(engine, Instant::now(), stdin, stop, pending, true);
+    engine.params_mut().cpuct_decay_plies = decay_plies;
+    let SearchResults { value, .. } = results?;
+    Ok(Some(value))
+}
+
+fn make_move_on_engine<G: GameImpl>(play: &str, engine: &mut Engine<'_, G>) -> Result<(), ParseError> {
+    let mv = notation::parse_legal_move(&engine.root(), play.trim_start_matches("play ").trim(), None)?;
+    engine.make_move(mv);
+    Ok(())
+}
+
+/// Sets the engine's position from a `position` command, in which `startpos` stands for `starting_position`,
+/// leaving it untouched if any part is invalid.
+fn parse_position<G: GameImpl>(
+    set_position: &str,
+    starting_position: G,
+    engine: &mut Engine<'_, G>,
+) -> Result<(), ParseError> {
+    let set_position = set_position.trim_start_matches("position ").trim();
+    let (start, moves) = notation::parse_position(set_position, starting_position)?;
+    engine.set_game(&start, &moves);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setoption_names_and_values_may_span_several_words() {
+        assert_eq!(parse_setoption("setoption name cpuct value 2.5"), Some(("cpuct", "2.5")));
+        assert_eq!(
+            parse_setoption(r"setoption name TreeFile value C:\my trees\tree.bin"),
+            Some(("TreeFile", r"C:\my trees\tree.bin"))
+        );
+        assert_eq!(parse_setoption("setoption name Clear Hash value  true "), Some(("Clear Hash", "true")));
+        assert_eq!(parse_setoption("setoption name cpuct"), None);
+        assert_eq!(parse_setoption("setoption name cpuct value"), None);
+        assert_eq!(parse_setoption("setoption cpuct 2.5"), None);
+    }
+
+    #[test]
+    fn priors_are_a_distribution_over_the_legal_moves() {
+        let pos = crate::uttt::Board::default();
+        let mut logits = vec![0.0; crate::uttt::Board::policy_dim()];
+        logits[0] = 2.0_f32.ln();
+        let priors = legal_priors(&pos, &logits);
+        assert_eq!(priors.len(), 81);
+        assert!((priors.iter().map(|&(_, prior)| prior).sum::<f64>() - 1.0).abs() < 1e-9);
+        let most_probable = priors.iter().max_by(|a, b| a.1.total_cmp(&b.1)).unwrap();
+        assert_eq!(most_probable.0.policy_index(), 0);
+        assert!((most_probable.1 - 2.0 / 82.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tree_commands_take_a_top_k_and_depth() {
+        assert_eq!(parse_tree_command("tree"), Some((TREE_DEFAULT_TOP, TREE_DEFAULT_DEPTH)));
+        assert_eq!(parse_tree_command("tree top 3"), Some((3, TREE_DEFAULT_DEPTH)));
+        assert_eq!(parse_tree_command("tree depth 2 top 5"), Some((5, 2)));
+        assert_eq!(parse_tree_command("tree top"), None);
+        assert_eq!(parse_tree_command("tree width 3"), None);
+    }
+
+    #[test]
+    fn limited_strength_follows_the_elo_rating() {
+        assert_eq!(strength_limit(MAX_SKILL, false, MIN_ELO), None);
+        assert_eq!(strength_limit(5, false, MIN_ELO), Some(5));
+        assert_eq!(strength_limit(5, true, MIN_ELO), Some(0));
+        assert_eq!(strength_limit(5, true, MAX_ELO), None);
+    }
+
+    #[test]
+    fn position_changes_are_recognised() {
+        assert!(changes_position("position startpos moves e5"));
+        assert!(changes_position("play e5"));
+        assert!(changes_position("undo"));
+        assert!(changes_position("uginewgame"));
+        assert!(!changes_position("positions"));
+        assert!(!changes_position("go nodes 100"));
+        assert!(!changes_position("setoption name Temperature value 1"));
+    }
+
+    #[test]
+    fn only_new_positions_and_searches_end_analysis() {
+        assert!(ends_analysis("position startpos"));
+        assert!(ends_analysis("go infinite"));
+        assert!(ends_analysis("quit"));
+        assert!(!ends_analysis("isready"));
+        assert!(!ends_analysis("setoption name Temperature value 1"));
+        assert!(!ends_analysis("query p1turn"));
+    }
+
+    #[test]
+    fn only_playable_games_can_be_switched_to() {
+        assert_eq!("gomoku15".parse::<GameSpec>().unwrap(), GameSpec::Gomoku15);
+        assert_eq!("ataxx:5".parse::<GameSpec>().unwrap(), GameSpec::SizedAtaxx(5));
+        assert!("mnk:7x6x4".parse::<GameSpec>().is_ok());
+        assert!("ataxx:20".parse::<GameSpec>().is_err());
+        assert!("mnk:7x6".parse::<GameSpec>().is_err());
+        assert!("chess".parse::<GameSpec>().is_err());
+    }
+
+    #[test]
+    fn switching_games_keeps_queued_commands() {
+        let (_sender, stdin) = mpsc::channel();
+        let pending = ["setoption name UGI_Variant value gomoku9", "isready"].map(String::from).into();
+        let options = ExecutorOptions { device: Device::Cpu, ..ExecutorOptions::default() };
+        let exit =
+            main_loop("uttt", crate::uttt::Board::default(), Some("./no/such/model.onnx"), options, &stdin, pending);
+        let Ok(Exit::Switch { game, pending }) = exit else {
+            panic!("the variant was not switched");
+        };
+        assert_eq!(game, "gomoku9");
+        assert_eq!(pending, ["isready"]);
+    }
 }
@@ -0,0 +1,396 @@
+//! Ultimate tic-tac-toe: nine local tic-tac-toe boards arranged in a 3x3 grid.
+//!
+//! Playing on a given cell of a local board sends the opponent to the local board
+//! at the corresponding position. If that board is already decided, the opponent may
+//! play on any undecided board. Winning three local boards in a row wins the game.
+
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+use crate::game::Player;
+
+/// The number of cells on the full board.
+pub const CELLS: usize = 81;
+
+/// The eight winning lines of a 3x3 board, as bitmasks over its cells.
+const LINES: [u16; 8] = [0o007, 0o070, 0o700, 0o111, 0o222, 0o444, 0o421, 0o124];
+
+/// A full local (or macro) board.
+const FULL: u16 = 0o777;
+
+const fn has_line(mask: u16) -> bool {
+    let mut i = 0;
+    while i < LINES.len() {
+        if mask & LINES[i] == LINES[i] {
+            return true;
+        }
+        i += 1;
+    }
+    false
+}
+
+/// Converts a global cell index (`row * 9 + col`) into a (local board, local cell) pair.
+const fn split(index: usize) -> (usize, usize) {
+    let (row, col) = (index / 9, index % 9);
+    ((row / 3) * 3 + col / 3, (row % 3) * 3 + col % 3)
+}
+
+/// Converts a (local board, local cell) pair into a global cell index.
+const fn join(board: usize, cell: usize) -> usize {
+    let row = (board / 3) * 3 + cell / 3;
+    let col = (board % 3) * 3 + cell % 3;
+    row * 9 + col
+}
+
+/// A move in ultimate tic-tac-toe, i.e. the cell on which to place a stone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Move(u8);
+
+impl Move {
+    /// The global index of the cell, `row * 9 + col`.
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    fn from_parts(board: usize, cell: usize) -> Self {
+        Self(join(board, cell).try_into().expect("cell index out of range"))
+    }
+}
+
+impl Display for Move {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file = (b'a' + self.0 % 9) as char;
+        write!(f, "{file}{}", self.0 / 9 + 1)
+    }
+}
+
+impl FromStr for Move {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut chars = s.chars();
+        let file = chars.next().with_context(|| "empty move string")?;
+        if !('a'..='i').contains(&file) {
+            anyhow::bail!("invalid file {file:?} in move {s:?}");
+        }
+        let rank: u8 = chars.as_str().parse().with_context(|| format!("invalid rank in move {s:?}"))?;
+        if !(1..=9).contains(&rank) {
+            anyhow::bail!("invalid rank {rank} in move {s:?}");
+        }
+        Ok(Self((rank - 1) * 9 + (file as u8 - b'a')))
+    }
+}
+
+/// An ultimate tic-tac-toe position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Board {
+    /// The stones of the first and second players on each local board.
+    local: [[u16; 9]; 2],
+    /// The local boards won by the first and second players.
+    won: [u16; 2],
+    /// The local boards that are won or full.
+    closed: u16,
+    /// The local board the side to move must play on, if constrained.
+    forced: Option<u8>,
+    /// Whether the second player is to move.
+    second_to_move: bool,
+}
+
+impl Board {
+    pub const fn turn(&self) -> Player {
+        if self.second_to_move {
+            Player::Second
+        } else {
+            Player::First
+        }
+    }
+
+    const fn side(&self) -> usize {
+        self.second_to_move as usize
+    }
+
+    /// The player whose stone is on the given global cell.
+    pub const fn stone_at(&self, index: usize) -> Player {
+        let (board, cell) = split(index);
+        if self.local[0][board] & 1 << cell != 0 {
+            Player::First
+        } else if self.local[1][board] & 1 << cell != 0 {
+            Player::Second
+        } else {
+            Player::None
+        }
+    }
+
+    pub const fn outcome(&self) -> Option<Player> {
+        if has_line(self.won[0]) {
+            Some(Player::First)
+        } else if has_line(self.won[1]) {
+            Some(Player::Second)
+        } else if self.closed == FULL {
+            Some(Player::None)
+        } else {
+            None
+        }
+    }
+
+    /// The local boards the side to move may play on, as a bitmask.
+    const fn playable_boards(&self) -> u16 {
+        match self.forced {
+            Some(board) => 1 << board,
+            None => FULL & !self.closed,
+        }
+    }
+
+    pub fn make_move(&mut self, mv: Move) {
+        let (board, cell) = split(mv.index());
+        let side = self.side();
+        self.local[side][board] |= 1 << cell;
+        if has_line(self.local[side][board]) {
+            self.won[side] |= 1 << board;
+            self.closed |= 1 << board;
+        } else if self.local[0][board] | self.local[1][board] == FULL {
+            self.closed |= 1 << board;
+        }
+        self.forced =
+            if self.closed & 1 << cell == 0 { Some(cell.try_into().expect("cell out of range")) } else { None };
+        self.second_to_move = !self.second_to_move;
+    }
+
+    /// Generate a list of legal moves.
+    /// Iteration short-circuits if the callback returns `true`.
+    pub fn generate_moves(&self, mut f: impl FnMut(Move) -> bool) {
+        if self.outcome().is_some() {
+            return;
+        }
+        let playable = self.playable_boards();
+        for board in (0..9).filter(|board| playable & 1 << board != 0) {
+            let occupied = self.local[0][board] | self.local[1][board];
+            for cell in (0..9).filter(|cell| occupied & 1 << cell == 0) {
+                if f(Move::from_parts(board, cell)) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Calls the callback with the index of every active feature:
+    /// the side to move's stones, the opponent's stones, the cells of the local boards
+    /// the side to move may play on, and the cells of the decided local boards.
+    pub fn feature_map(&self, mut f: impl FnMut(usize)) {
+        let us = self.side();
+        let playable = self.playable_boards();
+        for index in 0..CELLS {
+            let (board, cell) = split(index);
+            if self.local[us][board] & 1 << cell != 0 {
+                f(index);
+            } else if self.local[1 - us][board] & 1 << cell != 0 {
+                f(CELLS + index);
+            }
+            if playable & 1 << board != 0 {
+                f(2 * CELLS + index);
+            }
+            if self.closed & 1 << board != 0 {
+                f(3 * CELLS + index);
+            }
+        }
+    }
+
+    /// Writes the features of `feature_map` as a dense row of `4 * CELLS` ones and zeros,
+    /// which avoids a callback per feature and branches on the cell contents.
+    pub fn write_features(&self, row: &mut [f32]) {
+        let us = self.side();
+        let playable = self.playable_boards();
+        let (ours, rest) = row[..4 * CELLS].split_at_mut(CELLS);
+        let (theirs, rest) = rest.split_at_mut(CELLS);
+        let (playable_row, closed_row) = rest.split_at_mut(CELLS);
+        let bit = |mask: u16, i: usize| f32::from(mask >> i & 1);
+        for index in 0..CELLS {
+            let (board, cell) = split(index);
+            ours[index] = bit(self.local[us][board], cell);
+            theirs[index] = bit(self.local[1 - us][board], cell);
+            playable_row[index] = bit(playable, board);
+            closed_row[index] = bit(self.closed, board);
+        }
+    }
+
+    pub fn fen(&self) -> String {
+        let mut fen = String::new();
+        for row in (0..9).rev() {
+            let mut empty = 0;
+            for col in 0..9 {
+                let c = match self.stone_at(row * 9 + col) {
+                    Player::None => {
+                        empty += 1;
+                        continue;
+                    }
+                    Player::First => 'x',
+                    Player::Second => 'o',
+                };
+                if empty > 0 {
+                    fen += &empty.to_string();
+                    empty = 0;
+                }
+                fen.push(c);
+            }
+            if empty > 0 {
+                fen += &empty.to_string();
+            }
+            if row > 0 {
+                fen.push('/');
+            }
+        }
+        let side = if self.second_to_move { 'o' } else { 'x' };
+        let forced = self.forced.map_or_else(|| "-".to_string(), |board| (board + 1).to_string());
+        format!("{fen} {side} {forced}")
+    }
+}
+
+impl Display for Board {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in (0..9).rev() {
+            for col in 0..9 {
+                let c = match self.stone_at(row * 9 + col) {
+                    Player::None => '.',
+                    Player::First => 'x',
+                    Player::Second => 'o',
+                };
+                write!(f, "{c}")?;
+                if col % 3 == 2 && col < 8 {
+                    write!(f, "|")?;
+                }
+            }
+            if row > 0 {
+                writeln!(f)?;
+                if row % 3 == 0 {
+                    writeln!(f, "---+---+---")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Board {
+    type Err = anyhow::Error;
+
+    /// Parses a FEN of the form `9/9/9/9/4x4/9/9/9/9 o 5`, where the last field is the
+    /// local board (1-9, numbered from the bottom-left) the side to move must play on, or `-`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_ascii_whitespace();
+        let rows = parts.next().with_context(|| format!("empty fen {s:?}"))?.split('/').collect::<Vec<_>>();
+        if rows.len() != 9 {
+            anyhow::bail!("expected 9 rows in fen {s:?}, found {}", rows.len());
+        }
+        let mut board = Self::default();
+        for (i, row_text) in rows.iter().enumerate() {
+            let row = 8 - i;
+            let mut col = 0;
+            for c in row_text.chars() {
+                if let Some(digit) = c.to_digit(10) {
+                    col += digit as usize;
+                    continue;
+                }
+                if col >= 9 {
+                    anyhow::bail!("row {row_text:?} is too long in fen {s:?}");
+                }
+                let (local, cell) = split(row * 9 + col);
+                match c {
+                    'x' => board.local[0][local] |= 1 << cell,
+                    'o' => board.local[1][local] |= 1 << cell,
+                    _ => anyhow::bail!("invalid character {c:?} in fen {s:?}"),
+                }
+                col += 1;
+            }
+            if col != 9 {
+                anyhow::bail!("row {row_text:?} does not have 9 cells in fen {s:?}");
+            }
+        }
+        for local in 0..9 {
+            for side in 0..2 {
+                if has_line(board.local[side][local]) {
+                    board.won[side] |= 1 << local;
+                }
+            }
+            if board.won[0] & 1 << local != 0
+                || board.won[1] & 1 << local != 0
+                || board.local[0][local] | board.local[1][local] == FULL
+            {
+                board.closed |= 1 << local;
+            }
+        }
+        board.second_to_move = match parts.next() {
+            Some("x") => false,
+            Some("o") => true,
+            other => anyhow::bail!("invalid side-to-move {other:?} in fen {s:?}"),
+        };
+        board.forced = match parts.next() {
+            None | Some("-") => None,
+            Some(forced) => {
+                let forced: u8 = forced.parse().with_context(|| format!("invalid forced board in fen {s:?}"))?;
+                if !(1..=9).contains(&forced) || board.closed & 1 << (forced - 1) != 0 {
+                    anyhow::bail!("forced board {forced} is not playable in fen {s:?}");
+                }
+                Some(forced - 1)
+            }
+        };
+        Ok(board)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::perft;
+
+    #[test]
+    fn startpos_perft() {
+        let board = Board::default();
+        assert_eq!(perft(&board, 1), 81);
+        assert_eq!(perft(&board, 2), 720);
+        assert_eq!(perft(&board, 3), 6336);
+    }
+
+    #[test]
+    fn sent_to_decided_board_plays_anywhere() {
+        // x has won the bottom-left local board, and o is sent there.
+        let board: Board = "9/9/9/9/9/9/x8/1x7/2x6 o -".parse().unwrap();
+        let mut child = board;
+        child.make_move("d1".parse().unwrap());
+        // d1 is cell 0 of local board 1, which sends x back to the won board 0.
+        assert_eq!(child.forced, None);
+        assert_eq!(child.won[0], 1);
+        let mut moves = 0;
+        child.generate_moves(|_| {
+            moves += 1;
+            false
+        });
+        assert_eq!(moves, 81 - 9 - 1);
+    }
+
+    #[test]
+    fn fen_round_trip() {
+        let mut board = Board::default();
+        for mv in ["e5", "d4", "a1"] {
+            board.make_move(mv.parse().unwrap());
+        }
+        let fen = board.fen();
+        assert_eq!(fen, "9/9/9/9/4x4/3o5/9/9/x8 o 1");
+        assert_eq!(fen.parse::<Board>().unwrap(), board);
+    }
+
+    #[test]
+    fn dense_features_match_the_feature_map() {
+        let board: Board = "9/9/9/9/9/9/x8/1x7/2xo5 x 2".parse().unwrap();
+        for board in [Board::default(), board] {
+            let mut sparse = vec![0.0; 4 * CELLS];
+            board.feature_map(|index| sparse[index] = 1.0);
+            let mut dense = vec![0.5; 4 * CELLS];
+            board.write_features(&mut dense);
+            assert_eq!(dense, sparse);
+        }
+    }
+}
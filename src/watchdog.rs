@@ -0,0 +1,148 @@
+//! A watchdog that emits a best move if a search overruns its hard time limit.
+//!
+//! The search loop only checks the clock between iterations, so a blocked evaluation or a
+//! stalled loop could otherwise make the engine lose on time. The watchdog thread is armed with
+//! a deadline counted from the arrival of each timed `go`, and is told the current best move as it changes.
+//! If the deadline passes before the search is disarmed, it prints `bestmove` itself and signals
+//! the search to stop, and the caller must then not print a second `bestmove`.
+
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// How far past the hard limit the watchdog waits before firing, so that it does not race
+/// a search that is stopping normally at the limit.
+pub const GRACE: Duration = Duration::from_millis(25);
+
+#[derive(Default)]
+struct State {
+    /// When the watchdog fires, if it is armed.
+    deadline: Option<Instant>,
+    /// The best move of the search in progress.
+    best_move: Option<String>,
+    /// Whether the watchdog has printed a best move since it was last armed.
+    fired: bool,
+    /// Whether the watchdog thread should exit.
+    shutdown: bool,
+}
+
+pub struct Watchdog {
+    shared: Arc<(Mutex<State>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Starts the watchdog thread, initially disarmed.
+    pub fn spawn() -> anyhow::Result<Self> {
+        let shared = Arc::new((Mutex::new(State::default()), Condvar::new()));
+        let thread_shared = Arc::clone(&shared);
+        let thread = std::thread::Builder::new().name("watchdog".into()).spawn(move || watch(&thread_shared))?;
+        Ok(Self { shared, thread: Some(thread) })
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.shared.0.lock().expect("watchdog lock poisoned")
+    }
+
+    /// Arms the watchdog to fire at `deadline`, forgetting any previous best move.
+    pub fn arm(&self, deadline: Instant) {
+        let mut state = self.state();
+        state.deadline = Some(deadline);
+        state.best_move = None;
+        state.fired = false;
+        drop(state);
+        self.shared.1.notify_one();
+    }
+
+    /// Records the current best move, to be printed if the watchdog fires.
+    pub fn report_best_move(&self, best_move: String) {
+        self.state().best_move = Some(best_move);
+        // a watchdog past its deadline is waiting for a move to print.
+        self.shared.1.notify_one();
+    }
+
+    /// Whether the watchdog has fired, in which case the search should stop at once.
+    pub fn fired(&self) -> bool {
+        self.state().fired
+    }
+
+    /// Disarms the watchdog, returning whether it had already fired and printed a best move.
+    /// The firing is forgotten, so that a later search that never arms the watchdog is not stopped by it.
+    pub fn disarm(&self) -> bool {
+        let mut state = self.state();
+        state.deadline = None;
+        std::mem::take(&mut state.fired)
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.state().shutdown = true;
+        self.shared.1.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch(shared: &(Mutex<State>, Condvar)) {
+    let (lock, condvar) = shared;
+    let mut state = lock.lock().expect("watchdog lock poisoned");
+    while !state.shutdown {
+        let Some(deadline) = state.deadline else {
+            state = condvar.wait(state).expect("watchdog lock poisoned");
+            continue;
+        };
+        let now = Instant::now();
+        if now < deadline {
+            state = condvar.wait_timeout(state, deadline - now).expect("watchdog lock poisoned").0;
+            continue;
+        }
+        // the search has overrun its hard limit, but can only be answered for once it has a move.
+        let Some(best_move) = &state.best_move else {
+            state = condvar.wait(state).expect("watchdog lock poisoned");
+            continue;
+        };
+        println!("info string search exceeded its hard time limit");
+        println!("bestmove {best_move}");
+        std::io::Write::flush(&mut std::io::stdout()).expect("couldn't flush stdout");
+        state.deadline = None;
+        state.fired = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_only_after_deadline() {
+        let watchdog = Watchdog::spawn().unwrap();
+        watchdog.arm(Instant::now() + Duration::from_secs(30));
+        watchdog.report_best_move("a1".into());
+        assert!(!watchdog.disarm());
+
+        watchdog.arm(Instant::now() + Duration::from_millis(10));
+        watchdog.report_best_move("a1".into());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(watchdog.fired());
+        assert!(watchdog.disarm());
+        assert!(!watchdog.fired());
+        assert!(!watchdog.disarm());
+    }
+
+    #[test]
+    fn fires_only_once_there_is_a_move_to_print() {
+        let watchdog = Watchdog::spawn().unwrap();
+        watchdog.arm(Instant::now());
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!watchdog.fired());
+        watchdog.report_best_move("a1".into());
+        while !watchdog.fired() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert!(watchdog.disarm());
+    }
+}